@@ -0,0 +1,236 @@
+use bdd::BootDiskData;
+use crate::bios::{self, RegisterState};
+use crate::time;
+
+const SECTOR_SIZE: usize = 512;
+
+/// Low memory region used as a bounce buffer for all disk reads. It must be accessible by
+/// BIOS (below 0x10000) and it cannot cross 64KB boundary because of ISA DMA limitations.
+/// Memory between the boot disk descriptor (0x9000) and the bootloader (0x10000) is unused.
+const BOUNCE_BUFFER_ADDRESS: usize = 0xa000;
+const BOUNCE_BUFFER_SECTORS: usize = (0x10000 - BOUNCE_BUFFER_ADDRESS) / SECTOR_SIZE;
+
+/// Reads which are at least this big report progress.
+const PROGRESS_THRESHOLD: usize = 1024 * 1024;
+
+/// Reads which are at least this big report throughput.
+const STATISTICS_THRESHOLD: usize = 64 * 1024;
+
+fn extended_reads_supported(boot_disk_data: &BootDiskData) -> bool {
+    let ax: u16 = 0x4100;
+    let bx: u16 = 0x55aa;
+    let dx: u16 = boot_disk_data.disk_number as u16;
+
+    let mut regs = RegisterState {
+        eax: ax as u32,
+        ebx: bx as u32,
+        edx: dx as u32,
+        ..Default::default()
+    };
+
+    unsafe { bios::interrupt(0x13, &mut regs); }
+
+    // The carry flag will be set if extensions are not supported. BX will be also
+    // swapped by BIOSes which support them.
+    regs.eflags & (1 << 0) == 0 && regs.ebx & 0xffff == 0xaa55
+}
+
+fn reset_disk_system(boot_disk_data: &BootDiskData) {
+    let mut regs = RegisterState {
+        eax: 0,
+        edx: boot_disk_data.disk_number as u32,
+        ..Default::default()
+    };
+
+    unsafe { bios::interrupt(0x13, &mut regs); }
+
+    assert!(regs.eflags & 1 == 0, "Reseting boot disk system failed.");
+}
+
+/// Read `sectors` consecutive sectors starting at `lba` to the bounce buffer using
+/// CHS addressing. All sectors must be on the same track.
+fn read_chs(boot_disk_data: &BootDiskData, lba: u32, sectors: usize) {
+    // Convert LBA to CHS using drive geometry from BIOS.
+    let cylinder = lba / boot_disk_data.sectors_per_cylinder;
+    let head     = (lba / boot_disk_data.sectors_per_track) %
+                    boot_disk_data.heads_per_cylinder;
+    let sector   = lba % boot_disk_data.sectors_per_track + 1;
+
+    assert!(cylinder < 1024, "LBA {} cannot be addressed using CHS.", lba);
+
+    for tries in 0..5 {
+        // If we have failed before, restart boot disk system.
+        if tries > 0 {
+            reset_disk_system(boot_disk_data);
+        }
+
+        // Setup proper register state to perform the read.
+
+        let al: u8 = sectors as u8;
+        let ah: u8 = 2;
+
+        let cl: u8 = (sector as u8) | ((cylinder >> 2) & 0xc0) as u8;
+        let ch: u8 = cylinder as u8;
+
+        let dl: u8 = boot_disk_data.disk_number;
+        let dh: u8 = head as u8;
+
+        // Ask BIOS to read the sectors.
+        let mut regs = RegisterState {
+            eax: ((ah as u32) << 8) | ((al as u32) << 0),
+            ecx: ((ch as u32) << 8) | ((cl as u32) << 0),
+            edx: ((dh as u32) << 8) | ((dl as u32) << 0),
+            ebx: BOUNCE_BUFFER_ADDRESS as u32,
+            ..Default::default()
+        };
+
+        unsafe { bios::interrupt(0x13, &mut regs); }
+
+        if regs.eax & 0xff == sectors as u32 && regs.eflags & 1 == 0 {
+            return;
+        }
+
+        println!("Retrying disk read...");
+    }
+
+    panic!("Failed to read {} sectors from disk at LBA {}.", sectors, lba);
+}
+
+/// Read `sectors` consecutive sectors starting at `lba` to the bounce buffer using
+/// extended disk services.
+fn read_extended(boot_disk_data: &BootDiskData, lba: u32, sectors: usize) {
+    #[repr(C)]
+    struct DiskAddressPacket {
+        size:    u8,
+        zero:    u8,
+        sectors: u16,
+        offset:  u16,
+        segment: u16,
+        lo_lba:  u32,
+        hi_lba:  u32,
+    }
+
+    // Make sure that disk address packet has expected layout.
+    assert!(core::mem::size_of::<DiskAddressPacket>() == 16 &&
+            core::mem::align_of::<DiskAddressPacket>() >= 4,
+            "Invalid shape of disk address packet.");
+
+    for tries in 0..5 {
+        // If we have failed before, restart boot disk system.
+        if tries > 0 {
+            reset_disk_system(boot_disk_data);
+        }
+
+        // BIOS may change the sector count on failure so the DAP is recreated every time.
+        let mut dap = DiskAddressPacket {
+            size:    16,
+            zero:    0,
+            sectors: sectors as u16,
+            offset:  BOUNCE_BUFFER_ADDRESS as u16,
+            segment: 0,
+            lo_lba:  lba,
+            hi_lba:  0,
+        };
+
+        let dap_ptr = &mut dap as *mut _ as usize;
+
+        // Make sure that the DAP is accessible for BIOS.
+        assert!(dap_ptr.checked_add(16).unwrap() < 0x10000, "DAP is inaccesible for BIOS.");
+
+        // Setup proper register state to perform the extended read and ask BIOS to
+        // read the sectors.
+        let mut regs = RegisterState {
+            eax: 0x4200,
+            edx: boot_disk_data.disk_number as u32,
+            esi: dap_ptr as u32,
+            ..Default::default()
+        };
+
+        unsafe { bios::interrupt(0x13, &mut regs); }
+
+        if regs.eflags & 1 == 0 {
+            return;
+        }
+
+        println!("Retrying disk read...");
+    }
+
+    panic!("Failed to read {} sectors from disk at LBA {}.", sectors, lba);
+}
+
+/// Read consecutive sectors starting at `lba` to fill the whole `buffer`. Size of the buffer
+/// must be a multiple of sector size. Big reads report progress and throughput using `name`.
+pub fn read_sectors(boot_disk_data: &BootDiskData, lba: u32, buffer: &mut [u8], name: &str) {
+    assert!(buffer.len() % SECTOR_SIZE == 0, "Buffer size is not a multiple of sector size.");
+
+    // Check if extended disk services are available.
+    let extended_reads_supported = extended_reads_supported(boot_disk_data);
+
+    let total_sectors = buffer.len() / SECTOR_SIZE;
+    let report        = buffer.len() >= PROGRESS_THRESHOLD;
+    let start_tsc     = time::get_tsc();
+
+    let mut sector   = 0;
+    let mut reported = 0;
+
+    if report {
+        print!("Reading {}:", name);
+    }
+
+    while sector < total_sectors {
+        // BDD LBAs are relative to the boot partition.
+        let lba       = boot_disk_data.partition_lba + lba + sector as u32;
+        let remaining = total_sectors - sector;
+
+        // Read as many sectors as fit in the bounce buffer. CHS reads cannot cross track
+        // boundary on some BIOSes so they are additionally limited to the current track.
+        let sectors = if extended_reads_supported {
+            let sectors = remaining.min(BOUNCE_BUFFER_SECTORS);
+
+            read_extended(boot_disk_data, lba, sectors);
+
+            sectors
+        } else {
+            let track_sectors = boot_disk_data.sectors_per_track;
+            let track_left    = (track_sectors - lba % track_sectors) as usize;
+            let sectors       = remaining.min(BOUNCE_BUFFER_SECTORS).min(track_left);
+
+            read_chs(boot_disk_data, lba, sectors);
+
+            sectors
+        };
+
+        // Copy read sectors from the bounce buffer to the actual destination.
+        let size   = sectors * SECTOR_SIZE;
+        let offset = sector * SECTOR_SIZE;
+
+        let bounce_buffer = unsafe {
+            core::slice::from_raw_parts(BOUNCE_BUFFER_ADDRESS as *const u8, size)
+        };
+
+        buffer[offset..][..size].copy_from_slice(bounce_buffer);
+
+        sector += sectors;
+
+        // Report progress in 10% steps.
+        let percent = sector * 100 / total_sectors;
+
+        if report && percent / 10 > reported / 10 {
+            print!(" {}%", percent / 10 * 10);
+
+            reported = percent;
+        }
+    }
+
+    if report {
+        println!();
+    }
+
+    if buffer.len() >= STATISTICS_THRESHOLD {
+        let ms = time::elapsed_ms(start_tsc).max(1);
+        let kb = (buffer.len() / 1024) as u64;
+
+        println!("Read {} KB of {} in {} ms ({} KB/s, {} reads).", kb, name, ms,
+                 kb * 1000 / ms, if extended_reads_supported { "extended" } else { "CHS" });
+    }
+}
@@ -7,6 +7,9 @@
 ; VGA memory size (in words).
 %define VGA_SIZE_WORDS 25 * 80
 
+; Offset of the MBR partition table.
+%define PARTITION_TABLE 446
+
 ; Ensure that CS == 0 and IP == 0x7C00.
 jmp 0x00:entry_16
 
@@ -77,13 +80,18 @@ entry_16:
     mul     dword [sectors_per_track]
     mov     dword [sectors_per_cylinder], eax
 
-    ; Read 2 additional sectors so bootloader ends at 0x8200. Skip LBA 1 because it contains
-    ; boot disk descriptor.
+    ; Everything else is stored in the boot partition which must be the first entry in
+    ; the MBR partition table. All further reads are relative to its start.
+    mov     eax, dword [0x7c00 + PARTITION_TABLE + 8]
+    mov     dword [partition_lba], eax
+
+    ; Read 2 additional sectors so bootloader ends at 0x8200. Skip partition LBA 0 because
+    ; it contains boot disk descriptor.
     mov     ebx, 0x7c00 + 1 * 0x200
-    mov     eax, 2
+    mov     eax, 1
     call    read_sector
     mov     ebx, 0x7c00 + 2 * 0x200
-    mov     eax, 3
+    mov     eax, 2
     call    read_sector
 
     ; We are out of space so jump to newly loaded part of bootloader.
@@ -91,7 +99,7 @@ entry_16:
 
 ; Read disk sector into buffer. Works only with 16 byte aligned buffers
 ; in low (<1MB) memory.
-; Input: EAX = LBA
+; Input: EAX = LBA (relative to the boot partition)
 ;        EBX = buffer address
 ; Doesn't return on error.
 ; Doesn't clobber anything.
@@ -140,6 +148,7 @@ read_sector:
         ; Convert LBA to CHS. BIOS routines use CHS. Output values are already
         ; in registers used by INT 0x13, AH=0x02.
         mov     eax, dword [esp]
+        add     eax, dword [partition_lba]
         call    lba_to_chs
 
         ; Read 1 sector into buffer. Data is already filled by previous
@@ -283,6 +292,7 @@ disk_number:            db 0
 sectors_per_track:      dd 0
 heads_per_cylinder:     dd 0
 sectors_per_cylinder:   dd 0
+partition_lba:          dd 0
 
 ; Error message. Set it before calling error_16.
 error_string_addr: dw 0
@@ -295,8 +305,8 @@ read_error_str:   db "READ FAIL.",   0
 ; disk. This is 0 when launching APs.
 first_boot: db 1
 
-; Data between byte 446 and 510 will be overwritten by BIOS.
-%if ($ - $$) > 446
+; Data between byte 446 and 510 will be overwritten by the partition table.
+%if ($ - $$) > PARTITION_TABLE
 %error "Code over 446 bytes", $
 %endif
 
@@ -304,6 +314,7 @@ first_boot: db 1
 times 510 - ($ - $$) db 0x00
 dw 0xaa55
 
+; Boot disk descriptor is stored in the first sector of the boot partition.
 %define BDD_SIGNATURE   0x1778cf9d
 %define BOOT_DISK_DESC  0x9000
 %define BOOTLOADER_BASE 0x10000
@@ -311,7 +322,7 @@ dw 0xaa55
 entry_16_continue:
     ; Read boot disk descriptor.
     mov     ebx, BOOT_DISK_DESC
-    mov     eax, 1
+    mov     eax, 0
     call    read_sector
 
     mov     word [error_string_addr], signature_error_str
@@ -79,9 +79,10 @@ enter_kernel:
     ; Load kernel arguments.
     mov rdi, qword [rsp + 0x14]
     mov rsi, qword [rsp + 0x2c]
+    mov rdx, qword [rsp + 0x24]
 
     ; Get the entrypoint of the kernel.
-    mov rdx, qword [rsp + 0x04]
+    mov rcx, qword [rsp + 0x04]
 
     ; Get the actual page tables used by the kernel.
     mov eax, dword [rsp + 0x1c]
@@ -97,7 +98,7 @@ enter_kernel:
     sub rsp, 0x30
 
     ; Call the 64 bit kernel! (Jump cannot be used because of the ABI.)
-    call rdx
+    call rcx
 
 ; GDT used to enter long mode.
 align 8
@@ -11,5 +11,3 @@ impl lock::Interrupts for EmptyInterrupts {
     unsafe fn enable_interrupts()  {}
     unsafe fn disable_interrupts() {}
 }
-
-pub type Lock<T> = lock::Lock<T, EmptyInterrupts>;
@@ -0,0 +1,55 @@
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// TSC frequency in KHz. It's 0 if TSC wasn't calibrated yet.
+static TSC_KHZ: AtomicU32 = AtomicU32::new(0);
+
+/// Get the current TSC value.
+pub fn get_tsc() -> u64 {
+    unsafe { core::arch::x86::_rdtsc() }
+}
+
+/// Get the number of milliseconds elapsed since `start_tsc`.
+pub fn elapsed_ms(start_tsc: u64) -> u64 {
+    let khz = TSC_KHZ.load(Ordering::Relaxed);
+
+    assert!(khz != 0, "TSC wasn't calibrated yet.");
+
+    get_tsc().saturating_sub(start_tsc) / khz as u64
+}
+
+/// Calibrate the TSC using PIT channel 2. Timing is only used to report statistics so
+/// precision isn't very important.
+pub unsafe fn initialize() {
+    const PIT_FREQUENCY:  u64 = 1_193_182;
+    const CALIBRATION_MS: u64 = 10;
+
+    // Skip calibration if it was already done by other CPU.
+    if TSC_KHZ.load(Ordering::Relaxed) != 0 {
+        return;
+    }
+
+    let ticks = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+
+    // Enable channel 2 gate and disable the PC speaker.
+    let port61 = cpu::inb(0x61);
+    cpu::outb(0x61, (port61 & !0x02) | 0x01);
+
+    // Channel 2, low byte and high byte access, mode 0 (interrupt on terminal count).
+    cpu::outb(0x43, 0b1011_0000);
+    cpu::outb(0x42, ticks as u8);
+    cpu::outb(0x42, (ticks >> 8) as u8);
+
+    let start_tsc = get_tsc();
+
+    // Wait until channel 2 output goes high, which happens when the counter reaches 0.
+    while cpu::inb(0x61) & 0x20 == 0 {}
+
+    let end_tsc = get_tsc();
+
+    cpu::outb(0x61, port61);
+
+    let khz = ((end_tsc - start_tsc) / CALIBRATION_MS).max(1);
+
+    TSC_KHZ.store(khz.try_into().unwrap_or(u32::MAX), Ordering::Relaxed);
+}
@@ -1,14 +1,69 @@
 #!/bin/bash
 
-DEVICE=/dev/sda1
+# Install Flugzeug to a pendrive.
+#
+# ./install_to_pendrive.sh [uefi] [PARTITION]
+#     Copy the UEFI bootloader to an existing FAT partition (default: /dev/sda1) which
+#     contains the `EFI/boot/flugzeug_marker` file. The kernel must be embedded in the
+#     bootloader (images built without `--separate-kernel`).
+#
+# ./install_to_pendrive.sh bios|hybrid DISK
+#     Write `build/flugzeug_bios` or `build/flugzeug_hybrid` to the whole DISK (like
+#     /dev/sdb, not a partition). Everything on DISK is overwritten.
+#
+# BIOS and hybrid images are partitioned. Early bootloader in the MBR reads the boot
+# partition LBA from the first MBR partition entry (offset 446 + 8), so the image must start
+# at the first sector of the disk. Images with a GPT (`--hybrid-gpt` and hybrid images) have
+# the backup GPT at the end of the image instead of the end of the disk. Partitioning tools
+# will complain about it, `sgdisk -e DISK` moves it to the end of the disk.
 
-mount $DEVICE /mnt/pendrive
+TARGET=${1:-uefi}
 
-# Check for marker empty file to make sure we won't replace something important.
-if test -f "/mnt/pendrive/EFI/boot/flugzeug_marker"; then
-    cp build/uefi_bootloader/x86_64-unknown-uefi/release/uefi_bootloader.efi /mnt/pendrive/EFI/boot/BOOTX64.efi
-else
-    echo "No marker found - invalid device."
-fi
+case $TARGET in
+    uefi)
+        DEVICE=${2:-/dev/sda1}
 
-umount /mnt/pendrive
+        mount $DEVICE /mnt/pendrive
+
+        # Check for marker empty file to make sure we won't replace something important.
+        if test -f "/mnt/pendrive/EFI/boot/flugzeug_marker"; then
+            cp build/uefi_bootloader/x86_64-unknown-uefi/release/uefi_bootloader.efi /mnt/pendrive/EFI/boot/BOOTX64.efi
+        else
+            echo "No marker found - invalid device."
+        fi
+
+        umount /mnt/pendrive
+        ;;
+    bios|hybrid)
+        DEVICE=$2
+        IMAGE=build/flugzeug_$TARGET
+
+        if test -z "$DEVICE"; then
+            echo "Writing $TARGET image requires a disk device."
+            exit 1
+        fi
+
+        # Partitioned image must be written to the whole disk, not to one of its partitions.
+        if test "$(lsblk -dno TYPE $DEVICE)" != "disk"; then
+            echo "$DEVICE is not a whole disk."
+            exit 1
+        fi
+
+        if ! test -f $IMAGE; then
+            echo "$IMAGE doesn't exist, build it first."
+            exit 1
+        fi
+
+        read -p "Everything on $DEVICE will be overwritten. Type \"yes\" to continue: " ANSWER
+
+        if test "$ANSWER" != "yes"; then
+            exit 1
+        fi
+
+        dd if=$IMAGE of=$DEVICE bs=1M conv=fsync
+        ;;
+    *)
+        echo "Unknown target $TARGET (uefi, bios or hybrid)."
+        exit 1
+        ;;
+esac
@@ -1,4 +1,4 @@
 fn main() {
-    asm::link(&["src/interrupts.asm"], asm::Format::Elf64);
+    asm::link(&["src/interrupts.asm", "src/pvh.asm"], asm::Format::Elf64);
     asm::embed(&["src/vm/vkernel.asm"]);
 }
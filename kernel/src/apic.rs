@@ -1,12 +1,15 @@
 use page_table::PhysAddr;
-use crate::{mm, time};
+use crate::{mm, time, config};
 
 const IA32_APIC_BASE: u32 = 0x1b;
 
-pub const APIC_TIMER_IRQ:    u8  = 0xfe;
-pub const SPURIOUS_IRQ:      u8  = 0xff;
-pub const PIC_BASE_IRQ:      u8  = 32;
-pub const APIC_TIMER_PERIOD: f64 = 0.1;
+pub const TLB_SHOOTDOWN_IRQ: u8 = 0xfd;
+pub const APIC_TIMER_IRQ:    u8 = 0xfe;
+pub const SPURIOUS_IRQ:      u8 = 0xff;
+pub const PIC_BASE_IRQ:      u8 = 32;
+
+/// ICR value which sends NMI to the destination core.
+pub const NMI_IPI: u32 = (1 << 14) | (0b100 << 8);
 
 pub enum Register {
     ApicID                   = 0x20,
@@ -122,12 +125,13 @@ impl Apic {
         self.write(Register::TimerLvt,                 lvt);
         self.write(Register::TimerInitialCount,        0xffff_ffff);
 
+        let period     = config::apic_timer_period();
         let start_time = time::get();
 
-        // Wait for about `APIC_TIMER_PERIOD` seconds.
+        // Wait for about `period` seconds.
         loop {
             let time = time::get();
-            if  time::difference(start_time, time) >= APIC_TIMER_PERIOD {
+            if  time::difference(start_time, time) >= period {
                 break;
             }
         }
@@ -135,15 +139,15 @@ impl Apic {
         // Stop the APIC timer.
         self.write(Register::TimerLvt, masked_lvt);
 
-        // Get the amount of ticks it takes to elapse `APIC_TIMER_PERIOD` seconds.
+        // Get the amount of ticks it takes to elapse `period` seconds.
         let ticks = 0xffff_ffff - self.read(Register::TimerCurrentCount);
 
         if core!().id == 0 {
             println!("APIC timer period: {}ms ({} ticks).",
-                     (APIC_TIMER_PERIOD * 1000.0) as u32, ticks);
+                     (period * 1000.0) as u32, ticks);
         }
 
-        // Configure the APIC timer to tick in `APIC_TIMER_PERIOD`.
+        // Configure the APIC timer to tick in `period`.
         self.write(Register::TimerLvt,          periodic_lvt);
         self.write(Register::TimerInitialCount, ticks);
     }
@@ -0,0 +1,238 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::arch::{asm, global_asm};
+use core::fmt;
+
+use boot_block::{KERNEL_STACK_SIZE, KERNEL_STACK_PADDING};
+use symbol_table::SymbolTable;
+
+/// Maximum number of frames which will be printed in a single backtrace.
+const MAX_FRAMES: usize = 32;
+
+// Space reserved for the symbol table. Host builder fills it after the kernel is linked.
+global_asm!(concat!(r#"
+    .pushsection "#, symbol_table::section_name!(), r#", "a", @progbits
+    .balign 16
+    .global KERNEL_SYMBOL_TABLE
+    .global KERNEL_SYMBOL_TABLE_END
+    KERNEL_SYMBOL_TABLE:
+    .space 256 * 1024
+    KERNEL_SYMBOL_TABLE_END:
+    .popsection
+"#));
+
+extern "C" {
+    static KERNEL_SYMBOL_TABLE:     u8;
+    static KERNEL_SYMBOL_TABLE_END: u8;
+
+    /// ELF header is loaded at the start of the kernel image. Defined by the linker.
+    static __ehdr_start: u8;
+}
+
+/// State of the code interrupted by an unhandled exception. Panic handler uses it to print
+/// backtrace of the faulting code instead of the exception handler.
+pub struct ExceptionContext {
+    rip: AtomicU64,
+    rbp: AtomicU64,
+    rsp: AtomicU64,
+}
+
+impl ExceptionContext {
+    pub const fn new() -> Self {
+        Self {
+            rip: AtomicU64::new(0),
+            rbp: AtomicU64::new(0),
+            rsp: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set(&self, rip: u64, rbp: u64, rsp: u64) {
+        self.rbp.store(rbp, Ordering::Relaxed);
+        self.rsp.store(rsp, Ordering::Relaxed);
+        self.rip.store(rip, Ordering::Relaxed);
+    }
+
+    /// Get saved state (RIP, RBP, RSP) and clear it.
+    pub fn take(&self) -> Option<(u64, u64, u64)> {
+        let rip = self.rip.swap(0, Ordering::Relaxed);
+        if  rip == 0 {
+            return None;
+        }
+
+        Some((rip, self.rbp.load(Ordering::Relaxed), self.rsp.load(Ordering::Relaxed)))
+    }
+}
+
+fn symbol_table() -> Option<SymbolTable<'static>> {
+    let table = unsafe {
+        let start = &KERNEL_SYMBOL_TABLE     as *const u8;
+        let end   = &KERNEL_SYMBOL_TABLE_END as *const u8;
+
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    };
+
+    SymbolTable::parse(table)
+}
+
+fn kernel_base() -> u64 {
+    unsafe { &__ehdr_start as *const u8 as u64 }
+}
+
+/// Get name of the function which contains `address` and the offset within it.
+pub fn symbolize(address: u64) -> Option<(&'static str, u64)> {
+    let offset = address.checked_sub(kernel_base())?;
+
+    symbol_table()?
+        .lookup(offset)
+        .map(|(symbol, offset)| (symbol.name, offset))
+}
+
+/// Get bounds of the kernel stack which contains `rsp`. Stacks of all cores are allocated
+/// by the bootloader at fixed stride from the layout stack base.
+fn stack_bounds(rsp: u64) -> Option<(u64, u64)> {
+    if !crate::panic::has_core_locals() {
+        return None;
+    }
+
+    // Don't lock, this is used on panic when the lock may be held.
+    let stack_base = unsafe { (*core!().boot_block.kernel_layout.bypass()).stack_base };
+    let stride     = KERNEL_STACK_SIZE + KERNEL_STACK_PADDING;
+
+    let index = rsp.checked_sub(stack_base)? / stride;
+    let start = stack_base + index * stride;
+    let end   = start + KERNEL_STACK_SIZE;
+
+    if rsp < end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+/// Walk frame pointer chain starting at `rbp` and call `callback` with every return address.
+/// `rsp` is used to find the stack, frames outside of it stop the walk.
+pub unsafe fn walk(rbp: u64, rsp: u64, mut callback: impl FnMut(u64)) {
+    let (_, stack_end) = match stack_bounds(rsp) {
+        Some(bounds) => bounds,
+        None         => return,
+    };
+
+    let mut frame = rbp;
+    let mut lower = rsp;
+
+    for _ in 0..MAX_FRAMES {
+        // Every next frame must be higher on the stack, otherwise the chain is corrupted.
+        if frame % 8 != 0 || frame < lower || frame + 16 > stack_end {
+            break;
+        }
+
+        let next           = core::ptr::read_volatile(frame as *const u64);
+        let return_address = core::ptr::read_volatile((frame + 8) as *const u64);
+
+        if return_address == 0 {
+            break;
+        }
+
+        callback(return_address);
+
+        lower = frame + 16;
+        frame = next;
+    }
+}
+
+/// Get RBP and RSP of the caller.
+#[inline(always)]
+pub fn current_frame() -> (u64, u64) {
+    let rbp: u64;
+    let rsp: u64;
+
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp);
+        asm!("mov {}, rsp", out(reg) rsp);
+    }
+
+    (rbp, rsp)
+}
+
+fn write_frame(f: &mut dyn fmt::Write, index: usize, address: u64,
+               lookup: u64) -> fmt::Result {
+    write!(f, "  {:>2}: {:x}", index, address)?;
+
+    if let Some((name, offset)) = symbolize(lookup) {
+        writeln!(f, " {}+{:#x}", name, offset + (address - lookup))
+    } else {
+        writeln!(f, " <unknown>")
+    }
+}
+
+/// Print backtrace which starts at `rip` (if known) and continues with frames from `rbp`.
+pub unsafe fn print(f: &mut dyn fmt::Write, rip: Option<u64>, rbp: u64, rsp: u64) {
+    let _ = writeln!(f, "Backtrace:");
+
+    let mut index = 0;
+
+    if let Some(rip) = rip {
+        let _ = write_frame(f, index, rip, rip);
+
+        index += 1;
+    }
+
+    walk(rbp, rsp, |return_address| {
+        // Return address may point to the next function if the call was the last
+        // instruction, symbolize the call instead.
+        let _ = write_frame(f, index, return_address, return_address - 1);
+
+        index += 1;
+    });
+
+    if index == 0 {
+        let _ = writeln!(f, "  <unavailable>");
+    }
+}
+
+#[cfg(feature = "kernel_tests")]
+mod tests {
+    use super::*;
+    use crate::tests::kernel_test;
+    use alloc::vec::Vec;
+
+    #[inline(never)]
+    fn capture_backtrace() -> Vec<u64> {
+        let (rbp, rsp) = current_frame();
+
+        let mut addresses = Vec::new();
+
+        unsafe {
+            walk(rbp, rsp, |address| addresses.push(address));
+        }
+
+        addresses
+    }
+
+    #[inline(never)]
+    fn backtrace_caller() -> Vec<u64> {
+        let addresses = capture_backtrace();
+
+        // Use the result so the call isn't a tail call and this function stays on the stack.
+        assert!(!addresses.is_empty(), "Backtrace is empty.");
+
+        addresses
+    }
+
+    #[kernel_test]
+    fn symbolizes_backtrace() {
+        assert!(symbol_table().is_some(), "Kernel symbol table wasn't embedded.");
+
+        let (name, offset) = symbolize(capture_backtrace as usize as u64).unwrap();
+
+        assert!(name.ends_with("capture_backtrace"), "Invalid symbol {}.", name);
+        assert_eq!(offset, 0);
+
+        let addresses = backtrace_caller();
+
+        assert!(addresses.len() >= 2, "Backtrace is too short.");
+
+        let (name, _) = symbolize(addresses[0] - 1).unwrap();
+
+        assert!(name.ends_with("backtrace_caller"), "Invalid symbol {}.", name);
+    }
+}
@@ -0,0 +1,585 @@
+// Buddy allocator for physically contiguous memory. It takes naturally aligned chunks from
+// the free memory set and splits them into blocks of `2^order` pages. Freed blocks are merged
+// with their buddies and big enough blocks are returned to the free memory set. Memory is
+// split into DMA zones so devices with addressing limits can get memory they can access.
+// Free blocks are linked into per zone and order lists using headers stored in the blocks
+// themselves, so the allocator never allocates memory.
+
+use page_table::PhysAddr;
+use crate::lock::Lock;
+use crate::mm;
+
+/// Biggest block order. Blocks of this order are 1G in size.
+pub const MAX_ORDER: usize = 18;
+
+/// Order of chunks taken from the free memory set (2M). Free blocks of this order or bigger
+/// are given back immediately.
+const CHUNK_ORDER: usize = 9;
+
+const ZONE_COUNT: usize = 3;
+
+/// Marks the end of a free list.
+const NO_BLOCK: u64 = !0;
+
+/// Mixed with address and order of every free block and stored in its header.
+const FREE_BLOCK_TAG: u64 = 0x4255_4444_5946_5245;
+
+static BUDDY: Lock<Option<BuddyAllocator>> = Lock::new(None);
+
+/// Physical memory zone with an addressing limit.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DmaZone {
+    /// Memory below 16M, accessible by ISA DMA.
+    Below16M = 0,
+
+    /// Memory below 4G, accessible by devices which support only 32 bit addresses.
+    Below4G  = 1,
+
+    /// Any memory accessible by the kernel.
+    Any      = 2,
+}
+
+impl DmaZone {
+    /// Get the lowest address which isn't accessible by more restrictive zones.
+    fn min_address(self) -> u64 {
+        match self {
+            DmaZone::Below16M => 0,
+            DmaZone::Below4G  => DmaZone::Below16M.max_address() + 1,
+            DmaZone::Any      => DmaZone::Below4G.max_address() + 1,
+        }
+    }
+
+    fn max_address(self) -> u64 {
+        match self {
+            DmaZone::Below16M => 16 * 1024 * 1024 - 1,
+            DmaZone::Below4G  => 4 * 1024 * 1024 * 1024 - 1,
+            DmaZone::Any      => mm::MAX_ACCESSIBLE_PHYSICAL_ADDRESS,
+        }
+    }
+
+    /// Zones which can be used when this zone has no free blocks, most preferred first.
+    fn fallbacks(self) -> &'static [DmaZone] {
+        match self {
+            DmaZone::Below16M => &[],
+            DmaZone::Below4G  => &[DmaZone::Below16M],
+            DmaZone::Any      => &[DmaZone::Below4G, DmaZone::Below16M],
+        }
+    }
+
+    /// Get the most restrictive zone which contains the whole block.
+    fn of_block(start: u64, order: usize) -> Self {
+        let end = start + block_size(order) - 1;
+
+        if end <= DmaZone::Below16M.max_address() {
+            DmaZone::Below16M
+        } else if end <= DmaZone::Below4G.max_address() {
+            DmaZone::Below4G
+        } else {
+            DmaZone::Any
+        }
+    }
+}
+
+fn block_size(order: usize) -> u64 {
+    4096 << order
+}
+
+/// Get the smallest order of a block which can hold `size` bytes.
+fn order_for(size: u64) -> usize {
+    let pages = (size + 0xfff) / 4096;
+
+    (64 - (pages - 1).leading_zeros()) as usize
+}
+
+/// Header stored at the start of every free block.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct FreeBlock {
+    /// `FREE_BLOCK_TAG` mixed with the address and order of this block. Together with links
+    /// of neighbouring blocks it tells if the buddy of a freed block is free.
+    tag: u64,
+
+    /// Next and previous blocks in the free list or `NO_BLOCK`.
+    next: u64,
+    prev: u64,
+}
+
+impl FreeBlock {
+    fn tag(block: u64, order: usize) -> u64 {
+        FREE_BLOCK_TAG ^ block ^ order as u64
+    }
+
+    /// Get the header of `block` in the physical memory map. Returns `None` if the block is
+    /// not accessible.
+    fn get(block: u64) -> Option<*mut FreeBlock> {
+        unsafe {
+            mm::translate(PhysAddr(block), core::mem::size_of::<FreeBlock>())
+                .map(|header| header as *mut FreeBlock)
+        }
+    }
+
+    fn read(block: u64) -> FreeBlock {
+        let header = Self::get(block).expect("Free block is not accessible.");
+
+        unsafe { header.read() }
+    }
+
+    fn write(block: u64, value: FreeBlock) {
+        let header = Self::get(block).expect("Free block is not accessible.");
+
+        unsafe { header.write(value) }
+    }
+}
+
+/// Doubly linked list of free blocks with the same zone and order.
+#[derive(Copy, Clone)]
+struct FreeList {
+    head:  u64,
+    count: u64,
+}
+
+impl Default for FreeList {
+    fn default() -> Self {
+        Self {
+            head:  NO_BLOCK,
+            count: 0,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BuddyAllocator {
+    /// Free blocks for every zone and order.
+    free_lists: [[FreeList; MAX_ORDER + 1]; ZONE_COUNT],
+}
+
+impl BuddyAllocator {
+    /// Add free `block` with `order` to the free list of `zone`.
+    fn push(&mut self, zone: DmaZone, order: usize, block: u64) {
+        let list = &mut self.free_lists[zone as usize][order];
+
+        FreeBlock::write(block, FreeBlock {
+            tag:  FreeBlock::tag(block, order),
+            next: list.head,
+            prev: NO_BLOCK,
+        });
+
+        if list.head != NO_BLOCK {
+            FreeBlock::write(list.head, FreeBlock {
+                prev: block,
+                ..FreeBlock::read(list.head)
+            });
+        }
+
+        list.head   = block;
+        list.count += 1;
+    }
+
+    /// Remove `block` with `order` from the free list of `zone`. Block must be on that list.
+    fn unlink(&mut self, zone: DmaZone, order: usize, block: u64) {
+        let list   = &mut self.free_lists[zone as usize][order];
+        let header = FreeBlock::read(block);
+
+        if header.prev == NO_BLOCK {
+            list.head = header.next;
+        } else {
+            FreeBlock::write(header.prev, FreeBlock {
+                next: header.next,
+                ..FreeBlock::read(header.prev)
+            });
+        }
+
+        if header.next != NO_BLOCK {
+            FreeBlock::write(header.next, FreeBlock {
+                prev: header.prev,
+                ..FreeBlock::read(header.next)
+            });
+        }
+
+        list.count -= 1;
+
+        // Make sure that the block won't look free after it is allocated or merged.
+        FreeBlock::write(block, FreeBlock {
+            tag:  0,
+            next: NO_BLOCK,
+            prev: NO_BLOCK,
+        });
+    }
+
+    /// Check if `block` with `order` is on the free list of `zone`. Memory of blocks which
+    /// aren't free can contain anything, so the tag is not enough. Block must be also
+    /// linked from the list head or from another free block.
+    fn is_free(&self, zone: DmaZone, order: usize, block: u64) -> bool {
+        let header = match FreeBlock::get(block) {
+            Some(header) => unsafe { header.read() },
+            None         => return false,
+        };
+
+        if header.tag != FreeBlock::tag(block, order) {
+            return false;
+        }
+
+        if header.prev == NO_BLOCK {
+            return self.free_lists[zone as usize][order].head == block;
+        }
+
+        match FreeBlock::get(header.prev) {
+            Some(prev) => {
+                let prev = unsafe { prev.read() };
+
+                prev.tag == FreeBlock::tag(header.prev, order) && prev.next == block
+            }
+            None => false,
+        }
+    }
+
+    /// Take a free block with at least `order` from `zone` and split it so it has exactly
+    /// `order`.
+    fn take_block(&mut self, zone: DmaZone, order: usize) -> Option<u64> {
+        let free_lists = &self.free_lists[zone as usize];
+
+        let found = (order..=MAX_ORDER).find(|&order| free_lists[order].head != NO_BLOCK)?;
+        let block = free_lists[found].head;
+
+        self.unlink(zone, found, block);
+        self.split(block, found, order);
+
+        Some(block)
+    }
+
+    /// Split `block` with `from` order so its first part has `to` order. Other parts are
+    /// added to the free lists.
+    fn split(&mut self, block: u64, from: usize, to: usize) {
+        for order in (to..from).rev() {
+            let upper = block + block_size(order);
+
+            self.insert(upper, order);
+        }
+    }
+
+    fn insert(&mut self, block: u64, order: usize) {
+        self.push(DmaZone::of_block(block, order), order, block);
+    }
+
+    /// Take a new chunk which can hold `order` block from the free memory set. Chunk is taken
+    /// only from memory which isn't accessible by more restrictive zones.
+    fn refill(&mut self, zone: DmaZone, order: usize) -> Option<u64> {
+        let mut free_memory = core!().boot_block.free_memory.lock();
+
+        // Prefer big chunks so small allocations don't fragment the free memory set.
+        // Zones may be fragmented, so fall back to the exact size.
+        for &chunk_order in &[order.max(CHUNK_ORDER), order] {
+            let size  = block_size(chunk_order);
+            let chunk = free_memory.allocate_in_range(size, size, zone.min_address(),
+                                                      Some(zone.max_address()));
+
+            if let Some(chunk) = chunk {
+                drop(free_memory);
+
+                self.split(chunk as u64, chunk_order, order);
+
+                return Some(chunk as u64);
+            }
+        }
+
+        None
+    }
+
+    fn allocate_block(&mut self, zone: DmaZone, order: usize) -> Option<u64> {
+        if let Some(block) = self.take_block(zone, order) {
+            return Some(block);
+        }
+
+        if let Some(block) = self.refill(zone, order) {
+            return Some(block);
+        }
+
+        // Memory which is accessible by more restrictive zones is used only when there is
+        // nothing else, so devices which need it don't run out of it.
+        zone.fallbacks()
+            .iter()
+            .find_map(|&zone| self.take_block(zone, order).or_else(|| self.refill(zone, order)))
+    }
+
+    /// Allocate `size` bytes of physically contiguous memory aligned to `align` in `zone`.
+    /// Returns `None` if there is not enough memory.
+    pub fn allocate(&mut self, size: u64, align: u64, zone: DmaZone) -> Option<PhysAddr> {
+        assert!(size > 0 && size & 0xfff == 0, "Contiguous size {:x} is invalid.", size);
+        assert!(align.count_ones() == 1, "Contiguous alignment {:x} is invalid.", align);
+
+        let order = order_for(size.max(align));
+        if  order > MAX_ORDER {
+            return None;
+        }
+
+        let block = self.allocate_block(zone, order)?;
+
+        // Give back part of the block which wasn't requested.
+        self.free(block + size, block_size(order) - size);
+
+        Some(PhysAddr(block))
+    }
+
+    /// Free `size` bytes of memory at `start` which was allocated by this allocator.
+    pub fn free(&mut self, mut start: u64, mut size: u64) {
+        assert!(start & 0xfff == 0 && size & 0xfff == 0, "Freed region is not page aligned.");
+
+        // Split region into naturally aligned blocks.
+        while size > 0 {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&order| {
+                    let block_size = block_size(order);
+
+                    start & (block_size - 1) == 0 && block_size <= size
+                })
+                .unwrap();
+
+            self.free_block(start, order);
+
+            start += block_size(order);
+            size  -= block_size(order);
+        }
+    }
+
+    fn free_block(&mut self, mut block: u64, mut order: usize) {
+        loop {
+            if order >= CHUNK_ORDER {
+                let freed = unsafe {
+                    mm::try_free_phys(PhysAddr(block), block_size(order) as usize)
+                };
+
+                if freed {
+                    return;
+                }
+            }
+
+            if order == MAX_ORDER {
+                break;
+            }
+
+            let buddy = block ^ block_size(order);
+            let zone  = DmaZone::of_block(buddy, order);
+
+            if !self.is_free(zone, order, buddy) {
+                break;
+            }
+
+            self.unlink(zone, order, buddy);
+
+            // Merge both blocks.
+            block  = block.min(buddy);
+            order += 1;
+        }
+
+        self.insert(block, order);
+    }
+
+    /// Give all free blocks back to the free memory set. Returns the number of reclaimed
+    /// bytes.
+    pub fn trim(&mut self) -> usize {
+        let mut reclaimed = 0;
+
+        for &zone in &[DmaZone::Below16M, DmaZone::Below4G, DmaZone::Any] {
+            for order in 0..=MAX_ORDER {
+                loop {
+                    let block = self.free_lists[zone as usize][order].head;
+                    if  block == NO_BLOCK {
+                        break;
+                    }
+
+                    let size = block_size(order) as usize;
+
+                    self.unlink(zone, order, block);
+
+                    if !unsafe { mm::try_free_phys(PhysAddr(block), size) } {
+                        // Free memory set is full, keep remaining blocks.
+                        self.push(zone, order, block);
+
+                        return reclaimed;
+                    }
+
+                    reclaimed += size;
+                }
+            }
+        }
+
+        reclaimed
+    }
+
+    /// Get the number of free bytes cached in `zone`.
+    pub fn free_bytes(&self, zone: DmaZone) -> u64 {
+        self.free_lists[zone as usize]
+            .iter()
+            .enumerate()
+            .map(|(order, list)| list.count * block_size(order))
+            .sum()
+    }
+}
+
+/// Allocate `size` bytes of physically contiguous memory aligned to `align` in `zone`. Memory
+/// is not zeroed. Returns `None` if there is not enough memory.
+pub fn allocate(size: u64, align: u64, zone: DmaZone) -> Option<PhysAddr> {
+    BUDDY.lock()
+        .as_mut()
+        .expect("Buddy allocator wasn't initialized.")
+        .allocate(size, align, zone)
+}
+
+/// Free `size` bytes of physically contiguous memory allocated by `allocate`.
+pub unsafe fn free(phys_addr: PhysAddr, size: u64) {
+    BUDDY.lock()
+        .as_mut()
+        .expect("Buddy allocator wasn't initialized.")
+        .free(phys_addr.0, size);
+}
+
+/// Give all cached free blocks back to the free memory set. Returns the number of reclaimed
+/// bytes.
+pub fn trim() -> usize {
+    BUDDY.lock()
+        .as_mut()
+        .map(|buddy| buddy.trim())
+        .unwrap_or(0)
+}
+
+/// Get the number of free bytes cached in `zone`.
+#[allow(unused)]
+pub fn free_bytes(zone: DmaZone) -> u64 {
+    BUDDY.lock()
+        .as_ref()
+        .map(|buddy| buddy.free_bytes(zone))
+        .unwrap_or(0)
+}
+
+pub fn initialize() {
+    let mut buddy = BUDDY.lock();
+
+    assert!(buddy.is_none(), "Buddy allocator was already initialized.");
+
+    *buddy = Some(BuddyAllocator::default());
+}
+
+#[cfg(feature = "kernel_tests")]
+mod tests {
+    use super::*;
+    use crate::tests::kernel_test;
+
+    fn total_free_bytes(buddy: &BuddyAllocator) -> u64 {
+        [DmaZone::Below16M, DmaZone::Below4G, DmaZone::Any]
+            .iter()
+            .map(|&zone| buddy.free_bytes(zone))
+            .sum()
+    }
+
+    #[kernel_test]
+    fn blocks_are_split_and_merged() {
+        let mut buddy = BuddyAllocator::default();
+
+        let first  = buddy.allocate(4096, 4096, DmaZone::Any).expect("Allocation failed.");
+        let second = buddy.allocate(4096, 4096, DmaZone::Any).expect("Allocation failed.");
+
+        assert!(second.0 == first.0 + 4096, "Buddy allocator didn't split the lowest block.");
+
+        buddy.free(first.0,  4096);
+        buddy.free(second.0, 4096);
+
+        assert!(total_free_bytes(&buddy) == 0, "Merged chunk wasn't given back.");
+    }
+
+    #[kernel_test]
+    fn unused_tail_is_given_back() {
+        let mut buddy = BuddyAllocator::default();
+
+        let region = buddy.allocate(12288, 4096, DmaZone::Any).expect("Allocation failed.");
+        let page   = buddy.allocate(4096,  4096, DmaZone::Any).expect("Allocation failed.");
+
+        assert!(page.0 == region.0 + 12288, "Tail of the block wasn't reused.");
+
+        buddy.free(region.0, 12288);
+        buddy.free(page.0,   4096);
+
+        assert!(total_free_bytes(&buddy) == 0, "Merged chunk wasn't given back.");
+    }
+
+    #[kernel_test]
+    fn zones_and_alignment_are_respected() {
+        let mut buddy = BuddyAllocator::default();
+
+        let cases = [
+            (0x10000,  4096,     DmaZone::Below16M),
+            (0x3000,   0x10000,  DmaZone::Below4G),
+            (0x200000, 0x200000, DmaZone::Below4G),
+            (0x5000,   0x4000,   DmaZone::Any),
+        ];
+
+        for &(size, align, zone) in &cases {
+            let phys_addr = buddy.allocate(size, align, zone).expect("Allocation failed.");
+
+            assert!(phys_addr.0 & (align - 1) == 0, "Block is not aligned.");
+            assert!(phys_addr.0 + size - 1 <= zone.max_address(), "Block is outside of zone.");
+
+            buddy.free(phys_addr.0, size);
+        }
+
+        buddy.trim();
+
+        assert!(total_free_bytes(&buddy) == 0, "Trim didn't give back all blocks.");
+    }
+
+    #[kernel_test]
+    fn zones_prefer_their_own_memory() {
+        let mut buddy = BuddyAllocator::default();
+
+        let zone = DmaZone::Below4G;
+
+        // Check if there is a free chunk which can be used only by this zone.
+        let available = core!().boot_block.free_memory.lock()
+            .entries()
+            .iter()
+            .any(|range| {
+                let start = range.start.max(zone.min_address());
+                let end   = range.end.min(zone.max_address());
+
+                start < end && end - start + 1 >= 2 * block_size(CHUNK_ORDER)
+            });
+
+        if !available {
+            return;
+        }
+
+        let phys_addr = buddy.allocate(4096, 4096, zone).expect("Allocation failed.");
+
+        assert!(phys_addr.0 >= zone.min_address(), "Zone was refilled from lower memory.");
+
+        buddy.free(phys_addr.0, 4096);
+
+        assert!(total_free_bytes(&buddy) == 0, "Merged chunk wasn't given back.");
+    }
+
+    #[kernel_test]
+    fn allocated_blocks_dont_look_free() {
+        let mut buddy = BuddyAllocator::default();
+
+        let first  = buddy.allocate(4096, 4096, DmaZone::Any).expect("Allocation failed.");
+        let second = buddy.allocate(4096, 4096, DmaZone::Any).expect("Allocation failed.");
+        let zone   = DmaZone::of_block(second.0, 0);
+
+        buddy.free(first.0, 4096);
+
+        assert!(buddy.is_free(zone, 0, first.0), "Freed block doesn't look free.");
+
+        // Allocated memory can contain anything, including a valid looking header.
+        FreeBlock::write(second.0, FreeBlock {
+            tag:  FreeBlock::tag(second.0, 0),
+            next: NO_BLOCK,
+            prev: NO_BLOCK,
+        });
+
+        assert!(!buddy.is_free(zone, 0, second.0), "Allocated block looks free.");
+
+        buddy.free(second.0, 4096);
+
+        assert!(total_free_bytes(&buddy) == 0, "Merged chunk wasn't given back.");
+    }
+}
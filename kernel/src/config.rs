@@ -0,0 +1,187 @@
+// Runtime kernel configuration parsed from the command line passed by the bootloader.
+// All values are set by the BSP before launching APs and never change afterwards.
+
+use core::convert::TryInto;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, Ordering};
+
+use cmdline::CommandLine;
+use serial_port::{ComPort, DEFAULT_BAUD_RATE};
+
+/// Options which are consumed by the bootloader or the kernel. Other entries will
+/// cause a warning.
+const KNOWN_OPTIONS: &[&str] = &[
+    "print_in_interrupts",
+    "always_use_serial_port",
+    "apic_timer_period_ms",
+    "resolution",
+    "kaslr",
+    "gdb",
+    "serial_port",
+    "serial_baud",
+    "log_serial_port",
+    "log_serial_baud",
+];
+
+/// Value of `SERIAL_PORT` and `LOG_SERIAL_PORT` which means that the port is not used.
+const NO_SERIAL_PORT: u8 = 0xff;
+
+const COM_PORTS: [ComPort; 4] = [ComPort::Com1, ComPort::Com2, ComPort::Com3, ComPort::Com4];
+
+static PRINT_IN_INTERRUPTS: AtomicBool = AtomicBool::new(true);
+
+// QEMU test runner reads kernel output from the serial port.
+static ALWAYS_USE_SERIAL_PORT: AtomicBool = AtomicBool::new(cfg!(feature = "qemu_test"));
+
+/// Enable GDB stub on the serial port.
+static GDB_STUB: AtomicBool = AtomicBool::new(false);
+
+/// Index in `COM_PORTS` of the serial port used for console, input and GDB.
+static SERIAL_PORT: AtomicU8  = AtomicU8::new(0);
+static SERIAL_BAUD: AtomicU32 = AtomicU32::new(DEFAULT_BAUD_RATE);
+
+/// Index in `COM_PORTS` of the serial port used for machine-readable logs.
+static LOG_SERIAL_PORT: AtomicU8  = AtomicU8::new(NO_SERIAL_PORT);
+static LOG_SERIAL_BAUD: AtomicU32 = AtomicU32::new(DEFAULT_BAUD_RATE);
+
+/// APIC timer period in microseconds.
+static APIC_TIMER_PERIOD: AtomicU64 = AtomicU64::new(100_000);
+
+/// Returns `true` if interrupt handlers are allowed to print messages.
+pub fn print_in_interrupts() -> bool {
+    PRINT_IN_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Returns `true` if all output should go to the serial port even if the framebuffer
+/// is available.
+pub fn always_use_serial_port() -> bool {
+    ALWAYS_USE_SERIAL_PORT.load(Ordering::Relaxed)
+}
+
+/// Get APIC timer period in seconds.
+pub fn apic_timer_period() -> f64 {
+    APIC_TIMER_PERIOD.load(Ordering::Relaxed) as f64 / 1_000_000.0
+}
+
+/// Returns `true` if the kernel should stop and wait for GDB on breakpoints, unhandled
+/// exceptions, panics and Ctrl-C received on the serial port.
+pub fn gdb_stub() -> bool {
+    GDB_STUB.load(Ordering::Relaxed)
+}
+
+/// Get serial port used for console output, input and GDB together with its baud rate.
+pub fn serial_port() -> (ComPort, u32) {
+    let index = SERIAL_PORT.load(Ordering::Relaxed);
+
+    (COM_PORTS[index as usize], SERIAL_BAUD.load(Ordering::Relaxed))
+}
+
+/// Get serial port dedicated to machine-readable logs together with its baud rate.
+pub fn log_serial_port() -> Option<(ComPort, u32)> {
+    let index = LOG_SERIAL_PORT.load(Ordering::Relaxed);
+    if  index == NO_SERIAL_PORT {
+        return None;
+    }
+
+    Some((COM_PORTS[index as usize], LOG_SERIAL_BAUD.load(Ordering::Relaxed)))
+}
+
+fn parse_com_port(name: Option<&str>) -> Option<u8> {
+    let port = ComPort::from_name(name?)?;
+
+    COM_PORTS.iter().position(|&other| other == port).map(|index| index as u8)
+}
+
+fn parse_baud_rate(command_line: &CommandLine, key: &str) -> Option<u32> {
+    command_line.get_u64(key)
+        .and_then(|baud_rate| baud_rate.try_into().ok())
+        .filter(|&baud_rate| serial_port::baud_divisor(baud_rate).is_some())
+}
+
+/// Parse the command line from the boot block. Must be called on the BSP before
+/// launching other processors.
+pub fn initialize() {
+    let command_line = core!().boot_block.command_line.lock();
+    let command_line = match command_line.as_ref() {
+        Some(command_line) => {
+            match command_line.as_str() {
+                Some(command_line) => CommandLine::new(command_line),
+                None               => {
+                    println!("WARNING: Ignoring command line which is not valid UTF-8.");
+                    return;
+                }
+            }
+        }
+        None => return,
+    };
+
+    for (key, _) in command_line.entries() {
+        if !KNOWN_OPTIONS.contains(&key) {
+            println!("WARNING: Unknown command line option `{}`.", key);
+        }
+    }
+
+    macro_rules! warn_invalid {
+        ($key: expr) => {
+            if command_line.contains($key) {
+                println!("WARNING: Invalid value of command line option `{}`.", $key);
+            }
+        };
+    }
+
+    match command_line.get_bool("print_in_interrupts") {
+        Some(value) => PRINT_IN_INTERRUPTS.store(value, Ordering::Relaxed),
+        None        => warn_invalid!("print_in_interrupts"),
+    }
+
+    match command_line.get_bool("always_use_serial_port") {
+        Some(value) => {
+            // Serial port output cannot be disabled in QEMU test runs.
+            ALWAYS_USE_SERIAL_PORT.fetch_or(value, Ordering::Relaxed);
+        }
+        None => warn_invalid!("always_use_serial_port"),
+    }
+
+    // Period is stored in microseconds, reject values which don't fit.
+    match command_line.get_u64("apic_timer_period_ms")
+        .filter(|&period| period > 0)
+        .and_then(|period| period.checked_mul(1000))
+    {
+        Some(period) => APIC_TIMER_PERIOD.store(period, Ordering::Relaxed),
+        None         => warn_invalid!("apic_timer_period_ms"),
+    }
+
+    match command_line.get_bool("gdb") {
+        Some(value) => GDB_STUB.store(value, Ordering::Relaxed),
+        None        => warn_invalid!("gdb"),
+    }
+
+    match parse_com_port(command_line.get("serial_port")) {
+        Some(index) => SERIAL_PORT.store(index, Ordering::Relaxed),
+        None        => warn_invalid!("serial_port"),
+    }
+
+    match parse_baud_rate(&command_line, "serial_baud") {
+        Some(baud_rate) => SERIAL_BAUD.store(baud_rate, Ordering::Relaxed),
+        None            => warn_invalid!("serial_baud"),
+    }
+
+    match command_line.get("log_serial_port") {
+        Some("off") => LOG_SERIAL_PORT.store(NO_SERIAL_PORT, Ordering::Relaxed),
+        name        => {
+            match parse_com_port(name) {
+                Some(index) => LOG_SERIAL_PORT.store(index, Ordering::Relaxed),
+                None        => warn_invalid!("log_serial_port"),
+            }
+        }
+    }
+
+    match parse_baud_rate(&command_line, "log_serial_baud") {
+        Some(baud_rate) => LOG_SERIAL_BAUD.store(baud_rate, Ordering::Relaxed),
+        None            => warn_invalid!("log_serial_baud"),
+    }
+
+    // KASLR is handled by the bootloader, just make sure that the value is valid.
+    if command_line.get_bool("kaslr").is_none() {
+        warn_invalid!("kaslr");
+    }
+}
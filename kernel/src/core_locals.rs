@@ -1,17 +1,27 @@
 use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use core::alloc::Layout;
-use core::alloc::GlobalAlloc;
 use core::arch::asm;
 
 use page_table::{PhysAddr, PageType};
 use crate::lock::{Lock, KernelInterrupts};
 
 use crate::interrupts::Interrupts;
+use crate::backtrace::ExceptionContext;
 use crate::apic::{Apic, ApicMode};
-use crate::mm::{self, FreeList, PhysicalPage, BootBlock};
+use crate::mm::{self, PhysicalPage, BootBlock};
+use crate::slab::{CoreHeap, ObjectCache};
+use crate::processors::MAX_CORES;
 
 static NEXT_FREE_CORE_ID: AtomicU64 = AtomicU64::new(0);
 
+/// Addresses of `CoreLocals` of all initialized cores indexed by core ID. 0 if core with
+/// given ID isn't initialized yet.
+static CORE_LOCALS: [AtomicUsize; MAX_CORES] = {
+    const NONE: AtomicUsize = AtomicUsize::new(0);
+
+    [NONE; MAX_CORES]
+};
+
 #[macro_export]
 macro_rules! core {
     () => { $crate::core_locals::get_core_locals() }
@@ -35,6 +45,27 @@ pub fn get_core_locals() -> &'static CoreLocals {
     }
 }
 
+/// Get core locals of the core with ID `core_id`. Returns `None` if it isn't initialized.
+pub fn get_by_id(core_id: u64) -> Option<&'static CoreLocals> {
+    let core_locals = CORE_LOCALS.get(core_id as usize)?.load(Ordering::Acquire);
+    if  core_locals == 0 {
+        return None;
+    }
+
+    Some(unsafe { &*(core_locals as *const CoreLocals) })
+}
+
+/// Call `f` with core locals of every initialized core.
+pub fn for_each(mut f: impl FnMut(&'static CoreLocals)) {
+    let core_count = NEXT_FREE_CORE_ID.load(Ordering::Acquire) as usize;
+
+    for core_id in 0..core_count.min(MAX_CORES) {
+        if let Some(core_locals) = get_by_id(core_id as u64) {
+            f(core_locals);
+        }
+    }
+}
+
 struct DepthCounter {
     depth: AtomicU32,
 }
@@ -109,12 +140,14 @@ pub struct CoreLocals {
     /// 4KB of space for SVM to save host state on `vmrun`.
     pub host_save_area: Lock<Option<PhysicalPage<[u8; 4096]>>>,
 
+    /// State of the code interrupted by an unhandled exception.
+    pub exception_context: ExceptionContext,
+
     /// APIC ID for this core. !0 if not cached yet.
     apic_id: AtomicU32,
 
-    /// Free lists for each power-of-two size.
-    /// The free list size is `(1 << (index + 3))`.
-    free_lists: [Lock<FreeList>; 61],
+    /// Slab caches and virtual memory used by allocations made on this core.
+    pub heap: CoreHeap,
 }
 
 impl CoreLocals {
@@ -172,26 +205,6 @@ impl CoreLocals {
         enabled
     }
 
-    pub unsafe fn free_list(&self, layout: Layout) -> &Lock<FreeList> {
-        // Free lists start at 8 bytes, round it up if needed.
-        let size = core::cmp::max(layout.size(), 8);
-
-        // Round up size to the nearest power of two and get the log2 of it
-        // to determine the index into the free lists.
-        let index = 64 - (size - 1).leading_zeros();
-
-        // Compute the alignment of the free list associated with this memory.
-        // Free lists are naturally aligned until 4096 byte sizes, at which
-        // point they remain only 4096 byte aligned.
-        let free_list_align = 1 << core::cmp::min(index, 12);
-
-        assert!(free_list_align >= layout.align(),
-            "Cannot satisfy alignment requirement from the free list.");
-
-        // Get the free list corresponding to this size.
-        &self.free_lists[index as usize - 3]
-    }
-
     pub unsafe fn set_apic_id(&self, apic_id: u32) {
         self.apic_id.store(apic_id, Ordering::SeqCst);
     }
@@ -260,6 +273,10 @@ pub unsafe fn initialize(boot_block: PhysAddr, boot_tsc: u64) {
         assert!(boot_block.size == core::mem::size_of::<BootBlock<EarlyInterrupts>>() as u64,
                 "Boot block size mismatch.");
 
+        // Heap base is randomized by the bootloader so it needs to be known before reserving
+        // any virtual memory.
+        mm::initialize_layout(&boot_block.kernel_layout.lock());
+
         let size  = core::mem::size_of::<CoreLocals>()  as u64;
         let align = core::mem::align_of::<CoreLocals>() as u64;
 
@@ -315,96 +332,42 @@ pub unsafe fn initialize(boot_block: PhysAddr, boot_tsc: u64) {
         panic!("System doesn't support 2M or 1G pages.");
     };
 
+    // XSAVE area size is needed to create the XSAVE area cache.
+    let xsave_size = xsave_size();
+
     let core_locals = CoreLocals {
         boot_tsc,
         self_address:   core_locals_ptr,
         xsave_area:     0,
-        xsave_size:     AtomicUsize::new(0),
+        xsave_size:     AtomicUsize::new(xsave_size),
         id:             core_id,
         apic:           Lock::new(None),
         apic_id:        AtomicU32::new(!0),
         interrupts:     Lock::new(None),
         host_save_area: Lock::new(None),
         last_timer_tsc: AtomicU64::new(0),
+        exception_context: ExceptionContext::new(),
         boot_block,
         max_page_type,
         // We start with interrupts disabled so initial depth counter is 1.
         interrupts_disable: DepthCounter::new(1),
         in_interrupt:       DepthCounter::new(0),
         in_exception:       DepthCounter::new(0),
-        free_lists: [
-            Lock::new(FreeList::new(0x0000000000000008)),
-            Lock::new(FreeList::new(0x0000000000000010)),
-            Lock::new(FreeList::new(0x0000000000000020)),
-            Lock::new(FreeList::new(0x0000000000000040)),
-            Lock::new(FreeList::new(0x0000000000000080)),
-            Lock::new(FreeList::new(0x0000000000000100)),
-            Lock::new(FreeList::new(0x0000000000000200)),
-            Lock::new(FreeList::new(0x0000000000000400)),
-            Lock::new(FreeList::new(0x0000000000000800)),
-            Lock::new(FreeList::new(0x0000000000001000)),
-            Lock::new(FreeList::new(0x0000000000002000)),
-            Lock::new(FreeList::new(0x0000000000004000)),
-            Lock::new(FreeList::new(0x0000000000008000)),
-            Lock::new(FreeList::new(0x0000000000010000)),
-            Lock::new(FreeList::new(0x0000000000020000)),
-            Lock::new(FreeList::new(0x0000000000040000)),
-            Lock::new(FreeList::new(0x0000000000080000)),
-            Lock::new(FreeList::new(0x0000000000100000)),
-            Lock::new(FreeList::new(0x0000000000200000)),
-            Lock::new(FreeList::new(0x0000000000400000)),
-            Lock::new(FreeList::new(0x0000000000800000)),
-            Lock::new(FreeList::new(0x0000000001000000)),
-            Lock::new(FreeList::new(0x0000000002000000)),
-            Lock::new(FreeList::new(0x0000000004000000)),
-            Lock::new(FreeList::new(0x0000000008000000)),
-            Lock::new(FreeList::new(0x0000000010000000)),
-            Lock::new(FreeList::new(0x0000000020000000)),
-            Lock::new(FreeList::new(0x0000000040000000)),
-            Lock::new(FreeList::new(0x0000000080000000)),
-            Lock::new(FreeList::new(0x0000000100000000)),
-            Lock::new(FreeList::new(0x0000000200000000)),
-            Lock::new(FreeList::new(0x0000000400000000)),
-            Lock::new(FreeList::new(0x0000000800000000)),
-            Lock::new(FreeList::new(0x0000001000000000)),
-            Lock::new(FreeList::new(0x0000002000000000)),
-            Lock::new(FreeList::new(0x0000004000000000)),
-            Lock::new(FreeList::new(0x0000008000000000)),
-            Lock::new(FreeList::new(0x0000010000000000)),
-            Lock::new(FreeList::new(0x0000020000000000)),
-            Lock::new(FreeList::new(0x0000040000000000)),
-            Lock::new(FreeList::new(0x0000080000000000)),
-            Lock::new(FreeList::new(0x0000100000000000)),
-            Lock::new(FreeList::new(0x0000200000000000)),
-            Lock::new(FreeList::new(0x0000400000000000)),
-            Lock::new(FreeList::new(0x0000800000000000)),
-            Lock::new(FreeList::new(0x0001000000000000)),
-            Lock::new(FreeList::new(0x0002000000000000)),
-            Lock::new(FreeList::new(0x0004000000000000)),
-            Lock::new(FreeList::new(0x0008000000000000)),
-            Lock::new(FreeList::new(0x0010000000000000)),
-            Lock::new(FreeList::new(0x0020000000000000)),
-            Lock::new(FreeList::new(0x0040000000000000)),
-            Lock::new(FreeList::new(0x0080000000000000)),
-            Lock::new(FreeList::new(0x0100000000000000)),
-            Lock::new(FreeList::new(0x0200000000000000)),
-            Lock::new(FreeList::new(0x0400000000000000)),
-            Lock::new(FreeList::new(0x0800000000000000)),
-            Lock::new(FreeList::new(0x1000000000000000)),
-            Lock::new(FreeList::new(0x2000000000000000)),
-            Lock::new(FreeList::new(0x4000000000000000)),
-            Lock::new(FreeList::new(0x8000000000000000)),
-        ],
+        heap: CoreHeap::new(core_id, xsave_size),
     };
 
     core::ptr::write(core_locals_ptr as *mut CoreLocals, core_locals);
 
     cpu::wrmsr(IA32_GS_BASE, core_locals_ptr as u64);
 
-    initialize_xsave();
+    // Make core locals visible to other cores.
+    CORE_LOCALS[core_id as usize].store(core_locals_ptr, Ordering::Release);
+
+    initialize_xsave(xsave_size);
 }
 
-unsafe fn initialize_xsave() {
+/// Calculate size of the XSAVE area required by features enabled in XCR0.
+unsafe fn xsave_size() -> usize {
     // We start with legacy area size and XSAVE header size.
     let mut xsave_size = 512 + 64;
 
@@ -421,11 +384,14 @@ unsafe fn initialize_xsave() {
         }
     }
 
+    xsave_size as usize
+}
+
+unsafe fn initialize_xsave(xsave_size: usize) {
     // Allocate the XSAVE area.
-    let xsave_size   = xsave_size as usize;
     let xsave_layout = Layout::from_size_align(xsave_size, 64)
         .expect("Failed to create XSAVE layout.");
-    let xsave_area   = mm::GLOBAL_ALLOCATOR.alloc(xsave_layout);
+    let xsave_area   = ObjectCache::XsaveArea.alloc(xsave_layout);
 
     assert!(!xsave_area.is_null(), "Failed to allocate XSAVE area.");
 
@@ -437,7 +403,4 @@ unsafe fn initialize_xsave() {
 
     // Save address of XSAVE area.
     (*core_locals).xsave_area = xsave_area as usize;
-
-    // Save size of XSAVE area.
-    (*core_locals).xsave_size.store(xsave_size, Ordering::Relaxed);
 }
@@ -3,20 +3,15 @@ use core::arch::asm;
 
 use crate::{mm, font};
 use crate::lock::Lock;
-use crate::interrupts::PRINT_IN_INTERRUPTS;
 
 use boot_block::{FramebufferInfo, PixelFormat};
 use page_table::PhysAddr;
 
 pub const DEFAULT_FOREGROUND_COLOR: u32 = 0xffffff;
 
-static FRAMEBUFFER: Lock<Option<TextFramebuffer>> = {
-    if PRINT_IN_INTERRUPTS {
-        Lock::new_non_preemptible(None)
-    } else {
-        Lock::new(None)
-    }
-};
+// Printing in interrupts can be enabled on the command line so the lock must be always
+// non-preemptible.
+static FRAMEBUFFER: Lock<Option<TextFramebuffer>> = Lock::new_non_preemptible(None);
 
 enum ColorMode {
     RGB,
@@ -0,0 +1,479 @@
+// GDB Remote Serial Protocol stub. It takes over the serial port when the kernel stops
+// (breakpoint, single step, unhandled exception, panic or Ctrl-C sent by GDB), stops other
+// cores using NMIs and serves GDB requests until GDB resumes execution.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use core::fmt::Write;
+use core::arch::asm;
+
+use gdb_protocol::{Command, Connection, Registers, Response, MAX_PACKET_SIZE};
+use page_table::VirtAddr;
+use serial_port::SerialPort;
+
+use crate::interrupts::{InterruptFrame, RegisterState};
+use crate::processors::{self, CoreState};
+use crate::{mm, tlb, panic, time, apic, config, backtrace};
+use crate::lock::Lock;
+
+const MAX_BREAKPOINTS: usize = 64;
+
+const INT3:      u8  = 0xcc;
+const RFLAGS_TF: u64 = 1 << 8;
+
+// Signals reported to GDB in stop replies.
+const SIGINT:  u8 = 2;
+const SIGILL:  u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGFPE:  u8 = 8;
+const SIGSEGV: u8 = 11;
+
+const NO_OWNER: u64 = !0;
+
+/// ID of the core which is currently running the stub.
+static OWNER: AtomicU64 = AtomicU64::new(NO_OWNER);
+
+/// Set while the stub is active. Other cores wait in their NMI handlers until it's cleared.
+static STOPPED: AtomicBool = AtomicBool::new(false);
+
+/// Number of cores waiting in their NMI handlers.
+static PARKED_CORES: AtomicU32 = AtomicU32::new(0);
+
+/// Set when GDB has resumed execution and waits for a stop reply.
+static GDB_WAITING: AtomicBool = AtomicBool::new(false);
+
+/// Time when the stub resumed execution for the last time.
+static RESUME_TIME: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Copy, Clone)]
+struct Breakpoint {
+    address:  u64,
+    original: u8,
+}
+
+static BREAKPOINTS: Lock<[Option<Breakpoint>; MAX_BREAKPOINTS]> =
+    Lock::new_non_preemptible([None; MAX_BREAKPOINTS]);
+
+/// Returns `true` if the stub was enabled on the command line.
+pub fn enabled() -> bool {
+    config::gdb_stub()
+}
+
+/// Returns `true` if the stub has stopped the kernel after `time`. Stopped cores don't
+/// handle timer interrupts so their timer checks must skip that period.
+pub fn stopped_since(time: u64) -> bool {
+    RESUME_TIME.load(Ordering::Relaxed) > time
+}
+
+struct SerialConnection<'a> {
+    port: &'a mut SerialPort,
+}
+
+impl Connection for SerialConnection<'_> {
+    fn read_byte(&mut self) -> u8 {
+        self.port.read_byte()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        // Interrupts are disabled while the stub runs, so send everything synchronously.
+        self.port.write_raw_byte(byte);
+        self.port.flush();
+    }
+}
+
+/// Get the serial port without locking it. Other cores are stopped while the stub runs, but
+/// this core may have been stopped while holding the lock.
+unsafe fn serial_port() -> Option<&'static mut SerialPort> {
+    (*core!().boot_block.serial_port.bypass()).as_mut()
+}
+
+/// Get pointer to `address` in the physical memory map. Writes through it ignore page
+/// protection so breakpoints can be placed in read-only kernel code.
+unsafe fn memory_pointer(address: u64) -> Option<*mut u8> {
+    let phys_addr = mm::virt_to_phys(VirtAddr(address))?;
+
+    mm::translate(phys_addr, 1)
+}
+
+unsafe fn read_memory(address: u64) -> Option<u8> {
+    Some(core::ptr::read_volatile(memory_pointer(address)?))
+}
+
+unsafe fn write_memory(address: u64, value: u8) -> Option<()> {
+    core::ptr::write_volatile(memory_pointer(address)?, value);
+
+    Some(())
+}
+
+fn is_breakpoint(address: u64) -> bool {
+    BREAKPOINTS.lock()
+        .iter()
+        .flatten()
+        .any(|breakpoint| breakpoint.address == address)
+}
+
+unsafe fn insert_breakpoint(address: u64) -> Option<()> {
+    let mut breakpoints = BREAKPOINTS.lock();
+
+    if breakpoints.iter().flatten().any(|breakpoint| breakpoint.address == address) {
+        return Some(());
+    }
+
+    let slot     = breakpoints.iter_mut().find(|slot| slot.is_none())?;
+    let original = read_memory(address)?;
+
+    write_memory(address, INT3)?;
+
+    *slot = Some(Breakpoint { address, original });
+
+    Some(())
+}
+
+unsafe fn remove_breakpoint(address: u64) -> Option<()> {
+    let mut breakpoints = BREAKPOINTS.lock();
+
+    let slot = breakpoints.iter_mut()
+        .find(|slot| matches!(slot, Some(breakpoint) if breakpoint.address == address))?;
+
+    write_memory(address, slot.take().unwrap().original)
+}
+
+unsafe fn remove_all_breakpoints() {
+    for slot in BREAKPOINTS.lock().iter_mut() {
+        if let Some(breakpoint) = slot.take() {
+            let _ = write_memory(breakpoint.address, breakpoint.original);
+        }
+    }
+}
+
+/// Stop all other online cores by sending them NMIs. They will wait in `park_if_stopped`.
+unsafe fn stop_other_cores() {
+    STOPPED.store(true, Ordering::SeqCst);
+
+    // Cores are already halted forever if the kernel is panicking.
+    if panic::is_panicking() {
+        return;
+    }
+
+    if let Some(apic) = &mut *core!().apic.bypass() {
+        let current_apic_id = apic.apic_id();
+        let mut stopped     = 0;
+
+        for apic_id in 0..processors::MAX_CORES as u32 {
+            if apic_id != current_apic_id && processors::core_state(apic_id) == CoreState::Online {
+                apic.ipi(apic_id, apic::NMI_IPI);
+
+                stopped += 1;
+            }
+        }
+
+        // Wait for the cores to stop. Give up after a while, GDB is still usable even if some
+        // core doesn't respond.
+        let start = time::get();
+
+        while PARKED_CORES.load(Ordering::SeqCst) != stopped &&
+            time::difference(start, time::get()) < 0.2 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+unsafe fn resume_other_cores() {
+    RESUME_TIME.store(time::get(), Ordering::Relaxed);
+    STOPPED.store(false, Ordering::SeqCst);
+
+    // Make sure that all cores have left their NMI handlers so they can be stopped again.
+    while PARKED_CORES.load(Ordering::SeqCst) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Called from the NMI handler. If the stub is active, waits until it resumes execution
+/// and returns `true`.
+pub unsafe fn park_if_stopped() -> bool {
+    if !STOPPED.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    PARKED_CORES.fetch_add(1, Ordering::SeqCst);
+
+    // Panic while the stub is active will send another NMI which halts this core.
+    while STOPPED.load(Ordering::SeqCst) && !panic::is_panicking() {
+        // Cores which weren't stopped yet may be waiting for us to acknowledge TLB shootdown.
+        tlb::handle_pending();
+
+        core::hint::spin_loop();
+    }
+
+    PARKED_CORES.fetch_sub(1, Ordering::SeqCst);
+
+    true
+}
+
+/// Get signal which is reported to GDB for exception `vector`.
+pub fn exception_signal(vector: u8) -> u8 {
+    match vector {
+        0 | 16 | 19 => SIGFPE,
+        1 | 3       => SIGTRAP,
+        2           => SIGINT,
+        6           => SIGILL,
+        _           => SIGSEGV,
+    }
+}
+
+fn registers(frame: &InterruptFrame, regs: &RegisterState) -> Registers {
+    Registers {
+        rax:    regs.rax,
+        rbx:    regs.rbx,
+        rcx:    regs.rcx,
+        rdx:    regs.rdx,
+        rsi:    regs.rsi,
+        rdi:    regs.rdi,
+        rbp:    regs.rbp,
+        rsp:    frame.rsp,
+        r8:     regs.r8,
+        r9:     regs.r9,
+        r10:    regs.r10,
+        r11:    regs.r11,
+        r12:    regs.r12,
+        r13:    regs.r13,
+        r14:    regs.r14,
+        r15:    regs.r15,
+        rip:    frame.rip,
+        eflags: frame.rflags,
+        cs:     frame.cs,
+        ss:     frame.ss,
+        ..Default::default()
+    }
+}
+
+fn apply_registers(registers: &Registers, frame: &mut InterruptFrame, regs: &mut RegisterState) {
+    regs.rax = registers.rax;
+    regs.rbx = registers.rbx;
+    regs.rcx = registers.rcx;
+    regs.rdx = registers.rdx;
+    regs.rsi = registers.rsi;
+    regs.rdi = registers.rdi;
+    regs.rbp = registers.rbp;
+    regs.r8  = registers.r8;
+    regs.r9  = registers.r9;
+    regs.r10 = registers.r10;
+    regs.r11 = registers.r11;
+    regs.r12 = registers.r12;
+    regs.r13 = registers.r13;
+    regs.r14 = registers.r14;
+    regs.r15 = registers.r15;
+
+    // Segment registers cannot be changed, kernel uses flat segments.
+    frame.rsp    = registers.rsp;
+    frame.rip    = registers.rip;
+    frame.rflags = registers.eflags;
+}
+
+fn write_result(response: &mut Response, result: Option<()>) {
+    response.push_str(if result.is_some() { "OK" } else { "E14" });
+}
+
+/// Resume execution at `address` (or the current RIP). Always returns `true`.
+fn resume(registers: &mut Registers, address: Option<u64>, step: bool) -> bool {
+    if let Some(address) = address {
+        registers.rip = address;
+    }
+
+    if step {
+        registers.eflags |= RFLAGS_TF;
+    }
+
+    GDB_WAITING.store(true, Ordering::Relaxed);
+
+    true
+}
+
+/// Serve GDB requests until it resumes execution. Returns `false` if GDB has detached.
+unsafe fn serve(connection: &mut SerialConnection, signal: u8, registers: &mut Registers,
+                resumable: bool) -> bool {
+    let mut buffer   = [0u8; MAX_PACKET_SIZE];
+    let mut response = Response::new();
+
+    // GDB waits for a stop reply after resuming. Otherwise it will ask for it when it
+    // connects.
+    if GDB_WAITING.swap(false, Ordering::Relaxed) {
+        let _ = write!(response, "S{:02x}", signal);
+
+        response.send(connection);
+    } else {
+        for &byte in b"\nKernel stopped, waiting for GDB on the serial port.\n" {
+            connection.write_byte(byte);
+        }
+    }
+
+    loop {
+        let packet = gdb_protocol::receive_packet(connection, &mut buffer);
+
+        response.clear();
+
+        match Command::parse(packet) {
+            Command::StopReason => {
+                let _ = write!(response, "S{:02x}", signal);
+            }
+            Command::ReadRegisters => registers.read_all(&mut response),
+            Command::WriteRegisters(data) => {
+                write_result(&mut response, registers.write_all(data));
+            }
+            Command::ReadRegister(index) => {
+                if registers.read(index, &mut response).is_none() {
+                    response.push_str("E00");
+                }
+            }
+            Command::WriteRegister(index, value) => {
+                write_result(&mut response, registers.write(index, value));
+            }
+            Command::ReadMemory { address, size } => {
+                // Return as many bytes as possible, GDB handles partial reads.
+                for offset in 0..size.min(MAX_PACKET_SIZE / 2) as u64 {
+                    match address.checked_add(offset).and_then(|address| read_memory(address)) {
+                        Some(byte) => response.push_hex(&[byte]),
+                        None       => break,
+                    }
+                }
+
+                if response.data() == Some(b"") {
+                    response.push_str("E14");
+                }
+            }
+            Command::WriteMemory { address, data } => {
+                let result = data.chunks(2).enumerate().try_for_each(|(offset, hex)| {
+                    let mut byte = [0u8];
+
+                    gdb_protocol::decode_hex(hex, &mut byte)?;
+
+                    write_memory(address.checked_add(offset as u64)?, byte[0])
+                });
+
+                write_result(&mut response, result);
+            }
+            Command::InsertBreakpoint(address) => {
+                write_result(&mut response, insert_breakpoint(address));
+            }
+            Command::RemoveBreakpoint(address) => {
+                write_result(&mut response, remove_breakpoint(address));
+            }
+            Command::Continue(address) if resumable => return resume(registers, address, false),
+            Command::Step(address)     if resumable => return resume(registers, address, true),
+            Command::Continue(_) | Command::Step(_) => {
+                // Kernel cannot continue after panic, report the same stop again.
+                let _ = write!(response, "S{:02x}", signal);
+            }
+            Command::Detach => {
+                remove_all_breakpoints();
+
+                gdb_protocol::send_packet(connection, b"OK");
+
+                return false;
+            }
+            Command::Kill => {
+                remove_all_breakpoints();
+
+                return false;
+            }
+            Command::Supported => {
+                let _ = write!(response, "PacketSize={:x}", MAX_PACKET_SIZE);
+            }
+            Command::Attached      => response.push_str("1"),
+            Command::CurrentThread => response.push_str("QC1"),
+            Command::SetThread | Command::ThreadAlive => response.push_str("OK"),
+            Command::Unsupported => {}
+        }
+
+        response.send(connection);
+    }
+}
+
+/// Stop the kernel and let GDB inspect and modify state of the interrupted code. Returns
+/// `false` if GDB has detached or the stub cannot be used. Non resumable stops (panics)
+/// never return to the caller unless GDB detaches.
+pub unsafe fn enter(signal: u8, frame: &mut InterruptFrame, regs: &mut RegisterState,
+                    resumable: bool) -> bool {
+    let core_id = core!().id;
+
+    // Only one core can run the stub at a time. Other cores which want to enter it will be
+    // stopped by NMI while they wait. Don't deadlock if the stub itself panics.
+    loop {
+        match OWNER.compare_exchange(NO_OWNER, core_id, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_)                          => break,
+            Err(owner) if owner == core_id => return false,
+            Err(_)                         => core::hint::spin_loop(),
+        }
+    }
+
+    let port = match serial_port() {
+        Some(port) => port,
+        None       => {
+            OWNER.store(NO_OWNER, Ordering::SeqCst);
+
+            return false;
+        }
+    };
+
+    stop_other_cores();
+
+    // Trap flag is set only for a single step.
+    frame.rflags &= !RFLAGS_TF;
+
+    let mut connection = SerialConnection { port };
+    let mut registers  = registers(frame, regs);
+
+    let resumed = serve(&mut connection, signal, &mut registers, resumable);
+
+    apply_registers(&registers, frame, regs);
+
+    resume_other_cores();
+
+    OWNER.store(NO_OWNER, Ordering::SeqCst);
+
+    resumed
+}
+
+/// Handle `#DB` (single step) and `#BP` (breakpoint) exceptions.
+pub unsafe fn handle_debug_exception(vector: u8, frame: &mut InterruptFrame,
+                                     regs: &mut RegisterState) {
+    // Report breakpoints inserted by GDB at their address instead of after `int3`.
+    if vector == 3 && is_breakpoint(frame.rip.wrapping_sub(1)) {
+        frame.rip -= 1;
+    }
+
+    enter(SIGTRAP, frame, regs, true);
+}
+
+/// Check if GDB has sent Ctrl-C and stop the kernel if it did. Called from the timer
+/// interrupt on the BSP. Other received bytes are dropped.
+pub unsafe fn poll_interrupt(frame: &mut InterruptFrame, regs: &mut RegisterState) {
+    if core!().id != 0 {
+        return;
+    }
+
+    let received = serial_port().and_then(|port| port.try_read_byte());
+
+    if received == Some(gdb_protocol::INTERRUPT) {
+        enter(SIGINT, frame, regs, true);
+    }
+}
+
+/// Let GDB inspect the kernel after panic. The state is captured in this function so only
+/// the backtrace registers (RIP, RSP, RBP) are meaningful.
+pub unsafe fn enter_on_panic() {
+    let (rbp, rsp) = backtrace::current_frame();
+
+    let rip: u64;
+    let cs:  u64;
+    let ss:  u64;
+
+    asm!("lea {}, [rip]", out(reg) rip);
+    asm!("mov {:x}, cs",  out(reg) cs);
+    asm!("mov {:x}, ss",  out(reg) ss);
+
+    let mut frame = InterruptFrame { rip, cs, rflags: 0, rsp, ss };
+    let mut regs  = RegisterState { rbp, ..Default::default() };
+
+    enter(SIGABRT, &mut frame, &mut regs, false);
+}
@@ -0,0 +1,226 @@
+// Read-only file tree backed by the initial ramdisk loaded by the bootloader. Host packs
+// initrd directories as USTAR archives. Other initrds don't contain any files.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::mm;
+use page_table::PhysAddr;
+
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Copy, Clone)]
+enum Node {
+    Directory,
+    File(&'static [u8]),
+}
+
+struct Initrd {
+    nodes: BTreeMap<String, Node>,
+}
+
+impl Initrd {
+    fn read(&self, path: &str) -> Option<&'static [u8]> {
+        match self.nodes.get(&normalize(path))? {
+            Node::File(contents) => Some(contents),
+            Node::Directory      => None,
+        }
+    }
+}
+
+// We don't use lock here as we will initialize this before launching APs and never modify it
+// again.
+static mut INITRD: Option<Initrd> = None;
+
+/// Remove leading and trailing slashes and `.` components from the path.
+fn normalize(path: &str) -> String {
+    let components: Vec<&str> = path.split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+
+    components.join("/")
+}
+
+/// Parse NUL or space terminated octal number from the tar header field.
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let end   = field.iter().position(|&byte| byte == 0 || byte == b' ').unwrap_or(field.len());
+    let field = core::str::from_utf8(&field[..end]).ok()?;
+
+    usize::from_str_radix(field.trim_start_matches(' '), 8).ok()
+}
+
+/// Parse NUL terminated string from the tar header field.
+fn parse_string(field: &[u8]) -> Option<&str> {
+    let end = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+
+    core::str::from_utf8(&field[..end]).ok()
+}
+
+/// Insert a node and all its parent directories.
+fn insert(nodes: &mut BTreeMap<String, Node>, path: String, node: Node) {
+    let mut parent = path.as_str();
+
+    while let Some((directory, _)) = parent.rsplit_once('/') {
+        nodes.entry(String::from(directory)).or_insert(Node::Directory);
+
+        parent = directory;
+    }
+
+    nodes.insert(path, node);
+}
+
+/// Parse USTAR archive. Returns `None` if the archive is invalid.
+fn parse_tar(data: &'static [u8]) -> Option<BTreeMap<String, Node>> {
+    let mut nodes  = BTreeMap::new();
+    let mut offset = 0;
+
+    // Root directory always exists.
+    nodes.insert(String::new(), Node::Directory);
+
+    loop {
+        let header = data.get(offset..offset + BLOCK_SIZE)?;
+
+        // Archive ends with zero blocks.
+        if header.iter().all(|&byte| byte == 0) {
+            break;
+        }
+
+        if &header[257..262] != b"ustar" {
+            return None;
+        }
+
+        // Checksum is calculated with checksum field filled with spaces.
+        let checksum = header.iter()
+            .enumerate()
+            .map(|(index, &byte)| if (148..156).contains(&index) { b' ' } else { byte })
+            .fold(0usize, |sum, byte| sum + byte as usize);
+
+        if parse_octal(&header[148..156])? != checksum {
+            return None;
+        }
+
+        let name   = parse_string(&header[0..100])?;
+        let prefix = parse_string(&header[345..500])?;
+        let size   = parse_octal(&header[124..136])?;
+
+        let path = if prefix.is_empty() {
+            normalize(name)
+        } else {
+            normalize(&alloc::format!("{}/{}", prefix, name))
+        };
+
+        let contents_start = offset + BLOCK_SIZE;
+        let contents       = data.get(contents_start..contents_start.checked_add(size)?)?;
+
+        match header[156] {
+            b'0' | 0 => insert(&mut nodes, path, Node::File(contents)),
+            b'5'     => insert(&mut nodes, path, Node::Directory),
+            _        => (),
+        }
+
+        // Contents are padded to the block size.
+        offset = contents_start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+    }
+
+    Some(nodes)
+}
+
+fn get() -> Option<&'static Initrd> {
+    unsafe { INITRD.as_ref() }
+}
+
+/// Get contents of the file at `path`. Returns `None` if the file doesn't exist.
+pub fn read(path: &str) -> Option<&'static [u8]> {
+    get()?.read(path)
+}
+
+/// Map the initrd loaded by the bootloader and parse its file tree. Must be called on the
+/// BSP before launching other processors.
+pub unsafe fn initialize() {
+    let initrd = match *core!().boot_block.initrd.lock() {
+        Some(initrd) => initrd,
+        None         => return,
+    };
+
+    let size = initrd.size as usize;
+    let raw  = mm::translate(PhysAddr(initrd.phys_addr), size)
+        .map(|pointer| core::slice::from_raw_parts(pointer as *const u8, size))
+        .expect("Initrd is outside of the kernel physical region.");
+
+    let nodes = match parse_tar(raw) {
+        Some(nodes) => {
+            let files = nodes.values()
+                .filter(|node| matches!(node, Node::File(_)))
+                .count();
+
+            println!("Initrd: {} bytes, {} files.", size, files);
+
+            nodes
+        }
+        None => {
+            println!("Initrd: {} bytes, not a tar archive.", size);
+
+            BTreeMap::new()
+        }
+    };
+
+    INITRD = Some(Initrd {
+        nodes,
+    });
+}
+
+#[cfg(feature = "kernel_tests")]
+mod tests {
+    use super::*;
+    use crate::tests::kernel_test;
+    use alloc::vec;
+
+    fn header(name: &str, size: usize, kind: u8) -> Vec<u8> {
+        let mut header = vec![0u8; BLOCK_SIZE];
+
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(alloc::format!("{:011o}", size).as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[148..156].copy_from_slice(b"        ");
+
+        let checksum: usize = header.iter().map(|&byte| byte as usize).sum();
+
+        header[148..155].copy_from_slice(alloc::format!("{:06o}\0", checksum).as_bytes());
+
+        header
+    }
+
+    #[kernel_test]
+    fn tar_archive_is_parsed_to_file_tree() {
+        let mut archive = Vec::new();
+
+        archive.extend(header("bin/", 0, b'5'));
+        archive.extend(header("bin/guest", 3, b'0'));
+        archive.extend(b"abc");
+        archive.resize(BLOCK_SIZE * 3, 0);
+        archive.extend(header("./etc/config", 0, b'0'));
+        archive.resize(archive.len() + BLOCK_SIZE * 2, 0);
+
+        let archive: &'static [u8] = alloc::boxed::Box::leak(archive.into_boxed_slice());
+        let nodes = parse_tar(archive).expect("Failed to parse tar archive.");
+
+        assert!(matches!(nodes.get("bin/guest"), Some(Node::File(b"abc"))));
+        assert!(matches!(nodes.get("etc/config"), Some(Node::File(b""))));
+        assert!(matches!(nodes.get("etc"), Some(Node::Directory)));
+        assert!(matches!(nodes.get("bin"), Some(Node::Directory)));
+        assert!(nodes.get("guest").is_none());
+
+        assert!(parse_tar(&[0x41; BLOCK_SIZE]).is_none(), "Parsed invalid tar archive.");
+
+        let initrd = Initrd {
+            nodes,
+        };
+
+        assert!(initrd.read("/bin/./guest") == Some(&b"abc"[..]), "Failed to read file.");
+        assert!(initrd.read("etc/config/") == Some(&b""[..]), "Failed to read empty file.");
+        assert!(initrd.read("bin").is_none(), "Directory was read as a file.");
+        assert!(initrd.read("bin/missing").is_none(), "Missing file was read.");
+    }
+}
@@ -4,9 +4,7 @@ use alloc::{vec, vec::Vec, boxed::Box};
 
 use cpu::TableRegister;
 
-use crate::{mm, panic, apic, time};
-
-pub const PRINT_IN_INTERRUPTS: bool = true;
+use crate::{mm, panic, apic, time, config, gdb, serial, tlb};
 
 pub struct Interrupts {
     _idt: Box<[IdtGate]>,
@@ -25,7 +23,7 @@ pub struct InterruptFrame {
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct RegisterState {
     pub r15: u64,
     pub r14: u64,
@@ -216,7 +214,10 @@ fn panic_on_page_fault(frame: &InterruptFrame, error: u64) -> ! {
     }
 }
 
-fn panic_on_interrupt(vector: u8, frame: &InterruptFrame, error: u64, _regs: &RegisterState) -> ! {
+fn panic_on_interrupt(vector: u8, frame: &InterruptFrame, error: u64, regs: &RegisterState) -> ! {
+    // Let the panic handler print backtrace of the interrupted code.
+    core!().exception_context.set(frame.rip, regs.rbp, frame.rsp);
+
     if vector < 32 {
         if vector == 14 {
             panic_on_page_fault(frame, error);
@@ -264,7 +265,12 @@ unsafe extern "C" fn handle_interrupt(vector: u8, frame: &mut InterruptFrame, er
                                       regs: &mut RegisterState) {
     // On kernel panic NMI is sent to all cores on the system to halt execution.
     if vector == 2 && panic::is_panicking() {
-        panic::halt();
+        panic::halt_on_nmi(frame, regs);
+    }
+
+    // GDB stub stops other cores using NMI while it's active.
+    if vector == 2 && gdb::park_if_stopped() {
+        return;
     }
 
     // Inform that we are now handling interrupt or exception.
@@ -287,15 +293,23 @@ unsafe extern "C" fn handle_interrupt(vector: u8, frame: &mut InterruptFrame, er
         return;
     }
 
+    // Let GDB inspect unhandled exceptions. Continue execution if it resumes the kernel.
+    if exception && gdb::enabled() &&
+        gdb::enter(gdb::exception_signal(vector), frame, regs, true) {
+        core!().exit_exception();
+
+        return;
+    }
+
     // Unhandled interrupt, panic.
     panic_on_interrupt(vector, frame, error, regs);
 }
 
-fn try_handle_interrupt(vector: u8, _frame: &mut InterruptFrame, _error: u64,
-                        _regs: &mut RegisterState) -> bool {
+fn try_handle_interrupt(vector: u8, frame: &mut InterruptFrame, _error: u64,
+                        regs: &mut RegisterState) -> bool {
     // Ignore PIC interrupts.
     if vector >= apic::PIC_BASE_IRQ && vector < apic::PIC_BASE_IRQ + 16 {
-        if PRINT_IN_INTERRUPTS {
+        if config::print_in_interrupts() {
             let irq = vector - apic::PIC_BASE_IRQ;
 
             // Don't even print anything for PIC spurious interrupts.
@@ -317,13 +331,14 @@ fn try_handle_interrupt(vector: u8, _frame: &mut InterruptFrame, _error: u64,
             let tsc      = time::get();
             let last_tsc = core!().last_timer_tsc.load(Ordering::Relaxed);
 
-            // This is first timer tick on this core if last TSC == 0.
-            if last_tsc > 0 {
+            // This is first timer tick on this core if last TSC == 0. Skip the check if the
+            // kernel was stopped by GDB in the meantime.
+            if last_tsc > 0 && !gdb::stopped_since(last_tsc) {
                 // Get the time from last tick to this tick.
                 let difference = time::difference(last_tsc, tsc);
 
                 if false {
-                    if PRINT_IN_INTERRUPTS {
+                    if config::print_in_interrupts() {
                         println!("Timer tick on CPU {}. Elapsed time: {:.2}ms.", core!().id,
                                  difference * 1000.0);
                     }
@@ -331,13 +346,40 @@ fn try_handle_interrupt(vector: u8, _frame: &mut InterruptFrame, _error: u64,
 
                 // If the difference is too high that means that someone had interrupts disabled
                 // for too long.
-                if difference > apic::APIC_TIMER_PERIOD * 3.0 + 0.1 {
+                if difference > config::apic_timer_period() * 3.0 + 0.1 {
                     panic!("Interrupts were disabled for too long ({:.02}s).", difference);
                 }
             }
 
             core!().last_timer_tsc.store(tsc, Ordering::Relaxed);
 
+            if gdb::enabled() {
+                unsafe {
+                    gdb::poll_interrupt(frame, regs);
+                }
+            }
+
+            true
+        }
+        apic::TLB_SHOOTDOWN_IRQ => {
+            unsafe {
+                tlb::handle_interrupt();
+            }
+
+            true
+        }
+        vector if serial::is_serial_vector(vector) => {
+            unsafe {
+                serial::handle_interrupt();
+            }
+
+            true
+        }
+        1 | 3 if gdb::enabled() => {
+            unsafe {
+                gdb::handle_debug_exception(vector, frame, regs);
+            }
+
             true
         }
         apic::SPURIOUS_IRQ => {
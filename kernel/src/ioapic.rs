@@ -0,0 +1,227 @@
+// I/O APIC driver. It's used to deliver ISA IRQs (like serial port interrupts) to the
+// local APICs. All I/O APIC inputs are masked by default and must be routed explicitly.
+
+use alloc::vec::Vec;
+
+use page_table::PhysAddr;
+use crate::{mm, lock::Lock};
+
+/// Vector of ISA IRQ 0. ISA IRQ `n` is delivered at vector `ISA_BASE_IRQ + n`.
+pub const ISA_BASE_IRQ: u8 = 0x40;
+
+/// Number of legacy ISA IRQs.
+pub const ISA_IRQ_COUNT: u8 = 16;
+
+const IOREGSEL: usize = 0x00;
+const IOWIN:    usize = 0x10;
+
+const REG_VERSION:     u32 = 0x01;
+const REG_REDIRECTION: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL:      u64 = 1 << 15;
+const REDIRECTION_MASKED:     u64 = 1 << 16;
+
+// MPS INTI flags used by the Interrupt Source Override entries.
+const POLARITY_MASK:       u16 = 0b11;
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MASK:        u16 = 0b11 << 2;
+const TRIGGER_LEVEL:       u16 = 0b11 << 2;
+
+static STATE: Lock<Option<IoApicState>> = Lock::new(None);
+
+struct IoApic {
+    /// Virtual address of the register window.
+    base: u64,
+
+    /// First GSI handled by this I/O APIC.
+    gsi_base: u32,
+
+    /// Number of redirection entries.
+    entries: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, register: u32) -> u32 {
+        core::ptr::write_volatile((self.base as usize + IOREGSEL) as *mut u32, register);
+        core::ptr::read_volatile((self.base as usize + IOWIN) as *const u32)
+    }
+
+    unsafe fn write(&mut self, register: u32, value: u32) {
+        core::ptr::write_volatile((self.base as usize + IOREGSEL) as *mut u32, register);
+        core::ptr::write_volatile((self.base as usize + IOWIN) as *mut u32, value);
+    }
+
+    unsafe fn set_redirection(&mut self, entry: u32, value: u64) {
+        let register = REG_REDIRECTION + entry * 2;
+
+        // Mask the entry first so it's never active in a partially written state.
+        self.write(register + 0, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (value >> 32) as u32);
+        self.write(register + 0, (value >> 0)  as u32);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.entries
+    }
+}
+
+/// Interrupt Source Override from the MADT.
+#[derive(Copy, Clone)]
+struct SourceOverride {
+    gsi:   u32,
+    flags: u16,
+}
+
+struct IoApicState {
+    ioapics:   Vec<IoApic>,
+    overrides: [Option<SourceOverride>; ISA_IRQ_COUNT as usize],
+}
+
+unsafe fn parse_madt(payload: PhysAddr, payload_size: usize) -> IoApicState {
+    let mut state = IoApicState {
+        ioapics:   Vec::new(),
+        overrides: [None; ISA_IRQ_COUNT as usize],
+    };
+
+    // Get the address of Interrupt Controller Structure. We need to skip
+    // local interrupt controller address (4 bytes) and flags (4 bytes).
+    let mut ics = PhysAddr(payload.0 + 4 + 4);
+    let end     = payload.0 + payload_size as u64;
+
+    // Go through every ICS in the MADT.
+    loop {
+        // Make sure that there is enough space for ICS type and size.
+        if ics.0 + 2 > end {
+            break;
+        }
+
+        let ics_type: u8 = mm::read_phys(PhysAddr(ics.0 + 0));
+        let ics_size: u8 = mm::read_phys(PhysAddr(ics.0 + 1));
+
+        // Make sure that the ICS size is valid.
+        assert!(ics_size >= 2, "ICS size is invalid.");
+
+        // Make sure that there is enough space for the whole ICS entry.
+        if ics.0 + ics_size as u64 > end {
+            break;
+        }
+
+        match ics_type {
+            1 => {
+                // I/O APIC
+
+                // Make sure that the size that we expect is correct.
+                assert!(ics_size == 12, "Invalid I/O APIC entry size.");
+
+                let address:  u32 = mm::read_phys_unaligned(PhysAddr(ics.0 + 4));
+                let gsi_base: u32 = mm::read_phys_unaligned(PhysAddr(ics.0 + 8));
+
+                // Registers are within 0x20 bytes from the base, map the whole page.
+                let page_offset = address as u64 & 0xfff;
+                let mapping     = mm::map_mmio(PhysAddr(address as u64 - page_offset), 4096,
+                                               mm::PAGE_UNCACHEABLE);
+
+                let mut ioapic = IoApic {
+                    base:    mapping.0 + page_offset,
+                    gsi_base,
+                    entries: 0,
+                };
+
+                ioapic.entries = ((ioapic.read(REG_VERSION) >> 16) & 0xff) + 1;
+
+                state.ioapics.push(ioapic);
+            }
+            2 => {
+                // Interrupt Source Override
+
+                // Make sure that the size that we expect is correct.
+                assert!(ics_size == 10, "Invalid Interrupt Source Override entry size.");
+
+                let bus:    u8  = mm::read_phys_unaligned(PhysAddr(ics.0 + 2));
+                let source: u8  = mm::read_phys_unaligned(PhysAddr(ics.0 + 3));
+                let gsi:    u32 = mm::read_phys_unaligned(PhysAddr(ics.0 + 4));
+                let flags:  u16 = mm::read_phys_unaligned(PhysAddr(ics.0 + 8));
+
+                // Bus 0 is ISA, nothing else is defined.
+                if bus == 0 && source < ISA_IRQ_COUNT {
+                    state.overrides[source as usize] = Some(SourceOverride { gsi, flags });
+                }
+            }
+            _ => {}
+        }
+
+        // Go to the next ICS entry.
+        ics = PhysAddr(ics.0 + ics_size as u64);
+    }
+
+    state
+}
+
+/// Route ISA IRQ `irq` to the local APIC `apic_id`. It will be delivered at vector
+/// `ISA_BASE_IRQ + irq`. Returns `false` if there is no I/O APIC which handles this IRQ.
+pub unsafe fn route_isa_irq(irq: u8, apic_id: u32) -> bool {
+    assert!(irq < ISA_IRQ_COUNT, "Invalid ISA IRQ {}.", irq);
+    assert!(apic_id < 256, "APIC ID {} cannot be used as I/O APIC destination.", apic_id);
+
+    let mut state = STATE.lock();
+    let     state = match state.as_mut() {
+        Some(state) => state,
+        None        => return false,
+    };
+
+    // ISA IRQs are identity mapped to GSIs, edge triggered and active high unless
+    // overriden by the firmware.
+    let (gsi, flags) = match state.overrides[irq as usize] {
+        Some(source) => (source.gsi, source.flags),
+        None         => (irq as u32, 0),
+    };
+
+    let ioapic = match state.ioapics.iter_mut().find(|ioapic| ioapic.handles(gsi)) {
+        Some(ioapic) => ioapic,
+        None         => return false,
+    };
+
+    let mut entry = (ISA_BASE_IRQ + irq) as u64 | ((apic_id as u64) << 56);
+
+    if flags & POLARITY_MASK == POLARITY_ACTIVE_LOW {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+
+    if flags & TRIGGER_MASK == TRIGGER_LEVEL {
+        entry |= REDIRECTION_LEVEL;
+    }
+
+    let index = gsi - ioapic.gsi_base;
+
+    ioapic.set_redirection(index, entry);
+
+    true
+}
+
+pub unsafe fn initialize() {
+    let mut state = STATE.lock();
+
+    // Make sure that the I/O APIC hasn't been initialized yet.
+    assert!(state.is_none(), "I/O APIC was already initialized.");
+
+    let mut new_state = match crate::acpi::get_only_acpi_table("APIC") {
+        Some((payload, payload_size)) => parse_madt(payload, payload_size),
+        None                          => {
+            color_println!(0xffff00, "WARNING: No APIC table was found, I/O APIC \
+                                      won't be used.");
+            return;
+        }
+    };
+
+    // Mask all interrupts, they will be enabled when some driver routes them.
+    for ioapic in &mut new_state.ioapics {
+        for entry in 0..ioapic.entries {
+            ioapic.set_redirection(entry, REDIRECTION_MASKED);
+        }
+    }
+
+    println!("Found {} I/O APICs.", new_state.ioapics.len());
+
+    *state = Some(new_state);
+}
@@ -8,6 +8,14 @@ impl lock::Interrupts for KernelInterrupts {
 
     unsafe fn enable_interrupts()  { core!().enable_interrupts()  }
     unsafe fn disable_interrupts() { core!().disable_interrupts() }
+
+    fn spin_wait() {
+        // Lock owner may be waiting for us to acknowledge TLB shootdown. We won't get the IPI
+        // if we have interrupts disabled, so handle the request while waiting.
+        crate::tlb::handle_pending();
+
+        core::hint::spin_loop();
+    }
 }
 
 pub type Lock<T>          = lock::Lock<T, KernelInterrupts>;
@@ -9,7 +9,12 @@ extern crate alloc;
 #[macro_use] mod print;
 mod vm;
 mod mm;
+mod slab;
+mod buddy;
+mod vma;
+mod tlb;
 mod once;
+mod config;
 mod apic;
 mod lock;
 mod acpi;
@@ -17,15 +22,24 @@ mod font;
 mod time;
 mod hpet;
 mod panic;
+mod backtrace;
+mod gdb;
 mod processors;
 mod interrupts;
 mod framebuffer;
 mod interrupts_misc;
+mod initrd;
+mod ioapic;
+mod serial;
+mod pvh;
+
+#[cfg(feature = "qemu_test")] mod qemu;
+#[cfg(feature = "kernel_tests")] mod tests;
 
 use page_table::PhysAddr;
 
 #[no_mangle]
-extern "C" fn _start(boot_block: PhysAddr, boot_tsc: u64) -> ! {
+extern "C" fn _start(boot_block: PhysAddr, boot_tsc: u64, physical_region: u64) -> ! {
     // Make sure that LLVM data layout isn't broken.
     assert!(core::mem::size_of::<u64>() == 8 && core::mem::align_of::<u64>() == 8,
             "U64 has invalid size/alignment.");
@@ -34,10 +48,16 @@ extern "C" fn _start(boot_block: PhysAddr, boot_tsc: u64) -> ! {
         // Zero out the IDT so if there is any exception we will triple fault.
         cpu::set_idt(&cpu::TableRegister::zero());
 
+        // Physical memory map location is randomized, we need it to access the boot block.
+        mm::set_physical_region_base(physical_region);
+
         core_locals::initialize(boot_block, boot_tsc);
         mm::initialize();
 
         if core!().id == 0 {
+            // Parse the command line before anything else depends on it.
+            config::initialize();
+
             // Initialize framebuffer early so we can show logs on the screen.
             framebuffer::initialize();
         }
@@ -48,6 +68,9 @@ extern "C" fn _start(boot_block: PhysAddr, boot_tsc: u64) -> ! {
         if core!().id == 0 {
             acpi::initialize();
             time::initialize();
+            ioapic::initialize();
+            serial::initialize();
+            initrd::initialize();
 
             // Launch APs.
             processors::initialize();
@@ -61,22 +84,38 @@ extern "C" fn _start(boot_block: PhysAddr, boot_tsc: u64) -> ! {
         mm::on_finished_boot_process();
 
         interrupts::initial_enable();
+
+        // Interrupts are enabled so this core can respond to TLB shootdowns.
+        tlb::enable();
     }
 
     if core!().id == 0 {
         color_println!(0xff00ff, "Flugzeug OS loaded in {:.2}ms! {} CPUs, {:?}.",
                        time::uptime() * 1000.0, processors::total_cores(), core!().apic_mode());
 
+        serial_log!("boot_finished cpus={}", processors::total_cores());
+
         let mut buffer = [0u8; 256];
 
         if let Some(cpu_name) = cpu::cpuid_identifier(0x8000_0002, 4, &mut buffer) {
             color_println!(0xff00ff, "Running on {}.", cpu_name);
         }
 
+        // Failed kernel test panics and halts the kernel.
+        #[cfg(feature = "kernel_tests")]
+        tests::run();
+
         unsafe {
             vm::initialize();
         }
+
+        #[cfg(feature = "qemu_test")]
+        qemu::finish_successful_boot();
     }
 
+    // APs run parts of kernel tests which need to execute on another core.
+    #[cfg(feature = "kernel_tests")]
+    tests::serve_remote_calls();
+
     time::idle();
 }
@@ -1,18 +1,47 @@
 use core::alloc::{GlobalAlloc, Layout};
-use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use core::ops::{Deref, DerefMut};
 use core::marker::PhantomData;
-use alloc::vec::Vec;
 
-use crate::lock::Lock;
+use crate::{slab, buddy, vma, processors};
+use crate::vma::VmaAllocator;
+use crate::buddy::DmaZone;
+use crate::tlb::Shootdown;
 use rangeset::Range;
 use page_table::{VirtAddr, PhysAddr, PhysMem, PageType, PAGE_PRESENT, PAGE_WRITE,
                  PAGE_SIZE, PAGE_NX, PAGE_CACHE_DISABLE, PAGE_PAT, PAGE_PWT};
-use boot_block::{KERNEL_PHYSICAL_REGION_BASE, KERNEL_HEAP_BASE, KERNEL_HEAP_PADDING};
+use boot_block::{KERNEL_HEAP_PADDING, KernelLayout};
 pub use boot_block::{BootBlock, KERNEL_PHYSICAL_REGION_SIZE};
 
 pub const MAX_ACCESSIBLE_PHYSICAL_ADDRESS: u64 = KERNEL_PHYSICAL_REGION_SIZE - 1;
 
+/// Virtual address of the linear physical memory map chosen by the bootloader.
+static PHYSICAL_REGION_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Set the base of the linear physical memory map. Must be called on every core before
+/// accessing any physical memory.
+pub unsafe fn set_physical_region_base(base: u64) {
+    assert!(base != 0, "Physical region base is null.");
+
+    // All cores must use the same physical region.
+    if let Err(current) = PHYSICAL_REGION_BASE.compare_exchange(0, base, Ordering::SeqCst,
+                                                                Ordering::SeqCst) {
+        assert!(current == base, "Cores got different physical region bases.");
+    }
+}
+
+/// Initialize virtual memory regions using the layout chosen by the bootloader.
+pub unsafe fn initialize_layout(layout: &KernelLayout) {
+    assert!(layout.physical_region_base == PHYSICAL_REGION_BASE.load(Ordering::SeqCst),
+            "Kernel layout doesn't match physical region used to enter the kernel.");
+
+    // Only the first core sets up the heap base, others will see already advanced value.
+    let _ = NEXT_HEAP_ADDRESS.compare_exchange(0, layout.heap_base, Ordering::SeqCst,
+                                               Ordering::SeqCst);
+
+    HEAP_BASE.store(layout.heap_base, Ordering::SeqCst);
+}
+
 pub struct PhysicalMemory;
 
 impl PhysMem for PhysicalMemory {
@@ -60,7 +89,7 @@ pub unsafe fn translate(phys_addr: PhysAddr, size: usize) -> Option<*mut u8> {
         return None;
     }
 
-    Some((KERNEL_PHYSICAL_REGION_BASE + phys_addr.0) as *mut u8)
+    Some((PHYSICAL_REGION_BASE.load(Ordering::Relaxed) + phys_addr.0) as *mut u8)
 }
 
 pub unsafe fn phys_ref<T>(phys_addr: PhysAddr) -> Option<&'static T> {
@@ -122,9 +151,29 @@ pub unsafe fn write_phys_unaligned<T>(phys_addr: PhysAddr, value: T) {
     core::ptr::write_unaligned(virt_addr as *mut T, value);
 }
 
-/// Address of the next free virtual address in the kernel heap.
-static NEXT_HEAP_ADDRESS: AtomicU64 = AtomicU64::new(KERNEL_HEAP_BASE);
+/// Address of the next free virtual address in the kernel heap. Set by `initialize_layout`.
+static NEXT_HEAP_ADDRESS: AtomicU64 = AtomicU64::new(0);
 
+/// Base of the kernel heap region chosen by the bootloader.
+static HEAP_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Size of the heap part used by `reserve_virt_addr`. It's followed by the kernel VMA region
+/// and per-core heaps.
+const SHARED_HEAP_SIZE: u64 = 4 * 1024 * 1024 * 1024 * 1024;
+
+/// Size of the heap part used by the kernel VMA allocator.
+const KERNEL_VMA_SIZE: u64 = 4 * 1024 * 1024 * 1024 * 1024;
+
+/// Size of the heap part which is used by allocations of a single core. The core which
+/// owns an allocation can be determined from its address.
+const CORE_HEAP_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+
+/// Number of entries which are always kept available in `RangeSet`s when giving memory back,
+/// so allocations which split ranges won't run out of space.
+const RESERVED_RANGE_ENTRIES: usize = 16;
+
+/// Reserve virtual region which will be never freed. Use `vma` module for regions which
+/// can be freed.
 pub fn reserve_virt_addr(size: usize) -> VirtAddr {
     // Make sure that the requested size is valid.
     assert!(size > 0 && size % 4096 == 0, "Size to reserve is invalid.");
@@ -135,17 +184,115 @@ pub fn reserve_virt_addr(size: usize) -> VirtAddr {
     // Reserve the region.
     let address = NEXT_HEAP_ADDRESS.fetch_add(reserve, Ordering::SeqCst);
 
-    // Make sure that we haven't overflowed the heap region.
-    address.checked_add(reserve).expect("Heap virtual address overflowed.");
+    assert!(address != 0, "Kernel layout wasn't initialized.");
+
+    // Make sure that we haven't overflowed the shared heap region.
+    let end = address.checked_add(reserve).expect("Heap virtual address overflowed.");
+
+    assert!(end <= HEAP_BASE.load(Ordering::Relaxed) + SHARED_HEAP_SIZE,
+            "Shared heap region is exhausted.");
 
     VirtAddr(address)
 }
 
+/// Get the base address of per-core heaps.
+fn core_heaps_base() -> u64 {
+    let heap_base = HEAP_BASE.load(Ordering::Relaxed);
+
+    assert!(heap_base != 0, "Kernel layout wasn't initialized.");
+
+    heap_base + SHARED_HEAP_SIZE + KERNEL_VMA_SIZE
+}
+
+/// Get ID of the core whose heap contains `virt_addr`.
+pub fn heap_owner(virt_addr: VirtAddr) -> Option<u64> {
+    let core_id = virt_addr.0.checked_sub(core_heaps_base())? / CORE_HEAP_SIZE;
+    if  core_id >= processors::MAX_CORES as u64 {
+        return None;
+    }
+
+    Some(core_id)
+}
+
+/// Create an allocator for the heap part of `core_id`. Kernel layout must be initialized.
+pub fn core_heap_vmas(core_id: u64) -> VmaAllocator {
+    assert!(core_id < processors::MAX_CORES as u64, "Core ID is too big for the heap.");
+
+    VmaAllocator::new(VirtAddr(core_heaps_base() + core_id * CORE_HEAP_SIZE), CORE_HEAP_SIZE)
+}
+
+/// Map new heap region with `size` bytes in the current core heap. `size` must be a power
+/// of two and region will be aligned to it. Returns `None` if there is not enough memory.
+unsafe fn map_heap(size: usize) -> Option<VirtAddr> {
+    assert!(size.count_ones() == 1 && size >= 4096, "Invalid heap region size.");
+
+    let virt_addr = core!().heap.vmas.lock().allocate(size as u64, size as u64)?;
+
+    let mapped = {
+        let mut page_table = core!().boot_block.page_table.lock();
+        let page_table     = page_table.as_mut().unwrap();
+
+        // Map new memory region as readable and writable.
+        page_table.map(&mut PhysicalMemory, virt_addr, PageType::Page4K,
+                       size as u64, true, false, false)
+    };
+
+    if mapped.is_none() {
+        // Some pages may have been mapped before we ran out of memory, give them back.
+        unmap_heap(virt_addr, size);
+
+        return None;
+    }
+
+    Some(virt_addr)
+}
+
+/// Unmap heap region created by `map_heap` on the current core, return its pages to
+/// the physical memory allocator and release its virtual region. Returns `false` without
+/// changing anything if free memory set doesn't have enough space to hold all pages.
+unsafe fn unmap_heap(virt_addr: VirtAddr, size: usize) -> bool {
+    // In the worst case every page will create a new entry in the free memory set.
+    if core!().boot_block.free_memory.lock().free_entries() < size / 4096 + RESERVED_RANGE_ENTRIES {
+        return false;
+    }
+
+    {
+        let mut page_table = core!().boot_block.page_table.lock();
+        let page_table     = page_table.as_mut().unwrap();
+
+        // Pages were mapped using `map` so they will be returned to the free memory set.
+        // Objects could have been used by other cores, so invalidate TLB everywhere.
+        page_table.unmap(&mut Shootdown::default(), virt_addr, PageType::Page4K, size as u64)
+            .expect("Failed to unmap heap memory.");
+    }
+
+    core!().heap.vmas.lock().free(virt_addr, size as u64);
+
+    true
+}
+
+/// Return physical region to the physical memory allocator if it won't use up space
+/// reserved in the free memory set. Returns `false` if region wasn't freed.
+pub unsafe fn try_free_phys(phys_addr: PhysAddr, size: usize) -> bool {
+    let mut free_memory = core!().boot_block.free_memory.lock();
+
+    if free_memory.free_entries() <= RESERVED_RANGE_ENTRIES {
+        return false;
+    }
+
+    free_memory.insert(Range {
+        start: phys_addr.0,
+        end:   phys_addr.0 + size as u64 - 1,
+    });
+
+    true
+}
+
 pub unsafe fn map_mmio(phys_addr: PhysAddr, size: u64, flags: u64) -> VirtAddr {
     assert!(phys_addr.0 & 0xfff == 0, "MMIO base {:x} is not page aligned.", phys_addr.0);
     assert!(size        & 0xfff == 0, "MMIO size {:x} is not page aligned.", size);
 
-    let virt_addr = reserve_virt_addr(size as usize);
+    let virt_addr = vma::allocate(size, 4096);
 
     let mut page_table = core!().boot_block.page_table.lock();
     let page_table     = page_table.as_mut().unwrap();
@@ -164,6 +311,20 @@ pub unsafe fn map_mmio(phys_addr: PhysAddr, size: u64, flags: u64) -> VirtAddr {
     virt_addr
 }
 
+/// Unmap MMIO region created by `map_mmio` and free its virtual memory.
+#[allow(unused)]
+pub unsafe fn unmap_mmio(virt_addr: VirtAddr, size: u64) {
+    {
+        let mut page_table = core!().boot_block.page_table.lock();
+        let page_table     = page_table.as_mut().unwrap();
+
+        page_table.unmap(&mut Shootdown::default(), virt_addr, PageType::Page4K, size)
+            .expect("Failed to unmap MMIO.");
+    }
+
+    vma::free(virt_addr, size);
+}
+
 /// Amount of memory used by the stack metadata in `FreeListNode`.
 const STACK_HEADER_SIZE: usize = core::mem::size_of::<usize>() * 2;
 
@@ -232,6 +393,11 @@ impl FreeList {
         }
     }
 
+    /// Returns `true` if there are no free allocations in this list.
+    pub fn is_empty(&self) -> bool {
+        self.head == 0
+    }
+
     /// Put an allocation back to the free memory list.
     pub unsafe fn push(&mut self, virt_addr: *mut u8) {
         if self.use_stack() {
@@ -273,7 +439,8 @@ impl FreeList {
         }
     }
 
-    /// Get an allocation from the free memory list.
+    /// Get an allocation from the free memory list. Returns null pointer if there is not
+    /// enough physical memory.
     pub unsafe fn pop(&mut self) -> *mut u8 {
         if self.head == 0 {
             // This list is empty, we need to populate it with some memory.
@@ -285,16 +452,10 @@ impl FreeList {
                 self.size
             };
 
-            // Reserve virtual region.
-            let virt_addr = reserve_virt_addr(actual_size);
-
-            let mut page_table = core!().boot_block.page_table.lock();
-            let page_table     = page_table.as_mut().unwrap();
-
-            // Map new memory region as readable and writable.
-            page_table.map(&mut PhysicalMemory, virt_addr, PageType::Page4K,
-                           actual_size as u64, true, false, false)
-                .expect("Failed to map heap memory.");
+            let virt_addr = match map_heap(actual_size) {
+                Some(virt_addr) => virt_addr,
+                None            => return core::ptr::null_mut(),
+            };
 
             if actual_size != self.size {
                 // We have overallocated memory and we need to add all regions to the free list,
@@ -346,17 +507,132 @@ impl FreeList {
             head_node as *mut u8
         }
     }
+
+    /// Unmap all pages which contain only free allocations and return them to the physical
+    /// memory allocator. Returns the number of reclaimed bytes.
+    pub unsafe fn trim(&mut self) -> usize {
+        // Allocations smaller than a page share it, bigger ones are mapped separately.
+        let region_size = core::cmp::max(self.size, 4096);
+        let region_mask = if self.size < 4096 { !0xfff } else { !0 };
+        let allocations = region_size / self.size;
+
+        // Take all allocations from this list and link them together. Every allocation
+        // is at least pointer sized so it can hold the address of the next one.
+        let mut list = 0;
+
+        while self.head != 0 {
+            let allocation = self.pop() as *mut usize;
+
+            *allocation = list;
+            list        = allocation as usize;
+        }
+
+        // Sort allocations so ones from the same page are next to each other.
+        let mut current   = sort_allocations(list);
+        let mut reclaimed = 0;
+
+        while current != 0 {
+            let region = current & region_mask;
+
+            // Find the end of allocations which belong to this region.
+            let mut end   = current;
+            let mut count = 0;
+
+            while end != 0 && end & region_mask == region {
+                end    = *(end as *const usize);
+                count += 1;
+            }
+
+            if count == allocations && unmap_heap(VirtAddr(region as u64), region_size) {
+                reclaimed += region_size;
+            } else {
+                // Some part of this region is still used, put its allocations back.
+                while current != end {
+                    let next = *(current as *const usize);
+
+                    self.push(current as *mut u8);
+
+                    current = next;
+                }
+            }
+
+            current = end;
+        }
+
+        reclaimed
+    }
+}
+
+/// Sort linked list of allocations by address. Every allocation holds the address
+/// of the next one, last one holds 0.
+unsafe fn sort_allocations(list: usize) -> usize {
+    let next = |allocation: usize| *(allocation as *const usize);
+
+    if list == 0 || next(list) == 0 {
+        return list;
+    }
+
+    // Find the middle of the list and split it into two halves.
+    let mut middle = list;
+    let mut fast   = next(list);
+
+    while fast != 0 && next(fast) != 0 {
+        middle = next(middle);
+        fast   = next(next(fast));
+    }
+
+    let second = next(middle);
+
+    *(middle as *mut usize) = 0;
+
+    let mut first  = sort_allocations(list);
+    let mut second = sort_allocations(second);
+
+    // Merge both sorted halves.
+    let mut head = 0;
+    let mut tail = &mut head as *mut usize;
+
+    while first != 0 && second != 0 {
+        let allocation = if first < second {
+            let allocation = first;
+
+            first = next(first);
+
+            allocation
+        } else {
+            let allocation = second;
+
+            second = next(second);
+
+            allocation
+        };
+
+        *tail = allocation;
+        tail  = allocation as *mut usize;
+    }
+
+    *tail = if first != 0 { first } else { second };
+
+    head
+}
+
+/// Return heap pages which contain only free allocations of the current core and free blocks
+/// cached by the buddy allocator to the physical memory allocator. Returns the number of
+/// reclaimed bytes.
+#[allow(unused)]
+pub fn trim_heap() -> usize {
+    slab::trim() + buddy::trim()
 }
 
 pub struct GlobalAllocator;
 
 unsafe impl GlobalAlloc for GlobalAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        core!().free_list(layout).lock().pop()
+        slab::alloc(layout)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        core!().free_list(layout).lock().push(ptr)
+        slab::dealloc(ptr, layout)
     }
 }
 
@@ -381,13 +657,15 @@ unsafe fn enable_nx_on_physical_region() {
         _        => panic!("Bootloader set invalid physical map page size {:x}.", page_size),
     };
 
+    let physical_region = PHYSICAL_REGION_BASE.load(Ordering::Relaxed);
+
     let mut page_table = core!().boot_block.page_table.lock();
     let page_table     = page_table.as_mut().unwrap();
 
     // Recreate kernel physical memory map.
     for phys_addr in (0..KERNEL_PHYSICAL_REGION_SIZE).step_by(page_size as usize) {
-        // Map current `phys_addr` at virtual address `phys_addr` + `KERNEL_PHYSICAL_REGION_BASE`.
-        let virt_addr = VirtAddr(phys_addr + KERNEL_PHYSICAL_REGION_BASE);
+        // Map current `phys_addr` at virtual address `phys_addr` + physical region base.
+        let virt_addr = VirtAddr(phys_addr + physical_region);
 
         // This physical memory page will be writable. Unlike in bootloader, we can now set NX bit.
         let mut raw = phys_addr | PAGE_PRESENT | PAGE_WRITE | PAGE_NX;
@@ -461,8 +739,9 @@ unsafe fn cleanup_bootloader() {
 pub unsafe fn on_finished_boot_process() {
     type BootBlock = boot_block::BootBlock<crate::lock::KernelInterrupts>;
 
-    static READY_CORES:    AtomicU32   = AtomicU32::new(0);
-    static NEW_BOOT_BLOCK: AtomicUsize = AtomicUsize::new(0);
+    static READY_CORES:      AtomicU32   = AtomicU32::new(0);
+    static NEW_BOOT_BLOCK:   AtomicUsize = AtomicUsize::new(0);
+    static CLEANUP_FINISHED: AtomicBool  = AtomicBool::new(false);
 
     let core_id      = core!().id;
     let raw_locals   = crate::core_locals::get_raw_core_locals();
@@ -538,6 +817,14 @@ pub unsafe fn on_finished_boot_process() {
     if core_id == 0 {
         // We don't depend on bootloader anymore, clean up memory.
         cleanup_bootloader();
+
+        CLEANUP_FINISHED.store(true, Ordering::SeqCst);
+    } else {
+        // Interrupts are still disabled so TLB shootdowns can't be used. Wait for BSP
+        // to finish changing the page table and flush whole TLB afterwards instead.
+        while !CLEANUP_FINISHED.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
     }
 
     // Flush whole TLB.
@@ -565,6 +852,29 @@ pub fn dump_memory_ranges() {
     }
 }
 
+/// Print statistics of all heap caches which were ever used.
+#[allow(unused)]
+pub fn dump_heap_stats() {
+    println!("{:<12} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>8}", "cache", "size",
+             "allocs", "live", "live mem", "peak", "peak mem", "failures");
+
+    for index in 0..slab::CACHE_COUNT {
+        let stats = slab::stats(index);
+
+        if stats.allocations == 0 && stats.failures == 0 {
+            continue;
+        }
+
+        // `Memory` and `CacheName` don't support padding, format them first.
+        use alloc::string::ToString;
+
+        println!("{:<12} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>8}",
+                 stats.name.to_string(), Memory(stats.object_size as u64).to_string(),
+                 stats.allocations, stats.live_objects, Memory(stats.live_bytes).to_string(),
+                 stats.peak_objects, Memory(stats.peak_bytes).to_string(), stats.failures);
+    }
+}
+
 struct Memory(u64);
 
 impl core::fmt::Display for Memory {
@@ -601,6 +911,9 @@ pub const PAGE_WB:          u64 = 0;
 pub const PAGE_UNCACHEABLE: u64 = PAGE_CACHE_DISABLE;
 pub const PAGE_WC:          u64 = PAGE_PAT | PAGE_PWT;
 
+/// PAT bit in 2M and 1G page table entries.
+const PAGE_PAT_LARGE: u64 = 1 << 12;
+
 pub unsafe fn initialize() {
     const IA32_MTRRCAP:       u32 = 0xfe;
     const IA32_MTRR_DEF_TYPE: u32 = 0x2ff;
@@ -638,7 +951,12 @@ pub unsafe fn initialize() {
 
     cpu::wrmsr(IA32_PAT, pat);
 
-    *CONTIGUOUS_REGIONS.lock() = Some(ContiguousRegions::default());
+    if core!().id == 0 {
+        let heap_base = HEAP_BASE.load(Ordering::Relaxed);
+
+        vma::initialize(VirtAddr(heap_base + SHARED_HEAP_SIZE), KERNEL_VMA_SIZE);
+        buddy::initialize();
+    }
 }
 
 pub unsafe fn virt_to_phys(virt_addr: VirtAddr) -> Option<PhysAddr> {
@@ -657,11 +975,27 @@ pub unsafe fn virt_to_phys(virt_addr: VirtAddr) -> Option<PhysAddr> {
 pub struct PhysicalPage<T> {
     phys_addr: PhysAddr,
     virt_addr: VirtAddr,
+    cache:     Option<slab::ObjectCache>,
     _phantom:  PhantomData<T>,
 }
 
 impl<T> PhysicalPage<T> {
     pub fn new(value: T) -> Self {
+        Self::allocate(value, None)
+    }
+
+    /// Create a physical page allocated from object cache `cache`.
+    pub fn with_cache(value: T, cache: slab::ObjectCache) -> Self {
+        Self::allocate(value, Some(cache))
+    }
+
+    fn layout() -> Layout {
+        // Use 4K size and 4K alignment to allocate only one page. More pages won't be
+        // physically contigious.
+        Layout::from_size_align(4096, 4096).unwrap()
+    }
+
+    fn allocate(value: T, cache: Option<slab::ObjectCache>) -> Self {
         // Verify that `PhysicalPage` constraints are met.
         assert!(core::mem::size_of::<T>() > 0, "`PhsycialPage` doesn't support zero sized types.");
         assert!(core::mem::size_of::<T>() <= 4096, "`PhysicalPage` can hold 4096 bytes at most.");
@@ -670,11 +1004,13 @@ impl<T> PhysicalPage<T> {
 
         unsafe {
             // Allocate object in virtual heap and get its physical address.
-            // Use 4K size and 4K alignment to allocate only one page. More pages won't be
-            // physically contigious.
-            let virt_addr = VirtAddr(
-                GLOBAL_ALLOCATOR.alloc(Layout::from_size_align(4096, 4096).unwrap()) as u64
-            );
+            let virt_addr = VirtAddr(match cache {
+                Some(cache) => cache.alloc(Self::layout()),
+                None        => GLOBAL_ALLOCATOR.alloc(Self::layout()),
+            } as u64);
+
+            assert!(virt_addr.0 != 0, "Failed to allocate physical page.");
+
             let phys_addr = virt_to_phys(virt_addr).expect("Failed to translate allocated virtual \
                                                            address to physical address.");
 
@@ -687,6 +1023,7 @@ impl<T> PhysicalPage<T> {
             Self {
                 phys_addr,
                 virt_addr,
+                cache,
                 _phantom: PhantomData,
             }
         }
@@ -725,118 +1062,102 @@ impl<T> Drop for PhysicalPage<T> {
             // Destroy object in physical page.
             core::ptr::drop_in_place(self.virt_addr.0 as *mut T);
 
-            // Free object memory using the same layout as in constructor.
-            GLOBAL_ALLOCATOR.dealloc(
-                self.virt_addr.0 as *mut u8, Layout::from_size_align(4096, 4096).unwrap(),
-            );
+            // Free object memory using the same cache and layout as in constructor.
+            match self.cache {
+                Some(cache) => cache.dealloc(self.virt_addr.0 as *mut u8, Self::layout()),
+                None        => GLOBAL_ALLOCATOR.dealloc(self.virt_addr.0 as *mut u8,
+                                                        Self::layout()),
+            }
         }
     }
 }
 
-static CONTIGUOUS_REGIONS: Lock<Option<ContiguousRegions>> = Lock::new(None);
+pub struct ContiguousRegion {
+    phys_addr: PhysAddr,
+    virt_addr: VirtAddr,
+    size:      usize,
 
-enum ContiguousType {
-    Size4K  = 0,
-    Size8K  = 1,
-    Size12K = 2,
-    Size16K = 3,
-    Num,
-}
+    /// Size of the allocated physical region and virtual mapping.
+    mapped_size: u64,
 
-impl ContiguousType {
-    fn from_size(size: usize) -> Self {
-        match size {
-            4096  => Self::Size4K,
-            8192  => Self::Size8K,
-            12288 => Self::Size12K,
-            16384 => Self::Size16K,
-            _     => panic!("Unsupported contiguous memory size."),
-        }
-    }
+    /// Page type used to map this region.
+    page_type: PageType,
 }
 
-#[derive(Default)]
-struct ContiguousRegions {
-    regions: [Vec<ContiguousRegion>; ContiguousType::Num as usize],
-}
-
-impl ContiguousRegions {
-    fn regions(&mut self, size: usize) -> &mut Vec<ContiguousRegion> {
-        let size_type = ContiguousType::from_size(size);
-
-        &mut self.regions[size_type as usize]
+impl ContiguousRegion {
+    pub fn new(size: usize) -> Self {
+        Self::allocate(size, 4096, DmaZone::Any, PAGE_WB)
+            .expect("Failed to allocate physically contiguous region.")
     }
 
-    fn allocate(&mut self, size: usize) -> ContiguousRegion {
-        assert!(size % 4096 == 0, "Size is not page aligned.");
-
-        let mut region = if let Some(region) = self.regions(size).pop() {
-            region
+    /// Allocate zeroed, physically contiguous region of `size` bytes aligned to `align` in
+    /// `zone` and map it with caching `flags` (`PAGE_WB`, `PAGE_UNCACHEABLE` or `PAGE_WC`).
+    /// Regions aligned to 2M or 1G are mapped using large pages and their size is rounded
+    /// up to the page size. Returns `None` if there is not enough memory.
+    pub fn allocate(size: usize, align: usize, zone: DmaZone, flags: u64) -> Option<Self> {
+        assert!(size > 0, "Contiguous region cannot be empty.");
+        assert!(align.count_ones() == 1 && align >= 4096, "Invalid contiguous region \
+                alignment {:x}.", align);
+        assert!(flags & !PAGE_CACHE_DISABLE & !PAGE_PAT & !PAGE_PWT == 0,
+                "Invalid contiguous region caching flags.");
+
+        let max_page_type = core!().max_page_type as usize;
+
+        // Use the biggest page which is supported and doesn't waste too much memory.
+        let page_type = [PageType::Page1G, PageType::Page2M]
+            .iter()
+            .copied()
+            .find(|&page_type| {
+                let page_size = page_type as usize;
+
+                page_size <= max_page_type && align >= page_size && size >= page_size
+            })
+            .unwrap_or(PageType::Page4K);
+
+        let page_size   = page_type as u64;
+        let mapped_size = (size as u64 + page_size - 1) & !(page_size - 1);
+
+        let phys_addr = buddy::allocate(mapped_size, align as u64, zone)?;
+        let virt_addr = vma::allocate(mapped_size, page_size);
+
+        // PAT bit is placed in a different position in large page entries.
+        let flags = if page_type != PageType::Page4K && flags & PAGE_PAT != 0 {
+            (flags & !PAGE_PAT) | PAGE_PAT_LARGE
         } else {
-            // Allocate physically contiguous region.
-            let phys_addr = core!().boot_block
-                .free_memory
-                .lock()
-                .allocate_limited(size as u64, 4096, Some(MAX_ACCESSIBLE_PHYSICAL_ADDRESS))
-                .expect("Failed to allocate physically contiguous region.") as u64;
-
-            // Reserve virtual memory for this region.
-            let virt_addr = reserve_virt_addr(size);
+            flags
+        };
 
+        {
             let mut page_table = core!().boot_block.page_table.lock();
             let page_table     = page_table.as_mut().unwrap();
 
-            // Map the contiguous to the virtual memory as writable and non-executable.
-            for offset in (0..size).step_by(4096) {
-                let offset    = offset as u64;
-                let phys_addr = phys_addr + offset;
+            // Map the contiguous region to the virtual memory as writable and non-executable.
+            for offset in (0..mapped_size).step_by(page_size as usize) {
+                let phys_addr = phys_addr.0 + offset;
                 let virt_addr = VirtAddr(virt_addr.0 + offset);
 
-                let backing = PAGE_PRESENT | PAGE_WRITE | PAGE_NX | phys_addr;
+                let backing = PAGE_PRESENT | PAGE_WRITE | PAGE_NX | phys_addr | flags;
 
                 unsafe {
-                    page_table.map_raw(&mut PhysicalMemory, virt_addr, PageType::Page4K,
+                    page_table.map_raw(&mut PhysicalMemory, virt_addr, page_type,
                                        backing, true, false)
                         .expect("Failed to map contiguous region to the virtual memory.");
                 }
             }
+        }
 
-            ContiguousRegion {
-                phys_addr: PhysAddr(phys_addr as u64),
-                virt_addr,
-                size,
-            }
+        let mut region = Self {
+            phys_addr,
+            virt_addr,
+            size,
+            mapped_size,
+            page_type,
         };
 
-        // Make sure that the size matches.
-        assert!(region.size == size, "Corrupted region size.");
-
         // Zero out the region.
         region.iter_mut().for_each(|x| *x = 0);
 
-        region
-    }
-
-    unsafe fn free(&mut self, region: ContiguousRegion) {
-        self.regions(region.size)
-            .push(region);
-    }
-}
-
-pub struct ContiguousRegion {
-    phys_addr: PhysAddr,
-    virt_addr: VirtAddr,
-    size:      usize,
-}
-
-impl ContiguousRegion {
-    pub fn new(size: usize) -> Self {
-        // Get new region from the contiguous allocator.
-        CONTIGUOUS_REGIONS
-            .lock()
-            .as_mut()
-            .unwrap()
-            .allocate(size)
+        Some(region)
     }
 
     pub fn phys_addr(&self) -> PhysAddr {
@@ -865,18 +1186,267 @@ impl DerefMut for ContiguousRegion {
 impl Drop for ContiguousRegion {
     fn drop(&mut self) {
         unsafe {
-            let clone = ContiguousRegion {
-                phys_addr: self.phys_addr,
-                virt_addr: self.virt_addr,
-                size:      self.size,
-            };
+            {
+                let mut page_table = core!().boot_block.page_table.lock();
+                let page_table     = page_table.as_mut().unwrap();
 
-            // Return this region to the contiguous allocator.
-            CONTIGUOUS_REGIONS
-                .lock()
-                .as_mut()
-                .unwrap()
-                .free(clone);
+                page_table.unmap(&mut Shootdown::default(), self.virt_addr, self.page_type,
+                                 self.mapped_size)
+                    .expect("Failed to unmap contiguous region.");
+            }
+
+            // Return virtual and physical memory to their allocators.
+            vma::free(self.virt_addr, self.mapped_size);
+            buddy::free(self.phys_addr, self.mapped_size);
+        }
+    }
+}
+
+#[cfg(feature = "kernel_tests")]
+mod tests {
+    use super::*;
+    use crate::tests::kernel_test;
+    use alloc::vec::Vec;
+    use page_table::PageTable;
+
+    #[kernel_test]
+    fn kernel_runs_at_layout_base() {
+        fn function() {}
+
+        // Absolute addresses in statics are patched by the bootloader, while taking address
+        // in code uses RIP relative addressing. Both must agree.
+        static FUNCTION: fn() = function;
+
+        let layout  = *core!().boot_block.kernel_layout.lock();
+        let address = function as usize as u64;
+
+        // Read the static at runtime so the compiler cannot fold it to RIP relative address.
+        let stored = unsafe { core::ptr::read_volatile(&FUNCTION) } as usize as u64;
+
+        assert!(stored == address, "Kernel wasn't relocated properly.");
+        assert!(address >= layout.kernel_base, "Kernel runs below chosen kernel base.");
+
+        let reserved = reserve_virt_addr(4096);
+
+        assert!(reserved.0 >= layout.heap_base, "Heap doesn't use chosen heap base.");
+    }
+
+    #[kernel_test]
+    fn free_list_reuses_freed_allocations() {
+        for &size in &[8, 16, 64, 512, 4096, 16384] {
+            let mut free_list = FreeList::new(size);
+
+            unsafe {
+                let first  = free_list.pop();
+                let second = free_list.pop();
+
+                assert!(first != second, "Free list returned the same allocation twice.");
+
+                for &allocation in &[first, second] {
+                    assert!(allocation as usize % core::cmp::min(size, 4096) == 0,
+                            "Free list allocation is not aligned.");
+
+                    // Make sure that allocation is backed by writable memory.
+                    core::ptr::write_bytes(allocation, 0x41, size);
+                }
+
+                free_list.push(first);
+                free_list.push(second);
+
+                let mut reused = [free_list.pop(), free_list.pop()];
+                reused.sort_unstable();
+
+                let mut expected = [first, second];
+                expected.sort_unstable();
+
+                assert!(reused == expected, "Free list didn't reuse freed allocations.");
+            }
+        }
+    }
+
+    #[kernel_test]
+    fn free_list_fills_stack_nodes() {
+        let mut free_list = FreeList::new(64);
+        let slots         = free_list.max_slots();
+
+        unsafe {
+            // Allocate enough entries to require more than one stack node.
+            let allocations: Vec<*mut u8> = (0..slots * 3).map(|_| free_list.pop()).collect();
+
+            for &allocation in &allocations {
+                free_list.push(allocation);
+            }
+
+            let mut reused: Vec<*mut u8> = (0..allocations.len())
+                .map(|_| free_list.pop())
+                .collect();
+
+            let mut allocations = allocations;
+
+            reused.sort_unstable();
+            allocations.sort_unstable();
+
+            assert!(reused == allocations, "Free list lost some allocations.");
+        }
+    }
+
+    #[kernel_test]
+    fn contiguous_regions_are_physically_contiguous() {
+        for &size in &[4096, 8192, 12288, 16384] {
+            let region = ContiguousRegion::new(size);
+
+            assert!(region.len() == size, "Contiguous region has invalid size.");
+            assert!(region.iter().all(|&x| x == 0), "Contiguous region is not zeroed.");
+
+            for offset in (0..size as u64).step_by(4096) {
+                let virt_addr = VirtAddr(region.as_ptr() as u64 + offset);
+                let phys_addr = unsafe { virt_to_phys(virt_addr) };
+
+                assert!(phys_addr == Some(PhysAddr(region.phys_addr().0 + offset)),
+                        "Contiguous region is not physically contiguous.");
+            }
+        }
+    }
+
+    #[kernel_test]
+    fn contiguous_regions_are_reused_and_zeroed() {
+        let phys_addr = {
+            let mut region = ContiguousRegion::new(8192);
+
+            region.iter_mut().for_each(|x| *x = 0xcc);
+            region.phys_addr()
+        };
+
+        let region = ContiguousRegion::new(8192);
+
+        assert!(region.phys_addr() == phys_addr, "Freed contiguous region was not reused.");
+        assert!(region.iter().all(|&x| x == 0), "Reused contiguous region is not zeroed.");
+    }
+
+    #[kernel_test]
+    fn contiguous_regions_support_zones_and_large_pages() {
+        let cases = [
+            (12288,    4096,     DmaZone::Below16M, PAGE_UNCACHEABLE, 0xffffff),
+            (0x200000, 0x200000, DmaZone::Below4G,  PAGE_WC,          0xffffffff),
+            (0x201000, 0x200000, DmaZone::Any,      PAGE_WB,          !0),
+        ];
+
+        for &(size, align, zone, flags, max_address) in &cases {
+            let mut region = ContiguousRegion::allocate(size, align, zone, flags)
+                .expect("Failed to allocate contiguous region.");
+
+            let phys_addr = region.phys_addr().0;
+
+            assert!(region.len() == size, "Contiguous region has invalid size.");
+            assert!(phys_addr & (align as u64 - 1) == 0, "Contiguous region is not aligned.");
+            assert!(phys_addr + size as u64 - 1 <= max_address,
+                    "Contiguous region is outside of the zone.");
+            assert!(region.iter().all(|&x| x == 0), "Contiguous region is not zeroed.");
+
+            for offset in [0, 0x1234, size as u64 - 1] {
+                let virt_addr = VirtAddr(region.as_ptr() as u64 + offset);
+
+                assert!(unsafe { virt_to_phys(virt_addr) } == Some(PhysAddr(phys_addr + offset)),
+                        "Contiguous region is not physically contiguous.");
+            }
+
+            region.iter_mut().for_each(|x| *x = 0x41);
+        }
+    }
+
+    #[kernel_test]
+    fn unmapped_mmio_is_reused() {
+        let region = ContiguousRegion::new(8192);
+
+        unsafe {
+            let first = map_mmio(region.phys_addr(), 8192, PAGE_UNCACHEABLE);
+
+            assert!(virt_to_phys(VirtAddr(first.0 + 4096)) ==
+                    Some(PhysAddr(region.phys_addr().0 + 4096)), "MMIO is not mapped.");
+
+            unmap_mmio(first, 8192);
+
+            assert!(virt_to_phys(first).is_none(), "MMIO is still mapped.");
+
+            let second = map_mmio(region.phys_addr(), 8192, PAGE_UNCACHEABLE);
+
+            assert!(second == first, "Unmapped MMIO region was not reused.");
+
+            unmap_mmio(second, 8192);
+        }
+    }
+
+    #[kernel_test]
+    fn trim_reclaims_free_pages() {
+        for &size in &[64, 4096, 16384] {
+            let mut free_list = FreeList::new(size);
+            let region_size   = core::cmp::max(size, 4096);
+            let region_mask   = if size < 4096 { !0xfff } else { !0 };
+
+            unsafe {
+                // Allocate enough memory to fill 4 regions.
+                let count = region_size / size * 4;
+
+                let allocations: Vec<*mut u8> = (0..count).map(|_| free_list.pop()).collect();
+
+                // Keep one allocation alive so its region cannot be reclaimed.
+                let kept = allocations[0];
+
+                for &allocation in &allocations[1..] {
+                    free_list.push(allocation);
+                }
+
+                assert!(free_list.trim() == region_size * 3,
+                        "Trim didn't reclaim all free regions.");
+
+                let kept_region = kept as u64 & region_mask;
+
+                for &allocation in &allocations {
+                    let mapped = virt_to_phys(VirtAddr(allocation as u64)).is_some();
+
+                    assert!(mapped == (allocation as u64 & region_mask == kept_region),
+                            "Trim unmapped used region or left free region mapped.");
+                }
+
+                // Used region must stay intact.
+                core::ptr::write_bytes(kept, 0x41, size);
+
+                free_list.push(kept);
+
+                assert!(free_list.trim() == region_size, "Trim didn't reclaim last region.");
+                assert!(free_list.head == 0, "Free list is not empty after trim.");
+            }
+        }
+    }
+
+    #[kernel_test]
+    fn page_table_maps_and_translates() {
+        let mut page_table = PageTable::new(&mut PhysicalMemory)
+            .expect("Failed to create page table.");
+
+        let base = VirtAddr(0x1337_0000_0000);
+
+        page_table.map_init(&mut PhysicalMemory, base, PageType::Page4K, 8192,
+                            true, false, false, Some(|offset: u64| offset as u8))
+            .expect("Failed to map memory.");
+
+        for offset in [0, 0x123, 0x1fff] {
+            let phys_addr = page_table.virt_to_phys(&mut PhysicalMemory, VirtAddr(base.0 + offset))
+                .expect("Mapped memory has no physical backing.");
+
+            let value = unsafe { read_phys::<u8>(phys_addr) };
+
+            assert!(value == offset as u8, "Mapped memory was not initialized properly.");
+        }
+
+        assert!(page_table.virt_to_phys(&mut PhysicalMemory, VirtAddr(base.0 + 0x2000)).is_none(),
+                "Unmapped memory has physical backing.");
+        assert!(page_table.virt_to_phys(&mut PhysicalMemory, VirtAddr(0x8000_0000_0000)).is_none(),
+                "Non-canonical address has physical backing.");
+
+        unsafe {
+            page_table.destroy(&mut PhysicalMemory)
+                .expect("Failed to destroy page table.");
         }
     }
 }
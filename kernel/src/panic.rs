@@ -4,9 +4,11 @@ use core::fmt::Write;
 
 use crate::framebuffer::{self, TextFramebuffer};
 use crate::processors::{self, CoreState};
-use crate::time;
+use crate::{time, backtrace, gdb};
+use crate::interrupts::{InterruptFrame, RegisterState};
 
 use serial_port::SerialPort;
+use qemu_test::PANIC_MARKER;
 use crate::lock::{Lock, LockGuard};
 
 const CORE_UNLOCKED: u64 = 0xffff_ffff_ffff_ffff;
@@ -23,7 +25,7 @@ pub fn is_panicking() -> bool {
     IS_PANICKING.load(Ordering::Relaxed)
 }
 
-fn has_core_locals() -> bool {
+pub fn has_core_locals() -> bool {
     let gs_base = unsafe { cpu::rdmsr(0xc0000101) };
 
     gs_base != 0
@@ -145,6 +147,9 @@ impl core::fmt::Write for EmergencyWriter {
 
         let _ = core::fmt::Write::write_str(serial_port, string);
 
+        // Don't leave anything queued, interrupts may never be delivered again.
+        serial_port.flush();
+
         if let Some(framebuffer) = self.framebuffer.as_mut() {
             // Use red color for panics.
             framebuffer.set_color(0xff0000);
@@ -220,7 +225,7 @@ unsafe fn begin_panic() -> bool {
             }
 
             // Request to halt execution via NMI.
-            apic.ipi(apic_id, (1 << 14) | (0b100 << 8));
+            apic.ipi(apic_id, crate::apic::NMI_IPI);
 
             {
                 // Wait for the CPU to become halted.
@@ -251,9 +256,9 @@ fn dump_panic_info(writer: &mut EmergencyWriter, panic_info: &PanicInfo) {
     let _ = writeln!(writer);
 
     if has_core_locals() {
-        let _ = write!(writer, "Kernel panic on CPU {}", core!().id);
+        let _ = write!(writer, "{} CPU {}", PANIC_MARKER, core!().id);
     } else {
-        let _ = write!(writer, "Kernel panic on unknown CPU");
+        let _ = write!(writer, "{} unknown CPU", PANIC_MARKER);
     }
 
     if let Some(location) = panic_info.location() {
@@ -265,13 +270,63 @@ fn dump_panic_info(writer: &mut EmergencyWriter, panic_info: &PanicInfo) {
     if let Some(message) = panic_info.message() {
         let _ = writeln!(writer, "{}", message);
     }
+
+    // Show the faulting code if this panic was caused by an unhandled exception.
+    let context = if has_core_locals() {
+        core!().exception_context.take()
+    } else {
+        None
+    };
+
+    unsafe {
+        if let Some((rip, rbp, rsp)) = context {
+            backtrace::print(writer, Some(rip), rbp, rsp);
+        } else {
+            let (rbp, rsp) = backtrace::current_frame();
+
+            backtrace::print(writer, None, rbp, rsp);
+        }
+    }
+}
+
+/// Halt this core because other core has panicked. Prints what this core was doing.
+pub unsafe fn halt_on_nmi(frame: &InterruptFrame, regs: &RegisterState) -> ! {
+    {
+        let mut writer = EmergencyWriter::new();
+
+        if has_core_locals() {
+            let _ = writeln!(writer, "CPU {} halted by NMI.", core!().id);
+        } else {
+            let _ = writeln!(writer, "Unknown CPU halted by NMI.");
+        }
+
+        backtrace::print(&mut writer, Some(frame.rip), regs.rbp, frame.rsp);
+    }
+
+    halt();
 }
 
 #[panic_handler]
 fn panic(panic_info: &PanicInfo) -> ! {
     unsafe {
         if begin_panic() {
-            dump_panic_info(&mut EmergencyWriter::new(), panic_info);
+            let mut writer = EmergencyWriter::new();
+
+            // Let the test runner know which kernel test has failed.
+            #[cfg(feature = "kernel_tests")]
+            if has_core_locals() {
+                crate::tests::report_panic(&mut writer);
+            }
+
+            dump_panic_info(&mut writer, panic_info);
+
+            if has_core_locals() && gdb::enabled() {
+                gdb::enter_on_panic();
+            }
+
+            // Let the test runner know that the kernel has failed.
+            #[cfg(feature = "qemu_test")]
+            crate::qemu::exit(crate::qemu::ExitCode::Failure);
         }
 
         halt();
@@ -1,5 +1,3 @@
-pub const ALWAYS_USE_SERIAL_PORT: bool = false;
-
 #[macro_export]
 macro_rules! print {
     ($($arg: tt)*) => {{
@@ -21,7 +19,7 @@ macro_rules! println {
 macro_rules! color_print {
     ($color: expr, $($arg: tt)*) => {{
         let color: u32     = $color;
-        let mut use_serial = crate::print::ALWAYS_USE_SERIAL_PORT;
+        let mut use_serial = crate::config::always_use_serial_port();
 
         // Print to framebuffer if it is available.
         if let Some(framebuffer) = crate::framebuffer::get().lock().as_mut() {
@@ -47,6 +45,14 @@ macro_rules! color_print {
     }};
 }
 
+/// Write a machine-readable line to the log serial port.
+#[macro_export]
+macro_rules! serial_log {
+    ($($arg: tt)*) => {{
+        crate::serial::log(format_args!($($arg)*));
+    }};
+}
+
 #[macro_export]
 macro_rules! color_println {
     ($color: expr) => {{
@@ -54,7 +60,7 @@ macro_rules! color_println {
     }};
     ($color: expr, $($arg: tt)*) => {{
         let color: u32     = $color;
-        let mut use_serial = crate::print::ALWAYS_USE_SERIAL_PORT;
+        let mut use_serial = crate::config::always_use_serial_port();
 
         // Print to framebuffer if it is available.
         if let Some(framebuffer) = crate::framebuffer::get().lock().as_mut() {
@@ -0,0 +1,265 @@
+[bits 32]
+
+XEN_ELFNOTE_PHYS32_ENTRY equ 18
+
+PAGE_PRESENT equ 1 << 0
+PAGE_WRITE   equ 1 << 1
+PAGE_SIZE    equ 1 << 7
+
+STACK_SIZE equ 64 * 1024
+
+extern __ehdr_start
+extern pvh_main
+extern _start
+
+global pvh_start
+global pvh_enter_kernel
+
+; ELF note which tells PVH loaders (like QEMU `-kernel`) where is the 32 bit entrypoint.
+; Loaders expect physical address here, but the kernel is linked in the higher half. Linker
+; stores the offset of the entrypoint relative to the descriptor and the builder replaces it
+; with the physical address when assigning physical addresses to segments. Descriptor is
+; 64 bit wide in 64 bit ELF files.
+section .note.Xen note alloc noexec nowrite align=4
+
+    dd 4 ; Name size.
+    dd 8 ; Descriptor size.
+    dd XEN_ELFNOTE_PHYS32_ENTRY
+    db "Xen", 0
+    dq pvh_start - $
+
+section .text
+
+; Get the physical address of `%2` in `%1`. EBP must contain the physical address of
+; `pvh_base`. Loader doesn't apply relocations so the offset from the current location is
+; stored in the code and resolved by the linker.
+%macro physical_address 2
+    mov %1, [ebp + (%%offset - pvh_base)]
+    lea %1, [ebp + %1 + (%%offset - pvh_base)]
+    jmp %%next
+
+%%offset:
+    dd %2 - $
+
+%%next:
+%endmacro
+
+; EBX - Physical address of the `hvm_start_info` structure
+;
+; Paging is disabled and we can be loaded at any physical address. Build page tables which
+; identity map the first 4GB of physical memory and map the kernel at its link address,
+; enter long mode and continue in `pvh_main`.
+pvh_start:
+    cli
+    cld
+
+    ; Loader doesn't provide a stack. Use the first 8 bytes of `hvm_start_info` as
+    ; a temporary one to get our physical address and restore them afterwards.
+    mov  ecx, [ebx]
+    lea  esp, [ebx + 4]
+    call pvh_base
+
+pvh_base:
+    pop ebp
+    mov [ebx], ecx
+
+    physical_address eax, pvh_stack.end
+    mov esp, eax
+
+    ; Identity map first 4GB of physical memory using 2M pages.
+    physical_address edi, pvh_pd_low
+    xor ecx, ecx
+
+.identity_map:
+    mov eax, ecx
+    shl eax, 21
+    or  eax, PAGE_PRESENT | PAGE_WRITE | PAGE_SIZE
+    mov [edi + ecx * 8], eax
+    inc ecx
+    cmp ecx, 4 * 512
+    jb  .identity_map
+
+    ; Every 1GB of identity map has its own page directory.
+    physical_address esi, pvh_pdpt_low
+    xor ecx, ecx
+
+.identity_directories:
+    mov eax, ecx
+    shl eax, 12
+    lea eax, [edi + eax + (PAGE_PRESENT | PAGE_WRITE)]
+    mov [esi + ecx * 8], eax
+    inc ecx
+    cmp ecx, 4
+    jb  .identity_directories
+
+    physical_address edi, pvh_pml4
+    lea eax, [esi + (PAGE_PRESENT | PAGE_WRITE)]
+    mov [edi], eax
+
+    ; Map 1GB at the kernel link address to the physical address of the kernel using 2M
+    ; pages. Kernel base is 1GB aligned, but the physical address must be 2M aligned too.
+    physical_address edx, __ehdr_start
+    test edx, (1 << 21) - 1
+    jnz  .unaligned
+
+    physical_address edi, pvh_pd_high
+    xor ecx, ecx
+
+.kernel_map:
+    mov eax, ecx
+    shl eax, 21
+    lea eax, [edx + eax + (PAGE_PRESENT | PAGE_WRITE | PAGE_SIZE)]
+    mov [edi + ecx * 8], eax
+    inc ecx
+    cmp ecx, 512
+    jb  .kernel_map
+
+    ; Get the upper half of the kernel virtual address. It is enough to calculate
+    ; indices of the PML4 and PDPT entries.
+    physical_address esi, kernel_base
+    mov edx, [esi + 4]
+    mov eax, [esi]
+
+    ; PDPT index is bits 30..38 of the address.
+    shrd eax, edx, 30
+    and  eax, 511
+    physical_address esi, pvh_pdpt_high
+    lea  ecx, [edi + (PAGE_PRESENT | PAGE_WRITE)]
+    mov  [esi + eax * 8], ecx
+
+    ; PML4 index is bits 39..47 of the address.
+    shr edx, 7
+    and edx, 511
+    physical_address edi, pvh_pml4
+    lea ecx, [esi + (PAGE_PRESENT | PAGE_WRITE)]
+    mov [edi + edx * 8], ecx
+
+    mov cr3, edi
+
+    ; Enable PAE which is required for long mode and SSE and AVX which are used by the kernel.
+    mov eax, cr4
+    or  eax, (1 <<  5) ; PAE
+    or  eax, (1 <<  9) ; OSFXSR
+    or  eax, (1 << 10) ; OSXMMEXCPT
+    or  eax, (1 << 18) ; OSXSAVE
+    mov cr4, eax
+
+    ; Enable x87, SSE and AVX in XCR0.
+    xor ecx, ecx
+    xor edx, edx
+    mov eax, (1 << 0) | (1 << 1) | (1 << 2)
+    xsetbv
+
+    ; Enable LME and NXE.
+    mov ecx, 0xc0000080
+    rdmsr
+    or  eax, (1 << 8) | (1 << 11)
+    wrmsr
+
+    ; Enable paging, write protect and make sure that x87 instructions aren't emulated.
+    mov eax, cr0
+    and eax, ~(1 << 2) ; Emulation
+    or  eax, (1 <<  1) ; Monitor co-processor
+    or  eax, (1 << 16) ; Write protect
+    or  eax, (1 << 31) ; Paging enable
+    mov cr0, eax
+
+    ; Loader doesn't guarantee that its GDT is still valid and it doesn't have 64 bit code
+    ; segment anyway. Create GDTR on the stack and load our own GDT.
+    physical_address eax, gdt_64
+    sub  esp, 8
+    mov  word  [esp + 0], (gdt_64.end - gdt_64) - 1
+    mov  dword [esp + 2], eax
+    lgdt [esp]
+    add  esp, 8
+
+    ; Switch to 64 bit code segment.
+    physical_address eax, .long_mode
+    push 0x08
+    push eax
+    retf
+
+.unaligned:
+    ; Kernel cannot be mapped. We don't have any way to report the error so just halt.
+    cli
+    hlt
+    jmp .unaligned
+
+[bits 64]
+
+.long_mode:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+
+    ; We are executing code in the identity map. Switch to the kernel map by adding
+    ; the difference between kernel virtual and physical addresses to RIP.
+    lea  rsi, [rel __ehdr_start]
+    mov  rax, [rel kernel_base]
+    sub  rax, rsi
+    lea  rcx, [rel .kernel_map_entry]
+    add  rcx, rax
+    jmp  rcx
+
+.kernel_map_entry:
+    lea rsp, [rel pvh_stack.end]
+
+    ; Call `pvh_main` with the address of `hvm_start_info` and kernel physical address.
+    mov  edi, ebx
+    call pvh_main
+
+; RDI - Kernel CR3
+; RSI - Stack
+; RDX - Boot block
+; RCX - Boot TSC
+; R8  - Physical region base
+pvh_enter_kernel:
+    mov cr3, rdi
+    mov rsp, rsi
+
+    ; Load kernel arguments.
+    mov rdi, rdx
+    mov rsi, rcx
+    mov rdx, r8
+
+    ; Reserve some shadow stack space. Keep the stack 16 byte aligned.
+    sub rsp, 0x30
+
+    call _start
+
+section .data
+
+; Link address of the kernel. Loader doesn't apply relocations but the linker has already
+; stored the link-time value here.
+align 8
+kernel_base:
+    dq __ehdr_start
+
+section .rodata
+
+; GDT used when entering the kernel. Same as the one created by `enter_kernel` in the UEFI
+; bootloader.
+align 8
+gdt_64:
+    dq 0x0000000000000000 ; Null segment.
+    dq 0x00209a0000000000 ; Code segment.
+    dq 0x0000920000000000 ; Data segment.
+    .end:
+
+section .bss
+
+; Page tables used until `pvh_main` creates the kernel ones.
+align 4096
+pvh_pml4:      resb 4096
+pvh_pdpt_low:  resb 4096
+pvh_pd_low:    resb 4 * 4096
+pvh_pdpt_high: resb 4096
+pvh_pd_high:   resb 4096
+
+align 16
+pvh_stack:
+    resb STACK_SIZE
+    .end:
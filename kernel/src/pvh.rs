@@ -0,0 +1,463 @@
+// Entry used by PVH loaders (like QEMU `-kernel`). They load the kernel ELF image directly
+// so there is no bootloader which would prepare the boot block and page tables for us.
+// `pvh.asm` enters long mode using temporary page tables and calls `pvh_main` which does
+// the bootloader's work and enters the kernel through the usual entrypoint. PVH boot
+// doesn't provide AP entrypoint so only the BSP is launched.
+
+use core::alloc::Layout;
+use core::fmt::Write;
+use core::ptr::read_unaligned;
+
+use boot_block::{BootBlock, CommandLineBuffer, Initrd, KernelLayout, KERNEL_STACK_SIZE,
+                 KERNEL_PHYSICAL_REGION_SIZE};
+use page_table::{PageTable, PageType, PhysAddr, PhysMem, VirtAddr, PAGE_PRESENT, PAGE_WRITE,
+                 PAGE_NX, PAGE_SIZE};
+use serial_port::SerialPort;
+use rangeset::Range;
+
+/// Value of the `magic` field of `hvm_start_info`.
+const START_INFO_MAGIC: u32 = 0x336e_c578;
+
+const MEMORY_MAP_RAM: u32 = 1;
+
+/// `pvh.asm` identity maps only the first 4GB of physical memory.
+const MAX_IDENTITY_ADDRESS: u64 = 4 * 1024 * 1024 * 1024 - 1;
+
+const PT_LOAD: u32 = 1;
+const PF_X:    u32 = 1;
+const PF_W:    u32 = 2;
+
+macro_rules! println {
+    ($($arg: tt)*) => {{
+        let mut serial = BOOT_BLOCK.serial_port.lock();
+
+        if let Some(serial) = serial.as_mut() {
+            let _ = serial.write_fmt(format_args!($($arg)*));
+            let _ = serial.write_str("\n");
+        }
+    }};
+}
+
+struct PvhInterrupts;
+
+impl lock::Interrupts for PvhInterrupts {
+    fn in_exception() -> bool { false }
+    fn in_interrupt() -> bool { false }
+
+    // Only the BSP runs before entering the kernel.
+    fn core_id() -> u32 { 0 }
+
+    unsafe fn enable_interrupts()  {}
+    unsafe fn disable_interrupts() {}
+}
+
+/// Boot block passed to the kernel. Kernel moves it to the heap after finishing boot
+/// process, like the one created by bootloaders.
+static BOOT_BLOCK: BootBlock<PvhInterrupts> = BootBlock::new();
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct StartInfo {
+    magic:          u32,
+    version:        u32,
+    flags:          u32,
+    nr_modules:     u32,
+    modlist_paddr:  u64,
+    cmdline_paddr:  u64,
+    rsdp_paddr:     u64,
+    memmap_paddr:   u64,
+    memmap_entries: u32,
+    reserved:       u32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct ModuleEntry {
+    paddr:         u64,
+    size:          u64,
+    cmdline_paddr: u64,
+    reserved:      u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct MemoryMapEntry {
+    addr:     u64,
+    size:     u64,
+    typ:      u32,
+    reserved: u32,
+}
+
+/// Physical memory accessed through the identity map created by `pvh.asm`. Allocations
+/// come from the boot block free memory list.
+struct IdentityMemory;
+
+impl PhysMem for IdentityMemory {
+    unsafe fn translate(&mut self, phys_addr: PhysAddr, size: usize) -> Option<*mut u8> {
+        let phys_end = phys_addr.0.checked_add(size.checked_sub(1)? as u64)?;
+
+        if phys_end > MAX_IDENTITY_ADDRESS {
+            return None;
+        }
+
+        Some(phys_addr.0 as *mut u8)
+    }
+
+    fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr> {
+        let mut free_memory = BOOT_BLOCK.free_memory.lock();
+
+        free_memory.allocate_limited(layout.size() as u64, layout.align() as u64,
+                                     Some(MAX_IDENTITY_ADDRESS))
+            .map(|addr| PhysAddr(addr as u64))
+    }
+}
+
+extern "C" {
+    // Defined by the linker.
+    static __ehdr_start: u8;
+    static _end:         u8;
+
+    fn pvh_enter_kernel(cr3: u64, rsp: u64, boot_block: u64, boot_tsc: u64,
+                        physical_region: u64) -> !;
+}
+
+/// Get the length of NUL terminated string at `address` (excluding NUL terminator).
+unsafe fn string_length(address: u64) -> usize {
+    let mut length = 0;
+
+    while read_unaligned((address as usize + length) as *const u8) != 0 {
+        length += 1;
+    }
+
+    length
+}
+
+/// Get inclusive range of `size` bytes at `start`. Returns `None` if the range is empty.
+fn range(start: u64, size: u64) -> Option<Range> {
+    if start == 0 || size == 0 {
+        return None;
+    }
+
+    Some(Range {
+        start,
+        end: start.checked_add(size - 1).expect("PVH structure range overflowed."),
+    })
+}
+
+/// Boot information structure (`hvm_start_info`) passed by a PVH compliant loader.
+struct PvhInformation {
+    address:    u64,
+    start_info: StartInfo,
+}
+
+impl PvhInformation {
+    unsafe fn new(address: u64) -> Self {
+        let start_info: StartInfo = read_unaligned(address as *const StartInfo);
+
+        assert!(start_info.magic == START_INFO_MAGIC, "PVH start info has invalid magic.");
+
+        // Memory map is only present in version 1 and later.
+        assert!(start_info.version >= 1, "PVH start info version {} is not supported.",
+                start_info.version);
+
+        Self {
+            address,
+            start_info,
+        }
+    }
+
+    fn module(&self, index: u32) -> ModuleEntry {
+        let address = self.start_info.modlist_paddr +
+            index as u64 * core::mem::size_of::<ModuleEntry>() as u64;
+
+        unsafe { read_unaligned(address as *const ModuleEntry) }
+    }
+
+    fn memory_map_entry(&self, index: u32) -> MemoryMapEntry {
+        let address = self.start_info.memmap_paddr +
+            index as u64 * core::mem::size_of::<MemoryMapEntry>() as u64;
+
+        unsafe { read_unaligned(address as *const MemoryMapEntry) }
+    }
+
+    fn command_line(&self) -> Option<&'static [u8]> {
+        let address = self.start_info.cmdline_paddr;
+
+        if address == 0 {
+            return None;
+        }
+
+        Some(unsafe {
+            core::slice::from_raw_parts(address as *const u8, string_length(address))
+        })
+    }
+
+    /// Get all memory ranges occupied by the boot information. Unlike Multiboot2, PVH
+    /// information is scattered across multiple structures.
+    fn structures(&self, mut callback: impl FnMut(Range)) {
+        let start_info = &self.start_info;
+
+        let mut add = |start: u64, size: usize| {
+            if let Some(range) = range(start, size as u64) {
+                callback(range);
+            }
+        };
+
+        add(self.address, core::mem::size_of::<StartInfo>());
+        add(start_info.modlist_paddr, start_info.nr_modules as usize *
+            core::mem::size_of::<ModuleEntry>());
+        add(start_info.memmap_paddr, start_info.memmap_entries as usize *
+            core::mem::size_of::<MemoryMapEntry>());
+
+        if let Some(command_line) = self.command_line() {
+            add(start_info.cmdline_paddr, command_line.len() + 1);
+        }
+
+        for index in 0..start_info.nr_modules {
+            let module = self.module(index);
+
+            if module.cmdline_paddr != 0 {
+                let length = unsafe { string_length(module.cmdline_paddr) };
+
+                add(module.cmdline_paddr, length + 1);
+            }
+        }
+    }
+}
+
+/// Get physical memory range occupied by the kernel image loaded at `kernel_phys`.
+fn image_range(kernel_phys: u64) -> Range {
+    let size = unsafe { &_end as *const u8 as u64 - &__ehdr_start as *const u8 as u64 };
+
+    Range {
+        start: kernel_phys,
+        end:   kernel_phys + ((size + 0xfff) & !0xfff) - 1,
+    }
+}
+
+unsafe fn initialize_memory(info: &PvhInformation, kernel_phys: u64) {
+    let mut free_memory = BOOT_BLOCK.free_memory.lock();
+    let mut boot_memory = BOOT_BLOCK.boot_memory.lock();
+
+    assert!(info.start_info.memmap_entries > 0, "PVH loader didn't provide memory map.");
+
+    // Do two passes because some firmwares report overlapping regions.
+    for &cleanup_pass in &[false, true] {
+        for index in 0..info.start_info.memmap_entries {
+            let entry = info.memory_map_entry(index);
+            let free  = entry.typ == MEMORY_MAP_RAM;
+
+            if let Some(range) = range(entry.addr, entry.size) {
+                // First pass will add all free memory to the list.
+                // Second pass will remove all non-free memory from the list.
+                if free && !cleanup_pass {
+                    free_memory.insert(range);
+                } else if !free && cleanup_pass {
+                    free_memory.remove(range);
+                }
+            }
+        }
+    }
+
+    // Kernel image stays in memory for the whole lifetime of the system.
+    free_memory.remove(image_range(kernel_phys));
+
+    // Boot information structures are not needed after finishing boot process. Some
+    // loaders place them in reserved memory so only the usable parts can be reclaimed.
+    info.structures(|range| {
+        for entry in free_memory.entries() {
+            let start = range.start.max(entry.start);
+            let end   = range.end.min(entry.end);
+
+            if start <= end {
+                boot_memory.insert(Range { start, end });
+            }
+        }
+
+        free_memory.remove(range);
+    });
+
+    // Modules (initrd) must be kept intact.
+    for index in 0..info.start_info.nr_modules {
+        let module = info.module(index);
+
+        if let Some(range) = range(module.paddr, module.size) {
+            free_memory.remove(range);
+        }
+    }
+
+    // Remove the first page. It contains real mode IVT and BIOS data area which are often
+    // reported as usable memory. This also makes sure that we never allocate address 0.
+    free_memory.remove(Range { start: 0, end: 0xfff });
+}
+
+/// Pass everything provided by the loader (except memory map) to the kernel.
+fn load_boot_information(info: &PvhInformation) {
+    if let Some(command_line) = info.command_line().filter(|x| !x.is_empty()) {
+        *BOOT_BLOCK.command_line.lock() = Some(CommandLineBuffer::new(command_line)
+                                               .expect("Command line is too long."));
+
+        println!("Loaded {} byte command line.", command_line.len());
+    }
+
+    // First module is used as the initrd.
+    let mut ignored = 0;
+
+    for index in 0..info.start_info.nr_modules {
+        let module = info.module(index);
+        let mut initrd = BOOT_BLOCK.initrd.lock();
+
+        // Skip empty modules, they don't contain anything.
+        if module.size == 0 {
+            continue;
+        }
+
+        if initrd.is_some() {
+            ignored += 1;
+            continue;
+        }
+
+        *initrd = Some(Initrd {
+            phys_addr: module.paddr,
+            size:      module.size,
+        });
+
+        println!("Loaded {} byte initrd at 0x{:x}.", module.size, module.paddr);
+    }
+
+    if ignored > 0 {
+        println!("WARNING: Ignoring {} additional modules.", ignored);
+    }
+
+    // RSDP address is optional. If it isn't provided we need to search for it.
+    let acpi_tables = match info.start_info.rsdp_paddr {
+        0       => unsafe { bootlib::rsdp::find_rsdp() },
+        address => {
+            let data = unsafe {
+                core::slice::from_raw_parts(address as *const u8,
+                                            core::mem::size_of::<acpi::RsdpExtended>())
+            };
+
+            bootlib::rsdp::parse_rsdp(data)
+        }
+    };
+
+    *BOOT_BLOCK.acpi_tables.lock() = acpi_tables.expect("Loader didn't provide valid ACPI RSDP.");
+}
+
+/// Create the kernel page table. Kernel segments are mapped at their link addresses with
+/// correct permissions and physical memory is mapped at `layout.physical_region_base`.
+unsafe fn create_page_table(layout: &KernelLayout, kernel_phys: u64) -> PageTable {
+    let mut page_table = PageTable::new(&mut IdentityMemory)
+        .expect("Failed to allocate kernel page table.");
+
+    let kernel_base = &__ehdr_start as *const u8 as u64;
+
+    // Program headers are loaded together with the ELF header at the kernel base.
+    let header        = kernel_base as *const u8;
+    let header_offset = read_unaligned(header.add(0x20) as *const u64);
+    let header_size   = read_unaligned(header.add(0x36) as *const u16);
+    let header_count  = read_unaligned(header.add(0x38) as *const u16);
+
+    for index in 0..header_count as u64 {
+        let header = header.add((header_offset + index * header_size as u64) as usize);
+
+        let segment_type  = read_unaligned(header.add(0x00) as *const u32);
+        let segment_flags = read_unaligned(header.add(0x04) as *const u32);
+        let virt_addr     = read_unaligned(header.add(0x10) as *const u64);
+        let virt_size     = read_unaligned(header.add(0x28) as *const u64);
+
+        if segment_type != PT_LOAD || virt_size == 0 {
+            continue;
+        }
+
+        let mut flags = PAGE_PRESENT;
+
+        if segment_flags & PF_W != 0 {
+            flags |= PAGE_WRITE;
+        }
+
+        if segment_flags & PF_X == 0 {
+            flags |= PAGE_NX;
+        }
+
+        // Loader has put the segment at the same offset from the kernel physical base.
+        let start = virt_addr & !0xfff;
+        let end   = (virt_addr + virt_size + 0xfff) & !0xfff;
+
+        for page in (start..end).step_by(4096) {
+            let phys_addr = page - kernel_base + kernel_phys;
+
+            page_table.map_raw(&mut IdentityMemory, VirtAddr(page), PageType::Page4K,
+                               phys_addr | flags, true, false)
+                .expect("Failed to map kernel segment.");
+        }
+    }
+
+    let features  = cpu::get_features();
+    let page_type = if features.page1g {
+        PageType::Page1G
+    } else {
+        println!("WARNING: CPU doesn't support 1G pages, mapping physical region may take \
+                 a while.");
+
+        PageType::Page2M
+    };
+
+    let page_size = page_type as u64;
+
+    *BOOT_BLOCK.physical_map_page_size.lock() = Some(page_size);
+
+    // Like bootloaders, leave the physical memory map executable. Kernel makes it NX later.
+    for phys_addr in (0..KERNEL_PHYSICAL_REGION_SIZE).step_by(page_size as usize) {
+        let virt_addr = VirtAddr(phys_addr + layout.physical_region_base);
+
+        page_table.map_raw(&mut IdentityMemory, virt_addr, page_type,
+                           phys_addr | PAGE_PRESENT | PAGE_WRITE | PAGE_SIZE, true, false)
+            .expect("Failed to map physical region in the kernel page table.");
+    }
+
+    page_table
+}
+
+/// Called by `pvh.asm` on the BSP in long mode. Physical memory up to 4GB is identity mapped
+/// and the kernel is mapped at its link address.
+#[no_mangle]
+unsafe extern "C" fn pvh_main(start_info: u64, kernel_phys: u64) -> ! {
+    let boot_tsc = core::arch::x86_64::_rdtsc();
+
+    *BOOT_BLOCK.serial_port.lock() = Some(SerialPort::new());
+
+    bootlib::verify_cpu();
+
+    let info = PvhInformation::new(start_info);
+
+    initialize_memory(&info, kernel_phys);
+    load_boot_information(&info);
+
+    // Kernel is loaded at its link address and the loader doesn't apply relocations, so
+    // it cannot be randomized.
+    let layout = KernelLayout::fixed();
+
+    assert!(&__ehdr_start as *const u8 as u64 == layout.kernel_base,
+            "Kernel wasn't loaded at its link address.");
+
+    let mut page_table = create_page_table(&layout, kernel_phys);
+
+    page_table.map(&mut IdentityMemory, VirtAddr(layout.stack_base), PageType::Page4K,
+                   KERNEL_STACK_SIZE, true, false, false)
+        .expect("Failed to map kernel stack.");
+
+    let cr3 = page_table.table().0;
+
+    *BOOT_BLOCK.page_table.lock()    = Some(page_table);
+    *BOOT_BLOCK.kernel_layout.lock() = layout;
+
+    println!("Kernel loaded by PVH loader at 0x{:x}.", kernel_phys);
+
+    let boot_block = &BOOT_BLOCK as *const _ as u64 - layout.kernel_base + kernel_phys;
+    let rsp        = layout.stack_base + KERNEL_STACK_SIZE;
+
+    pvh_enter_kernel(cr3, rsp, boot_block, boot_tsc, layout.physical_region_base)
+}
+
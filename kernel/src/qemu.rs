@@ -0,0 +1,32 @@
+// Support for automated testing in QEMU. Kernel built with `qemu_test` feature prints
+// test markers to the serial port and shuts down the VM using `isa-debug-exit` device.
+
+use qemu_test::{QEMU_DEBUG_EXIT_PORT, QEMU_EXIT_SUCCESS, QEMU_EXIT_FAILURE, BOOT_SUCCESS_MARKER};
+
+/// Values written to `isa-debug-exit` device. QEMU will exit with status `(code << 1) | 1`.
+#[repr(u32)]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum ExitCode {
+    Success = QEMU_EXIT_SUCCESS,
+    Failure = QEMU_EXIT_FAILURE,
+}
+
+/// Exit QEMU with a given exit code. If `isa-debug-exit` device is not present
+/// this will just halt the current processor.
+pub unsafe fn exit(code: ExitCode) -> ! {
+    // Make sure that the test runner receives all output.
+    crate::serial::flush_unsafe();
+
+    cpu::outd(QEMU_DEBUG_EXIT_PORT, code as u32);
+
+    crate::panic::halt();
+}
+
+/// Report successful boot of all processors to the test runner and exit QEMU.
+pub fn finish_successful_boot() -> ! {
+    println!("{} ({} CPUs).", BOOT_SUCCESS_MARKER, crate::processors::total_cores());
+
+    unsafe {
+        exit(ExitCode::Success);
+    }
+}
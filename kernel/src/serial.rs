@@ -0,0 +1,113 @@
+// Kernel serial port setup. Console port (stored in the boot block) and optional log port
+// are opened according to the command line and switched to interrupt driven mode.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use serial_port::{SerialPort, ComPort, DEFAULT_BAUD_RATE};
+use crate::{apic, config, ioapic, time, lock::Lock};
+
+/// Serial port dedicated to machine-readable logs.
+static LOG_PORT: Lock<Option<SerialPort>> = Lock::new_non_preemptible(None);
+
+/// Bitmask of ISA IRQs which were routed to the serial port handler.
+static SERIAL_IRQS: AtomicU16 = AtomicU16::new(0);
+
+/// Returns `true` if `vector` is an interrupt raised by one of the serial ports.
+pub fn is_serial_vector(vector: u8) -> bool {
+    if vector < ioapic::ISA_BASE_IRQ || vector >= ioapic::ISA_BASE_IRQ + ioapic::ISA_IRQ_COUNT {
+        return false;
+    }
+
+    SERIAL_IRQS.load(Ordering::Relaxed) & (1 << (vector - ioapic::ISA_BASE_IRQ)) != 0
+}
+
+/// Handle serial port IRQ. IRQs can be shared between ports so all of them are checked.
+pub unsafe fn handle_interrupt() {
+    if let Some(port) = core!().boot_block.serial_port.lock().as_mut() {
+        port.handle_interrupt();
+    }
+
+    if let Some(port) = LOG_PORT.lock().as_mut() {
+        port.handle_interrupt();
+    }
+
+    apic::Apic::eoi();
+}
+
+/// Route IRQ of `com_port` to the current core and enable interrupts on `port`.
+unsafe fn enable_interrupts(com_port: ComPort, port: &mut SerialPort) {
+    let apic_id = core!().apic_id().unwrap();
+
+    if ioapic::route_isa_irq(com_port.irq(), apic_id) {
+        SERIAL_IRQS.fetch_or(1 << com_port.irq(), Ordering::Relaxed);
+
+        port.enable_interrupts();
+    }
+}
+
+/// Write a single line to the log serial port. Every line starts with uptime in seconds
+/// and the current core ID. Does nothing if the log port is disabled.
+pub fn log(args: core::fmt::Arguments) {
+    if let Some(port) = LOG_PORT.lock().as_mut() {
+        let _ = writeln!(port, "{:.6} cpu={} {}", time::uptime(), core!().id, args);
+    }
+}
+
+/// Open serial ports selected on the command line. Must be called on the BSP after
+/// the I/O APIC initialization.
+pub unsafe fn initialize() {
+    let (mut console_port, baud_rate) = config::serial_port();
+
+    // Bootloader has already opened COM1 with the default baud rate.
+    if (console_port, baud_rate) != (ComPort::Com1, DEFAULT_BAUD_RATE) {
+        if let Some(port) = SerialPort::open(console_port, baud_rate) {
+            *core!().boot_block.serial_port.lock() = Some(port);
+        } else {
+            color_println!(0xffff00, "WARNING: Serial port {:?} is not present, using COM1.",
+                           console_port);
+
+            console_port = ComPort::Com1;
+        }
+    }
+
+    if let Some(port) = core!().boot_block.serial_port.lock().as_mut() {
+        enable_interrupts(console_port, port);
+    }
+
+    if let Some((log_port, baud_rate)) = config::log_serial_port() {
+        let port = if log_port == console_port {
+            color_println!(0xffff00, "WARNING: Log serial port {:?} is already used for \
+                                      console.", log_port);
+            None
+        } else {
+            let port = SerialPort::open(log_port, baud_rate);
+            if  port.is_none() {
+                color_println!(0xffff00, "WARNING: Log serial port {:?} is not present.",
+                               log_port);
+            }
+
+            port
+        };
+
+        if port.is_some() {
+            let mut log = LOG_PORT.lock();
+
+            *log = port;
+
+            enable_interrupts(log_port, log.as_mut().unwrap());
+        }
+    }
+}
+
+/// Flush all serial ports without locking them. Used before shutting down or halting.
+#[allow(unused)]
+pub unsafe fn flush_unsafe() {
+    if let Some(port) = (*core!().boot_block.serial_port.bypass()).as_mut() {
+        port.flush();
+    }
+
+    if let Some(port) = (*LOG_PORT.bypass()).as_mut() {
+        port.flush();
+    }
+}
@@ -0,0 +1,685 @@
+// Slab allocator used by the kernel heap. Every core has caches of free objects for all
+// power-of-two size classes and for frequently allocated kernel objects. Each core allocates
+// memory from its own part of the heap, so objects freed on other cores can be sent back
+// to the owner using lock-free remote free queues.
+
+use core::alloc::Layout;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use page_table::{PhysAddr, PhysMem, VirtAddr};
+use crate::mm::{self, FreeList, PhysicalMemory};
+use crate::vma::VmaAllocator;
+use crate::lock::Lock;
+use crate::core_locals;
+
+/// Number of power-of-two size classes. Size class `n` holds objects of `1 << (n + 3)` bytes.
+pub const SIZE_CLASSES: usize = 61;
+
+/// Number of caches on every core.
+pub const CACHE_COUNT: usize = SIZE_CLASSES + OBJECT_CACHES.len();
+
+/// Caches for frequently allocated kernel objects. They don't share free objects with size
+/// classes so their memory usage can be tracked separately.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ObjectCache {
+    Vmcb,
+    NptPage,
+    XsaveArea,
+}
+
+const OBJECT_CACHES: [ObjectCache; 3] = [
+    ObjectCache::Vmcb,
+    ObjectCache::NptPage,
+    ObjectCache::XsaveArea,
+];
+
+impl ObjectCache {
+    pub fn name(self) -> &'static str {
+        match self {
+            ObjectCache::Vmcb      => "vmcb",
+            ObjectCache::NptPage   => "npt_page",
+            ObjectCache::XsaveArea => "xsave_area",
+        }
+    }
+
+    fn index(self) -> usize {
+        SIZE_CLASSES + self as usize
+    }
+
+    /// Allocate an object from this cache. Returns null pointer on failure.
+    pub unsafe fn alloc(self, layout: Layout) -> *mut u8 {
+        let cache = core!().heap.cache(self.index());
+
+        assert!(!cache.physical, "Cannot allocate heap objects from cache {}.", self.name());
+        assert!(layout.size() <= cache.size && layout.align() <= cache.size,
+                "Layout {:?} doesn't fit in cache {}.", layout, self.name());
+
+        allocate(self.index(), layout.size())
+    }
+
+    /// Free an object allocated from this cache with the same `layout`.
+    pub unsafe fn dealloc(self, object: *mut u8, layout: Layout) {
+        deallocate(self.index(), object, layout.size());
+    }
+
+    /// Allocate 4K physical page from this cache. Page is not zeroed.
+    pub fn alloc_page(self) -> Option<PhysAddr> {
+        let cache = core!().heap.cache(self.index());
+
+        assert!(cache.physical, "Cannot allocate physical pages from cache {}.", self.name());
+
+        let page = unsafe { allocate(self.index(), 4096) };
+        if  page.is_null() {
+            return None;
+        }
+
+        Some(unsafe { virt_to_phys(page) })
+    }
+
+    /// Free physical page allocated from this cache.
+    pub unsafe fn free_page(self, phys_addr: PhysAddr) {
+        let page = mm::translate(phys_addr, 4096)
+            .expect("Failed to translate physical page to free.");
+
+        // Physical pages don't have an owner, just put them in the cache of the current core.
+        let cache = core!().heap.cache(self.index());
+
+        cache.stats().record_free(4096);
+        cache.free_list.lock().push(page);
+    }
+}
+
+/// Get the physical address of a page in the physical memory map.
+unsafe fn virt_to_phys(page: *mut u8) -> PhysAddr {
+    mm::virt_to_phys(VirtAddr(page as u64)).expect("Physical page is not mapped.")
+}
+
+/// Name of a cache used when reporting statistics.
+#[derive(Copy, Clone)]
+pub enum CacheName {
+    SizeClass(usize),
+    Object(ObjectCache),
+}
+
+impl core::fmt::Display for CacheName {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            CacheName::SizeClass(size) => write!(f, "size_{}", size),
+            CacheName::Object(cache)   => write!(f, "{}", cache.name()),
+        }
+    }
+}
+
+struct CacheStats {
+    /// Total number of successful allocations.
+    allocations: AtomicU64,
+
+    /// Total number of frees.
+    frees: AtomicU64,
+
+    /// Number of requested bytes in objects which are currently allocated.
+    live_bytes: AtomicU64,
+
+    /// Highest number of objects allocated at the same time.
+    peak_objects: AtomicU64,
+
+    /// Highest number of requested bytes allocated at the same time.
+    peak_bytes: AtomicU64,
+
+    /// Number of allocations which failed because of lack of memory.
+    failures: AtomicU64,
+}
+
+impl CacheStats {
+    const fn new() -> Self {
+        Self {
+            allocations:  AtomicU64::new(0),
+            frees:        AtomicU64::new(0),
+            live_bytes:   AtomicU64::new(0),
+            peak_objects: AtomicU64::new(0),
+            peak_bytes:   AtomicU64::new(0),
+            failures:     AtomicU64::new(0),
+        }
+    }
+
+    fn record_allocation(&self, size: usize) {
+        let allocations = self.allocations.fetch_add(1, Ordering::Relaxed) + 1;
+        let live_bytes  = self.live_bytes.fetch_add(size as u64, Ordering::Relaxed) + size as u64;
+
+        // Objects may be freed on other cores while we are updating peak values. In this case
+        // we may slightly overestimate them.
+        let live_objects = allocations.saturating_sub(self.frees.load(Ordering::Relaxed));
+
+        self.peak_objects.fetch_max(live_objects, Ordering::Relaxed);
+        self.peak_bytes.fetch_max(live_bytes, Ordering::Relaxed);
+    }
+
+    fn record_free(&self, size: usize) {
+        self.frees.fetch_add(1, Ordering::Relaxed);
+        self.live_bytes.fetch_sub(size as u64, Ordering::Relaxed);
+    }
+}
+
+/// Statistics of physical caches. Physical pages don't have an owner and can be freed
+/// on a different core than the one which allocated them, so they are shared by all cores.
+/// `ObjectCache::NptPage` is the only physical cache.
+static PHYSICAL_STATS: CacheStats = CacheStats::new();
+
+/// Statistics of a cache summed over all cores.
+#[derive(Copy, Clone)]
+pub struct HeapStats {
+    pub name:         CacheName,
+    pub object_size:  usize,
+    pub allocations:  u64,
+    pub live_objects: u64,
+    pub live_bytes:   u64,
+
+    /// Sum of peak values of all cores. It is an upper bound of the real peak.
+    pub peak_objects: u64,
+    pub peak_bytes:   u64,
+
+    pub failures: u64,
+}
+
+pub struct SlabCache {
+    /// Free objects owned by this core.
+    free_list: Lock<FreeList>,
+
+    /// Objects freed by other cores. They are linked using their first word and the owner
+    /// takes all of them at once.
+    remote_frees: AtomicUsize,
+
+    /// Size of every object in this cache.
+    size: usize,
+
+    /// If `true` objects are 4K physical pages accessed using the physical memory map
+    /// instead of heap allocations.
+    physical: bool,
+
+    /// Statistics of objects owned by this core. Physical caches use `PHYSICAL_STATS`.
+    stats: CacheStats,
+}
+
+impl SlabCache {
+    fn new(size: usize) -> Self {
+        Self {
+            free_list:    Lock::new(FreeList::new(size)),
+            remote_frees: AtomicUsize::new(0),
+            size,
+            physical:     false,
+            stats:        CacheStats::new(),
+        }
+    }
+
+    fn new_physical() -> Self {
+        Self {
+            physical: true,
+            ..Self::new(4096)
+        }
+    }
+
+    /// Get statistics which should be updated when objects from this cache are allocated
+    /// or freed.
+    fn stats(&self) -> &CacheStats {
+        if self.physical {
+            &PHYSICAL_STATS
+        } else {
+            &self.stats
+        }
+    }
+
+    /// Send an object freed on another core to this cache.
+    unsafe fn push_remote(&self, object: *mut u8) {
+        let mut head = self.remote_frees.load(Ordering::Relaxed);
+
+        loop {
+            *(object as *mut usize) = head;
+
+            match self.remote_frees.compare_exchange_weak(head, object as usize,
+                                                          Ordering::Release,
+                                                          Ordering::Relaxed) {
+                Ok(_)        => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    /// Move all objects freed by other cores to the free list.
+    unsafe fn take_remote_frees(&self, free_list: &mut FreeList) {
+        let mut object = self.remote_frees.swap(0, Ordering::Acquire);
+
+        while object != 0 {
+            let next = *(object as *const usize);
+
+            free_list.push(object as *mut u8);
+
+            object = next;
+        }
+    }
+
+    /// Get a free object. Returns null pointer if there is not enough memory.
+    unsafe fn pop(&self) -> *mut u8 {
+        let mut free_list = self.free_list.lock();
+
+        if free_list.is_empty() {
+            self.take_remote_frees(&mut free_list);
+        }
+
+        if free_list.is_empty() && self.physical {
+            let page = PhysicalMemory.alloc_phys(Layout::from_size_align(4096, 4096).unwrap());
+
+            return match page {
+                Some(page) => mm::translate(page, 4096).unwrap(),
+                None       => core::ptr::null_mut(),
+            };
+        }
+
+        free_list.pop()
+    }
+
+    /// Give back memory which isn't used by any object. Returns the number of reclaimed
+    /// bytes.
+    unsafe fn trim(&self) -> usize {
+        let mut free_list = self.free_list.lock();
+
+        self.take_remote_frees(&mut free_list);
+
+        if !self.physical {
+            return free_list.trim();
+        }
+
+        let mut reclaimed = 0;
+
+        while !free_list.is_empty() {
+            let page = free_list.pop();
+
+            if !mm::try_free_phys(virt_to_phys(page), 4096) {
+                // Free memory set is full, keep remaining pages.
+                free_list.push(page);
+
+                break;
+            }
+
+            reclaimed += 4096;
+        }
+
+        reclaimed
+    }
+}
+
+/// Heap state of a single core.
+pub struct CoreHeap {
+    caches: [SlabCache; CACHE_COUNT],
+
+    /// Virtual memory used by the heap of this core.
+    pub vmas: Lock<VmaAllocator>,
+}
+
+impl CoreHeap {
+    pub fn new(core_id: u64, xsave_size: usize) -> Self {
+        Self {
+            vmas:  Lock::new(mm::core_heap_vmas(core_id)),
+            caches: [
+                SlabCache::new(0x0000000000000008),
+                SlabCache::new(0x0000000000000010),
+                SlabCache::new(0x0000000000000020),
+                SlabCache::new(0x0000000000000040),
+                SlabCache::new(0x0000000000000080),
+                SlabCache::new(0x0000000000000100),
+                SlabCache::new(0x0000000000000200),
+                SlabCache::new(0x0000000000000400),
+                SlabCache::new(0x0000000000000800),
+                SlabCache::new(0x0000000000001000),
+                SlabCache::new(0x0000000000002000),
+                SlabCache::new(0x0000000000004000),
+                SlabCache::new(0x0000000000008000),
+                SlabCache::new(0x0000000000010000),
+                SlabCache::new(0x0000000000020000),
+                SlabCache::new(0x0000000000040000),
+                SlabCache::new(0x0000000000080000),
+                SlabCache::new(0x0000000000100000),
+                SlabCache::new(0x0000000000200000),
+                SlabCache::new(0x0000000000400000),
+                SlabCache::new(0x0000000000800000),
+                SlabCache::new(0x0000000001000000),
+                SlabCache::new(0x0000000002000000),
+                SlabCache::new(0x0000000004000000),
+                SlabCache::new(0x0000000008000000),
+                SlabCache::new(0x0000000010000000),
+                SlabCache::new(0x0000000020000000),
+                SlabCache::new(0x0000000040000000),
+                SlabCache::new(0x0000000080000000),
+                SlabCache::new(0x0000000100000000),
+                SlabCache::new(0x0000000200000000),
+                SlabCache::new(0x0000000400000000),
+                SlabCache::new(0x0000000800000000),
+                SlabCache::new(0x0000001000000000),
+                SlabCache::new(0x0000002000000000),
+                SlabCache::new(0x0000004000000000),
+                SlabCache::new(0x0000008000000000),
+                SlabCache::new(0x0000010000000000),
+                SlabCache::new(0x0000020000000000),
+                SlabCache::new(0x0000040000000000),
+                SlabCache::new(0x0000080000000000),
+                SlabCache::new(0x0000100000000000),
+                SlabCache::new(0x0000200000000000),
+                SlabCache::new(0x0000400000000000),
+                SlabCache::new(0x0000800000000000),
+                SlabCache::new(0x0001000000000000),
+                SlabCache::new(0x0002000000000000),
+                SlabCache::new(0x0004000000000000),
+                SlabCache::new(0x0008000000000000),
+                SlabCache::new(0x0010000000000000),
+                SlabCache::new(0x0020000000000000),
+                SlabCache::new(0x0040000000000000),
+                SlabCache::new(0x0080000000000000),
+                SlabCache::new(0x0100000000000000),
+                SlabCache::new(0x0200000000000000),
+                SlabCache::new(0x0400000000000000),
+                SlabCache::new(0x0800000000000000),
+                SlabCache::new(0x1000000000000000),
+                SlabCache::new(0x2000000000000000),
+                SlabCache::new(0x4000000000000000),
+                SlabCache::new(0x8000000000000000),
+
+                // Object caches in `OBJECT_CACHES` order.
+                SlabCache::new(4096),
+                SlabCache::new_physical(),
+                SlabCache::new(xsave_size.next_power_of_two()),
+            ],
+        }
+    }
+
+    fn cache(&self, index: usize) -> &SlabCache {
+        &self.caches[index]
+    }
+}
+
+/// Get the size class which can hold objects with `layout`. Objects are naturally aligned
+/// so the alignment is handled by picking a big enough size class.
+fn size_class(layout: Layout) -> Option<usize> {
+    // Size classes start at 8 bytes, round it up if needed.
+    let size = layout.size().max(layout.align()).max(8);
+
+    // Round up size to the nearest power of two and get the log2 of it.
+    let index = (64 - (size - 1).leading_zeros()) as usize - 3;
+
+    if index < SIZE_CLASSES {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+/// Allocate an object from cache `index` of the current core. `size` is the requested size
+/// used for statistics.
+unsafe fn allocate(index: usize, size: usize) -> *mut u8 {
+    let cache = core!().heap.cache(index);
+
+    let mut object = cache.pop();
+    if object.is_null() {
+        // We are out of physical memory. Give back unused heap pages and try again.
+        trim();
+
+        object = cache.pop();
+    }
+
+    if object.is_null() {
+        cache.stats().failures.fetch_add(1, Ordering::Relaxed);
+    } else {
+        cache.stats().record_allocation(size);
+    }
+
+    object
+}
+
+/// Return object to cache `index` of the core which owns it.
+unsafe fn deallocate(index: usize, object: *mut u8, size: usize) {
+    let owner = mm::heap_owner(VirtAddr(object as u64))
+        .expect("Freed object doesn't belong to the heap.");
+
+    if owner == core!().id {
+        let cache = core!().heap.cache(index);
+
+        cache.stats().record_free(size);
+        cache.free_list.lock().push(object);
+    } else {
+        let cache = core_locals::get_by_id(owner)
+            .expect("Freed object belongs to the core which doesn't exist.")
+            .heap
+            .cache(index);
+
+        cache.stats().record_free(size);
+        cache.push_remote(object);
+    }
+}
+
+pub unsafe fn alloc(layout: Layout) -> *mut u8 {
+    match size_class(layout) {
+        Some(index) => allocate(index, layout.size()),
+        None        => core::ptr::null_mut(),
+    }
+}
+
+pub unsafe fn dealloc(object: *mut u8, layout: Layout) {
+    let index = size_class(layout).expect("Freed object has invalid layout.");
+
+    deallocate(index, object, layout.size());
+}
+
+/// Give back memory of the current core which isn't used by any object. Returns the number
+/// of reclaimed bytes.
+pub fn trim() -> usize {
+    core!().heap.caches
+        .iter()
+        .map(|cache| unsafe { cache.trim() })
+        .sum()
+}
+
+/// Get statistics of cache `index` summed over all cores.
+pub fn stats(index: usize) -> HeapStats {
+    assert!(index < CACHE_COUNT, "Invalid cache index {}.", index);
+
+    let name = if index < SIZE_CLASSES {
+        CacheName::SizeClass(8 << index)
+    } else {
+        CacheName::Object(OBJECT_CACHES[index - SIZE_CLASSES])
+    };
+
+    let mut result = HeapStats {
+        name,
+        object_size:  core!().heap.cache(index).size,
+        allocations:  0,
+        live_objects: 0,
+        live_bytes:   0,
+        peak_objects: 0,
+        peak_bytes:   0,
+        failures:     0,
+    };
+
+    let mut add = |stats: &CacheStats| {
+        let load = |value: &AtomicU64| value.load(Ordering::Relaxed);
+
+        let allocations = load(&stats.allocations);
+        let frees       = load(&stats.frees);
+
+        // Counters are read without synchronization with other cores, so they don't have
+        // to be consistent. Make sure that summing them never overflows.
+        result.allocations  = result.allocations.wrapping_add(allocations);
+        result.live_objects = result.live_objects.wrapping_add(allocations.saturating_sub(frees));
+        result.live_bytes   = result.live_bytes.wrapping_add(load(&stats.live_bytes));
+        result.peak_objects = result.peak_objects.wrapping_add(load(&stats.peak_objects));
+        result.peak_bytes   = result.peak_bytes.wrapping_add(load(&stats.peak_bytes));
+        result.failures     = result.failures.wrapping_add(load(&stats.failures));
+    };
+
+    if core!().heap.cache(index).physical {
+        add(&PHYSICAL_STATS);
+    } else {
+        core_locals::for_each(|core_locals| add(&core_locals.heap.cache(index).stats));
+    }
+
+    result
+}
+
+#[cfg(feature = "kernel_tests")]
+mod tests {
+    use super::*;
+    use crate::tests::{self, kernel_test};
+    use crate::mm::PhysicalPage;
+
+    #[kernel_test]
+    fn large_alignments_are_satisfied() {
+        for &(size, align) in &[(8192, 8192), (64, 0x10000), (4096, 0x200000)] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+
+            unsafe {
+                let object = alloc(layout);
+
+                assert!(!object.is_null(), "Failed to allocate aligned object.");
+                assert!(object as usize & (align - 1) == 0, "Object is not aligned.");
+
+                // Make sure that object is backed by writable memory.
+                core::ptr::write_bytes(object, 0x41, size);
+
+                dealloc(object, layout);
+            }
+        }
+    }
+
+    #[kernel_test]
+    fn remote_frees_are_returned_to_owner() {
+        let layout = Layout::from_size_align(128, 8).unwrap();
+        let index  = size_class(layout).unwrap();
+        let cache  = core!().heap.cache(index);
+
+        unsafe {
+            let first  = alloc(layout);
+            let second = alloc(layout);
+
+            // Pretend that both objects were freed by another core.
+            cache.push_remote(first);
+            cache.push_remote(second);
+
+            let mut free_list = cache.free_list.lock();
+
+            cache.take_remote_frees(&mut free_list);
+
+            let mut reused = [free_list.pop(), free_list.pop()];
+            reused.sort_unstable();
+
+            let mut expected = [first, second];
+            expected.sort_unstable();
+
+            assert!(reused == expected, "Remote frees were not returned to the owner.");
+            assert!(cache.remote_frees.load(Ordering::Relaxed) == 0,
+                    "Remote free queue is not empty.");
+
+            free_list.push(first);
+            free_list.push(second);
+        }
+    }
+
+    #[kernel_test]
+    fn object_caches_track_live_objects() {
+        let index  = ObjectCache::Vmcb.index();
+        let before = stats(index);
+
+        let page = PhysicalPage::with_cache([0u8; 4096], ObjectCache::Vmcb);
+        let live = stats(index);
+
+        assert!(live.allocations == before.allocations + 1, "Allocation wasn't counted.");
+        assert!(live.live_objects == before.live_objects + 1, "Live object wasn't counted.");
+        assert!(live.live_bytes == before.live_bytes + 4096, "Live bytes weren't counted.");
+        assert!(live.peak_objects >= live.live_objects, "Peak is lower than live objects.");
+
+        drop(page);
+
+        let after = stats(index);
+
+        assert!(after.live_objects == before.live_objects, "Free wasn't counted.");
+        assert!(after.live_bytes == before.live_bytes, "Freed bytes weren't counted.");
+
+        mm::dump_heap_stats();
+    }
+
+    #[kernel_test]
+    fn npt_pages_are_reused() {
+        let page = ObjectCache::NptPage.alloc_page().expect("Failed to allocate NPT page.");
+
+        assert!(page.0 & 0xfff == 0, "NPT page is not aligned.");
+
+        unsafe {
+            ObjectCache::NptPage.free_page(page);
+        }
+
+        let reused = ObjectCache::NptPage.alloc_page().expect("Failed to allocate NPT page.");
+
+        assert!(reused == page, "Freed NPT page was not reused.");
+
+        unsafe {
+            ObjectCache::NptPage.free_page(reused);
+        }
+    }
+
+    #[kernel_test]
+    fn frees_on_other_cores_are_counted() {
+        // Object and page passed between cores.
+        static OBJECT: AtomicUsize = AtomicUsize::new(0);
+        static PAGE:   AtomicU64   = AtomicU64::new(0);
+
+        let other_core = match tests::other_core() {
+            Some(core_id) => core_id,
+            None          => return,
+        };
+
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        let index  = size_class(layout).unwrap();
+        let npt    = ObjectCache::NptPage.index();
+
+        let before     = stats(index);
+        let npt_before = stats(npt);
+
+        // Allocate an object on the other core and free it here.
+        tests::run_on_core(other_core, || {
+            let object = unsafe { alloc(Layout::from_size_align(256, 8).unwrap()) };
+
+            OBJECT.store(object as usize, Ordering::SeqCst);
+        });
+
+        let object = OBJECT.load(Ordering::SeqCst) as *mut u8;
+
+        assert!(!object.is_null(), "Failed to allocate object on the other core.");
+        assert!(mm::heap_owner(VirtAddr(object as u64)) == Some(other_core),
+                "Object is not owned by the core which allocated it.");
+
+        unsafe {
+            dealloc(object, layout);
+        }
+
+        // Allocate a physical page here and free it on the other core.
+        let page = ObjectCache::NptPage.alloc_page().expect("Failed to allocate NPT page.");
+
+        PAGE.store(page.0, Ordering::SeqCst);
+
+        tests::run_on_core(other_core, || unsafe {
+            ObjectCache::NptPage.free_page(PhysAddr(PAGE.load(Ordering::SeqCst)));
+        });
+
+        let after     = stats(index);
+        let npt_after = stats(npt);
+
+        assert!(after.allocations == before.allocations + 1, "Allocation wasn't counted.");
+        assert!(after.live_objects == before.live_objects, "Remote free wasn't counted.");
+        assert!(after.live_bytes == before.live_bytes, "Remotely freed bytes weren't counted.");
+
+        assert!(npt_after.allocations == npt_before.allocations + 1,
+                "Page allocation wasn't counted.");
+        assert!(npt_after.live_objects == npt_before.live_objects,
+                "Page freed on another core wasn't counted.");
+        assert!(npt_after.live_bytes == npt_before.live_bytes,
+                "Page bytes freed on another core weren't counted.");
+
+        mm::dump_heap_stats();
+    }
+}
@@ -0,0 +1,153 @@
+use core::sync::atomic::{AtomicPtr, AtomicU64, AtomicUsize, AtomicBool, Ordering};
+use core::fmt::Write;
+
+use qemu_test::KERNEL_TEST_FAIL_MARKER;
+use crate::{time, core_locals};
+
+pub use kernel_test::kernel_test;
+
+/// Test registered using `#[kernel_test]` attribute.
+#[repr(C)]
+pub struct KernelTest {
+    pub name:     &'static str,
+    pub function: fn(),
+}
+
+extern "C" {
+    // Defined by the linker, mark start and end of the `kernel_tests` section.
+    static __start_kernel_tests: u8;
+    static __stop_kernel_tests:  u8;
+}
+
+/// Test which is currently running on the BSP. Null if no test is running.
+static CURRENT_TEST: AtomicPtr<KernelTest> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Function which a test wants to run on another core. Zero if there is no pending call.
+static REMOTE_FUNCTION: AtomicUsize = AtomicUsize::new(0);
+
+/// ID of the core which should run `REMOTE_FUNCTION`.
+static REMOTE_CORE: AtomicU64 = AtomicU64::new(0);
+
+/// Set when all tests have finished and APs don't need to serve remote calls anymore.
+static FINISHED: AtomicBool = AtomicBool::new(false);
+
+macro_rules! report {
+    ($($arg: tt)*) => {{
+        // Always report results to the serial port so the host can parse them.
+        let mut serial = core!().boot_block.serial_port.lock();
+        let     serial = serial.as_mut().unwrap();
+
+        let _ = writeln!(serial, $($arg)*);
+    }};
+}
+
+fn registered_tests() -> &'static [KernelTest] {
+    unsafe {
+        let start = &__start_kernel_tests as *const u8 as *const KernelTest;
+        let end   = &__stop_kernel_tests  as *const u8 as *const KernelTest;
+
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Report the currently running test as failed. Called by the panic handler, which then
+/// halts all cores as usual, so the test run ends with the first failed test.
+pub fn report_panic(writer: &mut impl Write) {
+    let test = CURRENT_TEST.load(Ordering::SeqCst);
+
+    if core!().id == 0 && !test.is_null() {
+        let _ = writeln!(writer, "{}{}", KERNEL_TEST_FAIL_MARKER, unsafe { (*test).name });
+    }
+}
+
+/// Run all registered kernel tests on the BSP. Results are reported to the serial port, one
+/// line per event, in the following format:
+///
+/// `[kernel_test] begin count=<N>`
+/// `[kernel_test] start name=<test>`
+/// `[kernel_test] pass name=<test> time_ms=<time>`
+/// `[kernel_test] end passed=<N>`
+///
+/// Failed test panics. Panic handler reports `[kernel_test] fail name=<test>` and halts
+/// the kernel, so remaining tests are not run.
+pub fn run() {
+    assert!(core!().id == 0, "Kernel tests can be only run on the BSP.");
+
+    let tests = registered_tests();
+
+    report!("[kernel_test] begin count={}", tests.len());
+
+    for test in tests {
+        report!("[kernel_test] start name={}", test.name);
+
+        let start_tsc = time::get();
+
+        CURRENT_TEST.store(test as *const KernelTest as *mut KernelTest, Ordering::SeqCst);
+
+        (test.function)();
+
+        CURRENT_TEST.store(core::ptr::null_mut(), Ordering::SeqCst);
+
+        let elapsed = time::difference(start_tsc, time::get()) * 1000.0;
+
+        report!("[kernel_test] pass name={} time_ms={:.3}", test.name, elapsed);
+    }
+
+    report!("[kernel_test] end passed={}", tests.len());
+
+    FINISHED.store(true, Ordering::SeqCst);
+}
+
+/// Get ID of any online core other than the BSP. Returns `None` if the BSP is the only one.
+pub fn other_core() -> Option<u64> {
+    let mut result = None;
+
+    core_locals::for_each(|core_locals| {
+        if core_locals.id != core!().id && result.is_none() {
+            result = Some(core_locals.id);
+        }
+    });
+
+    result
+}
+
+/// Start running `function` on core `core_id`. Caller must wait for it to return using
+/// `wait_for_remote_call` before starting another one.
+pub fn start_on_core(core_id: u64, function: fn()) {
+    assert!(core_id != core!().id, "Cannot run remote call on the current core.");
+    assert!(REMOTE_FUNCTION.load(Ordering::SeqCst) == 0, "Remote call is already running.");
+
+    REMOTE_CORE.store(core_id, Ordering::SeqCst);
+    REMOTE_FUNCTION.store(function as usize, Ordering::SeqCst);
+}
+
+/// Wait until the function started by `start_on_core` returns.
+pub fn wait_for_remote_call() {
+    while REMOTE_FUNCTION.load(Ordering::SeqCst) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Run `function` on core `core_id` and wait for it to return.
+pub fn run_on_core(core_id: u64, function: fn()) {
+    start_on_core(core_id, function);
+    wait_for_remote_call();
+}
+
+/// Run functions requested by `run_on_core` until all tests finish. Called by every core
+/// after boot, the BSP returns immediately because it has already run the tests.
+pub fn serve_remote_calls() {
+    while !FINISHED.load(Ordering::SeqCst) {
+        let function = REMOTE_FUNCTION.load(Ordering::SeqCst);
+
+        if function != 0 && REMOTE_CORE.load(Ordering::SeqCst) == core!().id {
+            let function: fn() = unsafe { core::mem::transmute(function) };
+
+            function();
+
+            REMOTE_FUNCTION.store(0, Ordering::SeqCst);
+        }
+
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,428 @@
+// TLB shootdown. Cores cache translations of shared kernel mappings, so when a mapping is
+// changed, the core which changed it sends an IPI with the list of invalidated ranges to all
+// other cores and waits until every one of them acknowledges the flush.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::alloc::Layout;
+
+use page_table::{PhysAddr, PhysMem, VirtAddr};
+use crate::processors::MAX_CORES;
+use crate::mm::PhysicalMemory;
+use crate::lock::Lock;
+use crate::{apic, gdb, time, panic, core_locals};
+
+/// Maximum number of ranges in a single batch. Bigger batches flush the whole TLB.
+pub const MAX_RANGES: usize = 16;
+
+/// Batches which invalidate more pages than this flush the whole TLB instead.
+const FULL_FLUSH_PAGES: u64 = 64;
+
+/// Number of seconds after which cores which haven't acknowledged shootdown are considered
+/// to be stuck.
+const SHOOTDOWN_TIMEOUT: f64 = 1.0;
+
+/// ICR value which sends TLB shootdown request to the destination core.
+const TLB_SHOOTDOWN_IPI: u32 = (1 << 14) | apic::TLB_SHOOTDOWN_IRQ as u32;
+
+/// Cores which have interrupts enabled and can respond to shootdown requests.
+static ACTIVE_CORES: CoreMask = CoreMask::new();
+
+/// Cores which haven't acknowledged the current shootdown request yet.
+static PENDING_CORES: CoreMask = CoreMask::new();
+
+/// Current shootdown request. Initiator holds the lock until all targeted cores acknowledge
+/// the request, so they can read it without locking.
+static REQUEST: Lock<TlbBatch> = Lock::new(TlbBatch::new());
+
+/// Number of shootdown requests handled on behalf of other cores.
+static HANDLED_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Set of cores indexed by core ID.
+struct CoreMask([AtomicU64; MAX_CORES / 64]);
+
+impl CoreMask {
+    const fn new() -> Self {
+        const EMPTY: AtomicU64 = AtomicU64::new(0);
+
+        Self([EMPTY; MAX_CORES / 64])
+    }
+
+    fn set(&self, core_id: u64) {
+        self.0[core_id as usize / 64].fetch_or(1 << (core_id % 64), Ordering::SeqCst);
+    }
+
+    fn clear(&self, core_id: u64) {
+        self.0[core_id as usize / 64].fetch_and(!(1 << (core_id % 64)), Ordering::SeqCst);
+    }
+
+    fn contains(&self, core_id: u64) -> bool {
+        self.0[core_id as usize / 64].load(Ordering::SeqCst) & (1 << (core_id % 64)) != 0
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.iter().all(|word| word.load(Ordering::SeqCst) == 0)
+    }
+}
+
+impl core::fmt::Display for CoreMask {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let mut first = true;
+
+        for core_id in (0..MAX_CORES as u64).filter(|&core_id| self.contains(core_id)) {
+            write!(f, "{}{}", if first { "" } else { ", " }, core_id)?;
+
+            first = false;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone)]
+struct TlbRange {
+    start: u64,
+    pages: u64,
+}
+
+/// Set of virtual ranges which need to be invalidated. If the set becomes too big it falls
+/// back to flushing the whole TLB.
+#[derive(Clone)]
+pub struct TlbBatch {
+    ranges:     [TlbRange; MAX_RANGES],
+    count:      usize,
+    pages:      u64,
+    full_flush: bool,
+}
+
+impl TlbBatch {
+    pub const fn new() -> Self {
+        Self {
+            ranges:     [TlbRange { start: 0, pages: 0 }; MAX_RANGES],
+            count:      0,
+            pages:      0,
+            full_flush: false,
+        }
+    }
+
+    /// Add `pages` 4K pages starting at `virt_addr` to the batch.
+    pub fn add(&mut self, virt_addr: VirtAddr, pages: u64) {
+        if self.full_flush {
+            return;
+        }
+
+        self.pages += pages;
+
+        if self.pages > FULL_FLUSH_PAGES {
+            self.full_flush = true;
+            return;
+        }
+
+        // Extend the last range if the new one directly follows it.
+        if let Some(last) = self.ranges[..self.count].last_mut() {
+            if last.start + last.pages * 4096 == virt_addr.0 {
+                last.pages += pages;
+                return;
+            }
+        }
+
+        if self.count == MAX_RANGES {
+            self.full_flush = true;
+            return;
+        }
+
+        self.ranges[self.count] = TlbRange {
+            start: virt_addr.0,
+            pages,
+        };
+
+        self.count += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0 && !self.full_flush
+    }
+
+    pub fn clear(&mut self) {
+        self.count      = 0;
+        self.pages      = 0;
+        self.full_flush = false;
+    }
+
+    /// Invalidate all ranges in the batch on the current core.
+    unsafe fn flush_local(&self) {
+        if self.full_flush {
+            // Kernel doesn't use global pages so reloading CR3 flushes everything.
+            cpu::set_cr3(cpu::get_cr3());
+            return;
+        }
+
+        for range in &self.ranges[..self.count] {
+            for page in 0..range.pages {
+                cpu::invlpg((range.start + page * 4096) as usize);
+            }
+        }
+    }
+}
+
+impl Default for TlbBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Flush the current shootdown request if it targets this core. Cores which may wait for
+/// other cores with interrupts disabled must call it while waiting.
+pub fn handle_pending() {
+    let core_id = core!().id;
+
+    if PENDING_CORES.contains(core_id) {
+        unsafe {
+            (*REQUEST.bypass()).flush_local();
+        }
+
+        HANDLED_REQUESTS.fetch_add(1, Ordering::Relaxed);
+
+        // Acknowledge the request. Initiator can reuse it from now on.
+        PENDING_CORES.clear(core_id);
+    }
+}
+
+/// Invalidate `batch` on all cores. Returns after every active core has flushed it.
+/// Caller can hold any locks, cores which wait for them handle the request while spinning.
+/// Panics if some core doesn't respond in `SHOOTDOWN_TIMEOUT` seconds.
+pub fn shootdown(batch: &TlbBatch) {
+    if batch.is_empty() {
+        return;
+    }
+
+    unsafe {
+        batch.flush_local();
+    }
+
+    // Other cores are halted when panicking and won't respond.
+    if panic::is_panicking() {
+        return;
+    }
+
+    let current_core = core!().id;
+
+    let mut request = loop {
+        if let Some(request) = unsafe { REQUEST.try_lock_unsafe() } {
+            break request;
+        }
+
+        // Initiator of the current request may be waiting for us and we may have interrupts
+        // disabled. Handle its request manually to avoid deadlock.
+        handle_pending();
+
+        core::hint::spin_loop();
+    };
+
+    *request = batch.clone();
+
+    let mut targets = 0;
+
+    for core_id in 0..MAX_CORES as u64 {
+        if core_id != current_core && ACTIVE_CORES.contains(core_id) {
+            PENDING_CORES.set(core_id);
+
+            targets += 1;
+        }
+    }
+
+    if targets == 0 {
+        return;
+    }
+
+    {
+        let mut apic = core!().apic.lock();
+        let apic     = apic.as_mut().expect("APIC wasn't initialized.");
+
+        for core_id in 0..MAX_CORES as u64 {
+            // Core could have already handled the request while waiting for the lock.
+            if !PENDING_CORES.contains(core_id) {
+                continue;
+            }
+
+            let apic_id = core_locals::get_by_id(core_id)
+                .and_then(|core_locals| core_locals.apic_id())
+                .expect("Active core doesn't have APIC ID.");
+
+            unsafe {
+                apic.ipi(apic_id, TLB_SHOOTDOWN_IPI);
+            }
+        }
+    }
+
+    // Wait for all targeted cores to flush their TLBs.
+    let mut start = time::get();
+
+    while !PENDING_CORES.is_empty() {
+        // Stopped cores don't respond, so don't count the time when GDB stopped the kernel.
+        if gdb::stopped_since(start) {
+            start = time::get();
+        }
+
+        if time::difference(start, time::get()) > SHOOTDOWN_TIMEOUT {
+            panic!("Cores {} didn't acknowledge TLB shootdown.", PENDING_CORES);
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+/// Handle TLB shootdown IPI sent by another core.
+pub unsafe fn handle_interrupt() {
+    handle_pending();
+
+    apic::Apic::eoi();
+}
+
+/// Start responding to shootdown requests on the current core. Interrupts must be enabled.
+pub unsafe fn enable() {
+    assert!(core!().interrupts_enabled(), "Enabling TLB shootdowns with interrupts disabled.");
+
+    ACTIVE_CORES.set(core!().id);
+
+    // Requests sent before this core became active weren't delivered to it.
+    cpu::set_cr3(cpu::get_cr3());
+}
+
+/// Physical memory which invalidates changed mappings on all cores. Invalidated pages
+/// are batched and flushed everywhere when the page table operation finishes.
+#[derive(Default)]
+pub struct Shootdown {
+    batch: TlbBatch,
+}
+
+impl PhysMem for Shootdown {
+    unsafe fn translate(&mut self, phys_addr: PhysAddr, size: usize) -> Option<*mut u8> {
+        PhysicalMemory.translate(phys_addr, size)
+    }
+
+    fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr> {
+        PhysicalMemory.alloc_phys(layout)
+    }
+
+    unsafe fn free_phys(&mut self, phys_addr: PhysAddr, size: usize) -> Option<()> {
+        PhysicalMemory.free_phys(phys_addr, size)
+    }
+
+    unsafe fn invalidate_tlb(&mut self, virt_addr: VirtAddr) {
+        // `invlpg` on any address in a large page invalidates the whole page.
+        self.batch.add(virt_addr, 1);
+    }
+
+    unsafe fn flush_tlb(&mut self) {
+        shootdown(&self.batch);
+
+        self.batch.clear();
+    }
+}
+
+#[cfg(feature = "kernel_tests")]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicBool;
+    use crate::tests::{self, kernel_test};
+
+    #[kernel_test]
+    fn adjacent_pages_are_merged() {
+        let mut batch = TlbBatch::new();
+
+        assert!(batch.is_empty(), "New batch is not empty.");
+
+        batch.add(VirtAddr(0x1000), 1);
+        batch.add(VirtAddr(0x2000), 2);
+        batch.add(VirtAddr(0x8000), 1);
+
+        assert!(batch.count == 2 && !batch.full_flush, "Adjacent ranges weren't merged.");
+        assert!(batch.ranges[0].pages == 3, "Merged range has invalid size.");
+
+        batch.clear();
+
+        assert!(batch.is_empty(), "Cleared batch is not empty.");
+    }
+
+    #[kernel_test]
+    fn big_batches_flush_everything() {
+        let mut batch = TlbBatch::new();
+
+        for index in 0..=MAX_RANGES as u64 {
+            batch.add(VirtAddr(index * 0x10000), 1);
+        }
+
+        assert!(batch.full_flush, "Too many ranges didn't cause full flush.");
+
+        let mut batch = TlbBatch::new();
+
+        batch.add(VirtAddr(0x1000), FULL_FLUSH_PAGES + 1);
+
+        assert!(batch.full_flush, "Too many pages didn't cause full flush.");
+        assert!(!batch.is_empty(), "Full flush batch is empty.");
+    }
+
+    #[kernel_test]
+    fn all_cores_acknowledge_shootdown() {
+        let others = (0..MAX_CORES as u64)
+            .filter(|&core_id| core_id != core!().id && ACTIVE_CORES.contains(core_id))
+            .count() as u64;
+
+        let mut batch = TlbBatch::new();
+
+        batch.add(VirtAddr(0x1000), 1);
+
+        let before = HANDLED_REQUESTS.load(Ordering::Relaxed);
+
+        shootdown(&batch);
+
+        assert!(PENDING_CORES.is_empty(), "Some cores didn't acknowledge shootdown.");
+        assert!(HANDLED_REQUESTS.load(Ordering::Relaxed) - before >= others,
+                "Shootdown wasn't handled by all cores.");
+    }
+
+    #[kernel_test]
+    fn cores_waiting_for_locks_acknowledge_shootdown() {
+        static LOCK:    Lock<()> = Lock::new_non_preemptible(());
+        static WAITING: AtomicBool = AtomicBool::new(false);
+
+        let other_core = match tests::other_core() {
+            Some(core_id) => core_id,
+            None          => return,
+        };
+
+        WAITING.store(false, Ordering::SeqCst);
+
+        // Other core will wait for the lock with interrupts disabled.
+        let guard = LOCK.lock();
+
+        tests::start_on_core(other_core, || {
+            WAITING.store(true, Ordering::SeqCst);
+
+            drop(LOCK.lock());
+        });
+
+        while !WAITING.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+
+        // Give the other core some time to start spinning.
+        let start = time::get();
+
+        while time::difference(start, time::get()) < 0.01 {
+            core::hint::spin_loop();
+        }
+
+        let mut batch = TlbBatch::new();
+
+        batch.add(VirtAddr(0x1000), 1);
+
+        shootdown(&batch);
+
+        assert!(!PENDING_CORES.contains(other_core), "Waiting core didn't acknowledge shootdown.");
+
+        drop(guard);
+
+        tests::wait_for_remote_call();
+    }
+}
@@ -11,7 +11,7 @@ use vkernel::VKernel;
 
 unsafe fn guest_entrypoint() -> ! {
     // If printing in interrupts is enabled we will mess up guest and host interrupt state.
-    if !crate::interrupts::PRINT_IN_INTERRUPTS {
+    if !crate::config::print_in_interrupts() {
         println!("Running in the VM! Uptime: {:.2}s.", crate::time::uptime());
     }
 
@@ -215,3 +215,57 @@ pub unsafe fn initialize() {
     run_kernel_in_vm();
     run_vkernel_in_vm();
 }
+
+#[cfg(feature = "kernel_tests")]
+mod tests {
+    use super::*;
+    use crate::tests::kernel_test;
+
+    #[kernel_test]
+    fn vm_registers_round_trip() {
+        let mut vm = Vm::new()
+            .expect("Failed to create virtual machine");
+
+        let registers = [
+            (Register::Rax,    0x1111_2222_3333_4444),
+            (Register::R15,    0x5555_6666_7777_8888),
+            (Register::Rip,    0xffff_8000_1234_5678),
+            (Register::Rflags, 0x202),
+            (Register::Cr3,    0x1000),
+            (Register::Efer,   0xd01),
+        ];
+
+        for &(register, value) in &registers {
+            vm.set_reg(register, value);
+        }
+
+        for &(register, value) in &registers {
+            assert!(vm.reg(register) == value, "VM register {:?} has invalid value.", register);
+        }
+    }
+
+    #[kernel_test]
+    fn vm_npt_maps_guest_memory() {
+        let mut vm = Vm::new()
+            .expect("Failed to create virtual machine");
+
+        vm.npt_mut().map(GuestAddr(0x20_0000), PageType::Page4K, 0x3000, true, false);
+
+        unsafe {
+            for offset in (0..0x3000).step_by(0x1000) {
+                assert!(vm.npt().guest_to_host(GuestAddr(0x20_0000 + offset)).is_some(),
+                        "Mapped guest memory has no host backing.");
+            }
+
+            assert!(vm.npt().guest_to_host(GuestAddr(0x20_3000)).is_none(),
+                    "Unmapped guest memory has host backing.");
+        }
+    }
+
+    #[kernel_test]
+    fn vm_runs_guest_until_vmmcall() {
+        // Reuse the full kernel-in-VM scenario which exercises segment and table loading,
+        // intercepts and nested paging.
+        run_kernel_in_vm();
+    }
+}
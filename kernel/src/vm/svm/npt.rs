@@ -2,6 +2,8 @@ pub use page_table::{PageTable, PageType, PhysAddr, VirtAddr};
 
 use core::alloc::Layout;
 
+use crate::slab::ObjectCache;
+
 pub const NPT_PRESENT: u64 = page_table::PAGE_PRESENT;
 pub const NPT_WRITE:   u64 = page_table::PAGE_WRITE;
 pub const NPT_NX:      u64 = page_table::PAGE_NX;
@@ -16,10 +18,21 @@ impl page_table::PhysMem for PhysicalMemory<'_> {
     }
 
     fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr> {
+        // Page tables are allocated and freed often, keep them in a separate cache.
+        if layout.size() == 4096 && layout.align() == 4096 {
+            return ObjectCache::NptPage.alloc_page();
+        }
+
         crate::mm::PhysicalMemory.alloc_phys(layout)
     }
 
     unsafe fn free_phys(&mut self, phys_addr: PhysAddr, size: usize) -> Option<()> {
+        if size == 4096 && phys_addr.0 & 0xfff == 0 {
+            ObjectCache::NptPage.free_page(phys_addr);
+
+            return Some(());
+        }
+
         crate::mm::PhysicalMemory.free_phys(phys_addr, size)
     }
 
@@ -1,6 +1,7 @@
-use core::alloc::{GlobalAlloc, Layout};
+use core::alloc::Layout;
 
-use crate::mm::{self, PhysicalPage};
+use crate::mm::PhysicalPage;
+use crate::slab::ObjectCache;
 use crate::lock::Lock;
 
 use super::VmError;
@@ -41,7 +42,7 @@ impl XsaveArea {
             let xsave_size   = core!().xsave_size();
             let xsave_layout = Layout::from_size_align(xsave_size, 64)
                 .expect("Failed to create XSAVE layout.");
-            let xsave_area   = mm::GLOBAL_ALLOCATOR.alloc(xsave_layout);
+            let xsave_area   = ObjectCache::XsaveArea.alloc(xsave_layout);
 
             assert!(!xsave_area.is_null(), "Failed to allocate XSAVE area.");
 
@@ -77,7 +78,7 @@ impl Drop for XsaveArea {
                 .expect("Failed to create XSAVE layout.");
 
             // Free the XSAVE area.
-            mm::GLOBAL_ALLOCATOR.dealloc(self.pointer, xsave_layout);
+            ObjectCache::XsaveArea.dealloc(self.pointer, xsave_layout);
         }
     }
 }
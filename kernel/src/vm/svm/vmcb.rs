@@ -1,6 +1,7 @@
 use core::mem::MaybeUninit;
 
 use crate::mm::PhysicalPage;
+use crate::slab::ObjectCache;
 
 #[repr(C)]
 pub struct VmcbSegmentDescriptor {
@@ -196,6 +197,6 @@ impl Vmcb {
         };
 
         // Move VMCB to a physical page as required by the SVM.
-        PhysicalPage::new(vmcb)
+        PhysicalPage::with_cache(vmcb, ObjectCache::Vmcb)
     }
 }
@@ -9,6 +9,8 @@ use alloc::vec;
 use core::alloc::Layout;
 use core::convert::TryInto;
 
+use crate::initrd;
+
 struct GuestMemory<'a>(&'a mut Npt, &'a mut u64);
 
 impl PhysMem for GuestMemory<'_> {
@@ -228,15 +230,20 @@ impl VKernel {
 }
 
 fn get_vkernel_image() -> VKernelImage {
-    const IMAGE: &[u8] = include_bytes!(
+    const BUILTIN_IMAGE: &[u8] = include_bytes!(
         concat!(env!("OUT_DIR"), "/vkernel.bin")
     );
 
-    let base       = u64::from_le_bytes(IMAGE[0.. 8].try_into().unwrap());
-    let rsp        = u64::from_le_bytes(IMAGE[8..16].try_into().unwrap());
+    // VKernel shipped in the initrd takes precedence over the built-in one.
+    let image = initrd::read("vkernel.bin").unwrap_or(BUILTIN_IMAGE);
+
+    assert!(image.len() >= 16, "VKernel image is too small.");
+
+    let base       = u64::from_le_bytes(image[0.. 8].try_into().unwrap());
+    let rsp        = u64::from_le_bytes(image[8..16].try_into().unwrap());
     let entrypoint = base + 16;
 
-    let image_size  = (IMAGE.len() + 0xfff) & !0xfff;
+    let image_size  = (image.len() + 0xfff) & !0xfff;
     let permissions = vec![0b11; (image_size + 3) / 4];
 
     VKernelImage {
@@ -244,6 +251,6 @@ fn get_vkernel_image() -> VKernelImage {
         entrypoint,
         permissions,
         rsp,
-        image: IMAGE.to_vec(),
+        image: image.to_vec(),
     }
 }
@@ -0,0 +1,178 @@
+// Allocator of kernel virtual memory areas. Every area is followed by unmapped padding which
+// catches overflows. Freed areas are reused by later allocations, so drivers and VMs can map
+// and unmap memory without exhausting the kernel address space.
+
+use page_table::VirtAddr;
+use rangeset::{Range, RangeSet};
+use boot_block::KERNEL_HEAP_PADDING;
+use crate::lock::Lock;
+
+/// Number of entries which are always kept available in the free region set. Freeing areas
+/// may require additional entries and it's better to leak them than to panic.
+const RESERVED_RANGE_ENTRIES: usize = 16;
+
+/// Allocator for general purpose kernel areas (MMIO and contiguous region mappings).
+static KERNEL_VMAS: Lock<Option<VmaAllocator>> = Lock::new(None);
+
+pub struct VmaAllocator {
+    /// Base address of the managed region.
+    base: u64,
+
+    /// Size of the managed region.
+    size: u64,
+
+    /// Offset of the first never used address.
+    next: u64,
+
+    /// Areas (including padding) which were freed and can be reused.
+    free_regions: RangeSet,
+}
+
+impl VmaAllocator {
+    /// Create an allocator which manages `size` bytes of virtual memory at `base`.
+    pub fn new(base: VirtAddr, size: u64) -> Self {
+        assert!(base.0 & 0xfff == 0 && size & 0xfff == 0, "VMA region is not page aligned.");
+
+        Self {
+            base:         base.0,
+            size,
+            next:         0,
+            free_regions: RangeSet::new(),
+        }
+    }
+
+    /// Allocate area of `size` bytes aligned to `align`. Returns `None` if managed region
+    /// is exhausted.
+    pub fn allocate(&mut self, size: u64, align: u64) -> Option<VirtAddr> {
+        assert!(size > 0 && size & 0xfff == 0, "VMA size {:x} is invalid.", size);
+        assert!(align.count_ones() == 1 && align >= 4096, "VMA alignment {:x} is invalid.",
+                align);
+
+        // Calculate actual size for the area we are allocating.
+        let reserve = KERNEL_HEAP_PADDING + size;
+
+        // Reuse freed area if possible.
+        if let Some(address) = self.free_regions.allocate(reserve, align) {
+            return Some(VirtAddr(address as u64));
+        }
+
+        let start   = self.base + self.next;
+        let address = start.checked_add(align - 1)? & !(align - 1);
+        let end     = address.checked_add(reserve)?;
+
+        if end > self.base + self.size {
+            return None;
+        }
+
+        self.next = end - self.base;
+
+        // Don't waste space skipped because of the alignment.
+        if address > start {
+            self.release(start, address - start);
+        }
+
+        Some(VirtAddr(address))
+    }
+
+    /// Free area of `size` bytes allocated by `allocate`. Area must not be mapped.
+    pub fn free(&mut self, virt_addr: VirtAddr, size: u64) {
+        let end = virt_addr.0 + size + KERNEL_HEAP_PADDING;
+
+        assert!(virt_addr.0 >= self.base && end <= self.base + self.next,
+                "Freed VMA {:x} doesn't belong to this allocator.", virt_addr.0);
+
+        self.release(virt_addr.0, size + KERNEL_HEAP_PADDING);
+    }
+
+    fn release(&mut self, start: u64, size: u64) {
+        // Virtual memory is plentiful, just leak the area if there is no space to track it.
+        if self.free_regions.free_entries() > RESERVED_RANGE_ENTRIES {
+            self.free_regions.insert(Range {
+                start,
+                end: start + size - 1,
+            });
+        }
+    }
+}
+
+/// Allocate kernel area of `size` bytes aligned to `align`. Panics if the kernel address
+/// space is exhausted.
+pub fn allocate(size: u64, align: u64) -> VirtAddr {
+    KERNEL_VMAS.lock()
+        .as_mut()
+        .expect("Kernel VMA allocator wasn't initialized.")
+        .allocate(size, align)
+        .expect("Kernel virtual address space is exhausted.")
+}
+
+/// Free kernel area allocated by `allocate`. Area must not be mapped.
+pub unsafe fn free(virt_addr: VirtAddr, size: u64) {
+    KERNEL_VMAS.lock()
+        .as_mut()
+        .expect("Kernel VMA allocator wasn't initialized.")
+        .free(virt_addr, size);
+}
+
+/// Initialize the kernel VMA allocator with region of `size` bytes at `base`.
+pub fn initialize(base: VirtAddr, size: u64) {
+    let mut vmas = KERNEL_VMAS.lock();
+
+    assert!(vmas.is_none(), "Kernel VMA allocator was already initialized.");
+
+    *vmas = Some(VmaAllocator::new(base, size));
+}
+
+#[cfg(feature = "kernel_tests")]
+mod tests {
+    use super::*;
+    use crate::tests::kernel_test;
+
+    const BASE: VirtAddr = VirtAddr(0xffff_9000_0000_0000);
+    const SIZE: u64      = 1024 * 1024 * 1024;
+
+    #[kernel_test]
+    fn freed_areas_are_reused() {
+        let mut vmas = VmaAllocator::new(BASE, SIZE);
+
+        let first  = vmas.allocate(8192, 4096).unwrap();
+        let second = vmas.allocate(4096, 4096).unwrap();
+
+        assert!(first == BASE, "First area doesn't start at the base.");
+        assert!(second.0 == first.0 + 8192 + KERNEL_HEAP_PADDING,
+                "Areas are not separated by padding.");
+
+        vmas.free(first, 8192);
+
+        assert!(vmas.allocate(4096, 4096) == Some(first), "Freed area was not reused.");
+    }
+
+    #[kernel_test]
+    fn alignment_is_respected() {
+        let mut vmas = VmaAllocator::new(BASE, SIZE);
+
+        let small   = vmas.allocate(4096, 4096).unwrap();
+        let aligned = vmas.allocate(0x200000, 0x200000).unwrap();
+
+        assert!(aligned.0 & 0x1fffff == 0, "Area is not aligned.");
+
+        // Space skipped because of the alignment can be used by other areas.
+        let next = vmas.allocate(4096, 4096).unwrap();
+
+        assert!(next.0 == small.0 + 4096 + KERNEL_HEAP_PADDING, "Skipped space was not reused.");
+    }
+
+    #[kernel_test]
+    fn exhausted_region_fails() {
+        let mut vmas = VmaAllocator::new(BASE, 0x10000);
+
+        assert!(vmas.allocate(0x20000, 4096).is_none(), "Too big area was allocated.");
+
+        let area = vmas.allocate(0x10000 - KERNEL_HEAP_PADDING, 4096).unwrap();
+
+        assert!(vmas.allocate(4096, 4096).is_none(), "Area was allocated in a full region.");
+
+        vmas.free(area, 0x10000 - KERNEL_HEAP_PADDING);
+
+        assert!(vmas.allocate(4096, 4096).is_some(), "Freed region was not reused.");
+    }
+}
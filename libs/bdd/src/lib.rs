@@ -1,11 +1,14 @@
 #![no_std]
 
+use integrity::Manifest;
+
 // Everything here must be exactly the same in 32 bit mode and 64 bit mode.
 
 /// Signature used to check if BDD is valid.
 pub const SIGNATURE: u32 = 0x1778cf9d;
 
-/// Disk data which is required to find all programs stored on the disk.
+/// Disk data which is required to find all programs stored on the disk. It is stored in
+/// the first sector of the boot partition and all LBAs are relative to its start.
 /// Don't change the offsets, they are hardcoded in the bootloader assembly file.
 #[repr(C)]
 pub struct BootDiskDescriptor {
@@ -18,17 +21,31 @@ pub struct BootDiskDescriptor {
     /// Size (in sectors) of the second state bootloader.
     pub bootloader_sectors:  u32,
 
-    /// Checksum (calculated by checksum() function) of the second stage bootloader.
+    /// Checksum (calculated by checksum() function) of the second stage bootloader. Early
+    /// bootloader cannot calculate anything stronger.
     pub bootloader_checksum: u32,
 
     /// LBA address of the kernel.
     pub kernel_lba: u32,
 
-    /// Size (in sectors) of the kernel.
+    /// Size (in sectors) of the kernel. If the kernel starts with `lz4::Header`, it is
+    /// compressed and the header contains both sizes and the compressed data digest.
     pub kernel_sectors: u32,
 
-    /// Checksum (calculated by checksum() function) of the kernel.
-    pub kernel_checksum: u32,
+    /// LBA address of the kernel command line.
+    pub cmdline_lba: u32,
+
+    /// Size (in bytes) of the kernel command line. 0 if there is no command line.
+    pub cmdline_size: u32,
+
+    /// LBA address of the initial ramdisk.
+    pub initrd_lba: u32,
+
+    /// Size (in bytes) of the initial ramdisk. 0 if there is no initial ramdisk.
+    pub initrd_size: u32,
+
+    /// SHA-256 digests of the kernel, the command line and the initial ramdisk.
+    pub manifest: Manifest,
 }
 
 /// Disk data which is required to read from the disk using BIOS interrupts.
@@ -42,6 +59,9 @@ pub struct BootDiskData {
     pub sectors_per_track:    u32,
     pub heads_per_cylinder:   u32,
     pub sectors_per_cylinder: u32,
+
+    /// First LBA of the boot partition. LBAs from the BDD are relative to it.
+    pub partition_lba: u32,
 }
 
 /// Calculate FNV-1a 32 bit checksum of the data.
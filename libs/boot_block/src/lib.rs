@@ -12,6 +12,9 @@ use serial_port::SerialPort;
 // 0xffff_f000_0000_0000 - 0xffff_ffff_8000_0000      - physical region (~16 TB)
 // 0xffff_ffff_8000_0000 - 0xffff_ffff_ff00_0000      - kernel area (~2 GB)
 // 0xffff_ffff_ff00_0000 - 0xffff_ffff_ffff_ffff + 1  - unused (16 MB)
+//
+// Constants below are default bases of these regions. With KASLR the bootloader picks
+// random bases inside of them and publishes the chosen `KernelLayout` in the boot block.
 
 /// A region which is used to allocate unique stacks for each core.
 pub const KERNEL_STACK_BASE:    u64 = 0xffff_8000_0000_0000;
@@ -29,9 +32,90 @@ pub const KERNEL_HEAP_PADDING: u64 = 4096;
 /// Base address of the kernel. As required by System V ABI, image must be between
 /// 0xffff_ffff_8000_0000 and 0xffff_ffff_ff00_0000.
 pub const KERNEL_BASE: u64 = 0xffff_ffff_8000_0000;
+const KERNEL_AREA_END: u64 = 0xffff_ffff_ff00_0000;
+
+/// Parts of the stack and heap regions in which randomized bases can be placed. The rest
+/// of these regions is left for allocations.
+const STACK_RANDOMIZATION_SIZE: u64 = 16 * 1024 * 1024 * 1024 * 1024;
+const HEAP_RANDOMIZATION_SIZE:  u64 = 40 * 1024 * 1024 * 1024 * 1024;
+
+/// Physical region may be mapped using 1G pages so its base needs a bigger alignment.
+const REGION_ALIGNMENT:          u64 = 2 * 1024 * 1024;
+const PHYSICAL_REGION_ALIGNMENT: u64 = 1024 * 1024 * 1024;
 
 pub const MAX_SUPPORTED_MODES: usize = 128;
 
+/// Maximum size (in bytes) of the command line passed to the kernel.
+pub const MAX_COMMAND_LINE_SIZE: usize = 4096;
+
+// Locations of boot files on the EFI System Partition, relative to its root.
+pub const ESP_KERNEL_PATH:   &str = "flugzeug/kernel";
+pub const ESP_INITRD_PATH:   &str = "flugzeug/initrd";
+pub const ESP_CONFIG_PATH:   &str = "flugzeug/config";
+pub const ESP_MANIFEST_PATH: &str = "flugzeug/manifest";
+
+/// Virtual memory layout of the kernel chosen by the bootloader. Kernel must use it instead
+/// of the default region bases.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct KernelLayout {
+    /// Address at which the kernel image is loaded.
+    pub kernel_base: u64,
+
+    /// Address of the first kernel stack. Next stacks are placed after it.
+    pub stack_base: u64,
+
+    /// Address of the first dynamic allocation in the kernel.
+    pub heap_base: u64,
+
+    /// Virtual address which maps physical address 0.
+    pub physical_region_base: u64,
+
+    /// `true` if all bases were randomized.
+    pub randomized: bool,
+}
+
+impl KernelLayout {
+    /// Create a layout which uses default bases of all regions.
+    pub const fn fixed() -> Self {
+        Self {
+            kernel_base:          KERNEL_BASE,
+            stack_base:           KERNEL_STACK_BASE,
+            heap_base:            KERNEL_HEAP_BASE,
+            physical_region_base: KERNEL_PHYSICAL_REGION_BASE,
+            randomized:           false,
+        }
+    }
+
+    /// Create a layout with random, aligned bases of all regions. `random` must return
+    /// uniformly distributed 64 bit values. Kernel image of `kernel_size` bytes must fit
+    /// in the kernel area.
+    pub fn randomized(mut random: impl FnMut() -> u64, kernel_size: u64) -> Self {
+        // Pick a base between `base` and `base + slack` (inclusive).
+        let mut pick = |base: u64, slack: u64, align: u64| {
+            base + (random() % (slack / align + 1)) * align
+        };
+
+        let kernel_size = (kernel_size + REGION_ALIGNMENT - 1) & !(REGION_ALIGNMENT - 1);
+        let kernel_room = KERNEL_AREA_END - KERNEL_BASE;
+
+        assert!(kernel_size <= kernel_room, "Kernel image doesn't fit in the kernel area.");
+
+        Self {
+            kernel_base:          pick(KERNEL_BASE, kernel_room - kernel_size,
+                                       REGION_ALIGNMENT),
+            stack_base:           pick(KERNEL_STACK_BASE, STACK_RANDOMIZATION_SIZE,
+                                       REGION_ALIGNMENT),
+            heap_base:            pick(KERNEL_HEAP_BASE, HEAP_RANDOMIZATION_SIZE,
+                                       REGION_ALIGNMENT),
+            physical_region_base: pick(KERNEL_PHYSICAL_REGION_BASE,
+                                       KERNEL_BASE - KERNEL_PHYSICAL_REGION_BASE -
+                                       KERNEL_PHYSICAL_REGION_SIZE, PHYSICAL_REGION_ALIGNMENT),
+            randomized:           true,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct AcpiTables {
@@ -66,6 +150,43 @@ pub struct SupportedModes {
     pub overflow: bool,
 }
 
+/// Physical memory region occupied by the initial ramdisk. It is never part of the free
+/// or boot memory lists so the kernel can keep using it.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Initrd {
+    pub phys_addr: u64,
+    pub size:      u64,
+}
+
+/// Raw command line loaded by the bootloader. Parsed by the `cmdline` crate.
+#[repr(C)]
+#[derive(Clone)]
+pub struct CommandLineBuffer {
+    pub bytes: [u8; MAX_COMMAND_LINE_SIZE],
+    pub size:  u32,
+}
+
+impl CommandLineBuffer {
+    /// Create a command line buffer from raw bytes. Returns `None` if the command line
+    /// is too long.
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        let mut buffer = Self {
+            bytes: [0; MAX_COMMAND_LINE_SIZE],
+            size:  bytes.len() as u32,
+        };
+
+        buffer.bytes.get_mut(..bytes.len())?.copy_from_slice(bytes);
+
+        Some(buffer)
+    }
+
+    /// Get the command line text. Returns `None` if it is not valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        core::str::from_utf8(&self.bytes[..self.size as usize]).ok()
+    }
+}
+
 /// Data shared between the bootloader and the kernel. Allows for concurrent access.
 #[repr(C)]
 pub struct BootBlock<I: Interrupts> {
@@ -79,7 +200,8 @@ pub struct BootBlock<I: Interrupts> {
     /// Free physical memory ranges available on the system.
     pub boot_memory: Lock<RangeSet, I>,
 
-    /// Serial port connection which allows for `print!` macros.
+    /// Serial port connection which allows for `print!` macros. Non preemptible because
+    /// kernel accesses it from the serial port interrupt handler.
     pub serial_port: Lock<Option<SerialPort>, I>,
 
     /// Page tables created by the bootloader and used by the kernel.
@@ -90,6 +212,15 @@ pub struct BootBlock<I: Interrupts> {
     pub acpi_tables:            Lock<AcpiTables, I>,
     pub framebuffer:            Lock<Option<FramebufferInfo>, I>,
     pub supported_modes:        Lock<Option<SupportedModes>, I>,
+
+    /// Command line loaded from the boot disk.
+    pub command_line: Lock<Option<CommandLineBuffer>, I>,
+
+    /// Initial ramdisk loaded from the boot disk.
+    pub initrd: Lock<Option<Initrd>, I>,
+
+    /// Virtual memory layout of the kernel.
+    pub kernel_layout: Lock<KernelLayout, I>,
 }
 
 impl<I: Interrupts> BootBlock<I> {
@@ -99,7 +230,7 @@ impl<I: Interrupts> BootBlock<I> {
             size:                   core::mem::size_of::<Self>() as u64,
             free_memory:            Lock::new(RangeSet::new()),
             boot_memory:            Lock::new(RangeSet::new()),
-            serial_port:            Lock::new(None),
+            serial_port:            Lock::new_non_preemptible(None),
             page_table:             Lock::new(None),
             physical_map_page_size: Lock::new(None),
             ap_entrypoint:          Lock::new(None),
@@ -109,6 +240,9 @@ impl<I: Interrupts> BootBlock<I> {
             }),
             framebuffer:     Lock::new(None),
             supported_modes: Lock::new(None),
+            command_line:    Lock::new(None),
+            initrd:          Lock::new(None),
+            kernel_layout:   Lock::new(KernelLayout::fixed()),
         }
     }
 }
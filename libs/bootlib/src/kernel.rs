@@ -0,0 +1,283 @@
+// Kernel loading shared by the 32 bit bootloaders. The kernel ELF image is mapped at
+// the base chosen by `kernel_layout`, relocated, and page tables required to enter it
+// are created.
+
+use core::convert::TryInto;
+use core::fmt::Write;
+
+use boot_block::{BootBlock, KERNEL_PHYSICAL_REGION_SIZE, KERNEL_STACK_BASE, KERNEL_STACK_SIZE,
+                 KERNEL_STACK_PADDING};
+use page_table::{PageTable, PageType, VirtAddr, PhysMem, PAGE_PRESENT, PAGE_WRITE, PAGE_SIZE};
+use elfparse::{Elf, Bitness, SegmentType, Machine};
+use lock::{Lock, Interrupts};
+
+macro_rules! println {
+    ($boot_block: expr, $($arg: tt)*) => {{
+        let mut serial = $boot_block.serial_port.lock();
+
+        if let Some(serial) = serial.as_mut() {
+            let _ = serial.write_fmt(format_args!($($arg)*));
+            let _ = serial.write_str("\n");
+        }
+    }};
+}
+
+/// Data required to enter the kernel.
+#[derive(Copy, Clone)]
+pub struct KernelEntryData {
+    pub entrypoint:      u64,
+    pub kernel_cr3:      u32,
+    pub trampoline_cr3:  u32,
+    pub physical_region: u64,
+}
+
+/// Kernel loader state shared by all cores which enter the kernel.
+pub struct KernelLoader<I: Interrupts> {
+    /// Data required to enter the kernel. If it is `None` then kernel wasn't mapped yet.
+    entry_data: Lock<Option<KernelEntryData>, I>,
+
+    /// Address of the next stack used to enter the kernel. Each CPU takes address from here
+    /// and advances the value. There is no 64 bit atomic value in 32 bit mode so `Lock`
+    /// is used.
+    next_stack_address: Lock<u64, I>,
+}
+
+impl<I: Interrupts> KernelLoader<I> {
+    pub const fn new() -> Self {
+        Self {
+            entry_data:         Lock::new(None),
+            next_stack_address: Lock::new(KERNEL_STACK_BASE),
+        }
+    }
+
+    /// Get data required to enter the kernel. Returns `None` if the kernel wasn't loaded yet.
+    pub fn entry_data(&self) -> Option<KernelEntryData> {
+        *self.entry_data.lock()
+    }
+
+    /// Creates a unique kernel stack required for entering the kernel. Returns the initial
+    /// stack pointer.
+    pub fn create_stack(&self, boot_block: &BootBlock<I>, phys_mem: &mut impl PhysMem) -> u64 {
+        // It is possible that the kernel uses free memory memory list or page tables too.
+        // This is fine as everything is locked.
+
+        let mut page_table = boot_block.page_table.lock();
+        let page_table     = page_table.as_mut().unwrap();
+
+        let mut next_stack_address = self.next_stack_address.lock();
+
+        // Get a unique stack address.
+        let stack = VirtAddr(*next_stack_address);
+
+        // Map the stack to the kernel address space.
+        page_table.map(phys_mem, stack, PageType::Page4K, KERNEL_STACK_SIZE, true, false, false)
+            .expect("Failed to map kernel stack.");
+
+        // Update stack address which will be used by the next AP.
+        *next_stack_address += KERNEL_STACK_SIZE + KERNEL_STACK_PADDING;
+
+        stack.0 + KERNEL_STACK_SIZE
+    }
+
+    /// Map `kernel` ELF image at the base chosen by `kernel_layout`, relocate it and create
+    /// page tables used to enter it. Bootloader code must be located in the first
+    /// `trampoline_region_size` bytes of physical memory. Physical memory must be identity
+    /// mapped.
+    pub fn load(
+        &self,
+        boot_block:             &BootBlock<I>,
+        phys_mem:               &mut impl PhysMem,
+        kernel:                 &[u8],
+        trampoline_region_size: u64,
+    ) -> KernelEntryData {
+        // Parse the kernel ELF file and make sure that it is 64 bit.
+        let elf = Elf::parse(kernel).expect("Failed to parse kernel ELF file.");
+        assert!(elf.bitness() == Bitness::Bits64, "Loaded kernel is not 64 bit.");
+        assert!(elf.machine() == Machine::Amd64, "Loaded kernel is not an AMD64 binary.");
+        assert!(elf.base_address() == boot_block::KERNEL_BASE,
+                "Loaded kernel has invalid base address.");
+
+        // Choose virtual addresses of the kernel and its memory regions.
+        let layout = crate::kernel_layout(boot_block.command_line.lock().as_ref(),
+                                          elf.image_size());
+        let bias   = layout.kernel_base - elf.base_address();
+
+        *boot_block.kernel_layout.lock()  = layout;
+        *self.next_stack_address.lock()   = layout.stack_base;
+
+        // Allocate a page table that will be used by the kernel.
+        let mut kernel_page_table = PageTable::new(phys_mem)
+            .expect("Failed to allocate kernel page table.");
+
+        // Allocate a page table that will be used when transitioning to the kernel.
+        let mut trampoline_page_table = PageTable::new(phys_mem)
+            .expect("Failed to allocate trampoline page table.");
+
+        // Map kernel to the virtual memory.
+        elf.segments(|segment| {
+            // Skip non-loadable segments.
+            if segment.seg_type != SegmentType::Load {
+                return;
+            }
+
+            // Page table `map_init` function requires both address and size to be page
+            // aligned, but segments in ELF files are often unaligned.
+
+            // Move the segment to the chosen kernel base and align virtual address down.
+            let segment_addr = segment.virt_addr + bias;
+            let virt_addr    = VirtAddr(segment_addr & !0xfff);
+
+            // Calculate the number of bytes we have added in front of segment to satisfy
+            // alignemnt requirements.
+            let front_padding = segment_addr - virt_addr.0;
+
+            // Align virtual size up (accounting for front padding).
+            let virt_size = (segment.virt_size + front_padding + 0xfff) & !0xfff;
+
+            // Map the segment with correct permissions using standard 4K pages.
+            // If some segments overlap, this routine will return an error.
+            kernel_page_table.map_init(phys_mem, virt_addr, PageType::Page4K, virt_size,
+                                       segment.write, segment.execute, false,
+                                       Some(|offset: u64| {
+                                           // Get a byte for given segment offset. Because we
+                                           // have possibly changed segment start address,
+                                           // we need to account for that. If offset is part
+                                           // of front padding then return 0, otherwise get
+                                           // actual offset by subtracting `front_padding`.
+
+                                           let offset = match offset.checked_sub(front_padding) {
+                                               Some(offset) => offset,
+                                               None         => return 0,
+                                           };
+
+                                           // Get a byte. If the memory is not initialized then
+                                           // initialize it to 0.
+                                           segment.bytes.get(offset as usize).copied()
+                                               .unwrap_or(0)
+                                       }))
+                .expect("Failed to map kernel segment.");
+        });
+
+        // Patch absolute addresses in the kernel so it can run at the chosen base.
+        elf.relocate(layout.kernel_base, |virt_addr, value| {
+            for (index, &byte) in value.to_le_bytes().iter().enumerate() {
+                let phys_addr = kernel_page_table.virt_to_phys(phys_mem,
+                                                               VirtAddr(virt_addr + index as u64))
+                    .expect("Kernel relocation points outside of the kernel image.");
+
+                unsafe {
+                    *phys_mem.translate(phys_addr, 1).unwrap() = byte;
+                }
+            }
+        }).expect("Kernel cannot be relocated.");
+
+        // Bootloader uses identity physical memory map, but kernel will use linear physical
+        // memory map that starts at `layout.physical_region_base`.
+        // To be able to transition to the kernel we need to a allocate trampoline page table
+        // that will map physical address 0 to virtual address 0 (like in bootloader) and
+        // physical address 0 to virtual address `layout.physical_region_base` (like in kernel).
+
+        // Transition code will work like this:
+        // 1. Bootloader executes `enter_kernel`. Enable long mode and setup paging with
+        //    trampoline page table.
+        // 2. Jump to the next part of `enter_kernel`, but add physical region base
+        //    to RIP in order to use kernel-valid address.
+        // 3. Switch to the actual kernel page tables, switch stack and jump to the kernel.
+
+        // We will only execute bootloader code using trampoline page tables so we just need
+        // to map the region which contains it.
+        assert!(KERNEL_PHYSICAL_REGION_SIZE >= trampoline_region_size);
+
+        // Setup trampoline page table.
+        for phys_addr in (0..trampoline_region_size).step_by(4096) {
+            // Map current `phys_addr` at virtual address `phys_addr` and virtual address
+            // `phys_addr` + physical region base. All this memory will be both
+            // writable and executable.
+            for &virt_addr in &[VirtAddr(phys_addr),
+                                VirtAddr(phys_addr + layout.physical_region_base)] {
+                unsafe {
+                    trampoline_page_table.map_raw(phys_mem, virt_addr, PageType::Page4K,
+                                                  phys_addr | PAGE_WRITE | PAGE_PRESENT,
+                                                  true, false)
+                        .expect("Failed to map physical region in the trampoline page table.");
+                }
+            }
+        }
+
+        // Create linear physical memory map used by kernel at address.
+        {
+            let features = cpu::get_features();
+
+            // We will map a lot of memory so use the largest possible page type.
+            let page_type = if features.page1g {
+                PageType::Page1G
+            } else if features.page2m {
+                println!(boot_block, "WARNING: CPU doesn't support 1G pages, mapping physical \
+                         region may take a while.");
+
+                PageType::Page2M
+            } else {
+                // Mapping using 4K pages would take too long and would waste too much memory.
+                panic!("CPU needs to support at least 2M pages.")
+            };
+
+            let page_size = page_type as u64;
+            let page_mask = page_size - 1;
+
+            *boot_block.physical_map_page_size.lock() = Some(page_size.try_into().unwrap());
+
+            // Make sure physical region address and size are properly aligned for used
+            // page type.
+            assert!(layout.physical_region_base & page_mask == 0,
+                    "Physical region base is not aligned.");
+            assert!(KERNEL_PHYSICAL_REGION_SIZE & page_mask == 0,
+                    "KERNEL_PHYSICAL_REGION_SIZE is not aligned.");
+
+            // Setup kernel physical memory map.
+            for phys_addr in (0..KERNEL_PHYSICAL_REGION_SIZE).step_by(page_size as usize) {
+                // Map current `phys_addr` at virtual address
+                // `phys_addr` + physical region base.
+                let virt_addr = VirtAddr(phys_addr + layout.physical_region_base);
+
+                // This physical memory page will be both writable and executable.
+                // Unfortunately we can't set NX bit because we will execute some code using
+                // this mapping when transitioning from bootloader to the kernel. Kernel should
+                // later make these mappings NX.
+                let mut raw = phys_addr | PAGE_PRESENT | PAGE_WRITE;
+
+                // Set PAGE_SIZE bit if we aren't using standard 4K pages.
+                if page_type != PageType::Page4K {
+                    raw |= PAGE_SIZE;
+                }
+
+                unsafe {
+                    kernel_page_table.map_raw(phys_mem, virt_addr, page_type, raw, true, false)
+                        .expect("Failed to map physical region in the kernel page table.");
+                }
+            }
+        }
+
+        // Get physical addresses of page tables and make sure they fit in 32 bit integer.
+        let kernel_cr3:     u32 = kernel_page_table.table().0.try_into().unwrap();
+        let trampoline_cr3: u32 = trampoline_page_table.table().0.try_into().unwrap();
+
+        // Cache page tables which will be used by all APs.
+        *boot_block.page_table.lock() = Some(kernel_page_table);
+
+        println!(boot_block, "Kernel base is 0x{:x}{}.", layout.kernel_base,
+                 if layout.randomized { " (randomized)" } else { "" });
+        println!(boot_block, "Kernel entrypoint is 0x{:x}.", elf.entrypoint() + bias);
+
+        let entry_data = KernelEntryData {
+            entrypoint:      elf.entrypoint() + bias,
+            kernel_cr3,
+            trampoline_cr3,
+            physical_region: layout.physical_region_base,
+        };
+
+        // Cache entry data so APs can use them later to enter the kernel.
+        *self.entry_data.lock() = Some(entry_data);
+
+        entry_data
+    }
+}
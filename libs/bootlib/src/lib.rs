@@ -1,5 +1,14 @@
 #![no_std]
 
+pub mod kernel;
+pub mod rsdp;
+
+use core::convert::TryInto;
+
+use boot_block::{CommandLineBuffer, KernelLayout};
+use cmdline::CommandLine;
+use integrity::Manifest;
+
 pub fn verify_cpu() {
     let features = cpu::get_features();
 
@@ -28,3 +37,97 @@ pub fn verify_cpu() {
     verify_feature!(bits64);
     verify_feature!(page2m);
 }
+
+/// Get Ed25519 public key which boot payloads must be signed with. It is compiled into the
+/// bootloader when the host builder signs payloads.
+fn public_key() -> Option<[u8; 32]> {
+    option_env!(integrity::public_key_env!()).map(|key| {
+        integrity::parse_key(key).expect("Public key compiled into the bootloader is invalid.")
+    })
+}
+
+/// Make sure that the boot manifest is signed with the public key compiled into the
+/// bootloader. If there is no public key, only SHA-256 digests will be checked. Returns
+/// `true` if the signature was verified.
+pub fn verify_manifest(manifest: &Manifest) -> bool {
+    assert!(manifest.magic == integrity::MANIFEST_MAGIC, "Boot manifest is invalid.");
+
+    if let Some(public_key) = public_key() {
+        assert!(manifest.is_signed(), "Boot manifest is not signed, but the bootloader \
+                requires a signature.");
+        assert!(manifest.verify(&public_key), "Boot manifest signature is invalid.");
+
+        return true;
+    }
+
+    false
+}
+
+/// Make sure that SHA-256 digest of `data` matches the one from the boot manifest.
+pub fn verify_payload(name: &str, data: &[u8], digest: &[u8; 32]) {
+    assert!(integrity::sha256(data) == *digest,
+            "SHA-256 digest of the {} doesn't match the boot manifest.", name);
+}
+
+/// Check if the payload was compressed by the host builder. If it was, verify compressed
+/// data using its header and return it together with the decompressed size.
+pub fn compressed_payload<'a>(name: &str, data: &'a [u8]) -> Option<(&'a [u8], usize)> {
+    if !lz4::is_compressed(data) {
+        return None;
+    }
+
+    let (header, compressed) = lz4::Header::parse(data)
+        .unwrap_or_else(|| panic!("Compressed {} has invalid header.", name));
+
+    assert!(integrity::sha256(compressed) == header.compressed_digest,
+            "SHA-256 digest of the compressed {} doesn't match its header.", name);
+
+    let size = header.uncompressed_size.try_into()
+        .unwrap_or_else(|_| panic!("Decompressed {} doesn't fit in memory.", name));
+
+    Some((compressed, size))
+}
+
+/// Decompress payload returned by `compressed_payload`. Size of `output` must be equal to
+/// the decompressed size.
+pub fn decompress_payload(name: &str, compressed: &[u8], output: &mut [u8]) {
+    lz4::decompress(compressed, output)
+        .unwrap_or_else(|| panic!("Failed to decompress the {}.", name));
+}
+
+/// Get 64 random bits. RDRAND is used if it's supported. TSC is always mixed in so there is
+/// some entropy on older CPUs too.
+fn random_u64() -> u64 {
+    let mut value = 0;
+
+    if cpu::get_features().rdrand {
+        for _ in 0..2 {
+            value = (value << 32) | cpu::rdrand().unwrap_or(0) as u64;
+        }
+    }
+
+    // SplitMix64 finalizer spreads TSC bits over the whole value.
+    let mut value = value ^ cpu::rdtsc();
+
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+
+    value ^ (value >> 31)
+}
+
+/// Choose virtual memory layout of the kernel which has `kernel_size` bytes. Layout is
+/// randomized unless KASLR is disabled using `kaslr=off` on the command line.
+pub fn kernel_layout(command_line: Option<&CommandLineBuffer>, kernel_size: u64)
+    -> KernelLayout
+{
+    let kaslr = command_line
+        .and_then(|command_line| command_line.as_str())
+        .and_then(|command_line| CommandLine::new(command_line).get_bool("kaslr"))
+        .unwrap_or(true);
+
+    if !kaslr {
+        return KernelLayout::fixed();
+    }
+
+    KernelLayout::randomized(random_u64, kernel_size)
+}
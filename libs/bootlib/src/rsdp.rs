@@ -0,0 +1,74 @@
+// Location of ACPI tables passed to the kernel. Shared by loaders which don't get them
+// from the firmware directly.
+
+use core::ptr::read_unaligned;
+
+use boot_block::AcpiTables;
+use acpi::{Rsdp, RsdpExtended};
+
+/// Parse RSDP from `data`. Returns `None` if it is invalid.
+pub fn parse_rsdp(data: &[u8]) -> Option<AcpiTables> {
+    let checksum = |bytes: &[u8]| bytes.iter().fold(0u8, |acc, v| acc.wrapping_add(*v));
+
+    let rsdp_bytes = data.get(..core::mem::size_of::<Rsdp>())?;
+    let rsdp: Rsdp = unsafe { read_unaligned(rsdp_bytes.as_ptr() as *const Rsdp) };
+
+    // Make sure that the RSDP signature and checksum are valid.
+    if &rsdp.signature != b"RSD PTR " || checksum(rsdp_bytes) != 0 {
+        return None;
+    }
+
+    let mut tables = AcpiTables {
+        rsdt: Some(rsdp.rsdt_addr as u64),
+        xsdt: None,
+    };
+
+    if rsdp.revision > 0 {
+        let extended_bytes = data.get(..core::mem::size_of::<RsdpExtended>())?;
+        let extended       = unsafe {
+            read_unaligned(extended_bytes.as_ptr() as *const RsdpExtended)
+        };
+
+        // Make sure that the extended RSDP checksum is valid.
+        if checksum(extended_bytes) != 0 {
+            return None;
+        }
+
+        tables.xsdt = Some(extended.xsdt_addr as u64);
+    }
+
+    Some(tables)
+}
+
+/// Search for the RSDP in the memory areas specified by the ACPI specification. Works only
+/// on systems with legacy BIOS.
+pub unsafe fn find_rsdp() -> Option<AcpiTables> {
+    // Get the address of the EBDA from the BDA.
+    let ebda = (read_unaligned(0x40e as *const u16) as usize) << 4;
+
+    // Regions that we need to scan for the RSDP.
+    let regions = [
+        // First 1K of the EBDA.
+        (ebda, ebda + 1024),
+
+        // Constant range specified by ACPI specification.
+        (0xe0000, 0xfffff),
+    ];
+
+    for &(start, end) in &regions {
+        // 16 byte align the start address upwards and the end address downwards.
+        let start = (start + 0xf) & !0xf;
+        let end   = end & !0xf;
+
+        for phys_addr in (start..end).step_by(16) {
+            let data = core::slice::from_raw_parts(phys_addr as *const u8,
+                                                   core::mem::size_of::<RsdpExtended>());
+
+            if let Some(tables) = parse_rsdp(data) {
+                return Some(tables);
+            }
+        }
+    }
+
+    None
+}
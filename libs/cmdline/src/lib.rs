@@ -0,0 +1,138 @@
+#![no_std]
+
+// Parser of the kernel command line. Command line is a list of `key=value` entries
+// separated by whitespace. Entry without a value (`key`) is treated as an enabled flag.
+// Everything after `#` until the end of line is a comment so command line can be stored
+// as a multiline config file. If the same key is specified multiple times, the last
+// value is used.
+
+/// Parsed view of the kernel command line.
+#[derive(Copy, Clone)]
+pub struct CommandLine<'a> {
+    text: &'a str,
+}
+
+impl<'a> CommandLine<'a> {
+    /// Create a command line from its textual representation.
+    pub const fn new(text: &'a str) -> Self {
+        Self {
+            text,
+        }
+    }
+
+    /// Create an empty command line. All lookups will return `None`.
+    pub const fn empty() -> Self {
+        Self::new("")
+    }
+
+    /// Iterate over all `(key, value)` entries in order of appearance.
+    pub fn entries(&self) -> impl Iterator<Item = (&'a str, Option<&'a str>)> {
+        self.text
+            .lines()
+            .map(|line| line.split('#').next().unwrap())
+            .flat_map(|line| line.split_whitespace())
+            .map(|entry| {
+                match entry.split_once('=') {
+                    Some((key, value)) => (key, Some(value)),
+                    None               => (entry, None),
+                }
+            })
+    }
+
+    /// Get a raw value of the last entry with given key. Returns `Some(None)` if the entry
+    /// doesn't have a value.
+    fn lookup(&self, key: &str) -> Option<Option<&'a str>> {
+        self.entries()
+            .filter(|&(entry_key, _)| entry_key == key)
+            .map(|(_, value)| value)
+            .last()
+    }
+
+    /// Returns `true` if there is any entry with given key.
+    pub fn contains(&self, key: &str) -> bool {
+        self.lookup(key).is_some()
+    }
+
+    /// Get a string value of an entry. Entry without a value yields an empty string.
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.lookup(key).map(|value| value.unwrap_or(""))
+    }
+
+    /// Get a boolean value of an entry. Accepts `1`, `true`, `yes`, `on` and `0`, `false`,
+    /// `no`, `off`. Entry without a value is treated as `true`. Returns `None` if the
+    /// entry doesn't exist or has an invalid value.
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.lookup(key)? {
+            None => Some(true),
+            Some("1") | Some("true")  | Some("yes") | Some("on")  => Some(true),
+            Some("0") | Some("false") | Some("no")  | Some("off") => Some(false),
+            Some(_)   => None,
+        }
+    }
+
+    /// Get an unsigned integer value of an entry. Both decimal and hexadecimal (with `0x`
+    /// prefix) values are supported.
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        let value = self.lookup(key)??;
+
+        match value.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None      => value.parse().ok(),
+        }
+    }
+
+    /// Get a floating point value of an entry.
+    pub fn get_f64(&self, key: &str) -> Option<f64> {
+        self.lookup(key)??.parse().ok()
+    }
+
+    /// Get a resolution in `WIDTHxHEIGHT` format.
+    pub fn get_resolution(&self, key: &str) -> Option<(u32, u32)> {
+        let (width, height) = self.lookup(key)??.split_once('x')?;
+
+        Some((width.parse().ok()?, height.parse().ok()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries() {
+        let cmdline = CommandLine::new("a=1 b  c=\n# comment d=4\n  e=5 # f=6");
+
+        let mut entries = cmdline.entries();
+
+        assert_eq!(entries.next(), Some(("a", Some("1"))));
+        assert_eq!(entries.next(), Some(("b", None)));
+        assert_eq!(entries.next(), Some(("c", Some(""))));
+        assert_eq!(entries.next(), Some(("e", Some("5"))));
+        assert_eq!(entries.next(), None);
+
+        assert!(CommandLine::empty().entries().next().is_none());
+    }
+
+    #[test]
+    fn typed_values() {
+        let cmdline = CommandLine::new("flag x=off n=42 h=0x1f f=0.25 r=1920x1080 bad=1x n=7");
+
+        assert_eq!(cmdline.get_bool("flag"), Some(true));
+        assert_eq!(cmdline.get_bool("x"),    Some(false));
+        assert_eq!(cmdline.get_bool("n"),    None);
+        assert_eq!(cmdline.get_bool("none"), None);
+
+        // Last entry wins.
+        assert_eq!(cmdline.get_u64("n"),    Some(7));
+        assert_eq!(cmdline.get_u64("h"),    Some(0x1f));
+        assert_eq!(cmdline.get_u64("flag"), None);
+
+        assert_eq!(cmdline.get_f64("f"), Some(0.25));
+        assert_eq!(cmdline.get("flag"),  Some(""));
+
+        assert_eq!(cmdline.get_resolution("r"),   Some((1920, 1080)));
+        assert_eq!(cmdline.get_resolution("bad"), None);
+
+        assert!(cmdline.contains("flag") && !cmdline.contains("none"));
+    }
+}
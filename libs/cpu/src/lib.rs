@@ -104,6 +104,7 @@ pub struct CpuFeatures {
     pub page2m:         bool,
     pub page1g:         bool,
     pub invariant_tsc:  bool,
+    pub rdrand:         bool,
 }
 
 pub fn get_features() -> CpuFeatures {
@@ -138,6 +139,7 @@ pub fn get_features() -> CpuFeatures {
         features.aesni   = ((cpuid.ecx >> 25) & 1) == 1;
         features.xsave   = ((cpuid.ecx >> 26) & 1) == 1;
         features.avx     = ((cpuid.ecx >> 28) & 1) == 1;
+        features.rdrand  = ((cpuid.ecx >> 30) & 1) == 1;
     }
 
     if max_cpuid >= 7 {
@@ -171,6 +173,37 @@ pub fn get_features() -> CpuFeatures {
     features
 }
 
+pub fn rdtsc() -> u64 {
+    let low:  u32;
+    let high: u32;
+
+    unsafe {
+        asm!("rdtsc", out("eax") low, out("edx") high);
+    }
+
+    ((high as u64) << 32) | low as u64
+}
+
+/// Get 32 random bits from the hardware random number generator. Returns `None` if it
+/// failed to provide them. CPU must support RDRAND.
+pub fn rdrand() -> Option<u32> {
+    // Intel recommends retrying 10 times in case of transient failures.
+    for _ in 0..10 {
+        let value:   u32;
+        let success: u8;
+
+        unsafe {
+            asm!("rdrand {:e}", "setc {}", out(reg) value, out(reg_byte) success);
+        }
+
+        if success != 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
 pub unsafe fn outb(port: u16, value: u8) {
     asm!("out dx, al", in("dx") port, in("al") value);
 }
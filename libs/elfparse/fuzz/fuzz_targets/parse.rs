@@ -0,0 +1,37 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use elfparse::Elf;
+
+// Bootloaders parse the kernel read from disk, make sure that no input can cause a panic
+// or an arithmetic overflow (fuzzing builds have overflow checks enabled).
+fuzz_target!(|data: &[u8]| {
+    let elf = match Elf::parse(data) {
+        Some(elf) => elf,
+        None      => return,
+    };
+
+    assert!(elf.base_address().checked_add(elf.image_size()).is_some());
+
+    let _ = elf.segments(|_| ());
+    let _ = elf.sections(|section| {
+        let _ = elf.section_symbols(section, |_| ());
+        let _ = elf.section_relocations(section, |_| ());
+        let _ = elf.section_notes(section, |_| ());
+    });
+
+    let _ = elf.section_by_name(".text");
+    let _ = elf.symbol_by_name("_start");
+    let _ = elf.relocations(|_| ());
+    let _ = elf.dynamic_entries(|_| ());
+    let _ = elf.notes(|_| ());
+
+    for &base in &[0, elf.base_address(), 0xffff_ffff_8000_0000, !0xfff] {
+        let _ = elf.relocate(base, |virt_addr, _| {
+            assert!(virt_addr.wrapping_sub(base) + 8 <= elf.image_size(),
+                    "Relocation is outside of the image.");
+        });
+    }
+
+    let _ = format!("{:?}", elf);
+});
@@ -30,6 +30,16 @@ fn byte_slice(bytes: &[u8], offset: u64, size: u64) -> Option<&[u8]> {
     bytes.get(start..end)
 }
 
+/// Get null terminated string at `offset` in the string table `strings`.
+fn string_at(strings: &[u8], offset: u64) -> Option<&str> {
+    let start: usize = offset.try_into().ok()?;
+
+    let string = strings.get(start..)?;
+    let null   = string.iter().position(|x| *x == 0)?;
+
+    core::str::from_utf8(&string[..null]).ok()
+}
+
 trait Readable: Sized {
     fn read(bytes: &[u8], endianness: Endianness) -> Option<Self>;
 }
@@ -126,6 +136,83 @@ pub enum SectionType {
     Other(u32),
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SymbolType {
+    NoType,
+    Object,
+    Function,
+    Section,
+    File,
+    Common,
+    Tls,
+    Other(u8),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SymbolBinding {
+    Local,
+    Global,
+    Weak,
+    Other(u8),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DynamicTag {
+    Null,
+    Needed,
+    PltRelSize,
+    Hash,
+    StrTab,
+    SymTab,
+    Rela,
+    RelaSize,
+    RelaEntry,
+    StrSize,
+    SymEntry,
+    Init,
+    Fini,
+    SoName,
+    RPath,
+    Symbolic,
+    Rel,
+    RelSize,
+    RelEntry,
+    PltRel,
+    Debug,
+    TextRel,
+    JmpRel,
+    BindNow,
+    InitArray,
+    FiniArray,
+    InitArraySize,
+    FiniArraySize,
+    RunPath,
+    Flags,
+    Other(u64),
+}
+
+/// Relocation from the dynamic relocation table or a `Rela` section.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Relocation {
+    /// Address which needs to be patched. It is a virtual address (relative to the linked
+    /// base) for executables and an offset in the target section for object files.
+    pub offset: u64,
+
+    pub rel_type: u32,
+    pub symbol:   u32,
+    pub addend:   i64,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DynamicEntry {
+    pub tag:   DynamicTag,
+    pub value: u64,
+}
+
+/// `R_X86_64_RELATIVE` is the only supported relocation type. It doesn't reference any symbol,
+/// the value is the addend adjusted by the load bias.
+const R_X86_64_RELATIVE: u32 = 8;
+
 #[derive(Clone)]
 pub struct Elf<'a> {
     bytes:   &'a [u8],
@@ -140,6 +227,7 @@ pub struct Elf<'a> {
     section_entry_size: u64,
 
     base_address: u64,
+    image_size:   u64,
     entrypoint:   u64,
 
     bitness:    Bitness,
@@ -192,25 +280,40 @@ pub struct Section<'a> {
     pub entry_size: Option<u64>,
 }
 
-impl<'a> Elf<'a> {
-    fn get_string(&self, offset: u64) -> Option<&str> {
-        let start: usize = offset.try_into().ok()?;
+#[derive(Clone)]
+pub struct Symbol<'a> {
+    pub name: Option<&'a str>,
 
-        self.strings.and_then(|strings| {
-            let string = strings.get(start..)?;
-            let null   = string.iter().position(|x| *x == 0)?;
-            let string = string.get(..null)?;
+    pub value: u64,
+    pub size:  u64,
 
-            core::str::from_utf8(string).ok()
-        })
+    pub sym_type: SymbolType,
+    pub binding:  SymbolBinding,
+
+    /// Index of the section which contains the symbol. 0 means that the symbol is undefined.
+    pub section_index: u16,
+}
+
+#[derive(Clone)]
+pub struct Note<'a> {
+    /// Name of the note owner (without the null terminator).
+    pub name:      &'a [u8],
+    pub note_type: u32,
+    pub desc:      &'a [u8],
+}
+
+impl<'a> Elf<'a> {
+    fn get_string(&self, offset: u64) -> Option<&str> {
+        self.strings.and_then(|strings| string_at(strings, offset))
     }
 
     fn segment_reader(&self, index: u64) -> Option<Reader> {
         if index >= self.segment_count {
             return None;
         }
-        
-        let entry = index * self.segment_entry_size + self.segment_table;
+
+        let entry = index.checked_mul(self.segment_entry_size)?
+            .checked_add(self.segment_table)?;
 
         Reader::partial(self.bytes, entry, self.segment_entry_size, self.endianness)
     }
@@ -219,8 +322,9 @@ impl<'a> Elf<'a> {
         if index >= self.section_count {
             return None;
         }
-        
-        let entry = index * self.section_entry_size + self.section_table;
+
+        let entry = index.checked_mul(self.section_entry_size)?
+            .checked_add(self.section_table)?;
 
         Reader::partial(self.bytes, entry, self.section_entry_size, self.endianness)
     }
@@ -307,6 +411,7 @@ impl<'a> Elf<'a> {
             section_count,
 
             base_address: 0,
+            image_size:   0,
             entrypoint,
 
             bitness,
@@ -322,21 +427,36 @@ impl<'a> Elf<'a> {
         }
 
         let mut base_address = None;
+        let mut end_address  = None;
+        let mut valid        = true;
 
         elf.segments(|segment| {
             if segment.seg_type != SegmentType::Load {
                 return;
             }
 
-            let new_base = match base_address {
-                Some(base) => core::cmp::min(base, segment.virt_addr),
-                None       => segment.virt_addr,
+            // Loadable segments must be sorted by virtual address and cannot overlap. They also
+            // cannot contain more file data than memory.
+            let overlaps = match end_address {
+                Some(end) => segment.virt_addr < end,
+                None      => false,
             };
 
-            base_address = Some(new_base);
+            match segment.virt_addr.checked_add(segment.virt_size) {
+                Some(end) if !overlaps && segment.raw_size <= segment.virt_size => {
+                    base_address = base_address.or(Some(segment.virt_addr));
+                    end_address  = Some(end);
+                }
+                _ => valid = false,
+            }
         })?;
 
+        if !valid {
+            return None;
+        }
+
         elf.base_address = base_address?;
+        elf.image_size   = end_address? - elf.base_address;
 
         Some(elf)
     }
@@ -538,10 +658,401 @@ impl<'a> Elf<'a> {
         Some(())
     }
 
+    /// Get file offset of `size` bytes at virtual address `virt_addr`. The whole range must
+    /// be backed by the file contents of a single loadable segment.
+    fn virt_to_offset(&self, virt_addr: u64, size: u64) -> Option<u64> {
+        let end = virt_addr.checked_add(size)?;
+
+        for index in 0..self.segment_count {
+            let segment = self.segment_by_index(index)?;
+
+            if segment.seg_type == SegmentType::Load && virt_addr >= segment.virt_addr &&
+                end <= segment.virt_addr.checked_add(segment.raw_size)? {
+                return segment.raw_offset.checked_add(virt_addr - segment.virt_addr);
+            }
+        }
+
+        None
+    }
+
+    /// Get the dynamic relocation table as (file offset, size, entry size). Returns `Some(None)`
+    /// if the image doesn't have one and `None` if it is malformed or uses unsupported
+    /// `DT_REL` table.
+    fn relocation_table(&self) -> Option<Option<(u64, u64, u64)>> {
+        let mut table      = None;
+        let mut table_size = None;
+        let mut entry      = None;
+        let mut rel        = false;
+
+        self.dynamic_entries(|dynamic| {
+            match dynamic.tag {
+                DynamicTag::Rela      => table      = Some(dynamic.value),
+                DynamicTag::RelaSize  => table_size = Some(dynamic.value),
+                DynamicTag::RelaEntry => entry      = Some(dynamic.value),
+                DynamicTag::Rel       => rel        = true,
+                _                     => (),
+            }
+        })?;
+
+        if rel {
+            return None;
+        }
+
+        let table = match table {
+            Some(table) => table,
+            None        => return Some(None),
+        };
+
+        Some(Some((self.virt_to_offset(table, table_size?)?, table_size?, entry?)))
+    }
+
+    /// Go through all `Rela` entries in `bytes`.
+    fn read_relocations(&self, bytes: &[u8], entry_size: u64,
+                        mut callback: impl FnMut(&Relocation)) -> Option<()> {
+        let min_entry = match self.bitness {
+            Bitness::Bits32 => 12,
+            Bitness::Bits64 => 24,
+        };
+
+        if entry_size < min_entry {
+            return None;
+        }
+
+        let reader = Reader::new(bytes, self.endianness);
+
+        for entry in (0..bytes.len() as u64 / entry_size).map(|x| x * entry_size) {
+            let relocation = match self.bitness {
+                Bitness::Bits32 => {
+                    let info = reader.read::<u32>(entry + 4)?;
+
+                    Relocation {
+                        offset:   reader.read::<u32>(entry)? as u64,
+                        rel_type: info & 0xff,
+                        symbol:   info >> 8,
+                        addend:   reader.read::<i32>(entry + 8)? as i64,
+                    }
+                }
+                Bitness::Bits64 => {
+                    let info = reader.read::<u64>(entry + 8)?;
+
+                    Relocation {
+                        offset:   reader.read::<u64>(entry)?,
+                        rel_type: info as u32,
+                        symbol:   (info >> 32) as u32,
+                        addend:   reader.read::<i64>(entry + 16)?,
+                    }
+                }
+            };
+
+            callback(&relocation);
+        }
+
+        Some(())
+    }
+
+    /// Go through all relocations from the dynamic relocation table (`DT_RELA`).
+    pub fn relocations(&self, callback: impl FnMut(&Relocation)) -> Option<()> {
+        let (offset, size, entry_size) = match self.relocation_table()? {
+            Some(table) => table,
+            None        => return Some(()),
+        };
+
+        self.read_relocations(byte_slice(self.bytes, offset, size)?, entry_size, callback)
+    }
+
+    /// Go through all relocations from the `Rela` section `section`. Section which is patched
+    /// by these relocations is described by `section.info`.
+    pub fn section_relocations(&self, section: &Section,
+                               callback: impl FnMut(&Relocation)) -> Option<()> {
+        if section.sec_type != SectionType::Rela {
+            return None;
+        }
+
+        self.read_relocations(section.bytes?, section.entry_size?, callback)
+    }
+
+    /// Go through all entries of the dynamic segment up to the terminating `DynamicTag::Null`.
+    /// Does nothing if the image doesn't have a dynamic segment.
+    pub fn dynamic_entries(&self, mut callback: impl FnMut(&DynamicEntry)) -> Option<()> {
+        let mut dynamic = None;
+
+        for index in 0..self.segment_count {
+            let segment = self.segment_by_index(index)?;
+
+            if segment.seg_type == SegmentType::Dynamic {
+                dynamic = Some(segment);
+            }
+        }
+
+        let dynamic = match dynamic {
+            Some(dynamic) => dynamic,
+            None          => return Some(()),
+        };
+
+        let reader     = Reader::new(dynamic.bytes, self.endianness);
+        let entry_size = match self.bitness {
+            Bitness::Bits32 => 8,
+            Bitness::Bits64 => 16,
+        };
+
+        for offset in (0..dynamic.bytes.len() as u64 / entry_size).map(|x| x * entry_size) {
+            let (tag, value) = match self.bitness {
+                Bitness::Bits32 => (reader.read::<u32>(offset)? as u64,
+                                    reader.read::<u32>(offset + 4)? as u64),
+                Bitness::Bits64 => (reader.read::<u64>(offset)?, reader.read::<u64>(offset + 8)?),
+            };
+
+            let tag = match tag {
+                0  => DynamicTag::Null,
+                1  => DynamicTag::Needed,
+                2  => DynamicTag::PltRelSize,
+                4  => DynamicTag::Hash,
+                5  => DynamicTag::StrTab,
+                6  => DynamicTag::SymTab,
+                7  => DynamicTag::Rela,
+                8  => DynamicTag::RelaSize,
+                9  => DynamicTag::RelaEntry,
+                10 => DynamicTag::StrSize,
+                11 => DynamicTag::SymEntry,
+                12 => DynamicTag::Init,
+                13 => DynamicTag::Fini,
+                14 => DynamicTag::SoName,
+                15 => DynamicTag::RPath,
+                16 => DynamicTag::Symbolic,
+                17 => DynamicTag::Rel,
+                18 => DynamicTag::RelSize,
+                19 => DynamicTag::RelEntry,
+                20 => DynamicTag::PltRel,
+                21 => DynamicTag::Debug,
+                22 => DynamicTag::TextRel,
+                23 => DynamicTag::JmpRel,
+                24 => DynamicTag::BindNow,
+                25 => DynamicTag::InitArray,
+                26 => DynamicTag::FiniArray,
+                27 => DynamicTag::InitArraySize,
+                28 => DynamicTag::FiniArraySize,
+                29 => DynamicTag::RunPath,
+                30 => DynamicTag::Flags,
+                x  => DynamicTag::Other(x),
+            };
+
+            if tag == DynamicTag::Null {
+                break;
+            }
+
+            callback(&DynamicEntry { tag, value });
+        }
+
+        Some(())
+    }
+
+    /// Go through all symbols from the `Symtab` or `Dynsym` section `section`. Names are
+    /// resolved using the string table linked to the section.
+    pub fn section_symbols(&self, section: &Section,
+                           mut callback: impl FnMut(&Symbol<'a>)) -> Option<()> {
+        if section.sec_type != SectionType::Symtab && section.sec_type != SectionType::Dynsym {
+            return None;
+        }
+
+        let string_table = self.section_by_index(section.link?)?;
+        let strings      = byte_slice(self.bytes, string_table.raw_offset,
+                                      string_table.raw_size)?;
+
+        let bytes      = section.bytes?;
+        let entry_size = section.entry_size?;
+
+        let min_entry = match self.bitness {
+            Bitness::Bits32 => 16,
+            Bitness::Bits64 => 24,
+        };
+
+        if entry_size < min_entry {
+            return None;
+        }
+
+        let reader = Reader::new(bytes, self.endianness);
+
+        for entry in (0..bytes.len() as u64 / entry_size).map(|x| x * entry_size) {
+            let (name, value, size, info, section_index) = match self.bitness {
+                Bitness::Bits32 => {
+                    (reader.read::<u32>(entry)?, reader.read::<u32>(entry + 4)? as u64,
+                     reader.read::<u32>(entry + 8)? as u64, reader.read::<u8>(entry + 12)?,
+                     reader.read::<u16>(entry + 14)?)
+                }
+                Bitness::Bits64 => {
+                    (reader.read::<u32>(entry)?, reader.read::<u64>(entry + 8)?,
+                     reader.read::<u64>(entry + 16)?, reader.read::<u8>(entry + 4)?,
+                     reader.read::<u16>(entry + 6)?)
+                }
+            };
+
+            let sym_type = match info & 0xf {
+                0 => SymbolType::NoType,
+                1 => SymbolType::Object,
+                2 => SymbolType::Function,
+                3 => SymbolType::Section,
+                4 => SymbolType::File,
+                5 => SymbolType::Common,
+                6 => SymbolType::Tls,
+                x => SymbolType::Other(x),
+            };
+
+            let binding = match info >> 4 {
+                0 => SymbolBinding::Local,
+                1 => SymbolBinding::Global,
+                2 => SymbolBinding::Weak,
+                x => SymbolBinding::Other(x),
+            };
+
+            let name = match name {
+                0 => None,
+                x => Some(string_at(strings, x as u64)?),
+            };
+
+            callback(&Symbol {
+                name,
+
+                value,
+                size,
+
+                sym_type,
+                binding,
+
+                section_index,
+            });
+        }
+
+        Some(())
+    }
+
+    /// Go through symbols from all `Symtab` and `Dynsym` sections.
+    pub fn symbols(&self, mut callback: impl FnMut(&Symbol<'a>)) -> Option<()> {
+        for index in 0..self.section_count {
+            let section = self.section_by_index(index)?;
+
+            if section.sec_type == SectionType::Symtab || section.sec_type == SectionType::Dynsym {
+                self.section_symbols(&section, &mut callback)?;
+            }
+        }
+
+        Some(())
+    }
+
+    /// Get the first symbol named `name`.
+    pub fn symbol_by_name(&self, name: &str) -> Option<Symbol<'a>> {
+        let mut found = None;
+
+        self.symbols(|symbol| {
+            if found.is_none() && symbol.name == Some(name) {
+                found = Some(symbol.clone());
+            }
+        })?;
+
+        found
+    }
+
+    /// Go through all notes in `bytes`. Name and descriptor are padded to 4 bytes.
+    fn read_notes(&self, bytes: &'a [u8], mut callback: impl FnMut(&Note<'a>)) -> Option<()> {
+        let reader     = Reader::new(bytes, self.endianness);
+        let mut offset = 0u64;
+
+        let align = |value: u64| value.checked_add(3).map(|value| value & !3);
+
+        while offset < bytes.len() as u64 {
+            let name_size = reader.read::<u32>(offset)?     as u64;
+            let desc_size = reader.read::<u32>(offset + 4)? as u64;
+            let note_type = reader.read::<u32>(offset + 8)?;
+
+            let name_offset = offset + 12;
+            let desc_offset = name_offset.checked_add(align(name_size)?)?;
+
+            let mut name = byte_slice(bytes, name_offset, name_size)?;
+            let desc     = byte_slice(bytes, desc_offset, desc_size)?;
+
+            // Remove the null terminator.
+            if let Some((0, without_null)) = name.split_last() {
+                name = without_null;
+            }
+
+            callback(&Note {
+                name,
+                note_type,
+                desc,
+            });
+
+            offset = desc_offset.checked_add(align(desc_size)?)?;
+        }
+
+        Some(())
+    }
+
+    /// Go through notes from all `Note` segments.
+    pub fn notes(&self, mut callback: impl FnMut(&Note<'a>)) -> Option<()> {
+        for index in 0..self.segment_count {
+            let segment = self.segment_by_index(index)?;
+
+            if segment.seg_type == SegmentType::Note {
+                let bytes = byte_slice(self.bytes, segment.raw_offset, segment.raw_size)?;
+
+                self.read_notes(bytes, &mut callback)?;
+            }
+        }
+
+        Some(())
+    }
+
+    /// Go through all notes from the `Note` section `section`.
+    pub fn section_notes(&self, section: &Section,
+                         callback: impl FnMut(&Note<'a>)) -> Option<()> {
+        if section.sec_type != SectionType::Note {
+            return None;
+        }
+
+        self.read_notes(byte_slice(self.bytes, section.raw_offset, section.raw_size)?, callback)
+    }
+
+    /// Relocate 64 bit image so it can be loaded at `base` instead of `base_address`. For every
+    /// relocation `write` is called with the virtual address (after relocation) and the value
+    /// which needs to be stored there. Returns `None` without calling `write` if the image
+    /// cannot be loaded at `base`: it is not position independent or has relocations other
+    /// than `R_X86_64_RELATIVE`.
+    pub fn relocate(&self, base: u64, mut write: impl FnMut(u64, u64)) -> Option<()> {
+        let bias = base.wrapping_sub(self.base_address);
+
+        if bias != 0 && self.relocation_table()?.is_none() {
+            return None;
+        }
+
+        let amd64         = self.bitness == Bitness::Bits64 && self.machine == Machine::Amd64;
+        let mut supported = true;
+
+        self.relocations(|relocation| {
+            supported &= amd64 && relocation.rel_type == R_X86_64_RELATIVE &&
+                relocation.offset.checked_sub(self.base_address)
+                    .and_then(|offset| offset.checked_add(8))
+                    .map(|end| end <= self.image_size)
+                    .unwrap_or(false);
+        })?;
+
+        if !supported {
+            return None;
+        }
+
+        self.relocations(|relocation| {
+            write(relocation.offset.wrapping_add(bias),
+                  (relocation.addend as u64).wrapping_add(bias));
+        })
+    }
+
     pub fn base_address(&self) -> u64 {
         self.base_address
     }
 
+    /// Get the size of memory occupied by all loadable segments, starting at `base_address`.
+    pub fn image_size(&self) -> u64 {
+        self.image_size
+    }
+
     pub fn entrypoint(&self) -> u64 {
         self.entrypoint
     }
@@ -563,6 +1074,7 @@ impl fmt::Debug for Elf<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Elf")
          .field("base_address",&self.base_address)
+         .field("image_size",  &self.image_size)
          .field("entrypoint",  &self.entrypoint)
          .field("bitness",     &self.bitness)
          .field("endianness",  &self.endianness)
@@ -604,11 +1116,37 @@ impl fmt::Debug for Section<'_> {
     }
 }
 
+impl fmt::Debug for Symbol<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Symbol")
+         .field("name",          &self.name)
+         .field("value",         &self.value)
+         .field("size",          &self.size)
+         .field("type",          &self.sym_type)
+         .field("binding",       &self.binding)
+         .field("section_index", &self.section_index)
+         .finish()
+    }
+}
+
+impl fmt::Debug for Note<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Note")
+         .field("name", &core::str::from_utf8(self.name).ok())
+         .field("type", &self.note_type)
+         .field("desc", &self.desc.len())
+         .finish()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate std;
 
     use super::*;
+    use std::vec::Vec;
+    use std::vec;
+    use core::ops::Range;
 
     #[test]
     fn test() {
@@ -629,4 +1167,682 @@ mod tests {
 
         panic!("Done!");
     }
+
+    #[test]
+    fn symbols_and_dynamic_data() {
+        let bytes = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let elf   = Elf::parse(&bytes).unwrap();
+
+        // Test binary has a `main` function and is linked as a position independent
+        // executable.
+        let main = elf.symbol_by_name("main").unwrap();
+
+        assert_eq!(main.sym_type, SymbolType::Function);
+        assert_eq!(main.binding, SymbolBinding::Global);
+        assert!(main.section_index != 0 && main.value >= elf.base_address());
+
+        let mut tags = std::vec::Vec::new();
+
+        elf.dynamic_entries(|entry| tags.push(entry.tag)).unwrap();
+
+        assert!(tags.contains(&DynamicTag::Rela) && !tags.contains(&DynamicTag::Null));
+
+        // Dynamic relocations are also visible through `.rela.dyn` section.
+        let mut dynamic = 0;
+        let mut section = 0;
+
+        elf.relocations(|_| dynamic += 1).unwrap();
+        elf.section_relocations(&elf.section_by_name(".rela.dyn").unwrap(), |_| section += 1)
+            .unwrap();
+
+        assert!(dynamic > 0 && dynamic == section);
+
+        // Non-symbol sections are rejected.
+        let text = elf.section_by_name(".text").unwrap();
+
+        assert!(elf.section_symbols(&text, |_| ()).is_none());
+        assert!(elf.section_relocations(&text, |_| ()).is_none());
+        assert!(elf.section_notes(&text, |_| ()).is_none());
+
+        // glibc startup code adds `NT_GNU_ABI_TAG` note (type 1) with 16 byte descriptor.
+        let mut abi_tag = false;
+
+        elf.notes(|note| {
+            abi_tag |= note.name == b"GNU" && note.note_type == 1 && note.desc.len() == 16;
+        }).unwrap();
+
+        assert!(abi_tag);
+    }
+
+    /// Base address of all test fixtures.
+    const BASE: u64 = 0x40_0000;
+
+    /// Base address used when relocating test fixtures.
+    const NEW_BASE: u64 = 0x50_0000;
+
+    /// Writes integers using given bitness and endianness.
+    struct Writer {
+        bytes:      Vec<u8>,
+        bitness:    Bitness,
+        endianness: Endianness,
+    }
+
+    impl Writer {
+        fn new(bitness: Bitness, endianness: Endianness) -> Self {
+            Self {
+                bytes: Vec::new(),
+                bitness,
+                endianness,
+            }
+        }
+
+        fn int(&mut self, value: u64, size: usize) {
+            let bytes = &value.to_le_bytes()[..size];
+
+            match self.endianness {
+                Endianness::Little => self.bytes.extend(bytes.iter()),
+                Endianness::Big    => self.bytes.extend(bytes.iter().rev()),
+            }
+        }
+
+        fn u8(&mut self, value: u64)  { self.int(value, 1) }
+        fn u16(&mut self, value: u64) { self.int(value, 2) }
+        fn u32(&mut self, value: u64) { self.int(value, 4) }
+        fn u64(&mut self, value: u64) { self.int(value, 8) }
+
+        /// Write address sized integer.
+        fn word(&mut self, value: u64) {
+            match self.bitness {
+                Bitness::Bits32 => self.u32(value),
+                Bitness::Bits64 => self.u64(value),
+            }
+        }
+
+        fn pad_to(&mut self, offset: usize) {
+            assert!(self.bytes.len() <= offset, "Fixture data overflowed.");
+
+            self.bytes.resize(offset, 0);
+        }
+
+        fn patch(&mut self, offset: usize, value: u64, size: usize) {
+            let mut writer = Writer::new(self.bitness, self.endianness);
+
+            writer.int(value, size);
+
+            self.bytes[offset..offset + size].copy_from_slice(&writer.bytes);
+        }
+    }
+
+    struct TestSegment {
+        seg_type:  u32,
+        flags:     u32,
+        virt_addr: u64,
+        virt_size: u64,
+        data:      Range<usize>,
+    }
+
+    struct TestSection {
+        name:       &'static str,
+        sec_type:   u32,
+        virt_addr:  u64,
+        data:       Range<usize>,
+        link:       u32,
+        entry_size: u64,
+    }
+
+    struct Fixture {
+        writer: Writer,
+
+        /// End of the file data used by segments. Every shorter file is invalid.
+        segments_end: usize,
+    }
+
+    /// Build ELF file with given segments and sections. Their data ranges are relative to
+    /// `payload`. Section header string table is added after the last section.
+    fn build_elf(bitness: Bitness, endianness: Endianness, machine: u64, payload: &[u8],
+                 segments: &[TestSegment], sections: &[TestSection]) -> Fixture {
+        let (header_size, segment_entry, section_entry) = match bitness {
+            Bitness::Bits32 => (52, 32, 40),
+            Bitness::Bits64 => (64, 56, 64),
+        };
+
+        let mut strings = vec![0u8];
+        let mut names   = Vec::new();
+
+        for name in sections.iter().map(|section| section.name).chain(Some(".shstrtab")) {
+            names.push(strings.len());
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+        }
+
+        let payload_offset = (header_size + segments.len() * segment_entry + 15) & !15;
+        let strings_offset = payload_offset + payload.len();
+        let section_table  = (strings_offset + strings.len() + 7) & !7;
+        let section_count  = sections.len() + 2;
+
+        let mut w = Writer::new(bitness, endianness);
+
+        w.bytes.extend_from_slice(b"\x7fELF");
+        w.u8(match bitness { Bitness::Bits32 => 1, Bitness::Bits64 => 2 });
+        w.u8(match endianness { Endianness::Little => 1, Endianness::Big => 2 });
+        w.u8(1);
+        w.pad_to(16);
+
+        w.u16(2);
+        w.u16(machine);
+        w.u32(1);
+        w.word(BASE);
+        w.word(header_size as u64);
+        w.word(section_table as u64);
+        w.u32(0);
+        w.u16(header_size as u64);
+        w.u16(segment_entry as u64);
+        w.u16(segments.len() as u64);
+        w.u16(section_entry as u64);
+        w.u16(section_count as u64);
+        w.u16(section_count as u64 - 1);
+
+        for segment in segments {
+            let offset = (payload_offset + segment.data.start) as u64;
+            let size   = segment.data.len() as u64;
+
+            match bitness {
+                Bitness::Bits32 => {
+                    w.u32(segment.seg_type as u64);
+                    w.u32(offset);
+                    w.u32(segment.virt_addr);
+                    w.u32(segment.virt_addr);
+                    w.u32(size);
+                    w.u32(segment.virt_size);
+                    w.u32(segment.flags as u64);
+                    w.u32(0x1000);
+                }
+                Bitness::Bits64 => {
+                    w.u32(segment.seg_type as u64);
+                    w.u32(segment.flags as u64);
+                    w.u64(offset);
+                    w.u64(segment.virt_addr);
+                    w.u64(segment.virt_addr);
+                    w.u64(size);
+                    w.u64(segment.virt_size);
+                    w.u64(0x1000);
+                }
+            }
+        }
+
+        w.pad_to(payload_offset);
+        w.bytes.extend_from_slice(payload);
+        w.bytes.extend_from_slice(&strings);
+        w.pad_to(section_table);
+
+        // Null section.
+        w.bytes.resize(section_table + section_entry, 0);
+
+        let string_table = TestSection {
+            name:       ".shstrtab",
+            sec_type:   3,
+            virt_addr:  0,
+            data:       payload.len()..payload.len() + strings.len(),
+            link:       0,
+            entry_size: 0,
+        };
+
+        for (section, &name) in sections.iter().chain(Some(&string_table)).zip(&names) {
+            w.u32(name as u64);
+            w.u32(section.sec_type as u64);
+            w.word(0);
+            w.word(section.virt_addr);
+            w.word((payload_offset + section.data.start) as u64);
+            w.word(section.data.len() as u64);
+            w.u32(section.link as u64);
+            w.u32(0);
+            w.word(1);
+            w.word(section.entry_size);
+        }
+
+        let segments_end = segments.iter()
+            .map(|segment| payload_offset + segment.data.end)
+            .max()
+            .unwrap_or(0)
+            .max(header_size + segments.len() * segment_entry);
+
+        Fixture {
+            writer: w,
+            segments_end,
+        }
+    }
+
+    fn write_note(w: &mut Writer, name: &[u8], note_type: u64, desc: &[u8]) {
+        w.u32(name.len() as u64);
+        w.u32(desc.len() as u64);
+        w.u32(note_type);
+
+        for data in &[name, desc] {
+            w.bytes.extend_from_slice(data);
+            w.pad_to((w.bytes.len() + 3) & !3);
+        }
+    }
+
+    /// Build a small executable with code, data, relocation, dynamic, note and symbol
+    /// sections. `edit` can change its segments before building.
+    fn fixture_with(bitness: Bitness, endianness: Endianness,
+                    edit: impl FnOnce(&mut Vec<TestSegment>)) -> Fixture {
+        let machine = match (bitness, endianness) {
+            (Bitness::Bits32, Endianness::Little) => 0x03,
+            (Bitness::Bits64, Endianness::Little) => 0x3e,
+            (Bitness::Bits32, Endianness::Big)    => 0x14,
+            (Bitness::Bits64, Endianness::Big)    => 0x15,
+        };
+
+        let (relocation_entry, dynamic_entry, symbol_entry) = match bitness {
+            Bitness::Bits32 => (12, 8, 16),
+            Bitness::Bits64 => (24, 16, 24),
+        };
+
+        let mut w = Writer::new(bitness, endianness);
+
+        // Code.
+        w.bytes.resize(0x100, 0xcc);
+
+        // Relocation table with a single relative relocation (type 8, no symbol).
+        w.word(BASE + 0x1200);
+        w.word(8);
+        w.word(BASE + 0x10);
+
+        let relocations = 0x100..w.bytes.len();
+
+        // Relocated data followed by the dynamic table.
+        w.pad_to(0x240);
+
+        for &(tag, value) in &[(7, BASE + 0x100), (8, relocations.len() as u64),
+                               (9, relocation_entry), (0, 0)] {
+            w.word(tag);
+            w.word(value);
+        }
+
+        let dynamic = 0x240..w.bytes.len();
+
+        // Notes.
+        w.pad_to(0x300);
+
+        write_note(&mut w, b"GNU\0", 3, &[1, 2, 3, 4]);
+        write_note(&mut w, b"flugzeug\0", 0x1234, &[5, 6, 7]);
+
+        let notes = 0x300..w.bytes.len();
+
+        // Symbols: null, `_start` (global function), `counter` (local object) and
+        // `weak_function` (undefined weak symbol).
+        w.pad_to(0x340);
+
+        for &(name, value, size, info, section) in &[(0, 0, 0, 0, 0),
+                                                     (1, BASE, 0x10, 0x12, 1),
+                                                     (8, BASE + 0x1200, 8, 0x01, 1),
+                                                     (16, 0, 0, 0x20, 0)] {
+            match bitness {
+                Bitness::Bits32 => {
+                    w.u32(name);
+                    w.u32(value);
+                    w.u32(size);
+                    w.u8(info);
+                    w.u8(0);
+                    w.u16(section);
+                }
+                Bitness::Bits64 => {
+                    w.u32(name);
+                    w.u8(info);
+                    w.u8(0);
+                    w.u16(section);
+                    w.u64(value);
+                    w.u64(size);
+                }
+            }
+        }
+
+        let symbols = 0x340..w.bytes.len();
+
+        w.bytes.extend_from_slice(b"\0_start\0counter\0weak_function\0");
+
+        let strings = symbols.end..w.bytes.len();
+
+        let mut segments = vec![
+            TestSegment { seg_type: 1, flags: 5, virt_addr: BASE, virt_size: 0x200,
+                          data: 0x000..0x200 },
+            TestSegment { seg_type: 1, flags: 6, virt_addr: BASE + 0x1200, virt_size: 0x1000,
+                          data: 0x200..0x300 },
+            TestSegment { seg_type: 2, flags: 6, virt_addr: BASE + 0x1240,
+                          virt_size: dynamic.len() as u64, data: dynamic.clone() },
+            TestSegment { seg_type: 4, flags: 4, virt_addr: 0, virt_size: notes.len() as u64,
+                          data: notes.clone() },
+        ];
+
+        edit(&mut segments);
+
+        let sections = [
+            TestSection { name: ".text", sec_type: 1, virt_addr: BASE, data: 0..0x100,
+                          link: 0, entry_size: 0 },
+            TestSection { name: ".rela.dyn", sec_type: 4, virt_addr: BASE + 0x100,
+                          data: relocations, link: 0, entry_size: relocation_entry },
+            TestSection { name: ".dynamic", sec_type: 6, virt_addr: BASE + 0x1240,
+                          data: dynamic, link: 0, entry_size: dynamic_entry },
+            TestSection { name: ".note", sec_type: 7, virt_addr: 0, data: notes,
+                          link: 0, entry_size: 0 },
+            TestSection { name: ".symtab", sec_type: 2, virt_addr: 0, data: symbols,
+                          link: 6, entry_size: symbol_entry },
+            TestSection { name: ".strtab", sec_type: 3, virt_addr: 0, data: strings,
+                          link: 0, entry_size: 0 },
+        ];
+
+        build_elf(bitness, endianness, machine, &w.bytes, &segments, &sections)
+    }
+
+    fn fixture(bitness: Bitness, endianness: Endianness) -> Fixture {
+        fixture_with(bitness, endianness, |_| ())
+    }
+
+    fn all_formats() -> [(Bitness, Endianness); 4] {
+        [
+            (Bitness::Bits32, Endianness::Little),
+            (Bitness::Bits64, Endianness::Little),
+            (Bitness::Bits32, Endianness::Big),
+            (Bitness::Bits64, Endianness::Big),
+        ]
+    }
+
+    /// Call every API on `bytes` and check basic invariants. It must never panic.
+    fn exercise(bytes: &[u8]) {
+        let elf = match Elf::parse(bytes) {
+            Some(elf) => elf,
+            None      => return,
+        };
+
+        assert!(elf.base_address().checked_add(elf.image_size()).is_some());
+
+        let _ = elf.segments(|_| ());
+        let _ = elf.sections(|section| {
+            let _ = elf.section_symbols(section, |_| ());
+            let _ = elf.section_relocations(section, |_| ());
+            let _ = elf.section_notes(section, |_| ());
+        });
+
+        let _ = elf.section_by_name(".text");
+        let _ = elf.symbol_by_name("_start");
+        let _ = elf.relocations(|_| ());
+        let _ = elf.dynamic_entries(|_| ());
+        let _ = elf.notes(|_| ());
+
+        for &base in &[0, elf.base_address(), NEW_BASE, !0xfff] {
+            let _ = elf.relocate(base, |virt_addr, _| {
+                assert!(virt_addr.wrapping_sub(base) + 8 <= elf.image_size(),
+                        "Relocation is outside of the image.");
+            });
+        }
+
+        let _ = std::format!("{:?}", elf);
+    }
+
+    #[test]
+    fn golden_fixtures() {
+        for &(bitness, endianness) in &all_formats() {
+            let fixture = fixture(bitness, endianness);
+            let elf     = Elf::parse(&fixture.writer.bytes).unwrap();
+
+            let machine = match (bitness, endianness) {
+                (Bitness::Bits32, Endianness::Little) => Machine::X86,
+                (Bitness::Bits64, Endianness::Little) => Machine::Amd64,
+                (Bitness::Bits32, Endianness::Big)    => Machine::PowerPC,
+                (Bitness::Bits64, Endianness::Big)    => Machine::PowerPC64,
+            };
+
+            assert_eq!(elf.bitness(), bitness);
+            assert_eq!(elf.endianness(), endianness);
+            assert_eq!(elf.machine(), machine);
+            assert_eq!(elf.entrypoint(), BASE);
+            assert_eq!(elf.base_address(), BASE);
+            assert_eq!(elf.image_size(), 0x2200);
+
+            let mut segments = Vec::new();
+
+            elf.segments(|segment| {
+                segments.push((segment.seg_type, segment.virt_addr, segment.virt_size,
+                               segment.bytes.len(), segment.read, segment.write,
+                               segment.execute));
+            }).unwrap();
+
+            assert_eq!(segments, [
+                (SegmentType::Load, BASE, 0x200, 0x200, true, false, true),
+                (SegmentType::Load, BASE + 0x1200, 0x1000, 0x100, true, true, false),
+                (SegmentType::Dynamic, BASE + 0x1240, segments[2].2, segments[2].3,
+                 true, true, false),
+                (SegmentType::Note, 0, 48, 48, true, false, false),
+            ]);
+
+            let mut sections = Vec::new();
+
+            elf.sections(|section| {
+                sections.push((section.name.map(std::string::String::from), section.sec_type));
+            }).unwrap();
+
+            let expected = [
+                ("", SectionType::Null),
+                (".text", SectionType::Progbits),
+                (".rela.dyn", SectionType::Rela),
+                (".dynamic", SectionType::Dynamic),
+                (".note", SectionType::Note),
+                (".symtab", SectionType::Symtab),
+                (".strtab", SectionType::Strtab),
+                (".shstrtab", SectionType::Strtab),
+            ];
+
+            assert_eq!(sections.len(), expected.len());
+
+            for ((name, sec_type), &(expected_name, expected_type)) in
+                sections.iter().zip(&expected) {
+                assert_eq!(name.as_deref(), Some(expected_name));
+                assert_eq!(*sec_type, expected_type);
+            }
+
+            assert_eq!(elf.section_by_name(".text").unwrap().bytes, Some(&[0xcc; 0x100][..]));
+            assert!(elf.section_by_name(".data").is_none());
+
+            let mut symbols = Vec::new();
+
+            elf.symbols(|symbol| {
+                symbols.push((symbol.name, symbol.value, symbol.size, symbol.sym_type,
+                              symbol.binding, symbol.section_index));
+            }).unwrap();
+
+            assert_eq!(symbols, [
+                (None, 0, 0, SymbolType::NoType, SymbolBinding::Local, 0),
+                (Some("_start"), BASE, 0x10, SymbolType::Function, SymbolBinding::Global, 1),
+                (Some("counter"), BASE + 0x1200, 8, SymbolType::Object, SymbolBinding::Local, 1),
+                (Some("weak_function"), 0, 0, SymbolType::NoType, SymbolBinding::Weak, 0),
+            ]);
+
+            assert_eq!(elf.symbol_by_name("counter").unwrap().value, BASE + 0x1200);
+            assert!(elf.symbol_by_name("missing").is_none());
+
+            let mut tags = Vec::new();
+
+            elf.dynamic_entries(|entry| tags.push(entry.tag)).unwrap();
+
+            assert_eq!(tags, [DynamicTag::Rela, DynamicTag::RelaSize, DynamicTag::RelaEntry]);
+
+            let expected = Relocation {
+                offset:   BASE + 0x1200,
+                rel_type: 8,
+                symbol:   0,
+                addend:   (BASE + 0x10) as i64,
+            };
+
+            let mut relocations = Vec::new();
+
+            elf.relocations(|relocation| relocations.push(*relocation)).unwrap();
+            elf.section_relocations(&elf.section_by_name(".rela.dyn").unwrap(),
+                                    |relocation| relocations.push(*relocation)).unwrap();
+
+            assert_eq!(relocations, [expected, expected]);
+
+            let mut notes = Vec::new();
+
+            elf.notes(|note| notes.push((note.name, note.note_type, note.desc))).unwrap();
+            elf.section_notes(&elf.section_by_name(".note").unwrap(),
+                              |note| notes.push((note.name, note.note_type, note.desc)))
+                .unwrap();
+
+            let expected: [(&[u8], u32, &[u8]); 2] = [(b"GNU", 3, &[1, 2, 3, 4]),
+                                                      (b"flugzeug", 0x1234, &[5, 6, 7])];
+
+            assert_eq!(notes[..2], expected);
+            assert_eq!(notes[2..], expected);
+
+            // Only AMD64 images can be relocated.
+            let mut writes = Vec::new();
+            let relocated  = elf.relocate(NEW_BASE, |virt_addr, value| {
+                writes.push((virt_addr, value));
+            });
+
+            if machine == Machine::Amd64 {
+                assert_eq!(relocated, Some(()));
+                assert_eq!(writes, [(NEW_BASE + 0x1200, NEW_BASE + 0x10)]);
+            } else {
+                assert_eq!(relocated, None);
+                assert!(writes.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_files() {
+        for &(bitness, endianness) in &all_formats() {
+            let fixture = fixture(bitness, endianness);
+            let bytes   = &fixture.writer.bytes;
+
+            for size in 0..bytes.len() {
+                exercise(&bytes[..size]);
+
+                assert_eq!(Elf::parse(&bytes[..size]).is_some(), size >= fixture.segments_end,
+                           "Unexpected result for {} byte file.", size);
+            }
+        }
+    }
+
+    #[test]
+    fn malformed_segments() {
+        fn parses(bitness: Bitness, edit: impl FnOnce(&mut Vec<TestSegment>)) -> bool {
+            let fixture = fixture_with(bitness, Endianness::Little, edit);
+
+            Elf::parse(&fixture.writer.bytes).is_some()
+        }
+
+        for &bitness in &[Bitness::Bits32, Bitness::Bits64] {
+            assert!(parses(bitness, |_| ()));
+
+            // Overlapping loadable segments.
+            assert!(!parses(bitness, |segments| segments[1].virt_addr = BASE + 0x100));
+
+            // Loadable segments in descending order.
+            assert!(!parses(bitness, |segments| segments[1].virt_addr = BASE - 0x2000));
+
+            // More file data than memory.
+            assert!(!parses(bitness, |segments| segments[1].virt_size = 0x80));
+
+            // No loadable segments.
+            assert!(!parses(bitness, |segments| segments.retain(|segment| {
+                segment.seg_type != 1
+            })));
+
+            // Segment data outside of the file.
+            assert!(!parses(bitness, |segments| segments[3].data = 0x300..0x100_0000));
+        }
+
+        // Segment which wraps around the address space.
+        assert!(!parses(Bitness::Bits64, |segments| {
+            segments[1].virt_addr = u64::MAX - 0x10;
+        }));
+    }
+
+    #[test]
+    fn huge_counts_and_offsets() {
+        // Header field offsets in 64 bit ELF files.
+        const SEGMENT_TABLE: usize = 0x20;
+        const SECTION_TABLE: usize = 0x28;
+        const SEGMENT_ENTRY: usize = 0x36;
+        const SEGMENT_COUNT: usize = 0x38;
+        const SECTION_COUNT: usize = 0x3c;
+        const STRING_INDEX:  usize = 0x3e;
+
+        let patched = |offset: usize, value: u64, size: usize| {
+            let mut fixture = fixture(Bitness::Bits64, Endianness::Little);
+
+            fixture.writer.patch(offset, value, size);
+            fixture.writer.bytes
+        };
+
+        assert!(Elf::parse(&patched(SEGMENT_COUNT, 0xffff, 2)).is_none());
+        assert!(Elf::parse(&patched(SEGMENT_TABLE, u64::MAX - 8, 8)).is_none());
+        assert!(Elf::parse(&patched(SEGMENT_TABLE, u64::MAX, 8)).is_none());
+        assert!(Elf::parse(&patched(SEGMENT_ENTRY, 0, 2)).is_none());
+        assert!(Elf::parse(&patched(SEGMENT_ENTRY, 0xffff, 2)).is_none());
+
+        // Invalid section table doesn't prevent loading, but sections cannot be accessed.
+        for &(offset, value, size) in &[(SECTION_COUNT, 0xffff, 2),
+                                        (SECTION_TABLE, u64::MAX - 8, 8),
+                                        (SECTION_TABLE, u64::MAX, 8)] {
+            let bytes = patched(offset, value, size);
+            let elf   = Elf::parse(&bytes).unwrap();
+
+            assert!(elf.sections(|_| ()).is_none());
+            assert!(elf.symbols(|_| ()).is_none());
+            assert!(elf.section_by_name("missing").is_none());
+
+            exercise(&bytes);
+        }
+
+        // Section names cannot be resolved without the string table.
+        let bytes = patched(STRING_INDEX, 0xfff0, 2);
+        let elf   = Elf::parse(&bytes).unwrap();
+
+        assert_eq!(elf.section_by_index(1).unwrap().name, None);
+        assert!(elf.section_by_name(".text").is_none());
+    }
+
+    #[test]
+    fn mutated_files_never_panic() {
+        const INTERESTING: [u64; 8] = [0, 1, 0x7f, 0xff, 0xffff, 0x7fff_ffff, 0xffff_ffff,
+                                       u64::MAX];
+
+        // Deterministic xorshift generator so failures are reproducible.
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut random = move |limit: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            (state % limit as u64) as usize
+        };
+
+        for &(bitness, endianness) in &all_formats() {
+            let original = fixture(bitness, endianness).writer;
+
+            for _ in 0..5000 {
+                let mut mutated = Writer::new(bitness, endianness);
+
+                mutated.bytes = original.bytes.clone();
+
+                for _ in 0..1 + random(8) {
+                    let size   = [1, 2, 4, 8][random(4)];
+                    let offset = random(mutated.bytes.len() - size);
+
+                    let value = match random(2) {
+                        0 => INTERESTING[random(INTERESTING.len())],
+                        _ => random(usize::MAX) as u64,
+                    };
+
+                    mutated.patch(offset, value, size);
+                }
+
+                exercise(&mutated.bytes);
+            }
+        }
+    }
 }
@@ -0,0 +1,639 @@
+#![no_std]
+
+//! GDB Remote Serial Protocol: packet framing, command parsing and register encoding used
+//! by the kernel debugger stub. Only the subset needed to debug a single x86_64 target is
+//! supported, unknown packets should get an empty response.
+
+use core::fmt;
+
+/// Maximum size of packet data. Advertised to GDB in the `qSupported` response.
+pub const MAX_PACKET_SIZE: usize = 4096;
+
+/// Byte sent by GDB outside of packets to stop the target (Ctrl-C).
+pub const INTERRUPT: u8 = 0x03;
+
+/// Number of registers sent in response to `g`: general purpose registers, RIP, EFLAGS and
+/// segment registers. FPU and SSE registers are not reported, GDB marks them unavailable.
+pub const REGISTER_COUNT: usize = 24;
+
+/// Byte stream used to talk with GDB.
+pub trait Connection {
+    /// Receive a byte. Blocks until the byte is available.
+    fn read_byte(&mut self) -> u8;
+
+    /// Send a byte.
+    fn write_byte(&mut self, byte: u8);
+}
+
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789abcdef"[(value & 0xf) as usize]
+}
+
+fn parse_hex_digit(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _           => None,
+    }
+}
+
+/// Parse big endian hexadecimal number (used for addresses, lengths and register numbers).
+fn parse_number(text: &[u8]) -> Option<u64> {
+    if text.is_empty() || text.len() > 16 {
+        return None;
+    }
+
+    text.iter().try_fold(0u64, |value, &digit| {
+        Some((value << 4) | parse_hex_digit(digit)? as u64)
+    })
+}
+
+/// Parse little endian value of `size` bytes encoded as hex (used for register values).
+fn parse_value(hex: &[u8], size: usize) -> Option<u64> {
+    let mut bytes = [0u8; 8];
+
+    if hex.len() != size * 2 || decode_hex(hex, &mut bytes[..size])? != size {
+        return None;
+    }
+
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Decode hex encoded `hex` into `output`. Returns the number of decoded bytes.
+pub fn decode_hex(hex: &[u8], output: &mut [u8]) -> Option<usize> {
+    if hex.len() & 1 != 0 || hex.len() / 2 > output.len() {
+        return None;
+    }
+
+    for (index, pair) in hex.chunks(2).enumerate() {
+        output[index] = (parse_hex_digit(pair[0])? << 4) | parse_hex_digit(pair[1])?;
+    }
+
+    Some(hex.len() / 2)
+}
+
+/// Receive data of the next packet into `buffer`. Packets with invalid checksum or too big
+/// to fit are rejected and GDB will retransmit them. Bytes outside of packets (including
+/// `INTERRUPT`) are ignored.
+pub fn receive_packet<'a>(connection: &mut impl Connection, buffer: &'a mut [u8]) -> &'a [u8] {
+    loop {
+        while connection.read_byte() != b'$' {}
+
+        let mut size     = 0;
+        let mut checksum = 0u8;
+        let mut overflow = false;
+
+        loop {
+            let byte = connection.read_byte();
+
+            match byte {
+                b'#' => break,
+                b'$' => {
+                    // Previous packet was incomplete, start receiving a new one.
+                    size     = 0;
+                    checksum = 0;
+                    overflow = false;
+                }
+                _ => {
+                    checksum = checksum.wrapping_add(byte);
+
+                    if size < buffer.len() {
+                        buffer[size] = byte;
+                        size        += 1;
+                    } else {
+                        overflow = true;
+                    }
+                }
+            }
+        }
+
+        let high     = parse_hex_digit(connection.read_byte());
+        let low      = parse_hex_digit(connection.read_byte());
+        let expected = high.and_then(|high| Some((high << 4) | low?));
+
+        if !overflow && expected == Some(checksum) {
+            connection.write_byte(b'+');
+
+            return &buffer[..size];
+        }
+
+        connection.write_byte(b'-');
+    }
+}
+
+/// Send packet with `data` and wait until GDB acknowledges it.
+pub fn send_packet(connection: &mut impl Connection, data: &[u8]) {
+    let checksum = data.iter().fold(0u8, |checksum, &byte| checksum.wrapping_add(byte));
+
+    loop {
+        connection.write_byte(b'$');
+
+        for &byte in data {
+            connection.write_byte(byte);
+        }
+
+        connection.write_byte(b'#');
+        connection.write_byte(hex_digit(checksum >> 4));
+        connection.write_byte(hex_digit(checksum));
+
+        // Retransmit the packet on `-`, ignore everything else until we get `+`.
+        loop {
+            match connection.read_byte() {
+                b'+' => return,
+                b'-' => break,
+                _    => continue,
+            }
+        }
+    }
+}
+
+/// Response packet data. It's built in a fixed size buffer so it doesn't need allocations.
+pub struct Response {
+    data:     [u8; MAX_PACKET_SIZE],
+    size:     usize,
+    overflow: bool,
+}
+
+impl Response {
+    pub fn new() -> Self {
+        Self {
+            data:     [0; MAX_PACKET_SIZE],
+            size:     0,
+            overflow: false,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        if self.size < self.data.len() {
+            self.data[self.size] = byte;
+            self.size           += 1;
+        } else {
+            self.overflow = true;
+        }
+    }
+
+    /// Append `bytes` encoded as hex.
+    pub fn push_hex(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push(hex_digit(byte >> 4));
+            self.push(hex_digit(byte));
+        }
+    }
+
+    pub fn push_str(&mut self, string: &str) {
+        for &byte in string.as_bytes() {
+            self.push(byte);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.size     = 0;
+        self.overflow = false;
+    }
+
+    /// Get response data. Returns `None` if the response didn't fit in the buffer.
+    pub fn data(&self) -> Option<&[u8]> {
+        if self.overflow {
+            None
+        } else {
+            Some(&self.data[..self.size])
+        }
+    }
+
+    /// Send the response to GDB. Error is sent instead if the response didn't fit.
+    pub fn send(&self, connection: &mut impl Connection) {
+        send_packet(connection, self.data().unwrap_or(b"E01"));
+    }
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Write for Response {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        self.push_str(string);
+
+        if self.overflow {
+            Err(fmt::Error)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Command<'a> {
+    /// `?`: report why the target has stopped.
+    StopReason,
+
+    /// `g`: read all registers.
+    ReadRegisters,
+
+    /// `G XX...`: write all registers (hex encoded in `g` layout).
+    WriteRegisters(&'a [u8]),
+
+    /// `p n`: read a single register.
+    ReadRegister(usize),
+
+    /// `P n=r...`: write a single register (hex encoded).
+    WriteRegister(usize, &'a [u8]),
+
+    /// `m addr,length`: read memory.
+    ReadMemory { address: u64, size: usize },
+
+    /// `M addr,length:XX...`: write memory. `data` is hex encoded and matches the length.
+    WriteMemory { address: u64, data: &'a [u8] },
+
+    /// `Z0,addr,kind`: insert software breakpoint.
+    InsertBreakpoint(u64),
+
+    /// `z0,addr,kind`: remove software breakpoint.
+    RemoveBreakpoint(u64),
+
+    /// `c [addr]`: continue execution, optionally at a new address.
+    Continue(Option<u64>),
+
+    /// `s [addr]`: execute a single instruction, optionally at a new address.
+    Step(Option<u64>),
+
+    /// `D`: detach the debugger.
+    Detach,
+
+    /// `k`: kill the target. Kernel cannot be killed so it's treated as detach.
+    Kill,
+
+    /// `qSupported`: exchange supported features.
+    Supported,
+
+    /// `qAttached`: check if GDB attached to an existing process.
+    Attached,
+
+    /// `qC`: get the current thread.
+    CurrentThread,
+
+    /// `H...`: select thread for subsequent operations.
+    SetThread,
+
+    /// `T...`: check if thread is alive.
+    ThreadAlive,
+
+    /// Packet which isn't supported or is malformed.
+    Unsupported,
+}
+
+/// Parse `addr,length` pair.
+fn parse_range(text: &[u8]) -> Option<(u64, u64)> {
+    let separator = text.iter().position(|&byte| byte == b',')?;
+
+    Some((parse_number(&text[..separator])?, parse_number(&text[separator + 1..])?))
+}
+
+/// Parse `0,addr,kind` breakpoint specification. Only software breakpoints are supported.
+fn parse_breakpoint(text: &[u8]) -> Option<u64> {
+    let (address, _kind) = parse_range(text.strip_prefix(b"0,")?)?;
+
+    Some(address)
+}
+
+/// Parse optional address of `c` and `s` packets.
+fn parse_resume_address(text: &[u8]) -> Option<Option<u64>> {
+    if text.is_empty() {
+        Some(None)
+    } else {
+        parse_number(text).map(Some)
+    }
+}
+
+impl<'a> Command<'a> {
+    fn parse_inner(packet: &'a [u8]) -> Option<Self> {
+        let (&kind, arguments) = packet.split_first()?;
+
+        Some(match kind {
+            b'?' if arguments.is_empty() => Command::StopReason,
+            b'g' if arguments.is_empty() => Command::ReadRegisters,
+            b'G' => Command::WriteRegisters(arguments),
+            b'p' => Command::ReadRegister(parse_number(arguments)? as usize),
+            b'P' => {
+                let separator = arguments.iter().position(|&byte| byte == b'=')?;
+
+                Command::WriteRegister(parse_number(&arguments[..separator])? as usize,
+                                       &arguments[separator + 1..])
+            }
+            b'm' => {
+                let (address, size) = parse_range(arguments)?;
+
+                Command::ReadMemory { address, size: size as usize }
+            }
+            b'M' => {
+                let separator       = arguments.iter().position(|&byte| byte == b':')?;
+                let (address, size) = parse_range(&arguments[..separator])?;
+                let data            = &arguments[separator + 1..];
+
+                if Some(data.len() as u64) != size.checked_mul(2) {
+                    return None;
+                }
+
+                Command::WriteMemory { address, data }
+            }
+            b'Z' => Command::InsertBreakpoint(parse_breakpoint(arguments)?),
+            b'z' => Command::RemoveBreakpoint(parse_breakpoint(arguments)?),
+            b'c' => Command::Continue(parse_resume_address(arguments)?),
+            b's' => Command::Step(parse_resume_address(arguments)?),
+            b'D' => Command::Detach,
+            b'k' => Command::Kill,
+            b'H' => Command::SetThread,
+            b'T' => Command::ThreadAlive,
+            b'q' => {
+                if arguments.starts_with(b"Supported") {
+                    Command::Supported
+                } else if arguments.starts_with(b"Attached") {
+                    Command::Attached
+                } else if arguments == b"C" {
+                    Command::CurrentThread
+                } else {
+                    return None;
+                }
+            }
+            _ => return None,
+        })
+    }
+
+    /// Parse command from packet data.
+    pub fn parse(packet: &'a [u8]) -> Self {
+        Self::parse_inner(packet).unwrap_or(Command::Unsupported)
+    }
+}
+
+/// Registers in the order used by GDB for x86_64 targets.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub rsp: u64,
+    pub r8:  u64,
+    pub r9:  u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+
+    /// EFLAGS and segment registers are 32 bit wide in GDB layout.
+    pub eflags: u64,
+    pub cs:     u64,
+    pub ss:     u64,
+    pub ds:     u64,
+    pub es:     u64,
+    pub fs:     u64,
+    pub gs:     u64,
+}
+
+impl Registers {
+    fn register_size(index: usize) -> usize {
+        if index < 17 { 8 } else { 4 }
+    }
+
+    fn values(&self) -> [u64; REGISTER_COUNT] {
+        [
+            self.rax, self.rbx, self.rcx, self.rdx, self.rsi, self.rdi, self.rbp, self.rsp,
+            self.r8,  self.r9,  self.r10, self.r11, self.r12, self.r13, self.r14, self.r15,
+            self.rip, self.eflags, self.cs, self.ss, self.ds, self.es, self.fs, self.gs,
+        ]
+    }
+
+    fn values_mut(&mut self) -> [&mut u64; REGISTER_COUNT] {
+        [
+            &mut self.rax, &mut self.rbx, &mut self.rcx, &mut self.rdx,
+            &mut self.rsi, &mut self.rdi, &mut self.rbp, &mut self.rsp,
+            &mut self.r8,  &mut self.r9,  &mut self.r10, &mut self.r11,
+            &mut self.r12, &mut self.r13, &mut self.r14, &mut self.r15,
+            &mut self.rip, &mut self.eflags,
+            &mut self.cs,  &mut self.ss,  &mut self.ds,  &mut self.es,
+            &mut self.fs,  &mut self.gs,
+        ]
+    }
+
+    /// Append register `index` to `response`. Returns `None` if the register doesn't exist.
+    pub fn read(&self, index: usize, response: &mut Response) -> Option<()> {
+        let value = *self.values().get(index)?;
+        let size  = Self::register_size(index);
+
+        response.push_hex(&value.to_le_bytes()[..size]);
+
+        Some(())
+    }
+
+    /// Set register `index` to hex encoded `value`.
+    pub fn write(&mut self, index: usize, value: &[u8]) -> Option<()> {
+        let size = Self::register_size(index);
+
+        let mut values = self.values_mut();
+
+        **values.get_mut(index)? = parse_value(value, size)?;
+
+        Some(())
+    }
+
+    /// Append all registers to `response` (in `g` packet format).
+    pub fn read_all(&self, response: &mut Response) {
+        for index in 0..REGISTER_COUNT {
+            self.read(index, response);
+        }
+    }
+
+    /// Set all registers from hex encoded `data` (in `G` packet format). Data of registers
+    /// which aren't supported is ignored. Nothing is modified if `data` is malformed.
+    pub fn write_all(&mut self, data: &[u8]) -> Option<()> {
+        let mut registers = *self;
+        let mut offset    = 0;
+
+        for index in 0..REGISTER_COUNT {
+            let size = Self::register_size(index) * 2;
+
+            registers.write(index, data.get(offset..offset + size)?)?;
+
+            offset += size;
+        }
+
+        *self = registers;
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    struct MockConnection {
+        input:  VecDeque<u8>,
+        output: Vec<u8>,
+    }
+
+    impl MockConnection {
+        fn new(input: &[u8]) -> Self {
+            Self {
+                input:  input.iter().copied().collect(),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Connection for MockConnection {
+        fn read_byte(&mut self) -> u8 {
+            self.input.pop_front().expect("Connection has no more input.")
+        }
+
+        fn write_byte(&mut self, byte: u8) {
+            self.output.push(byte);
+        }
+    }
+
+    #[test]
+    fn packets() {
+        let mut buffer = [0u8; 64];
+
+        // Garbage, interrupt and a packet with invalid checksum before the valid one.
+        let mut connection = MockConnection::new(b"xx\x03$g#00$g#67");
+
+        assert_eq!(receive_packet(&mut connection, &mut buffer), b"g");
+        assert_eq!(connection.output, b"-+");
+
+        // Incomplete packet is restarted.
+        let mut connection = MockConnection::new(b"$m12$?#3F");
+
+        assert_eq!(receive_packet(&mut connection, &mut buffer), b"?");
+
+        // Packet which doesn't fit in the buffer.
+        let mut connection = MockConnection::new(b"$mffff,1#eb$?#3f");
+
+        assert_eq!(receive_packet(&mut connection, &mut buffer[..4]), b"?");
+        assert_eq!(connection.output, b"-+");
+
+        // Retransmit after NAK.
+        let mut connection = MockConnection::new(b"-$+");
+
+        send_packet(&mut connection, b"OK");
+
+        assert_eq!(connection.output, b"$OK#9a$OK#9a");
+    }
+
+    #[test]
+    fn responses() {
+        let mut connection = MockConnection::new(b"++");
+        let mut response   = Response::new();
+
+        response.push_str("S");
+        response.push_hex(&[0x05]);
+        response.send(&mut connection);
+
+        assert_eq!(response.data(), Some(&b"S05"[..]));
+
+        for _ in 0..MAX_PACKET_SIZE {
+            response.push(b'0');
+        }
+
+        assert_eq!(response.data(), None);
+
+        response.send(&mut connection);
+
+        assert_eq!(connection.output, b"$S05#b8$E01#a6");
+    }
+
+    #[test]
+    fn commands() {
+        let parse = Command::parse;
+
+        assert_eq!(parse(b"?"), Command::StopReason);
+        assert_eq!(parse(b"g"), Command::ReadRegisters);
+        assert_eq!(parse(b"G0011"), Command::WriteRegisters(b"0011"));
+        assert_eq!(parse(b"p10"), Command::ReadRegister(16));
+        assert_eq!(parse(b"P7=0010"), Command::WriteRegister(7, b"0010"));
+        assert_eq!(parse(b"mffffffff80001000,40"),
+                   Command::ReadMemory { address: 0xffff_ffff_8000_1000, size: 0x40 });
+        assert_eq!(parse(b"M1000,2:cc90"),
+                   Command::WriteMemory { address: 0x1000, data: b"cc90" });
+        assert_eq!(parse(b"Z0,ffffffff80001234,1"),
+                   Command::InsertBreakpoint(0xffff_ffff_8000_1234));
+        assert_eq!(parse(b"z0,1234,1"), Command::RemoveBreakpoint(0x1234));
+        assert_eq!(parse(b"c"), Command::Continue(None));
+        assert_eq!(parse(b"s1000"), Command::Step(Some(0x1000)));
+        assert_eq!(parse(b"D"), Command::Detach);
+        assert_eq!(parse(b"k"), Command::Kill);
+        assert_eq!(parse(b"qSupported:multiprocess+;swbreak+"), Command::Supported);
+        assert_eq!(parse(b"qAttached"), Command::Attached);
+        assert_eq!(parse(b"qC"), Command::CurrentThread);
+        assert_eq!(parse(b"Hg0"), Command::SetThread);
+
+        // Unsupported and malformed commands.
+        for packet in &[&b""[..], b"vCont?", b"Z1,1000,1", b"M1000,2:cc", b"m1000", b"pzz",
+                        b"c10000000000000000", b"g1", b"qTStatus"] {
+            assert_eq!(parse(packet), Command::Unsupported);
+        }
+    }
+
+    #[test]
+    fn registers() {
+        let mut registers = Registers {
+            rax:    0x0123_4567_89ab_cdef,
+            rip:    0xffff_ffff_8000_1000,
+            eflags: 0x202,
+            cs:     0x08,
+            ..Default::default()
+        };
+
+        let mut response = Response::new();
+
+        registers.read_all(&mut response);
+
+        let data = response.data().unwrap();
+
+        assert_eq!(data.len(), (17 * 8 + 7 * 4) * 2);
+        assert_eq!(&data[..16], b"efcdab8967452301");
+        assert_eq!(&data[16 * 16..17 * 16], b"00100080ffffffff");
+        assert_eq!(&data[17 * 16..17 * 16 + 8], b"02020000");
+
+        let mut decoded = Registers::default();
+
+        assert_eq!(decoded.write_all(data), Some(()));
+        assert_eq!(decoded, registers);
+
+        // Additional registers are ignored, truncated and invalid data is rejected.
+        let mut extended = data.to_vec();
+
+        extended.extend_from_slice(b"xxxxxxxx");
+
+        assert_eq!(decoded.write_all(&extended), Some(()));
+        assert_eq!(decoded.write_all(&data[..data.len() - 2]), None);
+        assert_eq!(decoded.write_all(&extended[8..]), None);
+        assert_eq!(decoded, registers);
+
+        assert_eq!(registers.write(7, b"0010000000000000"), Some(()));
+        assert_eq!(registers.rsp, 0x1000);
+        assert_eq!(registers.write(18, b"10000000"), Some(()));
+        assert_eq!(registers.cs, 0x10);
+        assert_eq!(registers.write(18, b"1000"), None);
+        assert_eq!(registers.write(REGISTER_COUNT, b"00000000"), None);
+
+        response.clear();
+
+        assert_eq!(registers.read(REGISTER_COUNT, &mut response), None);
+        assert_eq!(registers.read(17, &mut response), Some(()));
+        assert_eq!(response.data(), Some(&b"02020000"[..]));
+    }
+}
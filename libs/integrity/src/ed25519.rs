@@ -0,0 +1,465 @@
+//! Ed25519 signatures (RFC 8032). Field elements are stored as 16 limbs of 16 bits so all
+//! arithmetic fits in 64 bit integers, which is required by the 32 bit BIOS bootloader.
+//! Signing is not constant time and it should be used only by the host builder.
+
+use crate::sha512::Sha512;
+
+type Field = [i64; 16];
+
+/// Point in extended coordinates (X, Y, Z, T).
+type Point = [Field; 4];
+
+const ZERO: Field = [0; 16];
+const ONE:  Field = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+/// Curve constant d.
+const D: Field = [
+    0x78a3, 0x1359, 0x4dca, 0x75eb, 0xd8ab, 0x4141, 0x0a4d, 0x0070,
+    0xe898, 0x7779, 0x4079, 0x8cc7, 0xfe73, 0x2b6f, 0x6cee, 0x5203,
+];
+
+/// Curve constant 2 * d.
+const D2: Field = [
+    0xf159, 0x26b2, 0x9b94, 0xebd6, 0xb156, 0x8283, 0x149a, 0x00e0,
+    0xd130, 0xeef3, 0x80f2, 0x198e, 0xfce7, 0x56df, 0xd9dc, 0x2406,
+];
+
+/// Coordinates of the base point.
+const BASE_X: Field = [
+    0xd51a, 0x8f25, 0x2d60, 0xc956, 0xa7b2, 0x9525, 0xc760, 0x692c,
+    0xdc5c, 0xfdd6, 0xe231, 0xc0a4, 0x53fe, 0xcd6e, 0x36d3, 0x2169,
+];
+const BASE_Y: Field = [
+    0x6658, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+    0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666, 0x6666,
+];
+
+/// Square root of -1.
+const SQRT_M1: Field = [
+    0xa0b0, 0x4a0e, 0x1b27, 0xc4ee, 0xe478, 0xad2f, 0x1806, 0x2f43,
+    0xd7a7, 0x3dfb, 0x0099, 0x2b4d, 0xdf0b, 0x4fc1, 0x2480, 0x2b83,
+];
+
+/// Order of the base point (little endian).
+const L: [i64; 32] = [
+    0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10,
+];
+
+fn carry(o: &mut Field) {
+    for index in 0..16 {
+        o[index] += 1 << 16;
+
+        let c = o[index] >> 16;
+
+        // 2^256 = 38 (mod 2^255 - 19).
+        if index < 15 {
+            o[index + 1] += c - 1;
+        } else {
+            o[0] += 38 * (c - 1);
+        }
+
+        o[index] -= c << 16;
+    }
+}
+
+/// Swap `p` and `q` if `swap` is set.
+fn select(p: &mut Field, q: &mut Field, swap: bool) {
+    let mask = -(swap as i64);
+
+    for index in 0..16 {
+        let t = mask & (p[index] ^ q[index]);
+
+        p[index] ^= t;
+        q[index] ^= t;
+    }
+}
+
+fn pack_field(n: &Field) -> [u8; 32] {
+    let mut t = *n;
+
+    carry(&mut t);
+    carry(&mut t);
+    carry(&mut t);
+
+    // Subtract the prime twice to get the canonical representation.
+    for _ in 0..2 {
+        let mut m = ZERO;
+
+        m[0] = t[0] - 0xffed;
+
+        for index in 1..15 {
+            m[index]      = t[index] - 0xffff - ((m[index - 1] >> 16) & 1);
+            m[index - 1] &= 0xffff;
+        }
+
+        m[15]  = t[15] - 0x7fff - ((m[14] >> 16) & 1);
+        m[14] &= 0xffff;
+
+        let borrow = (m[15] >> 16) & 1;
+
+        select(&mut t, &mut m, borrow == 0);
+    }
+
+    let mut o = [0u8; 32];
+
+    for index in 0..16 {
+        o[2 * index]     = t[index] as u8;
+        o[2 * index + 1] = (t[index] >> 8) as u8;
+    }
+
+    o
+}
+
+fn unpack_field(n: &[u8; 32]) -> Field {
+    let mut o = ZERO;
+
+    for index in 0..16 {
+        o[index] = n[2 * index] as i64 + ((n[2 * index + 1] as i64) << 8);
+    }
+
+    o[15] &= 0x7fff;
+
+    o
+}
+
+fn fields_equal(a: &Field, b: &Field) -> bool {
+    pack_field(a) == pack_field(b)
+}
+
+fn parity(a: &Field) -> u8 {
+    pack_field(a)[0] & 1
+}
+
+fn add(a: &Field, b: &Field) -> Field {
+    let mut o = ZERO;
+
+    for index in 0..16 {
+        o[index] = a[index] + b[index];
+    }
+
+    o
+}
+
+fn sub(a: &Field, b: &Field) -> Field {
+    let mut o = ZERO;
+
+    for index in 0..16 {
+        o[index] = a[index] - b[index];
+    }
+
+    o
+}
+
+fn mul(a: &Field, b: &Field) -> Field {
+    let mut t = [0i64; 31];
+
+    for i in 0..16 {
+        for j in 0..16 {
+            t[i + j] += a[i] * b[j];
+        }
+    }
+
+    for index in 0..15 {
+        t[index] += 38 * t[index + 16];
+    }
+
+    let mut o = ZERO;
+
+    o.copy_from_slice(&t[..16]);
+
+    carry(&mut o);
+    carry(&mut o);
+
+    o
+}
+
+fn square(a: &Field) -> Field {
+    mul(a, a)
+}
+
+/// Calculate a^(p - 2) which is the inverse of a.
+fn invert(a: &Field) -> Field {
+    let mut c = *a;
+
+    for bit in (0..=253).rev() {
+        c = square(&c);
+
+        if bit != 2 && bit != 4 {
+            c = mul(&c, a);
+        }
+    }
+
+    c
+}
+
+/// Calculate a^((p - 5) / 8).
+fn pow2523(a: &Field) -> Field {
+    let mut c = *a;
+
+    for bit in (0..=250).rev() {
+        c = square(&c);
+
+        if bit != 1 {
+            c = mul(&c, a);
+        }
+    }
+
+    c
+}
+
+fn point_add(p: &mut Point, q: &Point) {
+    let a = mul(&sub(&p[1], &p[0]), &sub(&q[1], &q[0]));
+    let b = mul(&add(&p[0], &p[1]), &add(&q[0], &q[1]));
+    let c = mul(&mul(&p[3], &q[3]), &D2);
+    let d = mul(&p[2], &q[2]);
+    let d = add(&d, &d);
+
+    let e = sub(&b, &a);
+    let f = sub(&d, &c);
+    let g = add(&d, &c);
+    let h = add(&b, &a);
+
+    p[0] = mul(&e, &f);
+    p[1] = mul(&h, &g);
+    p[2] = mul(&g, &f);
+    p[3] = mul(&e, &h);
+}
+
+fn point_swap(p: &mut Point, q: &mut Point, swap: bool) {
+    for (p, q) in p.iter_mut().zip(q.iter_mut()) {
+        select(p, q, swap);
+    }
+}
+
+fn pack_point(p: &Point) -> [u8; 32] {
+    let zi = invert(&p[2]);
+    let x  = mul(&p[0], &zi);
+    let y  = mul(&p[1], &zi);
+
+    let mut o = pack_field(&y);
+
+    o[31] ^= parity(&x) << 7;
+
+    o
+}
+
+/// Calculate s * q, `s` is a little endian scalar.
+fn scalar_mul(q: &Point, s: &[u8; 32]) -> Point {
+    let mut p = [ZERO, ONE, ONE, ZERO];
+    let mut q = *q;
+
+    for bit in (0..256).rev() {
+        let swap = (s[bit / 8] >> (bit & 7)) & 1 == 1;
+
+        point_swap(&mut p, &mut q, swap);
+
+        let current = p;
+
+        point_add(&mut q, &current);
+        point_add(&mut p, &current);
+
+        point_swap(&mut p, &mut q, swap);
+    }
+
+    p
+}
+
+fn scalar_mul_base(s: &[u8; 32]) -> Point {
+    let base = [BASE_X, BASE_Y, ONE, mul(&BASE_X, &BASE_Y)];
+
+    scalar_mul(&base, s)
+}
+
+/// Decode point `p` and negate it. Returns `None` if `p` is not a valid point.
+fn unpack_negated_point(p: &[u8; 32]) -> Option<Point> {
+    let y = unpack_field(p);
+    let z = ONE;
+
+    // x^2 = (y^2 - 1) / (d * y^2 + 1).
+    let num = square(&y);
+    let den = mul(&num, &D);
+    let num = sub(&num, &z);
+    let den = add(&z, &den);
+
+    let den2 = square(&den);
+    let den4 = square(&den2);
+    let den6 = mul(&den4, &den2);
+
+    let t = mul(&mul(&den6, &num), &den);
+    let t = mul(&mul(&mul(&pow2523(&t), &num), &den), &den);
+
+    let mut x = mul(&t, &den);
+
+    if !fields_equal(&mul(&square(&x), &den), &num) {
+        x = mul(&x, &SQRT_M1);
+    }
+
+    if !fields_equal(&mul(&square(&x), &den), &num) {
+        return None;
+    }
+
+    if parity(&x) == p[31] >> 7 {
+        x = sub(&ZERO, &x);
+    }
+
+    let t = mul(&x, &y);
+
+    Some([x, y, z, t])
+}
+
+/// Reduce 512 bit number `x` modulo L.
+fn reduce_wide(x: &mut [i64; 64]) -> [u8; 32] {
+    for i in (32..64).rev() {
+        let mut carry = 0;
+        let mut j     = i - 32;
+
+        while j < i - 12 {
+            x[j] += carry - 16 * x[i] * L[j - (i - 32)];
+            carry = (x[j] + 128) >> 8;
+            x[j] -= carry << 8;
+            j    += 1;
+        }
+
+        x[j] += carry;
+        x[i]  = 0;
+    }
+
+    let mut carry = 0;
+
+    for j in 0..32 {
+        x[j] += carry - (x[31] >> 4) * L[j];
+        carry = x[j] >> 8;
+        x[j] &= 255;
+    }
+
+    for j in 0..32 {
+        x[j] -= carry * L[j];
+    }
+
+    let mut r = [0u8; 32];
+
+    for i in 0..32 {
+        x[i + 1] += x[i] >> 8;
+        r[i]      = x[i] as u8;
+    }
+
+    r
+}
+
+fn reduce(hash: &[u8; 64]) -> [u8; 32] {
+    let mut x = [0i64; 64];
+
+    for (x, &byte) in x.iter_mut().zip(hash.iter()) {
+        *x = byte as i64;
+    }
+
+    reduce_wide(&mut x)
+}
+
+/// Check if little endian scalar `s` is smaller than L.
+fn is_canonical_scalar(s: &[u8; 32]) -> bool {
+    for index in (0..32).rev() {
+        let (s, l) = (s[index] as i64, L[index]);
+
+        if s != l {
+            return s < l;
+        }
+    }
+
+    false
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 64] {
+    let mut hasher = Sha512::new();
+
+    for part in parts {
+        hasher.update(part);
+    }
+
+    hasher.finish()
+}
+
+/// Expand 32 byte secret key into the clamped scalar and the nonce prefix.
+fn expand_secret_key(secret_key: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let digest = hash(&[secret_key]);
+
+    let mut scalar = [0u8; 32];
+    let mut prefix = [0u8; 32];
+
+    scalar.copy_from_slice(&digest[..32]);
+    prefix.copy_from_slice(&digest[32..]);
+
+    scalar[0]  &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+
+    (scalar, prefix)
+}
+
+/// Get public key which corresponds to 32 byte `secret_key`.
+pub fn public_key(secret_key: &[u8; 32]) -> [u8; 32] {
+    let (scalar, _) = expand_secret_key(secret_key);
+
+    pack_point(&scalar_mul_base(&scalar))
+}
+
+/// Sign `message` using 32 byte `secret_key`.
+pub fn sign(secret_key: &[u8; 32], message: &[u8]) -> [u8; 64] {
+    let (scalar, prefix) = expand_secret_key(secret_key);
+    let public_key       = pack_point(&scalar_mul_base(&scalar));
+
+    let r      = reduce(&hash(&[&prefix, message]));
+    let r_enc  = pack_point(&scalar_mul_base(&r));
+    let h      = reduce(&hash(&[&r_enc, &public_key, message]));
+
+    // S = r + h * scalar (mod L).
+    let mut x = [0i64; 64];
+
+    for i in 0..32 {
+        x[i] = r[i] as i64;
+    }
+
+    for i in 0..32 {
+        for j in 0..32 {
+            x[i + j] += h[i] as i64 * scalar[j] as i64;
+        }
+    }
+
+    let s = reduce_wide(&mut x);
+
+    let mut signature = [0u8; 64];
+
+    signature[..32].copy_from_slice(&r_enc);
+    signature[32..].copy_from_slice(&s);
+
+    signature
+}
+
+/// Verify Ed25519 `signature` of `message` created with key pair of `public_key`.
+pub fn verify(public_key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    let mut r_enc = [0u8; 32];
+    let mut s     = [0u8; 32];
+
+    r_enc.copy_from_slice(&signature[..32]);
+    s.copy_from_slice(&signature[32..]);
+
+    // Reject malleable signatures.
+    if !is_canonical_scalar(&s) {
+        return false;
+    }
+
+    let negated_a = match unpack_negated_point(public_key) {
+        Some(point) => point,
+        None        => return false,
+    };
+
+    let h = reduce(&hash(&[&r_enc, public_key, message]));
+
+    // Check if R = S * B - h * A.
+    let mut p = scalar_mul(&negated_a, &h);
+
+    point_add(&mut p, &scalar_mul_base(&s));
+
+    pack_point(&p) == r_enc
+}
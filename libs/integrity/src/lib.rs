@@ -0,0 +1,242 @@
+#![no_std]
+
+mod sha256;
+mod sha512;
+pub mod ed25519;
+
+pub use sha256::{Sha256, sha256};
+
+/// Name of the environment variable used to compile the public key into the bootloaders.
+/// It's a macro because `option_env!` requires a literal.
+#[macro_export]
+macro_rules! public_key_env {
+    () => { "FLUGZEUG_PUBLIC_KEY" };
+}
+
+/// Value of `public_key_env!` for code which doesn't need a literal.
+pub const PUBLIC_KEY_ENV: &str = public_key_env!();
+
+/// Magic value which starts every boot manifest.
+pub const MANIFEST_MAGIC: [u8; 8] = *b"FLZMNFST";
+
+/// Size of the serialized boot manifest.
+pub const MANIFEST_SIZE: usize = core::mem::size_of::<Manifest>();
+
+/// Size of the manifest part which is covered by the signature.
+const SIGNED_SIZE: usize = MANIFEST_SIZE - 64;
+
+/// SHA-256 digests of all boot payloads, optionally signed with Ed25519 key. The same
+/// layout is used in memory and on disk.
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Manifest {
+    /// This needs to be equal to `MANIFEST_MAGIC`, otherwise manifest is invalid.
+    pub magic: [u8; 8],
+
+    /// SHA-256 digest of the kernel ELF image (after decompression).
+    pub kernel: [u8; 32],
+
+    /// SHA-256 digest of the kernel command line (empty if there is no command line).
+    pub cmdline: [u8; 32],
+
+    /// SHA-256 digest of the initial ramdisk (empty if there is no initial ramdisk).
+    pub initrd: [u8; 32],
+
+    /// Ed25519 signature of all previous fields. All zeroes if the manifest is not signed.
+    pub signature: [u8; 64],
+}
+
+impl Manifest {
+    /// Create unsigned manifest which describes given payloads.
+    pub fn new(kernel: &[u8], cmdline: &[u8], initrd: &[u8]) -> Self {
+        Self {
+            magic:     MANIFEST_MAGIC,
+            kernel:    sha256(kernel),
+            cmdline:   sha256(cmdline),
+            initrd:    sha256(initrd),
+            signature: [0; 64],
+        }
+    }
+
+    /// Parse serialized manifest. Returns `None` if the data is not a valid manifest.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != MANIFEST_SIZE || bytes[..8] != MANIFEST_MAGIC {
+            return None;
+        }
+
+        let mut manifest = Self::new(&[], &[], &[]);
+
+        manifest.kernel.copy_from_slice(&bytes[8..40]);
+        manifest.cmdline.copy_from_slice(&bytes[40..72]);
+        manifest.initrd.copy_from_slice(&bytes[72..104]);
+        manifest.signature.copy_from_slice(&bytes[104..168]);
+
+        Some(manifest)
+    }
+
+    pub fn to_bytes(&self) -> [u8; MANIFEST_SIZE] {
+        let mut bytes = [0u8; MANIFEST_SIZE];
+
+        bytes[..8].copy_from_slice(&self.magic);
+        bytes[8..40].copy_from_slice(&self.kernel);
+        bytes[40..72].copy_from_slice(&self.cmdline);
+        bytes[72..104].copy_from_slice(&self.initrd);
+        bytes[104..168].copy_from_slice(&self.signature);
+
+        bytes
+    }
+
+    fn signed_bytes(&self) -> [u8; SIGNED_SIZE] {
+        let mut bytes = [0u8; SIGNED_SIZE];
+
+        bytes.copy_from_slice(&self.to_bytes()[..SIGNED_SIZE]);
+
+        bytes
+    }
+
+    /// Sign the manifest using 32 byte Ed25519 `secret_key`.
+    pub fn sign(&mut self, secret_key: &[u8; 32]) {
+        self.signature = ed25519::sign(secret_key, &self.signed_bytes());
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.signature.iter().any(|&byte| byte != 0)
+    }
+
+    /// Check if the manifest is signed with the key pair of `public_key`.
+    pub fn verify(&self, public_key: &[u8; 32]) -> bool {
+        self.magic == MANIFEST_MAGIC &&
+            ed25519::verify(public_key, &self.signed_bytes(), &self.signature)
+    }
+}
+
+/// Parse 32 byte key encoded as 64 hex digits. Surrounding whitespace is ignored.
+pub fn parse_key(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim().as_bytes();
+
+    if hex.len() != 64 {
+        return None;
+    }
+
+    let mut key = [0u8; 32];
+
+    for (byte, digits) in key.iter_mut().zip(hex.chunks_exact(2)) {
+        let digits = core::str::from_utf8(digits).ok()?;
+
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(hex: &str) -> [u8; 32] {
+        parse_key(hex).unwrap()
+    }
+
+    #[test]
+    fn sha256_vectors() {
+        assert_eq!(sha256(b""),
+                   hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"));
+        assert_eq!(sha256(b"abc"),
+                   hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"));
+        assert_eq!(sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+                   hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"));
+    }
+
+    #[test]
+    fn sha256_incremental() {
+        let data = [0x5au8; 1000];
+
+        let mut hasher = Sha256::new();
+
+        for chunk in data.chunks(7) {
+            hasher.update(chunk);
+        }
+
+        assert_eq!(hasher.finish(), sha256(&data));
+    }
+
+    #[test]
+    fn ed25519_rfc8032_vectors() {
+        // Test 1 and test 2 from RFC 8032 section 7.1.
+        let vectors: [(&str, &str, &[u8], &str); 2] = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                b"",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+                 5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                &[0x72],
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da\
+                 085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+        ];
+
+        for &(secret_key, public_key, message, signature) in &vectors {
+            let secret_key = hex(secret_key);
+            let public_key = hex(public_key);
+
+            let mut expected = [0u8; 64];
+
+            expected[..32].copy_from_slice(&hex(&signature[..64]));
+            expected[32..].copy_from_slice(&hex(signature[64..].trim()));
+
+            assert_eq!(ed25519::public_key(&secret_key), public_key);
+            assert_eq!(&ed25519::sign(&secret_key, message)[..], &expected[..]);
+            assert!(ed25519::verify(&public_key, message, &expected));
+        }
+    }
+
+    #[test]
+    fn ed25519_rejects_modified_data() {
+        let secret_key = [7u8; 32];
+        let public_key = ed25519::public_key(&secret_key);
+        let signature  = ed25519::sign(&secret_key, b"kernel");
+
+        assert!(ed25519::verify(&public_key, b"kernel", &signature));
+        assert!(!ed25519::verify(&public_key, b"kernal", &signature));
+
+        let mut modified = signature;
+
+        modified[40] ^= 1;
+
+        assert!(!ed25519::verify(&public_key, b"kernel", &modified));
+        assert!(!ed25519::verify(&ed25519::public_key(&[8; 32]), b"kernel", &signature));
+    }
+
+    #[test]
+    fn manifest() {
+        let secret_key = [1u8; 32];
+        let public_key = ed25519::public_key(&secret_key);
+
+        let mut manifest = Manifest::new(b"kernel", b"", b"initrd");
+
+        assert!(!manifest.is_signed());
+        assert!(!manifest.verify(&public_key));
+
+        manifest.sign(&secret_key);
+
+        assert!(manifest.is_signed());
+        assert!(manifest.verify(&public_key));
+
+        let parsed = Manifest::from_bytes(&manifest.to_bytes()).unwrap();
+
+        assert_eq!(parsed, manifest);
+        assert_eq!(MANIFEST_SIZE, 168);
+
+        let mut tampered = parsed;
+
+        tampered.initrd = sha256(b"other");
+
+        assert!(!tampered.verify(&public_key));
+        assert!(Manifest::from_bytes(&[0; MANIFEST_SIZE]).is_none());
+    }
+}
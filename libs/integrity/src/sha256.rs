@@ -0,0 +1,149 @@
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const BLOCK_SIZE: usize = 64;
+
+/// Incremental SHA-256 hasher.
+#[derive(Clone)]
+pub struct Sha256 {
+    state:  [u32; 8],
+    block:  [u8; BLOCK_SIZE],
+    used:   usize,
+    length: u64,
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self {
+            state:  INITIAL_STATE,
+            block:  [0; BLOCK_SIZE],
+            used:   0,
+            length: 0,
+        }
+    }
+
+    fn compress(state: &mut [u32; 8], block: &[u8]) {
+        let mut w = [0u32; 64];
+
+        for (index, word) in block.chunks_exact(4).enumerate() {
+            w[index] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+
+        for index in 16..64 {
+            let s0 = w[index - 15].rotate_right(7) ^ w[index - 15].rotate_right(18) ^
+                     (w[index - 15] >> 3);
+            let s1 = w[index - 2].rotate_right(17) ^ w[index - 2].rotate_right(19) ^
+                     (w[index - 2] >> 10);
+
+            w[index] = w[index - 16].wrapping_add(s0)
+                .wrapping_add(w[index - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+
+        for index in 0..64 {
+            let s1    = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch    = (e & f) ^ (!e & g);
+            let temp1 = h.wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[index])
+                .wrapping_add(w[index]);
+            let s0    = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj   = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+
+        for (state, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *state = state.wrapping_add(*value);
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length = self.length.wrapping_add(data.len() as u64);
+
+        // Fill partially used block first.
+        if self.used > 0 {
+            let size = (BLOCK_SIZE - self.used).min(data.len());
+
+            self.block[self.used..][..size].copy_from_slice(&data[..size]);
+            self.used += size;
+            data       = &data[size..];
+
+            if self.used < BLOCK_SIZE {
+                return;
+            }
+
+            Self::compress(&mut self.state, &self.block);
+
+            self.used = 0;
+        }
+
+        let mut blocks = data.chunks_exact(BLOCK_SIZE);
+
+        for block in &mut blocks {
+            Self::compress(&mut self.state, block);
+        }
+
+        let remainder = blocks.remainder();
+
+        self.block[..remainder.len()].copy_from_slice(remainder);
+        self.used = remainder.len();
+    }
+
+    pub fn finish(mut self) -> [u8; 32] {
+        let bit_length = self.length.wrapping_mul(8);
+
+        // Pad the message with a single set bit followed by zeroes and message length.
+        self.update(&[0x80]);
+
+        while self.used != BLOCK_SIZE - 8 {
+            self.update(&[0]);
+        }
+
+        self.update(&bit_length.to_be_bytes());
+
+        let mut digest = [0u8; 32];
+
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state.iter()) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+
+        digest
+    }
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Calculate SHA-256 digest of the data.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+
+    hasher.update(data);
+    hasher.finish()
+}
@@ -0,0 +1,41 @@
+use proc_macro::{TokenStream, TokenTree};
+
+/// Register function as a kernel test. Test function must have signature `fn()` and the
+/// kernel must be built with `kernel_tests` feature. Registered tests are placed in
+/// `kernel_tests` linker section and are collected by `kernel/src/tests.rs`.
+#[proc_macro_attribute]
+pub fn kernel_test(attribute: TokenStream, item: TokenStream) -> TokenStream {
+    assert!(attribute.is_empty(), "`kernel_test` doesn't take any arguments.");
+
+    let mut tokens = item.clone().into_iter();
+
+    // Find the function name which is the identifier right after `fn` keyword.
+    let name = loop {
+        match tokens.next() {
+            Some(TokenTree::Ident(ident)) if ident.to_string() == "fn" => {
+                match tokens.next() {
+                    Some(TokenTree::Ident(name)) => break name.to_string(),
+                    _                            => panic!("Expected function name."),
+                }
+            }
+            Some(_) => continue,
+            None    => panic!("`kernel_test` can be only applied to functions."),
+        }
+    };
+
+    let registration = format!(r#"
+        #[used]
+        #[link_section = "kernel_tests"]
+        static __KERNEL_TEST_{static_name}: crate::tests::KernelTest = crate::tests::KernelTest {{
+            name:     concat!(module_path!(), "::", "{name}"),
+            function: {name},
+        }};
+    "#, static_name = name.to_uppercase(), name = name);
+
+    let mut output = item;
+
+    output.extend(registration.parse::<TokenStream>()
+                  .expect("Failed to parse generated kernel test registration."));
+
+    output
+}
@@ -16,6 +16,11 @@ pub trait Interrupts {
 
     unsafe fn disable_interrupts();
     unsafe fn enable_interrupts();
+
+    /// Called repeatedly while waiting for a lock which is held by another core.
+    fn spin_wait() {
+        core::hint::spin_loop();
+    }
 }
 
 #[repr(C)]
@@ -89,7 +94,7 @@ impl<T: ?Sized, I: Interrupts> Lock<T, I> {
         while self.locked.compare_exchange_weak(false, true, Ordering::Acquire,
                                                 Ordering::Relaxed).is_err() {
             while self.is_locked() {
-                core::hint::spin_loop();
+                I::spin_wait();
             }
         }
 
@@ -0,0 +1,357 @@
+#![no_std]
+
+//! LZ4 block format compression and a minimal header used to store compressed kernels.
+//! Decompression validates all lengths and offsets so it's safe to use on untrusted data.
+
+use core::convert::TryInto;
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = 0xffff;
+
+/// Last 5 bytes are always literals and the last match must start at least 12 bytes
+/// before the end of input. Required by the block format specification.
+const LAST_LITERALS: usize = 5;
+const MATCH_FIND_LIMIT: usize = 12;
+
+const HASH_BITS: usize = 12;
+
+/// Magic value which starts every header.
+pub const MAGIC: [u8; 8] = *b"FLZLZ4\0\x01";
+
+/// Size of the serialized header.
+pub const HEADER_SIZE: usize = 56;
+
+/// Header which precedes compressed data.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Header {
+    /// Size of the data after decompression.
+    pub uncompressed_size: u64,
+
+    /// Size of the compressed data which follows the header.
+    pub compressed_size: u64,
+
+    /// SHA-256 digest of the compressed data.
+    pub compressed_digest: [u8; 32],
+}
+
+impl Header {
+    /// Create a header which describes `compressed` data.
+    pub fn new(compressed: &[u8], uncompressed_size: usize) -> Self {
+        Self {
+            uncompressed_size: uncompressed_size as u64,
+            compressed_size:   compressed.len() as u64,
+            compressed_digest: integrity::sha256(compressed),
+        }
+    }
+
+    /// Parse header at the start of `data`. Returns the header and compressed data which
+    /// follows it or `None` if `data` doesn't start with a valid header.
+    pub fn parse(data: &[u8]) -> Option<(Self, &[u8])> {
+        if data.len() < HEADER_SIZE || data[..8] != MAGIC {
+            return None;
+        }
+
+        let uncompressed_size = u64::from_le_bytes(data[8..16].try_into().ok()?);
+        let compressed_size   = u64::from_le_bytes(data[16..24].try_into().ok()?);
+
+        let mut compressed_digest = [0u8; 32];
+
+        compressed_digest.copy_from_slice(&data[24..56]);
+
+        let compressed = data.get(HEADER_SIZE..)?
+            .get(..compressed_size.try_into().ok()?)?;
+
+        Some((Self { uncompressed_size, compressed_size, compressed_digest }, compressed))
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+
+        bytes[..8].copy_from_slice(&MAGIC);
+        bytes[8..16].copy_from_slice(&self.uncompressed_size.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.compressed_size.to_le_bytes());
+        bytes[24..56].copy_from_slice(&self.compressed_digest);
+
+        bytes
+    }
+}
+
+/// Check if `data` starts with a header of compressed data.
+pub fn is_compressed(data: &[u8]) -> bool {
+    data.len() >= HEADER_SIZE && data[..8] == MAGIC
+}
+
+/// Get the maximum size of compressed `size` bytes.
+pub fn max_compressed_size(size: usize) -> usize {
+    size + size / 255 + 16
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Write length which didn't fit in the token (it was stored as 15).
+fn write_length(output: &mut [u8], out: &mut usize, mut length: usize) {
+    while length >= 255 {
+        output[*out] = 255;
+        *out        += 1;
+        length      -= 255;
+    }
+
+    output[*out] = length as u8;
+    *out        += 1;
+}
+
+/// Write a sequence: `literals` followed by an optional match (offset, length).
+fn write_sequence(output: &mut [u8], mut out: usize, literals: &[u8],
+                  matched: Option<(usize, usize)>) -> usize {
+    let literal_length = literals.len();
+    let match_length   = matched.map(|(_, length)| length - MIN_MATCH).unwrap_or(0);
+
+    let token = ((literal_length.min(15) as u8) << 4) | match_length.min(15) as u8;
+
+    output[out] = token;
+    out        += 1;
+
+    if literal_length >= 15 {
+        write_length(output, &mut out, literal_length - 15);
+    }
+
+    output[out..out + literal_length].copy_from_slice(literals);
+    out += literal_length;
+
+    if let Some((offset, _)) = matched {
+        output[out..out + 2].copy_from_slice(&(offset as u16).to_le_bytes());
+        out += 2;
+
+        if match_length >= 15 {
+            write_length(output, &mut out, match_length - 15);
+        }
+    }
+
+    out
+}
+
+/// Compress `input` to `output` which must be at least `max_compressed_size` bytes big.
+/// Returns the compressed size.
+pub fn compress(input: &[u8], output: &mut [u8]) -> usize {
+    assert!(output.len() >= max_compressed_size(input.len()), "LZ4 output buffer is too small.");
+
+    let mut table  = [0usize; 1 << HASH_BITS];
+    let mut anchor = 0;
+    let mut pos    = 0;
+    let mut out    = 0;
+
+    if input.len() > MATCH_FIND_LIMIT {
+        let match_limit = input.len() - MATCH_FIND_LIMIT;
+        let end_limit   = input.len() - LAST_LITERALS;
+
+        while pos < match_limit {
+            let sequence  = read_u32(input, pos);
+            let slot      = hash(sequence);
+            let candidate = table[slot];
+
+            table[slot] = pos;
+
+            if candidate < pos && pos - candidate <= MAX_OFFSET &&
+                read_u32(input, candidate) == sequence {
+                let mut length = MIN_MATCH;
+
+                while pos + length < end_limit && input[candidate + length] == input[pos + length] {
+                    length += 1;
+                }
+
+                out = write_sequence(output, out, &input[anchor..pos],
+                                     Some((pos - candidate, length)));
+
+                pos   += length;
+                anchor = pos;
+            } else {
+                pos += 1;
+            }
+        }
+    }
+
+    write_sequence(output, out, &input[anchor..], None)
+}
+
+/// Read length which didn't fit in the token.
+fn read_length(input: &[u8], position: &mut usize) -> Option<usize> {
+    let mut length = 0usize;
+
+    loop {
+        let byte = *input.get(*position)?;
+
+        *position += 1;
+        length     = length.checked_add(byte as usize)?;
+
+        if byte != 255 {
+            return Some(length);
+        }
+    }
+}
+
+/// Decompress `input` to `output`. Decompressed data must fill the whole `output`. Returns
+/// `None` if the input is malformed.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Option<()> {
+    let mut position = 0usize;
+    let mut out      = 0usize;
+
+    loop {
+        let token = *input.get(position)?;
+
+        position += 1;
+
+        let mut literal_length = (token >> 4) as usize;
+
+        if literal_length == 15 {
+            literal_length += read_length(input, &mut position)?;
+        }
+
+        let literals = input.get(position..position.checked_add(literal_length)?)?;
+
+        output.get_mut(out..out.checked_add(literal_length)?)?.copy_from_slice(literals);
+
+        position += literal_length;
+        out      += literal_length;
+
+        // The last sequence contains only literals.
+        if position == input.len() {
+            break;
+        }
+
+        let offset = u16::from_le_bytes(input.get(position..position + 2)?.try_into().ok()?)
+            as usize;
+
+        position += 2;
+
+        if offset == 0 || offset > out {
+            return None;
+        }
+
+        let mut match_length = (token & 0xf) as usize + MIN_MATCH;
+
+        if token & 0xf == 0xf {
+            match_length = match_length.checked_add(read_length(input, &mut position)?)?;
+        }
+
+        if out.checked_add(match_length)? > output.len() {
+            return None;
+        }
+
+        let source = out - offset;
+
+        if offset >= match_length {
+            output.copy_within(source..source + match_length, out);
+        } else {
+            // Overlapping match repeats the last `offset` bytes.
+            for index in 0..match_length {
+                output[out + index] = output[source + index];
+            }
+        }
+
+        out += match_length;
+    }
+
+    if out == output.len() {
+        Some(())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+    use std::vec;
+
+    fn roundtrip(data: &[u8]) -> usize {
+        let mut compressed = vec![0u8; max_compressed_size(data.len())];
+        let size           = compress(data, &mut compressed);
+
+        let mut decompressed = vec![0u8; data.len()];
+
+        assert_eq!(decompress(&compressed[..size], &mut decompressed), Some(()));
+        assert_eq!(decompressed, data);
+
+        size
+    }
+
+    fn pseudo_random(size: usize) -> Vec<u8> {
+        let mut state = 0x1234_5678u32;
+
+        (0..size).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+
+            state as u8
+        }).collect()
+    }
+
+    #[test]
+    fn roundtrips() {
+        roundtrip(b"");
+        roundtrip(b"a");
+        roundtrip(b"abcdefghijklmnop");
+        roundtrip(&pseudo_random(100_000));
+
+        assert!(roundtrip(&[0u8; 100_000]) < 1000);
+        assert!(roundtrip(&b"flugzeug ".repeat(10_000)) < 1000);
+
+        // Mix of compressible and incompressible data.
+        let mut mixed = pseudo_random(5000);
+
+        mixed.extend_from_slice(&[7u8; 5000]);
+        mixed.extend_from_slice(&pseudo_random(300));
+        mixed.extend_from_slice(&mixed.clone());
+
+        roundtrip(&mixed);
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let data = b"flugzeug ".repeat(100);
+
+        let mut compressed = vec![0u8; max_compressed_size(data.len())];
+        let size           = compress(&data, &mut compressed);
+        let compressed     = &compressed[..size];
+
+        let mut output = vec![0u8; data.len()];
+
+        // Truncated input, wrong output size and invalid offset.
+        assert_eq!(decompress(&compressed[..size - 1], &mut output), None);
+        assert_eq!(decompress(compressed, &mut output[..data.len() - 1]), None);
+        assert_eq!(decompress(&[0x04, b'a', 0x02, 0x00], &mut output), None);
+        assert_eq!(decompress(&[], &mut output), None);
+
+        let mut large = vec![0u8; data.len() + 1];
+
+        assert_eq!(decompress(compressed, &mut large), None);
+    }
+
+    #[test]
+    fn header() {
+        let mut blob = Header::new(b"data", 100).to_bytes().to_vec();
+
+        blob.extend_from_slice(b"data");
+        blob.extend_from_slice(b"padding");
+
+        let (header, compressed) = Header::parse(&blob).unwrap();
+
+        assert!(is_compressed(&blob));
+        assert_eq!(header.uncompressed_size, 100);
+        assert_eq!(compressed, b"data");
+        assert_eq!(header.compressed_digest, integrity::sha256(b"data"));
+
+        assert!(Header::parse(&blob[..HEADER_SIZE + 2]).is_none());
+        assert!(!is_compressed(b"\x7fELF"));
+    }
+}
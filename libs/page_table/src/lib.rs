@@ -71,9 +71,18 @@ pub trait PhysMem {
         panic!("Freeing is not supported.")
     }
 
+    /// Invalidate TLB entry for `virt_addr` after its page table entry was changed. Changes
+    /// are not guaranteed to be visible everywhere until `flush_tlb` is called.
     unsafe fn invalidate_tlb(&mut self, virt_addr: VirtAddr) {
         cpu::invlpg(virt_addr.0 as usize);
     }
+
+    /// Finish invalidations requested by `invalidate_tlb` since the last call. Environments
+    /// with multiple processors can batch invalidated addresses and send them to other
+    /// processors here. `PageTable` calls this at the end of every operation which can
+    /// modify present entries.
+    unsafe fn flush_tlb(&mut self) {
+    }
 }
 
 /// x86 page type. CPU may not support all these page types.
@@ -85,6 +94,25 @@ pub enum PageType {
     Page1G = 1 * 1024 * 1024 * 1024,
 }
 
+/// Calculate page table indices of `virt_addr` for given page type. Returns indices array
+/// and the number of used entries.
+fn page_indices(virt_addr: VirtAddr, page_type: PageType) -> ([u64; 4], usize) {
+    let indices = [
+        (virt_addr.0 >> 39) & 0x1ff,
+        (virt_addr.0 >> 30) & 0x1ff,
+        (virt_addr.0 >> 21) & 0x1ff,
+        (virt_addr.0 >> 12) & 0x1ff,
+    ];
+
+    let levels = match page_type {
+        PageType::Page4K => 4,
+        PageType::Page2M => 3,
+        PageType::Page1G => 2,
+    };
+
+    (indices, levels)
+}
+
 /// Wrapper that allows manipulating x86 page tables. It doesn't own any page tables so
 /// they won't get freed on `Drop`.
 pub struct PageTable {
@@ -212,7 +240,15 @@ impl PageTable {
         add:       bool,
         update:    bool,
     ) -> Option<()> {
-        self.map_raw_internal(phys_mem, virt_addr, page_type, raw, add, update, false)
+        let result = self.map_raw_internal(phys_mem, virt_addr, page_type, raw, add, update,
+                                           false);
+
+        // Updated entry may be cached by other cores.
+        if update {
+            phys_mem.flush_tlb();
+        }
+
+        result
     }
 
     /// Set page table entry value that describes `virt_addr` to `raw`. If `deallocate` flag
@@ -252,32 +288,8 @@ impl PageTable {
             return None;
         }
 
-        let mut indices = [0u64; 4];
-
-        // Calculate virtual address indices for given page type.
-        let indices = match page_type {
-            PageType::Page4K => {
-                indices[0] = (virt_addr.0 >> 39) & 0x1ff;
-                indices[1] = (virt_addr.0 >> 30) & 0x1ff;
-                indices[2] = (virt_addr.0 >> 21) & 0x1ff;
-                indices[3] = (virt_addr.0 >> 12) & 0x1ff;
-
-                &indices[..4]
-            }
-            PageType::Page2M => {
-                indices[0] = (virt_addr.0 >> 39) & 0x1ff;
-                indices[1] = (virt_addr.0 >> 30) & 0x1ff;
-                indices[2] = (virt_addr.0 >> 21) & 0x1ff;
-
-                &indices[..3]
-            }
-            PageType::Page1G => {
-                indices[0] = (virt_addr.0 >> 39) & 0x1ff;
-                indices[1] = (virt_addr.0 >> 30) & 0x1ff;
-
-                &indices[..2]
-            }
-        };
+        let indices = page_indices(virt_addr, page_type);
+        let indices = &indices.0[..indices.1];
 
         let mut table = self.table.0;
 
@@ -335,6 +347,190 @@ impl PageTable {
         unreachable!()
     }
 
+    /// Get a pointer to the last level entry which describes `virt_addr` mapped using
+    /// `page_type`. Returns `Some(None)` if the upper level tables are not present. Returns
+    /// `None` if the address is invalid or the region is mapped using a different page type.
+    unsafe fn leaf_entry(
+        &self,
+        phys_mem:  &mut impl PhysMem,
+        virt_addr: VirtAddr,
+        page_type: PageType,
+    ) -> Option<Option<*mut u64>> {
+        if !virt_addr.is_canonical() || virt_addr.0 & (page_type as u64 - 1) != 0 {
+            return None;
+        }
+
+        let indices = page_indices(virt_addr, page_type);
+        let indices = &indices.0[..indices.1];
+
+        let mut table = self.table.0;
+
+        for (depth, &index) in indices.iter().enumerate() {
+            let entry_ptr = PhysAddr(table + index * U64_SIZE);
+            let entry_ptr = phys_mem.translate(entry_ptr, U64_SIZE as usize)? as *mut u64;
+            let entry     = *entry_ptr;
+
+            if depth == indices.len() - 1 {
+                // Large page entries must have `PAGE_SIZE` bit set, otherwise they point
+                // to the next level table.
+                if entry & PAGE_PRESENT != 0 && page_type != PageType::Page4K &&
+                    entry & PAGE_SIZE == 0 {
+                    return None;
+                }
+
+                return Some(Some(entry_ptr));
+            }
+
+            if entry & PAGE_PRESENT == 0 {
+                return Some(None);
+            }
+
+            if entry & PAGE_SIZE != 0 {
+                // Mapped page type is different than what was specified in `page_type`.
+                return None;
+            }
+
+            // Go to the next level in paging hierarchy.
+            table = entry & 0xffffffffff000;
+        }
+
+        unreachable!()
+    }
+
+    /// Unmap region at `virt_addr` with size `size` which was mapped using `page_type` pages.
+    /// Pages which aren't mapped are skipped. Backing memory of pages created by `map`
+    /// is freed. Page tables are kept even if they become empty.
+    #[must_use]
+    pub unsafe fn unmap(
+        &mut self,
+        phys_mem:  &mut impl PhysMem,
+        virt_addr: VirtAddr,
+        page_type: PageType,
+        size:      u64,
+    ) -> Option<()> {
+        let page_size = page_type as u64;
+
+        // Make sure that size is correctly aligned. Address is checked by `leaf_entry`.
+        if size == 0 || size & (page_size - 1) != 0 {
+            return None;
+        }
+
+        let virt_end = virt_addr.0.checked_add(size - 1)?;
+
+        // Make sure that the whole region uses `page_type` pages before changing anything.
+        for current_virt_addr in (virt_addr.0..=virt_end).step_by(page_size as usize) {
+            self.leaf_entry(phys_mem, VirtAddr(current_virt_addr), page_type)?;
+        }
+
+        // Mark all pages as not present but keep their backing addresses.
+        for current_virt_addr in (virt_addr.0..=virt_end).step_by(page_size as usize) {
+            let current_virt_addr = VirtAddr(current_virt_addr);
+
+            let entry_ptr = match self.leaf_entry(phys_mem, current_virt_addr, page_type)? {
+                Some(entry_ptr) => entry_ptr,
+                None            => continue,
+            };
+
+            if *entry_ptr & PAGE_PRESENT == 0 {
+                continue;
+            }
+
+            *entry_ptr &= !PAGE_PRESENT;
+
+            if current_virt_addr.0 <= usize::MAX as u64 {
+                phys_mem.invalidate_tlb(current_virt_addr);
+            }
+        }
+
+        // Stale TLB entries must be gone (on every core) before the backing pages can
+        // be reused.
+        phys_mem.flush_tlb();
+
+        let mut result = Some(());
+
+        for current_virt_addr in (virt_addr.0..=virt_end).step_by(page_size as usize) {
+            let current_virt_addr = VirtAddr(current_virt_addr);
+
+            let entry_ptr = match self.leaf_entry(phys_mem, current_virt_addr, page_type)? {
+                Some(entry_ptr) => entry_ptr,
+                None            => continue,
+            };
+
+            let entry = *entry_ptr;
+
+            *entry_ptr = 0;
+
+            if entry & DEALLOCATE_FLAG != 0 {
+                // Large page entries use bit 12 for PAT, mask it off.
+                let backing = entry & 0xffffffffff000 & !(page_size - 1);
+
+                // Keep going so all entries are cleared even if freeing fails.
+                if phys_mem.free_phys(PhysAddr(backing), page_size as usize).is_none() {
+                    result = None;
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Change access permissions of region at `virt_addr` with size `size` which is mapped
+    /// using `page_type` pages. Fails without changing anything if some page in the region
+    /// isn't mapped.
+    #[must_use]
+    pub unsafe fn change_protection(
+        &mut self,
+        phys_mem:  &mut impl PhysMem,
+        virt_addr: VirtAddr,
+        page_type: PageType,
+        size:      u64,
+        write:     bool,
+        exec:      bool,
+        user:      bool,
+    ) -> Option<()> {
+        let page_size = page_type as u64;
+
+        // Make sure that size is correctly aligned. Address is checked by `leaf_entry`.
+        if size == 0 || size & (page_size - 1) != 0 {
+            return None;
+        }
+
+        let virt_end = virt_addr.0.checked_add(size - 1)?;
+
+        // Make sure that the whole region is mapped before changing anything.
+        for current_virt_addr in (virt_addr.0..=virt_end).step_by(page_size as usize) {
+            let entry_ptr = self.leaf_entry(phys_mem, VirtAddr(current_virt_addr), page_type)??;
+
+            if *entry_ptr & PAGE_PRESENT == 0 {
+                return None;
+            }
+        }
+
+        for current_virt_addr in (virt_addr.0..=virt_end).step_by(page_size as usize) {
+            let current_virt_addr = VirtAddr(current_virt_addr);
+
+            let entry_ptr = self.leaf_entry(phys_mem, current_virt_addr, page_type)??;
+            let entry     = *entry_ptr;
+
+            let new_entry = (entry & !(PAGE_WRITE | PAGE_NX | PAGE_USER)) |
+                if write { PAGE_WRITE } else { 0       } |
+                if exec  { 0          } else { PAGE_NX } |
+                if user  { PAGE_USER  } else { 0       };
+
+            if new_entry != entry {
+                *entry_ptr = new_entry;
+
+                if current_virt_addr.0 <= usize::MAX as u64 {
+                    phys_mem.invalidate_tlb(current_virt_addr);
+                }
+            }
+        }
+
+        phys_mem.flush_tlb();
+
+        Some(())
+    }
+
     #[must_use]
     pub fn virt_to_phys(
         &self,
@@ -438,3 +634,195 @@ impl PageTable {
         Some(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    /// Physical memory backed by host allocations. Physical addresses are equal to virtual
+    /// addresses of the allocations.
+    #[derive(Default)]
+    struct HostMemory {
+        allocations: Vec<(PhysAddr, Layout)>,
+        freed:       Vec<PhysAddr>,
+        invalidated: Vec<VirtAddr>,
+        flushed:     usize,
+    }
+
+    impl PhysMem for HostMemory {
+        unsafe fn translate(&mut self, phys_addr: PhysAddr, _size: usize) -> Option<*mut u8> {
+            Some(phys_addr.0 as *mut u8)
+        }
+
+        fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr> {
+            let phys_addr = PhysAddr(unsafe { std::alloc::alloc(layout) } as u64);
+
+            self.allocations.push((phys_addr, layout));
+
+            Some(phys_addr)
+        }
+
+        unsafe fn free_phys(&mut self, phys_addr: PhysAddr, _size: usize) -> Option<()> {
+            // Memory can be reused only after all invalidated entries were flushed.
+            assert_eq!(self.flushed, self.invalidated.len(), "Freed memory before TLB flush.");
+
+            self.freed.push(phys_addr);
+
+            Some(())
+        }
+
+        unsafe fn invalidate_tlb(&mut self, virt_addr: VirtAddr) {
+            self.invalidated.push(virt_addr);
+        }
+
+        unsafe fn flush_tlb(&mut self) {
+            self.flushed = self.invalidated.len();
+        }
+    }
+
+    impl Drop for HostMemory {
+        fn drop(&mut self) {
+            for &(phys_addr, layout) in &self.allocations {
+                unsafe {
+                    std::alloc::dealloc(phys_addr.0 as *mut u8, layout);
+                }
+            }
+        }
+    }
+
+    const BASE:       VirtAddr = VirtAddr(0x7f12_3456_0000);
+    const LARGE_BASE: VirtAddr = VirtAddr(0x7f12_3460_0000);
+
+    fn entry(page_table: &PageTable, memory: &mut HostMemory, virt_addr: VirtAddr,
+             page_type: PageType) -> u64 {
+        unsafe { *page_table.leaf_entry(memory, virt_addr, page_type).unwrap().unwrap() }
+    }
+
+    #[test]
+    fn unmap() {
+        let mut memory     = HostMemory::default();
+        let mut page_table = PageTable::new(&mut memory).unwrap();
+
+        page_table.map(&mut memory, BASE, PageType::Page4K, 3 * 4096, true, false, false)
+            .unwrap();
+
+        let middle  = VirtAddr(BASE.0 + 4096);
+        let backing = page_table.virt_to_phys(&mut memory, middle).unwrap();
+
+        unsafe {
+            page_table.unmap(&mut memory, middle, PageType::Page4K, 4096).unwrap();
+        }
+
+        assert_eq!(page_table.virt_to_phys(&mut memory, middle), None);
+        assert!(page_table.virt_to_phys(&mut memory, BASE).is_some());
+        assert!(page_table.virt_to_phys(&mut memory, VirtAddr(BASE.0 + 8192)).is_some());
+        assert_eq!(memory.invalidated, [middle]);
+        assert_eq!(memory.freed, [backing]);
+
+        // Unmapped pages are skipped.
+        unsafe {
+            page_table.unmap(&mut memory, BASE, PageType::Page4K, 3 * 4096).unwrap();
+            page_table.unmap(&mut memory, VirtAddr(0x1000_0000_0000), PageType::Page4K, 4096)
+                .unwrap();
+        }
+
+        assert_eq!(memory.invalidated.len(), 3);
+        assert_eq!(memory.freed.len(), 3);
+        assert_eq!(page_table.virt_to_phys(&mut memory, BASE), None);
+    }
+
+    #[test]
+    fn unmap_large_pages() {
+        let mut memory     = HostMemory::default();
+        let mut page_table = PageTable::new(&mut memory).unwrap();
+
+        let size = PageType::Page2M as u64;
+
+        page_table.map(&mut memory, LARGE_BASE, PageType::Page2M, size, true, false, false)
+            .unwrap();
+
+        unsafe {
+            // Page type must match.
+            assert!(page_table.unmap(&mut memory, LARGE_BASE, PageType::Page4K, 4096)
+                .is_none());
+
+            // Misaligned regions are rejected.
+            assert!(page_table.unmap(&mut memory, LARGE_BASE, PageType::Page2M, 4096).is_none());
+            assert!(page_table.unmap(&mut memory, VirtAddr(LARGE_BASE.0 + 4096),
+                                     PageType::Page2M, size).is_none());
+
+            page_table.unmap(&mut memory, LARGE_BASE, PageType::Page2M, size).unwrap();
+        }
+
+        assert_eq!(page_table.virt_to_phys(&mut memory, LARGE_BASE), None);
+        assert_eq!(memory.freed.len(), 1);
+        assert_eq!(memory.freed[0].0 & (size - 1), 0);
+    }
+
+    #[test]
+    fn change_protection() {
+        let mut memory     = HostMemory::default();
+        let mut page_table = PageTable::new(&mut memory).unwrap();
+
+        page_table.map(&mut memory, BASE, PageType::Page4K, 2 * 4096, true, false, false)
+            .unwrap();
+
+        let second = VirtAddr(BASE.0 + 4096);
+        let before = entry(&page_table, &mut memory, second, PageType::Page4K);
+
+        unsafe {
+            // Region which is not fully mapped is rejected without any changes.
+            assert!(page_table.change_protection(&mut memory, BASE, PageType::Page4K, 3 * 4096,
+                                                 false, true, true).is_none());
+        }
+
+        assert_eq!(entry(&page_table, &mut memory, second, PageType::Page4K), before);
+        assert!(memory.invalidated.is_empty());
+
+        unsafe {
+            page_table.change_protection(&mut memory, BASE, PageType::Page4K, 2 * 4096,
+                                         false, true, true).unwrap();
+        }
+
+        let after = entry(&page_table, &mut memory, second, PageType::Page4K);
+
+        assert_eq!(after & (PAGE_WRITE | PAGE_NX | PAGE_USER), PAGE_USER);
+        assert_eq!(after & !(PAGE_WRITE | PAGE_NX | PAGE_USER),
+                   before & !(PAGE_WRITE | PAGE_NX | PAGE_USER));
+        assert_eq!(memory.invalidated, [BASE, second]);
+
+        // Nothing changes, so nothing needs to be flushed.
+        unsafe {
+            page_table.change_protection(&mut memory, BASE, PageType::Page4K, 2 * 4096,
+                                         false, true, true).unwrap();
+        }
+
+        assert_eq!(memory.invalidated.len(), 2);
+        assert_eq!(memory.flushed, 2);
+    }
+
+    #[test]
+    fn updates_are_flushed() {
+        let mut memory     = HostMemory::default();
+        let mut page_table = PageTable::new(&mut memory).unwrap();
+
+        let raw = 0x1234_5000 | PAGE_PRESENT;
+
+        unsafe {
+            page_table.map_raw(&mut memory, BASE, PageType::Page4K, raw, true, false).unwrap();
+
+            // New entries are not cached so there is nothing to flush.
+            assert!(memory.invalidated.is_empty());
+
+            page_table.map_raw(&mut memory, BASE, PageType::Page4K, raw | PAGE_WRITE, false,
+                               true).unwrap();
+        }
+
+        assert_eq!(memory.invalidated, [BASE]);
+        assert_eq!(memory.flushed, 1);
+        assert_eq!(entry(&page_table, &mut memory, BASE, PageType::Page4K), raw | PAGE_WRITE);
+    }
+}
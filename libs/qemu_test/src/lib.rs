@@ -0,0 +1,20 @@
+#![no_std]
+
+// Protocol between the kernel and the host test runner. The kernel reports results using
+// the serial port and exits QEMU using `isa-debug-exit` device.
+
+/// I/O port of QEMU `isa-debug-exit` device used by the host test runner.
+pub const QEMU_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Values written to `isa-debug-exit` device. QEMU will exit with status `(code << 1) | 1`.
+pub const QEMU_EXIT_SUCCESS: u32 = 0x10;
+pub const QEMU_EXIT_FAILURE: u32 = 0x11;
+
+/// Marker printed to the serial port when the kernel has finished booting successfully.
+pub const BOOT_SUCCESS_MARKER: &str = "[flugzeug-test] boot successful";
+
+/// Start of the message printed when the kernel panics.
+pub const PANIC_MARKER: &str = "Kernel panic on";
+
+/// Marker printed to the serial port before the name of a failed kernel test.
+pub const KERNEL_TEST_FAIL_MARKER: &str = "[kernel_test] fail name=";
@@ -44,6 +44,11 @@ impl RangeSet {
         &self.ranges[..self.used as usize]
     }
 
+    /// Get the number of ranges which can be still added to this `RangeSet`.
+    pub fn free_entries(&self) -> usize {
+        self.ranges.len() - self.used as usize
+    }
+
     pub fn clear(&mut self) {
         self.used = 0;
     }
@@ -140,6 +145,14 @@ impl RangeSet {
 
     pub fn allocate_limited(&mut self, size: u64, align: u64, max_address: Option<u64>)
         -> Option<usize> 
+    {
+        self.allocate_in_range(size, align, 0, max_address)
+    }
+
+    /// Allocate `size` bytes aligned to `align` which start at or above `min_address` and end
+    /// at or below `max_address`.
+    pub fn allocate_in_range(&mut self, size: u64, align: u64, min_address: u64,
+                             max_address: Option<u64>) -> Option<usize>
     {
         #[derive(Copy, Clone)]
         struct Candidate {
@@ -170,9 +183,16 @@ impl RangeSet {
 
         // Try to find the best region for new allocation.
         for idx in 0..self.used as usize {
-            let current     = self.ranges[idx];
+            let mut current = self.ranges[idx];
             let region_size = current.size();
 
+            // Skip the part of this region which is below minimum allocation address.
+            if current.end < min_address {
+                continue;
+            }
+
+            current.start = current.start.max(min_address);
+
             // Calculate the amount of bytes required for front padding to satifsy
             // alignment requirements.
             let padding = (align - (current.start & align_mask)) & align_mask;
@@ -348,4 +368,20 @@ mod tests {
 
         panic!("Done!");
     }
+
+    #[test]
+    fn allocate_in_range_test() {
+        let mut rs = RangeSet::new();
+
+        rs.insert(Range { start: 0x1000, end: 0x4fff });
+        rs.insert(Range { start: 0x8000, end: 0xffff });
+
+        assert_eq!(rs.allocate_in_range(0x1000, 0x1000, 0x2000, Some(0x3fff)), Some(0x2000));
+        assert_eq!(rs.allocate_in_range(0x2000, 0x1000, 0x9000, None), Some(0x9000));
+        assert_eq!(rs.allocate_in_range(0x1000, 0x1000, 0x10000, None), None);
+
+        // Parts of regions below the minimum address stay free.
+        assert_eq!(rs.allocate_limited(0x1000, 0x1000, Some(0x1fff)), Some(0x1000));
+        assert_eq!(rs.allocate_limited(0x2000, 0x1000, Some(0x4fff)), Some(0x3000));
+    }
 }
@@ -3,30 +3,328 @@
 
 // Everything here must be exactly the same in 32 bit mode and 64 bit mode.
 
-/// Port address for COM1.
-const COM1: u16 = 0x3f8;
+/// Baud rate used by the bootloaders and by default by the kernel.
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// Size of the receive and transmit buffers.
+pub const BUFFER_SIZE: usize = 1024;
+
+/// UART input clock divided by 16. Divisor latch value is `MAX_BAUD_RATE / baud rate`.
+const MAX_BAUD_RATE: u32 = 115_200;
+
+/// Number of bytes which can be written to the transmit FIFO at once.
+const FIFO_SIZE: usize = 16;
+
+// UART registers (offsets from the base I/O port).
+const DATA:                  u16 = 0;
+const INTERRUPT_ENABLE:      u16 = 1;
+const INTERRUPT_ID:          u16 = 2;
+const FIFO_CONTROL:          u16 = 2;
+const LINE_CONTROL:          u16 = 3;
+const MODEM_CONTROL:         u16 = 4;
+const LINE_STATUS:           u16 = 5;
+const MODEM_STATUS:          u16 = 6;
+const SCRATCH:               u16 = 7;
+
+const IER_RX_AVAILABLE:   u8 = 1 << 0;
+const IER_TX_EMPTY:       u8 = 1 << 1;
+const LSR_DATA_READY:     u8 = 1 << 0;
+const LSR_TX_EMPTY:       u8 = 1 << 5;
+const IIR_NO_INTERRUPT:   u8 = 1 << 0;
+
+/// Standard PC serial port.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ComPort {
+    Com1,
+    Com2,
+    Com3,
+    Com4,
+}
+
+impl ComPort {
+    /// Parse port name (`com1` - `com4`).
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "com1" => Some(ComPort::Com1),
+            "com2" => Some(ComPort::Com2),
+            "com3" => Some(ComPort::Com3),
+            "com4" => Some(ComPort::Com4),
+            _      => None,
+        }
+    }
+
+    /// Base I/O port of the UART.
+    pub fn io_port(self) -> u16 {
+        match self {
+            ComPort::Com1 => 0x3f8,
+            ComPort::Com2 => 0x2f8,
+            ComPort::Com3 => 0x3e8,
+            ComPort::Com4 => 0x2e8,
+        }
+    }
+
+    /// ISA IRQ used by the UART. COM1 and COM3, COM2 and COM4 share IRQs.
+    pub fn irq(self) -> u8 {
+        match self {
+            ComPort::Com1 | ComPort::Com3 => 4,
+            ComPort::Com2 | ComPort::Com4 => 3,
+        }
+    }
+}
+
+/// Get divisor latch value for `baud_rate`. Returns `None` if the baud rate cannot be set
+/// exactly.
+pub fn baud_divisor(baud_rate: u32) -> Option<u16> {
+    if baud_rate == 0 || baud_rate > MAX_BAUD_RATE {
+        return None;
+    }
+
+    let divisor = MAX_BAUD_RATE / baud_rate;
+    if  divisor * baud_rate != MAX_BAUD_RATE {
+        return None;
+    }
+
+    Some(divisor as u16)
+}
+
+/// Fixed size FIFO queue of bytes.
+#[repr(C)]
+pub struct RingBuffer {
+    data: [u8; BUFFER_SIZE],
+    head: u32,
+    size: u32,
+}
+
+impl RingBuffer {
+    pub const fn new() -> Self {
+        Self {
+            data: [0; BUFFER_SIZE],
+            head: 0,
+            size: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.size as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.size as usize == BUFFER_SIZE
+    }
+
+    /// Add `byte` at the end of the queue. Returns `false` if the queue is full.
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+
+        let index = (self.head as usize + self.size as usize) % BUFFER_SIZE;
+
+        self.data[index] = byte;
+        self.size       += 1;
+
+        true
+    }
+
+    /// Remove byte from the front of the queue.
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let byte = self.data[self.head as usize];
+
+        self.head  = (self.head + 1) % BUFFER_SIZE as u32;
+        self.size -= 1;
+
+        Some(byte)
+    }
+}
+
+impl Default for RingBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 /// Serial port driver.
 #[repr(C)]
 pub struct SerialPort {
     /// IO address for this serial port.
     port: u16,
+
+    /// If `true`, transmission is driven by interrupts and bytes are queued in `transmit`.
+    /// Otherwise every byte is sent synchronously.
+    interrupt_driven: bool,
+
+    receive:  RingBuffer,
+    transmit: RingBuffer,
 }
 
 impl SerialPort {
     /// Initialize COM1 serial port.
     pub unsafe fn new() -> Self {
-        let port = COM1;
+        let port = Self::with_port(ComPort::Com1);
+
+        port.configure(baud_divisor(DEFAULT_BAUD_RATE).unwrap());
+
+        port
+    }
+
+    /// Initialize serial port `com_port` with `baud_rate`. Returns `None` if the baud rate
+    /// isn't supported or the UART is not present.
+    pub unsafe fn open(com_port: ComPort, baud_rate: u32) -> Option<Self> {
+        let divisor = baud_divisor(baud_rate)?;
+        let port    = Self::with_port(com_port);
 
-        cpu::outb(port + 1, 0x00); // Disable all interrupts.
-        cpu::outb(port + 3, 0x80); // Enable DLAB.
-        cpu::outb(port + 0, 0x01); // Low byte divisor (115200 baud).
-        cpu::outb(port + 1, 0x00); // High byte divisor.
-        cpu::outb(port + 3, 0x03); // 8 bits, 1 stop bit, no parity.
-        cpu::outb(port + 4, 0x03); // RTS/DSR set.
+        if !port.is_present() {
+            return None;
+        }
+
+        port.configure(divisor);
+
+        Some(port)
+    }
 
+    fn with_port(com_port: ComPort) -> Self {
         Self {
-            port,
+            port:             com_port.io_port(),
+            interrupt_driven: false,
+            receive:          RingBuffer::new(),
+            transmit:         RingBuffer::new(),
+        }
+    }
+
+    unsafe fn is_present(&self) -> bool {
+        // Missing UART won't keep the value in the scratch register.
+        for &value in &[0x55, 0xaa] {
+            cpu::outb(self.port + SCRATCH, value);
+
+            if cpu::inb(self.port + SCRATCH) != value {
+                return false;
+            }
+        }
+
+        // Check that the UART returns what we send in the loopback mode.
+        cpu::outb(self.port + INTERRUPT_ENABLE, 0x00);
+        cpu::outb(self.port + MODEM_CONTROL,    0x1e);
+        cpu::outb(self.port + DATA,             0xae);
+
+        let present = cpu::inb(self.port + DATA) == 0xae;
+
+        cpu::outb(self.port + MODEM_CONTROL, 0x03);
+
+        present
+    }
+
+    unsafe fn configure(&self, divisor: u16) {
+        let port = self.port;
+
+        cpu::outb(port + INTERRUPT_ENABLE, 0x00);                  // Disable all interrupts.
+        cpu::outb(port + LINE_CONTROL,     0x80);                  // Enable DLAB.
+        cpu::outb(port + DATA,             divisor as u8);         // Low byte divisor.
+        cpu::outb(port + INTERRUPT_ENABLE, (divisor >> 8) as u8);  // High byte divisor.
+        cpu::outb(port + LINE_CONTROL,     0x03);                  // 8 bits, 1 stop, no parity.
+        cpu::outb(port + FIFO_CONTROL,     0xc7);                  // Enable and clear FIFOs.
+        cpu::outb(port + MODEM_CONTROL,    0x0b);                  // RTS/DSR set, enable OUT2.
+    }
+
+    /// Get base I/O port of this serial port.
+    pub fn io_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Use interrupts to receive and transmit data. `handle_interrupt` must be called
+    /// when the UART raises an IRQ.
+    pub unsafe fn enable_interrupts(&mut self) {
+        self.interrupt_driven = true;
+
+        self.update_interrupts();
+    }
+
+    /// Send all queued data and go back to synchronous operation.
+    pub unsafe fn disable_interrupts(&mut self) {
+        self.flush();
+
+        self.interrupt_driven = false;
+
+        cpu::outb(self.port + INTERRUPT_ENABLE, 0x00);
+    }
+
+    /// Enable transmit interrupt only when there is something to send, otherwise UART will
+    /// constantly report empty transmit buffer.
+    unsafe fn update_interrupts(&mut self) {
+        if self.interrupt_driven {
+            let mut enable = IER_RX_AVAILABLE;
+
+            if !self.transmit.is_empty() {
+                enable |= IER_TX_EMPTY;
+            }
+
+            cpu::outb(self.port + INTERRUPT_ENABLE, enable);
+        }
+    }
+
+    fn line_status(&self) -> u8 {
+        unsafe { cpu::inb(self.port + LINE_STATUS) }
+    }
+
+    /// Move received bytes from the UART to the receive buffer. If the buffer is full, new
+    /// bytes are dropped.
+    fn receive_pending(&mut self) {
+        while self.line_status() & LSR_DATA_READY != 0 {
+            let byte = unsafe { cpu::inb(self.port + DATA) };
+
+            self.receive.push(byte);
+        }
+    }
+
+    /// Fill transmit FIFO with queued bytes if it's empty.
+    fn transmit_pending(&mut self) {
+        if self.line_status() & LSR_TX_EMPTY == 0 {
+            return;
+        }
+
+        for _ in 0..FIFO_SIZE {
+            match self.transmit.pop() {
+                Some(byte) => unsafe { cpu::outb(self.port + DATA, byte) },
+                None       => break,
+            }
+        }
+    }
+
+    /// Handle IRQ raised by the UART.
+    pub fn handle_interrupt(&mut self) {
+        // Limit number of iterations in case the UART is misbehaving.
+        for _ in 0..16 {
+            let interrupt_id = unsafe { cpu::inb(self.port + INTERRUPT_ID) };
+            if  interrupt_id & IIR_NO_INTERRUPT != 0 {
+                break;
+            }
+
+            // Reading line and modem status acknowledges their interrupts.
+            let _ = self.line_status();
+            let _ = unsafe { cpu::inb(self.port + MODEM_STATUS) };
+
+            self.receive_pending();
+            self.transmit_pending();
+        }
+
+        unsafe {
+            self.update_interrupts();
+        }
+    }
+
+    /// Wait until all queued data is sent.
+    pub fn flush(&mut self) {
+        while !self.transmit.is_empty() {
+            self.transmit_pending();
+
+            core::hint::spin_loop();
         }
     }
 
@@ -37,12 +335,52 @@ impl SerialPort {
             self.write_byte(b'\r');
         }
 
+        self.write_raw_byte(byte);
+    }
+
+    /// Send a byte to the serial port without any translation.
+    pub fn write_raw_byte(&mut self, byte: u8) {
+        if !self.interrupt_driven {
+            unsafe {
+                // Wait for empty transport.
+                while cpu::inb(self.port + LINE_STATUS) & LSR_TX_EMPTY == 0 {}
+
+                // Transmit byte.
+                cpu::outb(self.port + DATA, byte);
+            }
+
+            return;
+        }
+
+        // Send queued data synchronously if there is no space left. This also works when
+        // interrupts are disabled.
+        while !self.transmit.push(byte) {
+            self.transmit_pending();
+        }
+
+        self.transmit_pending();
+
         unsafe {
-            // Wait for empty transport.
-            while cpu::inb(self.port + 5) & 0x20 == 0 {}
+            self.update_interrupts();
+        }
+    }
 
-            // Transmit byte.
-            cpu::outb(self.port, byte);
+    /// Get a received byte. Returns `None` if no data is available.
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        // Check the UART too, interrupts may be disabled.
+        self.receive_pending();
+
+        self.receive.pop()
+    }
+
+    /// Wait for a byte and return it.
+    pub fn read_byte(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read_byte() {
+                return byte;
+            }
+
+            core::hint::spin_loop();
         }
     }
 }
@@ -58,3 +396,53 @@ impl core::fmt::Write for SerialPort {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_buffer() {
+        let mut buffer = RingBuffer::new();
+
+        assert_eq!(buffer.pop(), None);
+
+        // Wrap around the end of the buffer a few times.
+        for round in 0..3 {
+            for index in 0..BUFFER_SIZE - 1 {
+                assert!(buffer.push((index + round) as u8));
+            }
+
+            assert!(buffer.push(0xff));
+            assert!(buffer.is_full());
+            assert!(!buffer.push(0));
+
+            for index in 0..BUFFER_SIZE / 2 {
+                assert_eq!(buffer.pop(), Some((index + round) as u8));
+            }
+
+            while buffer.pop().is_some() {}
+
+            assert!(buffer.is_empty());
+            assert!(buffer.push(1));
+            assert_eq!(buffer.len(), 1);
+            assert_eq!(buffer.pop(), Some(1));
+        }
+    }
+
+    #[test]
+    fn baud_rates() {
+        assert_eq!(baud_divisor(115_200), Some(1));
+        assert_eq!(baud_divisor(57_600),  Some(2));
+        assert_eq!(baud_divisor(9_600),   Some(12));
+        assert_eq!(baud_divisor(50),      Some(2304));
+
+        assert_eq!(baud_divisor(0),       None);
+        assert_eq!(baud_divisor(230_400), None);
+        assert_eq!(baud_divisor(100_000), None);
+
+        assert_eq!(ComPort::from_name("com2").map(ComPort::io_port), Some(0x2f8));
+        assert_eq!(ComPort::from_name("com3").map(ComPort::irq),     Some(4));
+        assert_eq!(ComPort::from_name("COM1"), None);
+    }
+}
@@ -0,0 +1,272 @@
+#![no_std]
+
+//! Compact table which maps kernel addresses to function names. It is created by the host
+//! builder from the kernel ELF symbols and embedded in the kernel image, so the kernel can
+//! print symbolized backtraces.
+//!
+//! Layout: header (`MAGIC`, symbol count, strings size), entries sorted by start offset
+//! (start, size, name offset; all `u32`), strings (`u16` length followed by UTF-8 bytes).
+//! All integers are little endian and offsets are relative to the kernel base address.
+
+use core::convert::TryInto;
+
+/// Name of the kernel section which reserves space for the symbol table. It's a macro so
+/// the kernel can use it in `global_asm!`.
+#[macro_export]
+macro_rules! section_name {
+    () => { ".kernel_symbols" };
+}
+
+/// Value of `section_name!` for code which doesn't need a literal.
+pub const SECTION_NAME: &str = section_name!();
+
+/// Magic value which starts every symbol table.
+pub const MAGIC: [u8; 8] = *b"FLZSYM\0\x01";
+
+/// Size of the serialized header.
+pub const HEADER_SIZE: usize = 16;
+
+/// Size of a single serialized entry.
+pub const ENTRY_SIZE: usize = 12;
+
+/// Maximum length of the symbol name. Longer names are truncated.
+pub const MAX_NAME_LENGTH: usize = 1024;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Symbol<'a> {
+    /// Offset of the first byte of the function from the kernel base.
+    pub start: u32,
+
+    /// Size of the function in bytes.
+    pub size: u32,
+
+    pub name: &'a str,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset.checked_add(4)?)?.try_into().ok()?))
+}
+
+/// Truncate `name` to `MAX_NAME_LENGTH` bytes without splitting UTF-8 characters.
+fn truncated(name: &str) -> &str {
+    let mut length = name.len().min(MAX_NAME_LENGTH);
+
+    while !name.is_char_boundary(length) {
+        length -= 1;
+    }
+
+    &name[..length]
+}
+
+/// Get the size of the table which contains `symbols`.
+pub fn encoded_size(symbols: &[Symbol]) -> usize {
+    HEADER_SIZE + symbols.iter()
+        .map(|symbol| ENTRY_SIZE + 2 + truncated(symbol.name).len())
+        .sum::<usize>()
+}
+
+/// Create a table which contains `symbols` (sorted by start offset) in `output`. Returns the
+/// size of the table or `None` if `output` is too small.
+pub fn encode(symbols: &[Symbol], output: &mut [u8]) -> Option<usize> {
+    assert!(symbols.windows(2).all(|pair| pair[0].start <= pair[1].start),
+            "Symbols are not sorted.");
+
+    let size = encoded_size(symbols);
+    if  size > output.len() {
+        return None;
+    }
+
+    let strings_size = size - HEADER_SIZE - symbols.len() * ENTRY_SIZE;
+
+    output[..8].copy_from_slice(&MAGIC);
+    output[8..12].copy_from_slice(&(symbols.len() as u32).to_le_bytes());
+    output[12..16].copy_from_slice(&(strings_size as u32).to_le_bytes());
+
+    let mut entry  = HEADER_SIZE;
+    let mut string = HEADER_SIZE + symbols.len() * ENTRY_SIZE;
+
+    for symbol in symbols {
+        let name        = truncated(symbol.name);
+        let name_offset = string - (HEADER_SIZE + symbols.len() * ENTRY_SIZE);
+
+        output[entry..entry + 4].copy_from_slice(&symbol.start.to_le_bytes());
+        output[entry + 4..entry + 8].copy_from_slice(&symbol.size.to_le_bytes());
+        output[entry + 8..entry + 12].copy_from_slice(&(name_offset as u32).to_le_bytes());
+
+        output[string..string + 2].copy_from_slice(&(name.len() as u16).to_le_bytes());
+        output[string + 2..string + 2 + name.len()].copy_from_slice(name.as_bytes());
+
+        entry  += ENTRY_SIZE;
+        string += 2 + name.len();
+    }
+
+    Some(size)
+}
+
+#[derive(Copy, Clone)]
+pub struct SymbolTable<'a> {
+    entries: &'a [u8],
+    strings: &'a [u8],
+    count:   usize,
+}
+
+impl<'a> SymbolTable<'a> {
+    /// Parse symbol table at the start of `data`. Returns `None` if `data` doesn't contain
+    /// a valid table (e.g. the host builder didn't embed it).
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.get(..8)? != MAGIC {
+            return None;
+        }
+
+        let count: usize        = read_u32(data, 8)?.try_into().ok()?;
+        let strings_size: usize = read_u32(data, 12)?.try_into().ok()?;
+
+        let entries_size = count.checked_mul(ENTRY_SIZE)?;
+        let strings      = HEADER_SIZE.checked_add(entries_size)?;
+
+        Some(Self {
+            entries: data.get(HEADER_SIZE..strings)?,
+            strings: data.get(strings..strings.checked_add(strings_size)?)?,
+            count,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Get symbol at `index`. Returns `None` if the index or the entry is invalid.
+    pub fn get(&self, index: usize) -> Option<Symbol<'a>> {
+        let entry = index.checked_mul(ENTRY_SIZE)?;
+
+        let start       = read_u32(self.entries, entry)?;
+        let size        = read_u32(self.entries, entry + 4)?;
+        let name_offset = read_u32(self.entries, entry + 8)? as usize;
+
+        let length = u16::from_le_bytes(self.strings.get(name_offset..name_offset + 2)?
+                                        .try_into().ok()?) as usize;
+        let name   = self.strings.get(name_offset + 2..name_offset + 2 + length)?;
+
+        Some(Symbol {
+            start,
+            size,
+            name: core::str::from_utf8(name).ok()?,
+        })
+    }
+
+    /// Find symbol which contains `offset` (relative to the kernel base). Returns the symbol
+    /// and the offset within it.
+    pub fn lookup(&self, offset: u64) -> Option<(Symbol<'a>, u64)> {
+        let offset: u32 = offset.try_into().ok()?;
+
+        // Find the last symbol which starts at or before `offset`.
+        let mut low  = 0;
+        let mut high = self.count;
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+
+            if self.get(middle)?.start <= offset {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+
+        let symbol = self.get(low.checked_sub(1)?)?;
+        let within = offset - symbol.start;
+
+        if within < symbol.size {
+            Some((symbol, within as u64))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec;
+
+    const SYMBOLS: [Symbol; 4] = [
+        Symbol { start: 0x1000, size: 0x20, name: "kernel::_start" },
+        Symbol { start: 0x1020, size: 0x10, name: "kernel::mm::translate" },
+        Symbol { start: 0x1040, size: 0x80, name: "core::panicking::panic_fmt" },
+        Symbol { start: 0x2000, size: 0x01, name: "" },
+    ];
+
+    fn encoded(symbols: &[Symbol]) -> std::vec::Vec<u8> {
+        let mut output = vec![0u8; encoded_size(symbols)];
+
+        assert_eq!(encode(symbols, &mut output), Some(output.len()));
+
+        output
+    }
+
+    #[test]
+    fn lookup() {
+        let data  = encoded(&SYMBOLS);
+        let table = SymbolTable::parse(&data).unwrap();
+
+        assert_eq!(table.len(), 4);
+        assert_eq!(table.get(2), Some(SYMBOLS[2]));
+        assert_eq!(table.get(4), None);
+
+        assert_eq!(table.lookup(0x1000), Some((SYMBOLS[0], 0)));
+        assert_eq!(table.lookup(0x101f), Some((SYMBOLS[0], 0x1f)));
+        assert_eq!(table.lookup(0x1020), Some((SYMBOLS[1], 0)));
+        assert_eq!(table.lookup(0x1050), Some((SYMBOLS[2], 0x10)));
+        assert_eq!(table.lookup(0x2000), Some((SYMBOLS[3], 0)));
+
+        // Gaps and addresses outside of all symbols.
+        assert_eq!(table.lookup(0x0fff), None);
+        assert_eq!(table.lookup(0x1030), None);
+        assert_eq!(table.lookup(0x2001), None);
+        assert_eq!(table.lookup(u64::MAX), None);
+
+        let empty = encoded(&[]);
+
+        assert_eq!(SymbolTable::parse(&empty).unwrap().lookup(0x1000), None);
+    }
+
+    #[test]
+    fn long_names_are_truncated() {
+        let name   = "ż".repeat(MAX_NAME_LENGTH);
+        let data   = encoded(&[Symbol { start: 0, size: 1, name: &name }]);
+        let symbol = SymbolTable::parse(&data).unwrap().get(0).unwrap();
+
+        assert_eq!(symbol.name.len(), MAX_NAME_LENGTH);
+        assert!(name.starts_with(symbol.name));
+    }
+
+    #[test]
+    fn rejects_malformed_tables() {
+        let data = encoded(&SYMBOLS);
+
+        let mut output = vec![0u8; data.len() - 1];
+
+        assert_eq!(encode(&SYMBOLS, &mut output), None);
+
+        assert!(SymbolTable::parse(&[0u8; 64]).is_none());
+        assert!(SymbolTable::parse(&data[..data.len() - 1]).is_none());
+        assert!(SymbolTable::parse(&data[..HEADER_SIZE + 4]).is_none());
+
+        // Entry with name outside of the string table.
+        let mut corrupted = data.clone();
+
+        corrupted[HEADER_SIZE + 8..HEADER_SIZE + 12].copy_from_slice(&0xffffu32.to_le_bytes());
+
+        let table = SymbolTable::parse(&corrupted).unwrap();
+
+        assert_eq!(table.get(0), None);
+        assert_eq!(table.lookup(0x1000), None);
+        assert_eq!(table.lookup(0x1020), Some((SYMBOLS[1], 0)));
+    }
+}
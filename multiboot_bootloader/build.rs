@@ -0,0 +1,10 @@
+fn main() {
+    asm::link(&["src/entry.asm", "src/enter_kernel.asm"], asm::Format::Elf32);
+    asm::embed(&["src/ap_entrypoint.asm"]);
+
+    // Use absolute path so it does not depend on the directory in which linker is invoked.
+    let manifest_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+
+    println!("cargo:rerun-if-changed=linker.ld");
+    println!("cargo:rustc-link-arg=-T{}/linker.ld", manifest_dir);
+}
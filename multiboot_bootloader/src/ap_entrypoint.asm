@@ -0,0 +1,98 @@
+[org  0]
+[bits 16]
+
+; We don't know our base address at compile time so every memory access needs to add code base.
+
+; `ap_entrypoint.rs` will expect this instruction sequence.
+; It will modify `0xaabbccdd` to be our code base address.
+mov eax, 0xaabbccdd
+jmp entry_16
+
+; Don't change this without changing `ap_entrypoint.rs`.
+; This will be filled in.
+bootloader_entrypoint: dd 0
+
+entry_16:
+    ; Disable interrupts and clear direction flag.
+    cli
+    cld
+
+    ; A20 line was enabled by the Multiboot2 loader.
+
+    ; Save base address to EBX. We need to make sure that nothing in this whole code
+    ; will clobber it.
+    mov ebx, eax
+
+    ; Convert our base address to value which we can use as a segment.
+    shr eax, 4
+    mov ds, ax
+    mov es, ax
+
+    ; Set stack pointer to base address - 0x10.
+    sub ax, 0x0100
+    mov ss, ax
+    mov sp, 0x0ff0
+
+    ; Offset pointer to GDT by base address.
+    mov eax, gdt_32
+    add eax, ebx
+    mov dword [ds:gdt_32.pointer], eax
+
+    ; Load 32 bit GDT.
+    lgdt [ds:gdt_32.register]
+
+    ; Enable protected mode.
+    mov eax, cr0
+    or  eax, 1 << 0
+    mov cr0, eax
+
+    ; Get absolute address of 32 bit entrypoint.
+    mov eax, entry_32
+    add eax, ebx
+
+    ; Enter 32 bit mode. Normal far jump cannot be used because it uses absolute destination.
+    pushfd            ; EFLAGS
+    push dword 0x08   ; CS
+    push dword eax    ; EIP
+    iretd
+
+[bits 32]
+entry_32:
+    ; Reload segments.
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+
+    ; Set stack pointer to base address - 0x10.
+    mov esp, ebx
+    sub esp, 0x10
+
+    ; Clear two arguments to the bootloader (magic value and Multiboot2 information).
+    push dword 0
+    push dword 0
+
+    ; Call the bootloader entrypoint.
+    call [ebx + bootloader_entrypoint]
+
+    ; We should never return here.
+    .next:
+        cli
+        hlt
+        jmp .next
+
+; GDT used to enter protected mode.
+align 8
+gdt_32:
+    dq 0x0000000000000000 ; Null segment.
+    dq 0x00cf9a000000ffff ; Code segment.
+    dq 0x00cf92000000ffff ; Data segment.
+
+    .register:
+        dw (.register - gdt_32) - 1
+
+    ; Code must change this.
+    .pointer:
+        dd 0
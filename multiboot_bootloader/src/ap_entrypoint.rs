@@ -0,0 +1,62 @@
+use rangeset::Range;
+
+use crate::BOOT_BLOCK;
+
+/// Realmode AP entrypoint.
+const AP_ENTRYPOINT: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/ap_entrypoint.bin"));
+
+/// Copy realmode AP entrypoint to the low memory and register it in the boot block. APs
+/// will enter the bootloader at `bootloader_main` in protected mode.
+pub unsafe fn initialize() {
+    // 8KB of stack should be enough.
+    const STACK_SIZE: usize = 8 * 1024;
+
+    let code_size = (AP_ENTRYPOINT.len() + 0xfff) & !0xfff;
+    let area_size = (code_size + STACK_SIZE) as u64;
+
+    // Allocate AP area in low memory that is accesible by 16 bit code. SIPI vector can
+    // only encode addresses below 1MB.
+    let area_address = BOOT_BLOCK.free_memory
+        .lock()
+        .allocate_limited(area_size, 0x1000, Some(1024 * 1024 - 1))
+        .expect("Failed to allocate AP entrypoint.");
+
+    // Kernel can reclaim this memory after all APs have been launched.
+    BOOT_BLOCK.boot_memory.lock().insert(Range {
+        start: area_address as u64,
+        end:   area_address as u64 + area_size - 1,
+    });
+
+    let code_address = area_address + STACK_SIZE;
+    let code_buffer  = core::slice::from_raw_parts_mut(code_address as *mut u8,
+                                                       AP_ENTRYPOINT.len());
+
+    code_buffer.copy_from_slice(AP_ENTRYPOINT);
+
+    // Make sure that AP entrypoint starts with:
+    //   mov eax, 0xaabbccdd
+    //   jmp skip
+    //
+    // AP entrypoint will expect us to change 0xaabbccdd to its own address.
+    assert!(code_buffer[..6 + 1] == [0x66, 0xb8, 0xdd, 0xcc, 0xbb, 0xaa, 0xeb]);
+
+    // Replace imm in `mov` to code base address.
+    code_buffer[2..6].copy_from_slice(&(code_address as u32).to_le_bytes());
+
+    // Calculate jump target relative to `code_address`. 6 bytes for mov and 2 bytes for jmp.
+    let jmp_target_offset = (code_buffer[6 + 1] + 6 + 2) as usize;
+    let data_offset       = 6 + 2;
+
+    // Don't change this without changing `ap_entrypoint.asm`.
+
+    // bootloader_entrypoint: dd 0
+    let entrypoint = crate::bootloader_main as *const () as u32;
+
+    code_buffer[data_offset..][..4].copy_from_slice(&entrypoint.to_le_bytes());
+
+    // Make sure that we have filled whole region skipped by the jump.
+    assert_eq!(data_offset + 4, jmp_target_offset, "Data area in AP entrypoint was corrupted.");
+
+    // Set AP entrypoint address so it will be used by the kernel.
+    *BOOT_BLOCK.ap_entrypoint.lock() = Some(code_address as u64);
+}
@@ -0,0 +1,19 @@
+use boot_block::{AcpiTables, FramebufferInfo};
+use rangeset::Range;
+
+/// Information passed to the bootloader by a Multiboot2 loader.
+pub trait BootInformation {
+    /// Call `callback` with `(range, free)` for every memory map entry.
+    fn memory_map(&self, callback: impl FnMut(Range, bool));
+
+    /// Call `callback` for every memory range occupied by the boot information structures.
+    /// They are not needed after finishing boot process.
+    fn structures(&self, callback: impl FnMut(Range));
+
+    /// Call `callback` for every module loaded together with the bootloader.
+    fn modules(&self, callback: impl FnMut(Range));
+
+    fn command_line(&self) -> Option<&'static str>;
+    fn framebuffer(&self) -> Option<FramebufferInfo>;
+    fn acpi_tables(&self) -> Option<AcpiTables>;
+}
@@ -0,0 +1,97 @@
+[bits 32]
+
+MULTIBOOT2_MAGIC        equ 0xe85250d6
+MULTIBOOT2_ARCHITECTURE equ 0 ; i386 protected mode.
+
+STACK_SIZE equ 64 * 1024
+
+; Multiboot2 header must be 8 byte aligned and located in the first 32KB of the image.
+section .multiboot_header progbits alloc noexec nowrite align=8
+
+header:
+    dd MULTIBOOT2_MAGIC
+    dd MULTIBOOT2_ARCHITECTURE
+    dd header.end - header
+    dd 0x100000000 - (MULTIBOOT2_MAGIC + MULTIBOOT2_ARCHITECTURE + (header.end - header))
+
+    ; Framebuffer tag (optional). Ask for 32 bit linear framebuffer with any resolution.
+    align 8
+    dw 5
+    dw 1
+    dd 20
+    dd 0 ; Width.
+    dd 0 ; Height.
+    dd 32 ; Depth.
+
+    ; Module alignment tag. Modules will be page aligned.
+    align 8
+    dw 6
+    dw 0
+    dd 8
+
+    ; End tag.
+    align 8
+    dw 0
+    dw 0
+    dd 8
+    .end:
+
+section .text
+
+global _start
+extern bootloader_main
+
+; EAX - Multiboot2 magic value
+; EBX - Physical address of the Multiboot2 information structure
+_start:
+    cli
+    cld
+
+    ; EAX is needed to reload segments.
+    mov ecx, eax
+
+    ; Loader doesn't guarantee that its GDT is still valid so load our own.
+    lgdt [gdt_32.register]
+    jmp 0x08:.reload_segments
+
+.reload_segments:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov ss, ax
+    mov fs, ax
+    mov gs, ax
+
+    ; Loader doesn't provide a stack either.
+    mov esp, stack.end
+
+    ; Call the bootloader with information from the loader.
+    push ebx
+    push ecx
+    call bootloader_main
+
+    ; We should never return here.
+    .next:
+        cli
+        hlt
+        jmp .next
+
+section .data
+
+; GDT used by the bootloader. Same as the one used by the BIOS bootloader.
+align 8
+gdt_32:
+    dq 0x0000000000000000 ; Null segment.
+    dq 0x00cf9a000000ffff ; Code segment.
+    dq 0x00cf92000000ffff ; Data segment.
+
+    .register:
+        dw (.register - gdt_32) - 1
+        dd gdt_32
+
+section .bss
+
+align 16
+stack:
+    resb STACK_SIZE
+    .end:
@@ -0,0 +1,29 @@
+use bootlib::kernel::{KernelLoader, KernelEntryData};
+use crate::mm::{self, PhysicalMemory};
+use crate::lock::EmptyInterrupts;
+use crate::BOOT_BLOCK;
+
+/// ELF image of the kernel.
+const KERNEL: &[u8] = include_bytes!(env!("FLUGZEUG_KERNEL_PATH"));
+
+/// Kernel loaded from the embedded ELF image.
+static KERNEL_LOADER: KernelLoader<EmptyInterrupts> = KernelLoader::new();
+
+/// Allocates a unique stack and gets all data required to enter the kernel.
+/// If kernel isn't already mapped, it will be mapped from the embedded ELF image.
+pub fn setup_kernel() -> (KernelEntryData, u64) {
+    // If we are currently launching AP then the kernel has been already mapped.
+    let entry_data = KERNEL_LOADER.entry_data().unwrap_or_else(|| {
+        // We will only execute bootloader code using trampoline page tables. APs use stack
+        // located below 1MB and the BSP uses stack inside the bootloader image. Therefore we
+        // need to map everything up to the end of the bootloader image.
+        let trampoline_region_size = mm::image_range().end + 1;
+
+        KERNEL_LOADER.load(&BOOT_BLOCK, &mut PhysicalMemory, KERNEL, trampoline_region_size)
+    });
+
+    // Create a unique stack for this core.
+    let rsp = KERNEL_LOADER.create_stack(&BOOT_BLOCK, &mut PhysicalMemory);
+
+    (entry_data, rsp)
+}
@@ -0,0 +1,137 @@
+#![no_std]
+#![no_main]
+#![feature(panic_info_message, alloc_error_handler)]
+
+extern crate alloc;
+
+#[macro_use] mod serial;
+mod panic;
+mod lock;
+mod mm;
+mod kernel;
+mod boot_info;
+mod multiboot;
+mod ap_entrypoint;
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use boot_block::{BootBlock, CommandLineBuffer, Initrd};
+
+use boot_info::BootInformation;
+use multiboot::MultibootInformation;
+use crate::lock::EmptyInterrupts;
+
+// Bootloader is not thread safe. There can be only one instance of it running at a time.
+// Kernel launches cores one by one to make sure that this is indeed what happens.
+
+/// Boot block is a shared data structure between kernel and bootloader. It must have
+/// exactly the same shape in 32 bit and 64 bit mode. It allows for concurrent memory
+/// allocation and modification and serial port interface.
+/// It will be moved to the kernel after finishing boot process.
+pub static BOOT_BLOCK: BootBlock<EmptyInterrupts> = BootBlock::new();
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static CORE_ID:     AtomicU32  = AtomicU32::new(0);
+
+/// Pass everything provided by the loader (except memory map) to the kernel.
+fn load_boot_information(boot_information: &impl BootInformation) {
+    if let Some(command_line) = boot_information.command_line().filter(|x| !x.is_empty()) {
+        *BOOT_BLOCK.command_line.lock() = Some(CommandLineBuffer::new(command_line.as_bytes())
+                                               .expect("Command line is too long."));
+
+        println!("Loaded {} byte command line.", command_line.len());
+    }
+
+    // First module is used as the initrd.
+    let mut ignored = 0;
+
+    boot_information.modules(|range| {
+        let mut initrd = BOOT_BLOCK.initrd.lock();
+
+        if initrd.is_some() {
+            ignored += 1;
+            return;
+        }
+
+        let size = range.end - range.start + 1;
+
+        *initrd = Some(Initrd {
+            phys_addr: range.start,
+            size,
+        });
+
+        println!("Loaded {} byte initrd at 0x{:x}.", size, range.start);
+    });
+
+    if ignored > 0 {
+        println!("WARNING: Ignoring {} additional modules.", ignored);
+    }
+
+    match boot_information.framebuffer() {
+        Some(info) => {
+            println!("Using {}x{} framebuffer at 0x{:x}.", info.width, info.height,
+                     info.fb_base);
+
+            *BOOT_BLOCK.framebuffer.lock() = Some(info);
+        }
+        None => println!("WARNING: Loader hasn't set up a usable framebuffer."),
+    }
+
+    let acpi_tables = boot_information.acpi_tables()
+        .expect("Loader didn't provide valid ACPI RSDP.");
+
+    *BOOT_BLOCK.acpi_tables.lock() = acpi_tables;
+}
+
+/// Initialize the bootloader using information provided by the loader. Called only on the BSP.
+unsafe fn initialize(boot_information: &impl BootInformation) {
+    mm::initialize(boot_information);
+    load_boot_information(boot_information);
+    ap_entrypoint::initialize();
+}
+
+/// Entrypoint called by `entry.asm` on the BSP and by `ap_entrypoint.asm` on APs.
+/// `magic` must be the Multiboot2 bootloader magic on the BSP. APs pass 0 as both arguments.
+#[no_mangle]
+pub extern "C" fn bootloader_main(magic: u32, boot_information: u32) -> ! {
+    let boot_tsc = unsafe { core::arch::x86::_rdtsc() };
+
+    // Make sure that LLVM data layout isn't broken.
+    assert!(core::mem::size_of::<u64>() == 8 && core::mem::align_of::<u64>() == 8,
+            "U64 has invalid size/alignment.");
+
+    if !INITIALIZED.load(Ordering::Relaxed) {
+        // Initialize crucial bootloader components.
+        unsafe {
+            serial::initialize();
+            bootlib::verify_cpu();
+
+            assert!(magic == multiboot::BOOTLOADER_MAGIC,
+                    "Bootloader wasn't loaded by a Multiboot2 compliant loader.");
+
+            initialize(&MultibootInformation::new(boot_information as usize));
+        }
+
+        INITIALIZED.store(true, Ordering::Relaxed);
+    } else {
+        // If we are running for the second time (or later), increase core ID.
+        CORE_ID.fetch_add(1, Ordering::Relaxed);
+
+        bootlib::verify_cpu();
+    }
+
+    // Map kernel if required. Also allocate a unique stack for this core.
+    let (entry_data, rsp) = kernel::setup_kernel();
+
+    extern "C" {
+        fn enter_kernel(entrypoint: u64, rsp: u64, boot_block: u64, kernel_cr3: u32,
+                        trampoline_cr3: u32, physical_region: u64, boot_tsc: u64) -> !;
+    }
+
+    // Enter the 64 bit kernel!
+    unsafe {
+        enter_kernel(entry_data.entrypoint, rsp, &BOOT_BLOCK as *const _ as u64,
+                     entry_data.kernel_cr3, entry_data.trampoline_cr3,
+                     entry_data.physical_region, boot_tsc);
+    }
+}
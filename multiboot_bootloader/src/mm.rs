@@ -0,0 +1,118 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::convert::TryInto;
+
+use rangeset::Range;
+use page_table::{PhysMem, PhysAddr};
+use crate::boot_info::BootInformation;
+use crate::BOOT_BLOCK;
+
+pub struct GlobalAllocator;
+
+unsafe impl GlobalAlloc for GlobalAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        BOOT_BLOCK.free_memory.lock()
+            .allocate(layout.size() as u64, layout.align() as u64)
+            .unwrap_or(0) as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let start = ptr as u64;
+        let end   = start.checked_add(layout.size().checked_sub(1).unwrap() as u64).unwrap();
+
+        BOOT_BLOCK.free_memory.lock().insert(Range { start, end });
+    }
+}
+
+pub struct PhysicalMemory;
+
+impl PhysMem for PhysicalMemory {
+    unsafe fn translate(&mut self, phys_addr: PhysAddr, size: usize) -> Option<*mut u8> {
+        // We don't use paging in the bootloader so physcial address == virtual address.
+        // Just make sure that address fits in pointer and region doesn't overflow.
+
+        let phys_addr: usize = phys_addr.0.try_into().ok()?;
+        let _phys_end: usize = phys_addr.checked_add(size.checked_sub(1)?)?;
+
+        Some(phys_addr as *mut u8)
+    }
+
+    fn alloc_phys(&mut self, layout: Layout) -> Option<PhysAddr> {
+        let mut free_memory = BOOT_BLOCK.free_memory.lock();
+
+        free_memory.allocate(layout.size() as u64, layout.align() as u64)
+            .map(|addr| PhysAddr(addr as u64))
+    }
+}
+
+#[global_allocator]
+static GLOBAL_ALLOCATOR: GlobalAllocator = GlobalAllocator;
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    panic!("Allocation of memory with layout {:?} failed!", layout);
+}
+
+/// Get physical memory range occupied by the bootloader image.
+pub fn image_range() -> Range {
+    extern "C" {
+        // Defined by the linker.
+        static __ehdr_start: u8;
+        static _end:         u8;
+    }
+
+    let (start, end) = unsafe {
+        (&__ehdr_start as *const u8 as u64, &_end as *const u8 as u64)
+    };
+
+    Range {
+        start: start & !0xfff,
+        end:   ((end + 0xfff) & !0xfff) - 1,
+    }
+}
+
+pub unsafe fn initialize(boot_information: &impl BootInformation) {
+    let mut free_memory = BOOT_BLOCK.free_memory.lock();
+    let mut boot_memory = BOOT_BLOCK.boot_memory.lock();
+
+    assert!(free_memory.entries().is_empty(), "Free memory list was already initialized.");
+
+    // Do two passes because some firmwares report overlapping regions.
+    for &cleanup_pass in &[false, true] {
+        boot_information.memory_map(|range, free| {
+            // First pass will add all free memory to the list.
+            // Second pass will remove all non-free memory from the list.
+            if free && !cleanup_pass {
+                free_memory.insert(range);
+            } else if !free && cleanup_pass {
+                free_memory.remove(range);
+            }
+        });
+    }
+
+    // Bootloader image is not needed after finishing boot process. Boot block will be moved
+    // by the kernel so it can be freed too.
+    free_memory.remove(image_range());
+    boot_memory.insert(image_range());
+
+    // Boot information structures are not needed after finishing boot process either. Some
+    // loaders place them in reserved memory so only the usable parts can be reclaimed.
+    boot_information.structures(|range| {
+        for entry in free_memory.entries() {
+            let start = range.start.max(entry.start);
+            let end   = range.end.min(entry.end);
+
+            if start <= end {
+                boot_memory.insert(Range { start, end });
+            }
+        }
+
+        free_memory.remove(range);
+    });
+
+    // Modules (initrd) must be kept intact.
+    boot_information.modules(|range| free_memory.remove(range));
+
+    // Remove the first page. It contains real mode IVT and BIOS data area which are often
+    // reported as usable memory. This also makes sure that we never allocate address 0.
+    free_memory.remove(Range { start: 0, end: 0xfff });
+}
@@ -0,0 +1,197 @@
+use core::convert::TryInto;
+
+use boot_block::{AcpiTables, FramebufferInfo, PixelFormat};
+use rangeset::Range;
+
+use crate::boot_info::BootInformation;
+
+/// Value passed in EAX by a Multiboot2 compliant loader.
+pub const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+
+const TAG_END:          u32 = 0;
+const TAG_COMMAND_LINE: u32 = 1;
+const TAG_MODULE:       u32 = 3;
+const TAG_MEMORY_MAP:   u32 = 6;
+const TAG_FRAMEBUFFER:  u32 = 8;
+const TAG_ACPI_OLD:     u32 = 14;
+const TAG_ACPI_NEW:     u32 = 15;
+
+const MEMORY_AVAILABLE: u32 = 1;
+
+const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+
+fn read_u8(data: &[u8], offset: usize) -> u8 {
+    data[offset]
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..][..4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..][..8].try_into().unwrap())
+}
+
+/// Get NUL terminated string from the tag data. Returns `None` if it is not valid UTF-8.
+fn read_string(data: &[u8]) -> Option<&str> {
+    let end = data.iter().position(|&byte| byte == 0).unwrap_or(data.len());
+
+    core::str::from_utf8(&data[..end]).ok()
+}
+
+/// Boot information structure passed by a Multiboot2 compliant loader.
+pub struct MultibootInformation {
+    address: usize,
+    size:    usize,
+}
+
+impl MultibootInformation {
+    pub unsafe fn new(address: usize) -> Self {
+        assert!(address != 0 && address % 8 == 0,
+                "Multiboot2 information structure is not properly aligned.");
+
+        let size = core::ptr::read(address as *const u32) as usize;
+
+        assert!(size >= 16, "Multiboot2 information structure is too small.");
+
+        Self {
+            address,
+            size,
+        }
+    }
+
+    /// Iterate over all `(type, data)` tags. Data doesn't include the tag header.
+    fn tags(&self) -> impl Iterator<Item = (u32, &'static [u8])> {
+        let bytes: &'static [u8] = unsafe {
+            core::slice::from_raw_parts(self.address as *const u8, self.size)
+        };
+
+        // Tags start after total size and reserved field.
+        let mut offset = 8;
+
+        core::iter::from_fn(move || {
+            let header = bytes.get(offset..offset + 8)?;
+            let typ    = read_u32(header, 0);
+            let size   = read_u32(header, 4) as usize;
+
+            if typ == TAG_END || size < 8 {
+                return None;
+            }
+
+            let data = bytes.get(offset + 8..offset + size)?;
+
+            // Every tag is 8 byte aligned.
+            offset += (size + 7) & !7;
+
+            Some((typ, data))
+        })
+    }
+
+    fn tag(&self, typ: u32) -> Option<&'static [u8]> {
+        self.tags()
+            .find(|&(tag_type, _)| tag_type == typ)
+            .map(|(_, data)| data)
+    }
+
+}
+
+impl BootInformation for MultibootInformation {
+    fn memory_map(&self, mut callback: impl FnMut(Range, bool)) {
+        let data = self.tag(TAG_MEMORY_MAP)
+            .expect("Multiboot2 loader didn't provide memory map.");
+
+        let entry_size = read_u32(data, 0) as usize;
+
+        assert!(entry_size >= 24, "Multiboot2 memory map entry is too small.");
+
+        // Entries start after entry size and entry version.
+        for entry in data[8..].chunks_exact(entry_size) {
+            let base = read_u64(entry, 0);
+            let size = read_u64(entry, 8);
+            let typ  = read_u32(entry, 16);
+
+            if size == 0 {
+                continue;
+            }
+
+            // Create inclusive range required by `RangeSet`.
+            let range = Range {
+                start: base,
+                end:   base.checked_add(size - 1).expect("Memory map region overflowed."),
+            };
+
+            callback(range, typ == MEMORY_AVAILABLE);
+        }
+    }
+
+    fn structures(&self, mut callback: impl FnMut(Range)) {
+        // All information (including command lines) is contained in one structure.
+        callback(Range {
+            start: self.address as u64,
+            end:   (self.address + self.size - 1) as u64,
+        });
+    }
+
+    fn modules(&self, mut callback: impl FnMut(Range)) {
+        for (typ, data) in self.tags() {
+            if typ != TAG_MODULE {
+                continue;
+            }
+
+            let start = read_u32(data, 0) as u64;
+            let end   = read_u32(data, 4) as u64;
+
+            // Skip empty modules, they cannot be represented by `Range`.
+            if end > start {
+                callback(Range { start, end: end - 1 });
+            }
+        }
+    }
+
+    fn command_line(&self) -> Option<&'static str> {
+        self.tag(TAG_COMMAND_LINE).and_then(read_string)
+    }
+
+    fn framebuffer(&self) -> Option<FramebufferInfo> {
+        let data = self.tag(TAG_FRAMEBUFFER).filter(|data| data.len() >= 30)?;
+
+        let address        = read_u64(data, 0);
+        let pitch          = read_u32(data, 8);
+        let width          = read_u32(data, 12);
+        let height         = read_u32(data, 16);
+        let bits_per_pixel = read_u8(data, 20);
+
+        // Kernel supports only 32 bit direct color framebuffers.
+        if read_u8(data, 21) != FRAMEBUFFER_TYPE_RGB || bits_per_pixel != 32 || pitch % 4 != 0 {
+            return None;
+        }
+
+        // Create color mask from (position, size) pair.
+        let mask = |offset: usize| {
+            let position = read_u8(data, offset);
+            let size     = read_u8(data, offset + 1);
+
+            (((1u64 << size) - 1) << position) as u32
+        };
+
+        Some(FramebufferInfo {
+            width,
+            height,
+            pixel_format:        PixelFormat {
+                red:   mask(24),
+                green: mask(26),
+                blue:  mask(28),
+            },
+            pixels_per_scanline: pitch / 4,
+            fb_base:             address,
+            fb_size:             pitch as u64 * height as u64,
+        })
+    }
+
+    fn acpi_tables(&self) -> Option<AcpiTables> {
+        // Loader provides a copy of the RSDP. ACPI 2.0+ one is preferred.
+        self.tag(TAG_ACPI_NEW)
+            .or_else(|| self.tag(TAG_ACPI_OLD))
+            .and_then(bootlib::rsdp::parse_rsdp)
+    }
+}
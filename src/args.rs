@@ -0,0 +1,335 @@
+use std::path::PathBuf;
+use std::fs;
+
+use boot_block::MAX_COMMAND_LINE_SIZE;
+
+const USAGE: &str = "\
+Usage: flugzeug [COMMAND] [OPTIONS]
+
+Commands:
+    build [bios|uefi|all]    Build the kernel and selected bootable images (default: all).
+    build multiboot          Build the kernel as a Multiboot2 image (GRUB).
+    build pvh                Build the kernel as a PVH image (QEMU `-kernel`).
+    build hybrid             Build a single image bootable by both BIOS and UEFI.
+    run <TARGET>             Build selected image (bios, uefi, pvh or hybrid) and
+                             launch it in QEMU. Hybrid image is booted using UEFI.
+    test [bios|uefi|all]     Boot selected images in QEMU (TCG) and check if kernel boots.
+    test pvh                 Boot the PVH image directly (no firmware, single core, fastest).
+    test hybrid              Boot the hybrid image using both BIOS and UEFI.
+    clean                    Remove the `build` directory.
+
+Options:
+    --debug                  Build the kernel using the debug profile.
+    --smp <CORES>            Number of CPU cores given to QEMU (default: 4).
+    --memory <SIZE>          Amount of memory given to QEMU, e.g. `4G` (default: 4G).
+    --timeout <SECONDS>      Time limit for a single test boot (default: 300).
+    --kernel-tests           Build the kernel with `#[kernel_test]` cases which run at boot.
+    --image-size <MIB>       Size of the UEFI and hybrid disk images in MiB (default: 512,
+                             minimum: 64).
+    --separate-kernel        Store the kernel on the EFI System Partition instead of
+                             embedding it in the UEFI bootloader.
+    --hybrid-gpt             Add a hybrid GPT to the BIOS image (MBR partitions are kept).
+    --initrd <PATH>          Initial ramdisk: a file or a directory packed as a tar archive.
+    --config <FILE>          Boot config file with kernel command line entries (`key=value`).
+    --cmdline <ENTRIES>      Kernel command line entries, override ones from the config.
+    --signing-key <FILE>     Sign boot payloads with Ed25519 key from FILE (created if it
+                             doesn't exist). Bootloaders will accept only signed payloads.
+    --compress-kernel        Compress the kernel with LZ4 in BIOS, UEFI and hybrid images.
+    -h, --help               Print this message.";
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Target {
+    Bios,
+    Uefi,
+    Multiboot,
+    Pvh,
+    Hybrid,
+}
+
+impl Target {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "bios"      => Some(Target::Bios),
+            "uefi"      => Some(Target::Uefi),
+            "multiboot" => Some(Target::Multiboot),
+            "pvh"       => Some(Target::Pvh),
+            "hybrid"    => Some(Target::Hybrid),
+            _           => None,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Command {
+    Build(Vec<Target>),
+    Run(Target),
+    Test(Vec<Target>),
+    Clean,
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub debug:           bool,
+    pub smp:             u32,
+    pub memory:          String,
+    pub timeout:         u64,
+    pub kernel_tests:    bool,
+    pub image_size:      u64,
+    pub separate_kernel: bool,
+    pub hybrid_gpt:      bool,
+    pub initrd:          Option<PathBuf>,
+    pub config:          Option<PathBuf>,
+    pub cmdline:         Option<String>,
+    pub signing_key:     Option<PathBuf>,
+    pub compress_kernel: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            debug:           false,
+            smp:             4,
+            memory:          String::from("4G"),
+            timeout:         300,
+            kernel_tests:    false,
+            image_size:      512,
+            separate_kernel: false,
+            hybrid_gpt:      false,
+            initrd:          None,
+            config:          None,
+            cmdline:         None,
+            signing_key:     None,
+            compress_kernel: false,
+        }
+    }
+}
+
+impl Options {
+    /// Get the kernel command line: contents of the config file followed by `--cmdline`
+    /// entries, so they take precedence. Returns `None` if neither was specified.
+    pub fn command_line(&self) -> Option<Vec<u8>> {
+        if self.config.is_none() && self.cmdline.is_none() {
+            return None;
+        }
+
+        let mut command_line = Vec::new();
+
+        if let Some(config) = &self.config {
+            command_line = fs::read(config)
+                .unwrap_or_else(|_| panic!("Failed to read boot config `{}`.", config.display()));
+
+            command_line.push(b'\n');
+        }
+
+        if let Some(cmdline) = &self.cmdline {
+            command_line.extend_from_slice(cmdline.as_bytes());
+        }
+
+        assert!(command_line.len() <= MAX_COMMAND_LINE_SIZE,
+                "Kernel command line is too long ({} bytes, maximum is {}).",
+                command_line.len(), MAX_COMMAND_LINE_SIZE);
+
+        Some(command_line)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Arguments {
+    pub command: Command,
+    pub options: Options,
+}
+
+impl Arguments {
+    /// Parse process command line. Prints usage and exits on invalid arguments.
+    pub fn from_env() -> Self {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+
+        match Self::parse(&args) {
+            Ok(arguments) => arguments,
+            Err(message)  => {
+                if !message.is_empty() {
+                    eprintln!("{}\n", message);
+                }
+
+                eprintln!("{}", USAGE);
+
+                std::process::exit(if message.is_empty() { 0 } else { 1 });
+            }
+        }
+    }
+
+    /// Parse arguments (without program name). Empty error message means that help
+    /// was requested.
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options    = Options::default();
+        let mut positional = Vec::new();
+
+        let mut iterator = args.iter();
+
+        while let Some(arg) = iterator.next() {
+            let mut value = |name: &str| {
+                iterator.next()
+                    .ok_or_else(|| format!("Option `{}` requires a value.", name))
+            };
+
+            match arg.as_str() {
+                "--debug"           => options.debug = true,
+                "--kernel-tests"    => options.kernel_tests = true,
+                "--separate-kernel" => options.separate_kernel = true,
+                "--hybrid-gpt"      => options.hybrid_gpt = true,
+                "--compress-kernel" => options.compress_kernel = true,
+                "--smp"           => {
+                    let smp = value("--smp")?;
+
+                    options.smp = smp.parse().ok()
+                        .filter(|&smp| smp > 0)
+                        .ok_or_else(|| format!("Invalid core count `{}`.", smp))?;
+                }
+                "--timeout" => {
+                    let timeout = value("--timeout")?;
+
+                    options.timeout = timeout.parse()
+                        .map_err(|_| format!("Invalid timeout `{}`.", timeout))?;
+                }
+                "--image-size" => {
+                    let size = value("--image-size")?;
+
+                    // Smaller partitions cannot be formatted as FAT32.
+                    options.image_size = size.parse().ok()
+                        .filter(|&size| size >= 64)
+                        .ok_or_else(|| format!("Invalid image size `{}` (minimum is 64 MiB).",
+                                               size))?;
+                }
+                "--memory"      => options.memory = value("--memory")?.clone(),
+                "--initrd"      => options.initrd = Some(value("--initrd")?.into()),
+                "--config"      => options.config = Some(value("--config")?.into()),
+                "--cmdline"     => options.cmdline = Some(value("--cmdline")?.clone()),
+                "--signing-key" => options.signing_key = Some(value("--signing-key")?.into()),
+                "-h" | "--help" => return Err(String::new()),
+                _ if arg.starts_with('-') => return Err(format!("Unknown option `{}`.", arg)),
+                _ => positional.push(arg.as_str()),
+            }
+        }
+
+        let targets = |target: Option<&str>| {
+            match target {
+                None | Some("all") => Ok(vec![Target::Uefi, Target::Bios]),
+                Some(target)       => {
+                    Target::parse(target)
+                        .map(|target| vec![target])
+                        .ok_or_else(|| format!("Unknown target `{}`.", target))
+                }
+            }
+        };
+
+        let command = match positional.as_slice() {
+            []                => Command::Build(targets(None)?),
+            ["build"]         => Command::Build(targets(None)?),
+            ["build", target] => Command::Build(targets(Some(target))?),
+            ["test"]          => Command::Test(targets(None)?),
+            ["test", target]  => Command::Test(targets(Some(target))?),
+            ["run", target] => {
+                let target = Target::parse(target)
+                    .ok_or_else(|| format!("Unknown run target `{}`.", target))?;
+
+                Command::Run(target)
+            }
+            ["run"] => {
+                return Err(String::from(
+                    "`run` requires a target (bios, uefi, pvh or hybrid)."));
+            }
+            ["clean"] => Command::Clean,
+            _         => return Err(format!("Invalid command `{}`.", positional.join(" "))),
+        };
+
+        let booted = match &command {
+            Command::Run(target)   => vec![*target],
+            Command::Test(targets) => targets.clone(),
+            _                      => Vec::new(),
+        };
+
+        if booted.contains(&Target::Multiboot) {
+            return Err(String::from("QEMU cannot boot Multiboot2 images directly, use GRUB or \
+                                     another Multiboot2 loader (or the `pvh` target)."));
+        }
+
+        Ok(Self {
+            command,
+            options,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Arguments, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+
+        Arguments::parse(&args)
+    }
+
+    #[test]
+    fn defaults() {
+        let arguments = parse(&[]).unwrap();
+
+        assert_eq!(arguments.command, Command::Build(vec![Target::Uefi, Target::Bios]));
+        assert_eq!(arguments.options.smp, 4);
+        assert_eq!(arguments.options.memory, "4G");
+        assert_eq!(arguments.options.image_size, 512);
+    }
+
+    #[test]
+    fn unknown_option() {
+        assert_eq!(parse(&["build", "--foo"]).unwrap_err(), "Unknown option `--foo`.");
+        assert_eq!(parse(&["-x"]).unwrap_err(), "Unknown option `-x`.");
+    }
+
+    #[test]
+    fn missing_value() {
+        for option in &["--smp", "--memory", "--image-size", "--timeout", "--initrd",
+                        "--config", "--cmdline", "--signing-key"] {
+            assert_eq!(parse(&["run", "uefi", option]).unwrap_err(),
+                       format!("Option `{}` requires a value.", option));
+        }
+    }
+
+    #[test]
+    fn smp() {
+        assert_eq!(parse(&["--smp", "16"]).unwrap().options.smp, 16);
+
+        assert_eq!(parse(&["--smp", "0"]).unwrap_err(),   "Invalid core count `0`.");
+        assert_eq!(parse(&["--smp", "-1"]).unwrap_err(),  "Invalid core count `-1`.");
+        assert_eq!(parse(&["--smp", "two"]).unwrap_err(), "Invalid core count `two`.");
+    }
+
+    #[test]
+    fn memory() {
+        assert_eq!(parse(&["--memory", "512M"]).unwrap().options.memory, "512M");
+        assert_eq!(parse(&["--memory", "8G", "--memory", "1G"]).unwrap().options.memory, "1G");
+    }
+
+    #[test]
+    fn image_size() {
+        assert_eq!(parse(&["--image-size", "64"]).unwrap().options.image_size, 64);
+        assert_eq!(parse(&["--image-size", "1024"]).unwrap().options.image_size, 1024);
+
+        assert_eq!(parse(&["--image-size", "63"]).unwrap_err(),
+                   "Invalid image size `63` (minimum is 64 MiB).");
+        assert_eq!(parse(&["--image-size", "1G"]).unwrap_err(),
+                   "Invalid image size `1G` (minimum is 64 MiB).");
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse(&["run", "pvh", "--debug"]).unwrap().command, Command::Run(Target::Pvh));
+        assert_eq!(parse(&["test", "hybrid"]).unwrap().command,
+                   Command::Test(vec![Target::Hybrid]));
+
+        assert_eq!(parse(&["--help"]).unwrap_err(), "");
+        assert!(parse(&["run"]).is_err());
+        assert!(parse(&["run", "multiboot"]).is_err());
+        assert!(parse(&["build", "arm"]).is_err());
+    }
+}
@@ -1,6 +1,11 @@
 use std::path::{Path, PathBuf};
+use std::io::{Cursor, Seek, SeekFrom, Write};
 
-use crate::build::{ImageBuilder, BuildParameters, build};
+use crate::build::{Image, QemuImage, ImageBuilder, BuildParameters, build};
+use crate::args::Options;
+use crate::cache::Stamp;
+use crate::signing::{self, SigningKey};
+use crate::{gpt, initrd, compress};
 
 use elfparse::{Elf, Bitness, SegmentType, Machine};
 use bdd::BootDiskDescriptor;
@@ -11,7 +16,14 @@ const MAX_BOOTLOADER_SIZE:       usize = 400 * 1024;
 const BDD_SIZE:                  usize = 512;
 const BOOTLOADER_BASE:           u64   = 0x10000;
 
-fn prepare_bootloader_binary(binary: Vec<u8>) -> (Vec<u8>, u32) {
+/// MBR type of the boot partition (reserved for OS development). Early bootloader expects
+/// the boot partition to be the first MBR partition entry.
+pub const BOOT_PARTITION_TYPE: u8 = 0x7f;
+
+/// Offset of the partition table in the MBR.
+const MBR_PARTITION_TABLE: usize = 446;
+
+pub fn prepare_bootloader_binary(binary: Vec<u8>) -> (Vec<u8>, u32) {
     println!("\nPreparing bootloader binary...");
 
     let elf = Elf::parse(&binary).expect("Failed to parse bootloader ELF.");
@@ -56,7 +68,7 @@ fn prepare_bootloader_binary(binary: Vec<u8>) -> (Vec<u8>, u32) {
     (mapped, checksum)
 }
 
-fn prepare_kernel_binary(mut binary: Vec<u8>) -> (Vec<u8>, u32) {
+pub fn prepare_kernel_binary(mut binary: Vec<u8>) -> Vec<u8> {
     println!("\nPreparing kernel binary...");
 
     binary.extend(vec![0u8; ((binary.len() + 0xfff) & !0xfff) - binary.len()]);
@@ -65,78 +77,162 @@ fn prepare_kernel_binary(mut binary: Vec<u8>) -> (Vec<u8>, u32) {
 
     assert!(elf.bitness() == Bitness::Bits64, "Kernel is not 64 bit.");
 
-    let checksum = bdd::checksum(&binary);
-
     println!("Kernel base is {:#x}.", elf.base_address());
     println!("Kernel size is {:#x}.", binary.len());
-    println!("Kernel checksum is {:#x}.", checksum);
 
-    (binary, checksum)
+    binary
+}
+
+/// Get the number of sectors required to store `size` bytes.
+fn sectors(size: usize) -> u32 {
+    size.div_ceil(512) as u32
+}
+
+/// LBA (relative to the boot partition) of the bootloader. Boot partition starts with BDD
+/// followed by the rest of the early bootloader (its first sector is stored in the MBR).
+pub fn bootloader_lba(early_bootloader: &[u8]) -> u32 {
+    (early_bootloader.len() / 512) as u32
 }
 
-fn create_boot_image(early_bootloader: &[u8], bootloader: &[u8], kernel: &[u8],
-                     bootloader_checksum: u32, kernel_checksum: u32) -> Vec<u8> {
+/// Create contents of the boot partition: `bdd`, the rest of the early bootloader, the
+/// bootloader and `payload` blobs, each padded to the sector boundary. All LBAs in `bdd`
+/// are relative to the partition start.
+pub fn create_boot_partition(early_bootloader: &[u8], bootloader: &[u8],
+                             bdd: &BootDiskDescriptor, payload: &[&[u8]]) -> Vec<u8> {
     assert!(early_bootloader.len() <= MAX_EARLY_BOOTLOADER_SIZE, "Early bootloader is too big.");
     assert!(bootloader.len() <= MAX_BOOTLOADER_SIZE, "Bootloader is too big.");
 
-    assert!(early_bootloader.len() % 512 == 0, "Early bootloader size is not aligned.");
-    assert!(bootloader.len() % 4096 == 0, "Bootloader size is not aligned.");
-    assert!(kernel.len() % 4096 == 0, "Kernel size is not aligned.");
+    assert!(early_bootloader.len().is_multiple_of(512), "Early bootloader size is not aligned.");
+    assert!(bootloader.len().is_multiple_of(4096), "Bootloader size is not aligned.");
     
     assert!(std::mem::size_of::<BootDiskDescriptor>() <= BDD_SIZE,
             "Boot disk descriptor is too big.");
 
-    let bootloader_sectors = (bootloader.len() / 512) as u32;
-    let kernel_sectors     = (kernel.len()     / 512) as u32;
+    let mut bdd_sector = vec![0u8; BDD_SIZE];
+    unsafe {
+        std::ptr::copy_nonoverlapping(bdd as *const BootDiskDescriptor as *const u8,
+                                      bdd_sector.as_mut_ptr(),
+                                      std::mem::size_of::<BootDiskDescriptor>());
+    }
+
+    let mut partition = Vec::new();
+
+    partition.extend_from_slice(&bdd_sector);
+    partition.extend_from_slice(&early_bootloader[512..]);
+    partition.extend_from_slice(bootloader);
+
+    for blob in payload {
+        partition.extend_from_slice(blob);
+        partition.extend(vec![0u8; ((partition.len() + 511) & !511) - partition.len()]);
+    }
+
+    assert!(partition.len().is_multiple_of(512), "Created boot partition was not aligned.");
 
-    // Add 1 to skip BDD.
-    let first_free_lba = (early_bootloader.len() / 512 + 1) as u32;
+    partition
+}
+
+/// Create MBR which contains the early bootloader code and partition table `entries`. Boot
+/// partition must be the first entry.
+pub fn create_mbr(early_bootloader: &[u8], entries: &[[u8; 16]]) -> Vec<u8> {
+    assert!(entries.len() <= 4, "Too many MBR partition entries.");
+
+    let mut mbr = early_bootloader[..512].to_owned();
+
+    mbr[MBR_PARTITION_TABLE..510].fill(0);
 
-    let bootloader_lba = first_free_lba;
-    let kernel_lba     = bootloader_lba + bootloader_sectors;
+    for (index, entry) in entries.iter().enumerate() {
+        mbr[MBR_PARTITION_TABLE + index * 16..][..16].copy_from_slice(entry);
+    }
+
+    mbr
+}
 
-    let bdd = BootDiskDescriptor {
-        signature: bdd::SIGNATURE,
-        bootloader_lba,
-        bootloader_sectors,
-        bootloader_checksum,
-        kernel_lba,
-        kernel_sectors,
-        kernel_checksum,
+/// Create a disk image with MBR partition table and the boot partition. If `hybrid_gpt` is
+/// set, the disk will also get a GPT describing the boot partition as a BIOS boot partition.
+fn create_boot_image(early_bootloader: &[u8], boot_partition: &[u8],
+                     hybrid_gpt: bool) -> Vec<u8> {
+    let partition = gpt::Partition {
+        type_guid: gpt::BIOS_BOOT_TYPE_GUID,
+        name:      "flugzeug boot",
+        first_lba: gpt::PARTITION_ALIGNMENT,
+        last_lba:  gpt::PARTITION_ALIGNMENT + boot_partition.len() as u64 / 512 - 1,
     };
 
-    let mut bdd_sector = vec![0u8; 512];
-    unsafe {
-        std::ptr::copy_nonoverlapping(&bdd as *const BootDiskDescriptor as *const u8,
-                                      bdd_sector.as_mut_ptr(),
-                                      std::mem::size_of::<BootDiskDescriptor>());
+    // Reserve space for the backup GPT (it has the same size as the primary one) and round
+    // the image size up to 1MB.
+    let gpt_sectors   = if hybrid_gpt { gpt::FIRST_USABLE_LBA } else { 0 };
+    let used_sectors  = partition.last_lba + 1 + gpt_sectors;
+    let total_sectors = used_sectors.div_ceil(gpt::PARTITION_ALIGNMENT) *
+        gpt::PARTITION_ALIGNMENT;
+
+    let mut image = Cursor::new(vec![0u8; (total_sectors * gpt::SECTOR_SIZE) as usize]);
+
+    if hybrid_gpt {
+        gpt::write_gpt(&mut image, total_sectors, &[partition])
+            .expect("Failed to write GPT.");
     }
 
-    let mut image = Vec::new();
+    // Early bootloader occupies the MBR code area. Hybrid MBR additionally contains
+    // a protective partition which covers the GPT.
+    let sectors = partition.last_lba - partition.first_lba + 1;
+    let mut entries = vec![
+        gpt::mbr_entry(true, BOOT_PARTITION_TYPE, partition.first_lba, sectors),
+    ];
 
-    image.extend_from_slice(&early_bootloader[..512]);
-    image.extend_from_slice(&bdd_sector);
-    image.extend_from_slice(&early_bootloader[512..]);
-    image.extend_from_slice(&bootloader);
-    image.extend_from_slice(&kernel);
+    if hybrid_gpt {
+        entries.push(gpt::mbr_entry(false, 0xee, 1, partition.first_lba - 1));
+    }
+
+    let mbr = create_mbr(early_bootloader, &entries);
 
-    assert!(image.len() % 512 == 0, "Created image was not aligned.");
+    image.seek(SeekFrom::Start(0))
+        .and_then(|_| image.write_all(&mbr))
+        .and_then(|_| image.seek(SeekFrom::Start(partition.offset())))
+        .and_then(|_| image.write_all(boot_partition))
+        .expect("Failed to write boot partition.");
 
-    image
+    image.into_inner()
 }
 
 pub struct BiosBuilder {
     kernel_path:          PathBuf,
     bootloader_dir:       PathBuf,
     bootloader_build_dir: PathBuf,
+    command_line:         Vec<u8>,
+    initrd_path:          Option<PathBuf>,
+    hybrid_gpt:           bool,
+    compress_kernel:      bool,
+    signing_key:          Option<SigningKey>,
+}
+
+impl Image for BiosBuilder {
+    fn image_name() -> &'static str {
+        "flugzeug_bios"
+    }
+}
+
+impl QemuImage for BiosBuilder {
+    fn qemu_arguments(image_path: &Path, _options: &Options) -> Vec<String> {
+        vec![
+            String::from("-drive"),
+            format!("file={},format=raw,index=0,media=disk", make_path!(image_path)),
+        ]
+    }
 }
 
 impl ImageBuilder for BiosBuilder {
-    fn new(kernel_path: &Path, bootloader_dir: &Path, bootloader_build_dir: &Path) -> Self {
+    fn new(kernel_path: &Path, bootloader_dir: &Path, bootloader_build_dir: &Path,
+           options: &Options) -> Self {
         Self {
             kernel_path:          kernel_path.to_owned(),
             bootloader_dir:       bootloader_dir.to_owned(),
             bootloader_build_dir: bootloader_build_dir.to_owned(),
+            command_line:         options.command_line().unwrap_or_default(),
+            initrd_path:          options.initrd.clone(),
+            hybrid_gpt:           options.hybrid_gpt,
+            compress_kernel:      options.compress_kernel,
+            signing_key:          options.signing_key.as_deref()
+                .map(SigningKey::load_or_create),
         }
     }
 
@@ -144,11 +240,19 @@ impl ImageBuilder for BiosBuilder {
         "bios_bootloader"
     }
 
-    fn image_name() -> &'static str {
-        "flugzeug_bios"
-    }
-
     fn build_bootloader_dependencies(&mut self) {
+        let source = self.bootloader_dir.join("src").join("early.asm");
+        let output = self.bootloader_build_dir.join("early.bin");
+        let stamp  = Stamp::from_files(&self.bootloader_build_dir.join("early.stamp"), &[&source])
+            .expect("Failed to read early bootloader source.");
+
+        if stamp.is_up_to_date(&[&output]) {
+            println!("\nEarly bootloader stage is up to date.");
+            return;
+        }
+
+        stamp.invalidate();
+
         println!("\nCompiling early bootloader stage...");
         if !build(
             "nasm", None,
@@ -162,35 +266,108 @@ impl ImageBuilder for BiosBuilder {
         ) {
             std::process::exit(1);
         }
+
+        stamp.save();
     }
 
     fn bootloader_build_parameters(&mut self) -> BuildParameters {
         BuildParameters {
             args: Vec::new(),
-            envs: Vec::new(),
+            envs: self.signing_key.iter().map(SigningKey::bootloader_env).collect(),
         }
     }
 
     fn create_image(&mut self, image_path: &Path) {
-        let early_bootloader = std::fs::read(make_path!(self.bootloader_build_dir, "early.bin"))
-            .expect("Failed to read early bootloader binary.");
-
-        let bootloader = std::fs::read(make_path!(self.bootloader_build_dir, "i586-unknown-none",
-                                                  "release", "bios_bootloader"))
-            .expect("Failed to read bootloader binary.");
+        let early_bootloader = self.early_bootloader();
+        let bootloader       = self.bootloader();
 
         let kernel = std::fs::read(&self.kernel_path)
             .expect("Failed to read kernel binary.");
 
+        let initrd = self.initrd_path.as_deref()
+            .map(initrd::create)
+            .unwrap_or_default();
+
+        assert!(initrd.len() <= u32::MAX as usize, "Initrd is too big.");
+
+        let stamp = Stamp::new(&self.bootloader_build_dir.join("image.stamp"),
+                               &[&early_bootloader, &bootloader, &kernel,
+                                 &self.command_line, &initrd,
+                                 &[self.hybrid_gpt as u8, self.compress_kernel as u8]]);
+
+        if stamp.is_up_to_date(&[image_path]) {
+            println!("\nBootable image is up to date.");
+            return;
+        }
+
+        stamp.invalidate();
+
         let (bootloader, bootloader_checksum) = prepare_bootloader_binary(bootloader);
-        let (kernel,     kernel_checksum)     = prepare_kernel_binary(kernel);
+        let kernel                            = prepare_kernel_binary(kernel);
 
         println!("\nCreating bootable image...");
 
-        let image = create_boot_image(&early_bootloader, &bootloader, &kernel,
-                                      bootloader_checksum, kernel_checksum);
+        // Manifest always describes the decompressed kernel.
+        let manifest = signing::create_manifest(self.signing_key.as_ref(), &kernel,
+                                                &self.command_line, &initrd);
+
+        let kernel = if self.compress_kernel {
+            compress::compress_kernel(&kernel)
+        } else {
+            kernel
+        };
+
+        // Kernel, command line and initrd are stored in the boot partition right after
+        // the bootloader.
+        let bootloader_lba     = bootloader_lba(&early_bootloader);
+        let bootloader_sectors = sectors(bootloader.len());
+        let kernel_lba         = bootloader_lba + bootloader_sectors;
+        let kernel_sectors     = sectors(kernel.len());
+        let cmdline_lba        = kernel_lba + kernel_sectors;
+        let initrd_lba         = cmdline_lba + sectors(self.command_line.len());
+
+        let bdd = BootDiskDescriptor {
+            signature: bdd::SIGNATURE,
+            bootloader_lba,
+            bootloader_sectors,
+            bootloader_checksum,
+            kernel_lba,
+            kernel_sectors,
+            cmdline_lba,
+            cmdline_size: self.command_line.len() as u32,
+            initrd_lba,
+            initrd_size:  initrd.len() as u32,
+            manifest,
+        };
+
+        let boot_partition = create_boot_partition(&early_bootloader, &bootloader, &bdd,
+                                                   &[&kernel, &self.command_line, &initrd]);
+
+        let image = create_boot_image(&early_bootloader, &boot_partition, self.hybrid_gpt);
 
         std::fs::write(image_path, &image)
             .expect("Failed to write created image to disk.");
+
+        stamp.save();
+    }
+}
+
+impl BiosBuilder {
+    /// Read the early bootloader stage (MBR code followed by the rest of the stage).
+    pub fn early_bootloader(&self) -> Vec<u8> {
+        std::fs::read(make_path!(self.bootloader_build_dir, "early.bin"))
+            .expect("Failed to read early bootloader binary.")
+    }
+
+    /// Get the key used to sign boot payloads (compiled into the bootloader).
+    pub fn signing_key(&self) -> Option<&SigningKey> {
+        self.signing_key.as_ref()
+    }
+
+    /// Read the bootloader ELF.
+    pub fn bootloader(&self) -> Vec<u8> {
+        std::fs::read(make_path!(self.bootloader_build_dir, "i586-unknown-none", "release",
+                                 "bios_bootloader"))
+            .expect("Failed to read bootloader binary.")
     }
 }
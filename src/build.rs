@@ -2,6 +2,8 @@ use std::process::Command;
 use std::path::{Path, PathBuf};
 use std::io;
 
+use crate::args::Options;
+
 #[macro_export]
 macro_rules! make_path {
     ($path: expr) => {
@@ -64,12 +66,31 @@ pub struct BuildParameters {
     pub envs: Vec<(String, String)>,
 }
 
-pub trait ImageBuilder {
+pub trait Image {
+    fn image_name() -> &'static str;
+}
+
+/// Image which can be booted by QEMU.
+pub trait QemuImage: Image {
+    /// QEMU arguments (firmware, drives) required to boot image at `image_path`.
+    fn qemu_arguments(image_path: &Path, options: &Options) -> Vec<String>;
+
+    /// QEMU arguments used by integration tests. Tests must not modify tracked files.
+    fn test_qemu_arguments(image_path: &Path, options: &Options) -> Vec<String> {
+        Self::qemu_arguments(image_path, options)
+    }
+
+    /// Number of cores launched by the kernel booted from this image.
+    fn cores(options: &Options) -> u32 {
+        options.smp
+    }
+}
+
+pub trait ImageBuilder: Image {
     fn new(kernel_path: &Path, bootloader_dir: &Path,
-           bootloader_build_dir: &Path) -> Self;
+           bootloader_build_dir: &Path, options: &Options) -> Self;
 
     fn bootloader_name() -> &'static str;
-    fn image_name() -> &'static str;
 
     fn build_bootloader_dependencies(&mut self);
     fn bootloader_build_parameters(&mut self) -> BuildParameters;
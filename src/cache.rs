@@ -0,0 +1,69 @@
+use std::path::{Path, PathBuf};
+use std::fs;
+
+/// 64 bit FNV-1a hash. Used only to detect changes in build inputs.
+fn hash(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Hash of build stage inputs stored on disk to skip stages whose inputs haven't changed.
+pub struct Stamp {
+    path: PathBuf,
+    hash: u64,
+}
+
+impl Stamp {
+    /// Create a stamp at `path` from the contents of all `inputs`.
+    pub fn new(path: &Path, inputs: &[&[u8]]) -> Self {
+        let hash = inputs.iter().fold(0xcbf2_9ce4_8422_2325, |current, input| {
+            // Include the input length so moving bytes between inputs changes the hash.
+            let current = hash(current, &(input.len() as u64).to_le_bytes());
+
+            hash(current, input)
+        });
+
+        Self {
+            path: path.to_owned(),
+            hash,
+        }
+    }
+
+    /// Create a stamp at `path` from the contents of files at `inputs`. Returns `None` if
+    /// some input file cannot be read.
+    pub fn from_files(path: &Path, inputs: &[&Path]) -> Option<Self> {
+        let contents = inputs.iter()
+            .map(fs::read)
+            .collect::<Result<Vec<_>, _>>()
+            .ok()?;
+
+        let contents: Vec<&[u8]> = contents.iter().map(|x| x.as_slice()).collect();
+
+        Some(Self::new(path, &contents))
+    }
+
+    /// Returns `true` if the stage was previously completed with the same inputs and
+    /// all `outputs` still exist.
+    pub fn is_up_to_date(&self, outputs: &[&Path]) -> bool {
+        let saved = fs::read_to_string(&self.path).ok()
+            .and_then(|saved| u64::from_str_radix(saved.trim(), 16).ok());
+
+        saved == Some(self.hash) && outputs.iter().all(|output| output.exists())
+    }
+
+    /// Record that the stage was completed with current inputs.
+    pub fn save(&self) {
+        fs::write(&self.path, format!("{:016x}\n", self.hash))
+            .expect("Failed to write build stamp.");
+    }
+
+    /// Remove the stamp so the stage will be rerun. Used before the stage modifies
+    /// its outputs in case it fails in the middle.
+    pub fn invalidate(&self) {
+        if self.path.exists() {
+            fs::remove_file(&self.path)
+                .expect("Failed to remove build stamp.");
+        }
+    }
+}
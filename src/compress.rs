@@ -0,0 +1,17 @@
+/// Compress the kernel using LZ4. Compressed data is preceded by `lz4::Header` which records
+/// both sizes and the compressed data digest, so the bootloaders can detect and verify it.
+/// Digest of the decompressed kernel is stored in the boot manifest.
+pub fn compress_kernel(kernel: &[u8]) -> Vec<u8> {
+    let mut compressed = vec![0u8; lz4::max_compressed_size(kernel.len())];
+    let size           = lz4::compress(kernel, &mut compressed);
+
+    compressed.truncate(size);
+
+    let mut result = lz4::Header::new(&compressed, kernel.len()).to_bytes().to_vec();
+
+    result.extend_from_slice(&compressed);
+
+    println!("Compressed kernel: {} -> {} bytes.", kernel.len(), result.len());
+
+    result
+}
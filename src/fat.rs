@@ -0,0 +1,191 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::convert::TryInto;
+
+const DIRECTORY_ENTRY_SIZE: usize = 32;
+
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_LONG_NAME: u8 = 0x0f;
+
+/// FAT entries with this value or bigger mark the end of cluster chain.
+const END_OF_CHAIN: u32 = 0x0fff_fff8;
+
+/// Location of a file on the filesystem.
+#[derive(Copy, Clone, Debug)]
+pub struct Extent {
+    /// Offset of the file data from the start of the filesystem.
+    pub offset: u64,
+    pub size:   u64,
+}
+
+/// Convert file name to the FAT short name. Returns `None` if the name doesn't fit.
+fn short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, extension) = name.split_once('.').unwrap_or((name, ""));
+
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 || !name.is_ascii() {
+        return None;
+    }
+
+    let mut short_name = [b' '; 11];
+
+    short_name[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short_name[8..][..extension.len()].copy_from_slice(extension.to_ascii_uppercase().as_bytes());
+
+    Some(short_name)
+}
+
+/// Minimal read-only FAT32 parser used to find where files are stored on the disk.
+pub struct Fat32<D> {
+    disk:         D,
+    fat_offset:   u64,
+    data_offset:  u64,
+    cluster_size: u64,
+    root_cluster: u32,
+}
+
+impl<D: Read + Seek> Fat32<D> {
+    pub fn new(mut disk: D) -> io::Result<Self> {
+        let mut boot_sector = [0u8; 512];
+
+        disk.seek(SeekFrom::Start(0))?;
+        disk.read_exact(&mut boot_sector)?;
+
+        let u16_at = |offset: usize| {
+            u16::from_le_bytes(boot_sector[offset..][..2].try_into().unwrap()) as u64
+        };
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes(boot_sector[offset..][..4].try_into().unwrap())
+        };
+
+        let bytes_per_sector    = u16_at(11);
+        let sectors_per_cluster = boot_sector[13] as u64;
+        let reserved_sectors    = u16_at(14);
+        let fat_count           = boot_sector[16] as u64;
+        let fat_sectors         = u32_at(36) as u64;
+
+        if bytes_per_sector == 0 || sectors_per_cluster == 0 ||
+           &boot_sector[82..90] != b"FAT32   " {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid FAT32 filesystem."));
+        }
+
+        Ok(Self {
+            disk,
+            fat_offset:   reserved_sectors * bytes_per_sector,
+            data_offset:  (reserved_sectors + fat_count * fat_sectors) * bytes_per_sector,
+            cluster_size: sectors_per_cluster * bytes_per_sector,
+            root_cluster: u32_at(44),
+        })
+    }
+
+    fn next_cluster(&mut self, cluster: u32) -> io::Result<u32> {
+        let mut entry = [0u8; 4];
+
+        self.disk.seek(SeekFrom::Start(self.fat_offset + cluster as u64 * 4))?;
+        self.disk.read_exact(&mut entry)?;
+
+        Ok(u32::from_le_bytes(entry) & 0x0fff_ffff)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        self.data_offset + (cluster as u64 - 2) * self.cluster_size
+    }
+
+    /// Read the whole cluster chain starting at `cluster`.
+    fn read_chain(&mut self, mut cluster: u32) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+
+        while (2..END_OF_CHAIN).contains(&cluster) {
+            let start = data.len();
+
+            data.resize(start + self.cluster_size as usize, 0);
+
+            self.disk.seek(SeekFrom::Start(self.cluster_offset(cluster)))?;
+            self.disk.read_exact(&mut data[start..])?;
+
+            cluster = self.next_cluster(cluster)?;
+        }
+
+        Ok(data)
+    }
+
+    /// Find `name` in the directory starting at `cluster`. Returns its first cluster,
+    /// size and attributes.
+    fn find_entry(&mut self, cluster: u32, name: &str) -> io::Result<Option<(u32, u64, u8)>> {
+        let short_name = match short_name(name) {
+            Some(short_name) => short_name,
+            None             => return Ok(None),
+        };
+
+        let directory = self.read_chain(cluster)?;
+
+        for entry in directory.chunks_exact(DIRECTORY_ENTRY_SIZE) {
+            match entry[0] {
+                0x00 => break,
+                0xe5 => continue,
+                _    => (),
+            }
+
+            let attributes = entry[11];
+
+            if attributes == ATTRIBUTE_LONG_NAME || entry[..11] != short_name {
+                continue;
+            }
+
+            let high    = u16::from_le_bytes(entry[20..22].try_into().unwrap()) as u32;
+            let low     = u16::from_le_bytes(entry[26..28].try_into().unwrap()) as u32;
+            let size    = u32::from_le_bytes(entry[28..32].try_into().unwrap()) as u64;
+
+            return Ok(Some(((high << 16) | low, size, attributes)));
+        }
+
+        Ok(None)
+    }
+
+    /// Find where file at `path` (components separated by `/`) is stored. Returns `None` if
+    /// the file doesn't exist, it's empty or its clusters are not contiguous.
+    pub fn contiguous_file(&mut self, path: &str) -> io::Result<Option<Extent>> {
+        let mut cluster = self.root_cluster;
+        let mut file    = None;
+
+        for (index, component) in path.split('/').enumerate() {
+            // Only the last component can be a file.
+            if index > 0 && file.is_some() {
+                return Ok(None);
+            }
+
+            let (first_cluster, size, attributes) = match self.find_entry(cluster, component)? {
+                Some(entry) => entry,
+                None        => return Ok(None),
+            };
+
+            if attributes & ATTRIBUTE_DIRECTORY == 0 {
+                file = Some(size);
+            }
+
+            cluster = first_cluster;
+        }
+
+        let size = match file {
+            Some(size) if size > 0 => size,
+            _                      => return Ok(None),
+        };
+
+        // Make sure that all clusters of the file follow each other.
+        let clusters = size.div_ceil(self.cluster_size);
+        let first    = cluster;
+
+        for index in 1..clusters {
+            let next = self.next_cluster(cluster)?;
+
+            if next != first + index as u32 {
+                return Ok(None);
+            }
+
+            cluster = next;
+        }
+
+        Ok(Some(Extent {
+            offset: self.cluster_offset(first),
+            size,
+        }))
+    }
+}
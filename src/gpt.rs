@@ -0,0 +1,332 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::convert::TryInto;
+
+pub const SECTOR_SIZE: u64 = 512;
+
+const HEADER_SIZE:     usize = 92;
+const ENTRY_SIZE:      usize = 128;
+const ENTRY_COUNT:     usize = 128;
+const ENTRIES_SECTORS: u64   = (ENTRY_SIZE * ENTRY_COUNT) as u64 / SECTOR_SIZE;
+
+/// First LBA which can be used by partitions. Preceded by protective MBR, GPT header
+/// and partition entries.
+pub const FIRST_USABLE_LBA: u64 = 2 + ENTRIES_SECTORS;
+
+/// Partitions are aligned to 1MB boundary.
+pub const PARTITION_ALIGNMENT: u64 = 1024 * 1024 / SECTOR_SIZE;
+
+pub type Guid = [u8; 16];
+
+/// Create GUID from its textual representation fields. First three fields are stored
+/// as little endian.
+pub const fn guid(a: u32, b: u16, c: u16, d: [u8; 8]) -> Guid {
+    let a = a.to_le_bytes();
+    let b = b.to_le_bytes();
+    let c = c.to_le_bytes();
+
+    [
+        a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1],
+        d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7],
+    ]
+}
+
+/// EFI System Partition.
+pub const ESP_TYPE_GUID: Guid =
+    guid(0xc12a7328, 0xf81f, 0x11d2, [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b]);
+
+/// BIOS boot partition. Reserved for bootloader code, firmwares and OSes leave it alone.
+pub const BIOS_BOOT_TYPE_GUID: Guid =
+    guid(0x21686148, 0x6449, 0x6e6f, [0x74, 0x4e, 0x65, 0x65, 0x64, 0x45, 0x46, 0x49]);
+
+fn random_guid() -> Guid {
+    let mut hasher = RandomState::new().build_hasher();
+    let mut guid   = [0u8; 16];
+
+    for chunk in guid.chunks_mut(8) {
+        hasher.write_u64(0);
+
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+    }
+
+    // Mark GUID as random (version 4, variant 1).
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+
+    guid
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in bytes {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+#[derive(Copy, Clone)]
+pub struct Partition {
+    pub type_guid: Guid,
+    pub name:      &'static str,
+    pub first_lba: u64,
+    pub last_lba:  u64,
+}
+
+impl Partition {
+    pub fn offset(&self) -> u64 {
+        self.first_lba * SECTOR_SIZE
+    }
+
+    pub fn size(&self) -> u64 {
+        (self.last_lba - self.first_lba + 1) * SECTOR_SIZE
+    }
+}
+
+/// Last LBA which can be used by partitions. Followed by backup partition entries and
+/// backup GPT header.
+pub fn last_usable_lba(total_sectors: u64) -> u64 {
+    total_sectors - 2 - ENTRIES_SECTORS
+}
+
+/// Create a partition which spans all usable space of the disk (aligned to 1MB).
+pub fn whole_disk_partition(total_sectors: u64, type_guid: Guid,
+                            name: &'static str) -> Partition {
+    Partition {
+        type_guid,
+        name,
+        first_lba: PARTITION_ALIGNMENT,
+        last_lba:  last_usable_lba(total_sectors),
+    }
+}
+
+/// Create protective MBR partition entry which covers the whole GPT disk.
+pub fn protective_mbr_entry(total_sectors: u64) -> [u8; 16] {
+    let sectors = std::cmp::min(total_sectors - 1, 0xffff_ffff) as u32;
+
+    let mut entry = [0u8; 16];
+
+    // Start CHS, partition type, end CHS.
+    entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+    entry[4] = 0xee;
+    entry[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+
+    entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+
+    entry
+}
+
+/// Create MBR partition entry which starts at `first_lba` and spans `sectors` sectors.
+/// CHS fields are set to the maximum values so only LBA fields are used.
+pub fn mbr_entry(active: bool, partition_type: u8, first_lba: u64, sectors: u64) -> [u8; 16] {
+    let first_lba = std::cmp::min(first_lba, 0xffff_ffff) as u32;
+    let sectors   = std::cmp::min(sectors, 0xffff_ffff) as u32;
+
+    let mut entry = [0u8; 16];
+
+    // Status, start CHS, partition type, end CHS.
+    entry[0] = if active { 0x80 } else { 0x00 };
+    entry[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    entry[4] = partition_type;
+    entry[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+
+    entry[8..12].copy_from_slice(&first_lba.to_le_bytes());
+    entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+
+    entry
+}
+
+/// Write MBR with a protective GPT partition to the first sector of the disk.
+pub fn write_protective_mbr<D: Write + Seek>(disk: &mut D, total_sectors: u64) -> io::Result<()> {
+    let mut mbr = [0u8; SECTOR_SIZE as usize];
+
+    mbr[446..462].copy_from_slice(&protective_mbr_entry(total_sectors));
+    mbr[510] = 0x55;
+    mbr[511] = 0xaa;
+
+    disk.seek(SeekFrom::Start(0))?;
+    disk.write_all(&mbr)
+}
+
+fn header(current_lba: u64, backup_lba: u64, entries_lba: u64, total_sectors: u64,
+          disk_guid: &Guid, entries_crc: u32) -> Vec<u8> {
+    let mut header = vec![0u8; SECTOR_SIZE as usize];
+
+    header[0..8].copy_from_slice(b"EFI PART");
+    header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+    header[12..16].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+    header[24..32].copy_from_slice(&current_lba.to_le_bytes());
+    header[32..40].copy_from_slice(&backup_lba.to_le_bytes());
+    header[40..48].copy_from_slice(&FIRST_USABLE_LBA.to_le_bytes());
+    header[48..56].copy_from_slice(&last_usable_lba(total_sectors).to_le_bytes());
+    header[56..72].copy_from_slice(disk_guid);
+    header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+    header[80..84].copy_from_slice(&(ENTRY_COUNT as u32).to_le_bytes());
+    header[84..88].copy_from_slice(&(ENTRY_SIZE as u32).to_le_bytes());
+    header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+
+    let header_crc = crc32(&header[..HEADER_SIZE]);
+
+    header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+
+    header
+}
+
+/// Write primary and backup GPT describing `partitions` to the disk.
+pub fn write_gpt<D: Write + Seek>(disk: &mut D, total_sectors: u64,
+                                  partitions: &[Partition]) -> io::Result<()> {
+    assert!(partitions.len() <= ENTRY_COUNT, "Too many GPT partitions.");
+
+    let mut entries = vec![0u8; ENTRY_SIZE * ENTRY_COUNT];
+
+    for (partition, entry) in partitions.iter().zip(entries.chunks_mut(ENTRY_SIZE)) {
+        assert!(partition.first_lba >= FIRST_USABLE_LBA &&
+                partition.last_lba <= last_usable_lba(total_sectors) &&
+                partition.first_lba <= partition.last_lba, "Invalid GPT partition bounds.");
+
+        entry[0..16].copy_from_slice(&partition.type_guid);
+        entry[16..32].copy_from_slice(&random_guid());
+        entry[32..40].copy_from_slice(&partition.first_lba.to_le_bytes());
+        entry[40..48].copy_from_slice(&partition.last_lba.to_le_bytes());
+
+        for (index, character) in partition.name.encode_utf16().take(36).enumerate() {
+            entry[56 + index * 2..][..2].copy_from_slice(&character.to_le_bytes());
+        }
+    }
+
+    let entries_crc = crc32(&entries);
+    let disk_guid   = random_guid();
+
+    let backup_lba         = total_sectors - 1;
+    let backup_entries_lba = backup_lba - ENTRIES_SECTORS;
+
+    let primary = header(1, backup_lba, 2, total_sectors, &disk_guid, entries_crc);
+    let backup  = header(backup_lba, 1, backup_entries_lba, total_sectors, &disk_guid,
+                         entries_crc);
+
+    disk.seek(SeekFrom::Start(SECTOR_SIZE))?;
+    disk.write_all(&primary)?;
+    disk.write_all(&entries)?;
+
+    disk.seek(SeekFrom::Start(backup_entries_lba * SECTOR_SIZE))?;
+    disk.write_all(&entries)?;
+    disk.write_all(&backup)
+}
+
+/// Read the primary GPT and return `(type GUID, first LBA, last LBA)` of the partition
+/// with index `index`. Returns `None` if GPT is invalid or partition entry is unused.
+pub fn read_partition<D: Read + Seek>(disk: &mut D, index: usize) -> Option<(Guid, u64, u64)> {
+    let mut header = [0u8; HEADER_SIZE];
+
+    disk.seek(SeekFrom::Start(SECTOR_SIZE)).ok()?;
+    disk.read_exact(&mut header).ok()?;
+
+    let expected_crc = u32::from_le_bytes(header[16..20].try_into().unwrap());
+
+    header[16..20].copy_from_slice(&[0; 4]);
+
+    if &header[0..8] != b"EFI PART" || crc32(&header) != expected_crc || index >= ENTRY_COUNT {
+        return None;
+    }
+
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let mut entry   = [0u8; ENTRY_SIZE];
+
+    disk.seek(SeekFrom::Start(entries_lba * SECTOR_SIZE + (index * ENTRY_SIZE) as u64)).ok()?;
+    disk.read_exact(&mut entry).ok()?;
+
+    let type_guid: Guid = entry[0..16].try_into().unwrap();
+    let first_lba       = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+    let last_lba        = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+
+    if type_guid == [0; 16] {
+        return None;
+    }
+
+    Some((type_guid, first_lba, last_lba))
+}
+
+/// View of a single partition of the disk. Used to give filesystem code access to the
+/// partition only.
+pub struct PartitionStream<D> {
+    disk:     D,
+    offset:   u64,
+    size:     u64,
+    position: u64,
+}
+
+impl<D: Seek> PartitionStream<D> {
+    pub fn new(disk: D, partition: &Partition) -> Self {
+        Self {
+            disk,
+            offset:   partition.offset(),
+            size:     partition.size(),
+            position: 0,
+        }
+    }
+
+    /// Seek the disk to the current position and get the number of bytes which can be
+    /// accessed without going outside of the partition.
+    fn prepare(&mut self, length: usize) -> io::Result<usize> {
+        self.disk.seek(SeekFrom::Start(self.offset + self.position))?;
+
+        Ok(std::cmp::min(length as u64, self.size - self.position) as usize)
+    }
+}
+
+impl<D: Read + Seek> Read for PartitionStream<D> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let length = self.prepare(buffer.len())?;
+        let read   = self.disk.read(&mut buffer[..length])?;
+
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl<D: Write + Seek> Write for PartitionStream<D> {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        let length  = self.prepare(buffer.len())?;
+        let written = self.disk.write(&buffer[..length])?;
+
+        self.position += written as u64;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.disk.flush()
+    }
+}
+
+impl<D: Seek> Seek for PartitionStream<D> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta)      => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta)  => self.position.checked_add_signed(delta),
+        };
+
+        match position {
+            Some(position) if position <= self.size => {
+                self.position = position;
+
+                Ok(position)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                    "Seek outside of the partition.")),
+        }
+    }
+}
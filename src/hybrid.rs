@@ -0,0 +1,189 @@
+use std::path::Path;
+use std::io::{Seek, SeekFrom, Write};
+use std::fs;
+
+use crate::args::Options;
+use crate::bios::{self, BiosBuilder};
+use crate::uefi::{self, UefiBuilder};
+use crate::cache::Stamp;
+use crate::fat::Fat32;
+use crate::{gpt, initrd, signing, compress};
+
+use bdd::BootDiskDescriptor;
+
+pub const IMAGE_NAME: &str = "flugzeug_hybrid";
+
+/// Size of the BIOS boot partition. It contains only BDD and the bootloaders.
+const BOOT_PARTITION_SECTORS: u64 = gpt::PARTITION_ALIGNMENT;
+
+/// Create an image bootable by both BIOS and UEFI firmware. The disk has a GPT with the BIOS
+/// boot partition followed by the EFI System Partition, and a hybrid MBR with the early BIOS
+/// bootloader. The kernel, the initrd and the config are stored only once on the ESP
+/// and BDD points the BIOS bootloader to their sectors.
+///
+/// `uefi` must be built with a separate kernel.
+pub fn create_image(bios: &BiosBuilder, uefi: &UefiBuilder, kernel_path: &Path,
+                    options: &Options, image_path: &Path) {
+    let early_bootloader = bios.early_bootloader();
+    let bios_bootloader  = bios.bootloader();
+    let uefi_bootloader  = uefi.bootloader();
+
+    let kernel = fs::read(kernel_path)
+        .expect("Failed to read kernel binary.");
+
+    let initrd = options.initrd.as_deref()
+        .map(initrd::create)
+        .unwrap_or_default();
+
+    let command_line = options.command_line().unwrap_or_default();
+
+    assert!(initrd.len() <= u32::MAX as usize, "Initrd is too big.");
+
+    let image_size = options.image_size * 1024 * 1024;
+    let size_bytes = image_size.to_le_bytes();
+
+    let stamp = Stamp::new(&Path::new("build").join("hybrid.stamp"),
+                           &[&size_bytes, &early_bootloader, &bios_bootloader,
+                             &uefi_bootloader, &kernel, &command_line, &initrd,
+                             &[options.compress_kernel as u8]]);
+
+    if stamp.is_up_to_date(&[image_path]) {
+        println!("\nBootable image is up to date.");
+        return;
+    }
+
+    stamp.invalidate();
+
+    let (bios_bootloader, bootloader_checksum) =
+        bios::prepare_bootloader_binary(bios_bootloader);
+    let kernel = bios::prepare_kernel_binary(kernel);
+
+    println!("\nCreating bootable image...");
+
+    // Both bootloaders use the same manifest, UEFI one reads it from the ESP.
+    let manifest = signing::create_manifest(bios.signing_key(), &kernel, &command_line,
+                                            &initrd);
+    let manifest_bytes = manifest.to_bytes();
+
+    let kernel = if options.compress_kernel {
+        compress::compress_kernel(&kernel)
+    } else {
+        kernel
+    };
+
+    let total_sectors  = image_size / gpt::SECTOR_SIZE;
+    let boot_partition = gpt::Partition {
+        type_guid: gpt::BIOS_BOOT_TYPE_GUID,
+        name:      "flugzeug boot",
+        first_lba: gpt::PARTITION_ALIGNMENT,
+        last_lba:  gpt::PARTITION_ALIGNMENT + BOOT_PARTITION_SECTORS - 1,
+    };
+    let esp = gpt::Partition {
+        type_guid: gpt::ESP_TYPE_GUID,
+        name:      "EFI System Partition",
+        first_lba: boot_partition.last_lba + 1,
+        last_lba:  gpt::last_usable_lba(total_sectors),
+    };
+
+    // Image is always created from scratch so files on the ESP are not fragmented.
+    let image_file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(image_path)
+        .expect("Failed to create hybrid bootable image.");
+
+    image_file
+        .set_len(image_size)
+        .expect("Failed to set length of hybrid image file.");
+
+    gpt::write_gpt(&mut &image_file, total_sectors, &[boot_partition, esp])
+        .expect("Failed to write GPT.");
+
+    uefi::format_esp(&image_file, &esp);
+
+    let files: [(&str, &[u8]); 5] = [
+        (uefi::BOOTLOADER_PATH, &uefi_bootloader),
+        (uefi::KERNEL_PATH,     &kernel),
+        (uefi::INITRD_PATH,     &initrd),
+        (uefi::CONFIG_PATH,     &command_line),
+        (uefi::MANIFEST_PATH,   &manifest_bytes),
+    ];
+
+    {
+        let fs = fatfs::FileSystem::new(gpt::PartitionStream::new(&image_file, &esp),
+                                        fatfs::FsOptions::new())
+            .expect("Failed to open FAT32 filesystem.");
+
+        for (path, contents) in &files {
+            // UEFI bootloader treats missing initrd and config as not provided.
+            if !contents.is_empty() {
+                uefi::write_file(&fs.root_dir(), path, contents);
+            }
+        }
+    }
+
+    // Find sectors of the files which are loaded by the BIOS bootloader. BDD LBAs are
+    // relative to the boot partition.
+    let mut fat = Fat32::new(gpt::PartitionStream::new(&image_file, &esp))
+        .expect("Failed to read FAT32 filesystem.");
+
+    let mut locate = |path: &str, size: usize| {
+        if size == 0 {
+            return 0;
+        }
+
+        let extent = fat.contiguous_file(path)
+            .expect("Failed to read FAT32 filesystem.")
+            .unwrap_or_else(|| panic!("File `{}` is not stored contiguously on the ESP.", path));
+
+        assert!(extent.size == size as u64 && extent.offset.is_multiple_of(gpt::SECTOR_SIZE),
+                "File `{}` was stored incorrectly on the ESP.", path);
+
+        (esp.first_lba - boot_partition.first_lba + extent.offset / gpt::SECTOR_SIZE) as u32
+    };
+
+    let bootloader_lba = bios::bootloader_lba(&early_bootloader);
+
+    // Compressed kernel isn't padded to the sector size. The rest of its last sector belongs
+    // to the same FAT cluster so it can be read too.
+    let bdd = BootDiskDescriptor {
+        signature:          bdd::SIGNATURE,
+        bootloader_lba,
+        bootloader_sectors: (bios_bootloader.len() / 512) as u32,
+        bootloader_checksum,
+        kernel_lba:         locate(uefi::KERNEL_PATH, kernel.len()),
+        kernel_sectors:     kernel.len().div_ceil(512) as u32,
+        cmdline_lba:        locate(uefi::CONFIG_PATH, command_line.len()),
+        cmdline_size:       command_line.len() as u32,
+        initrd_lba:         locate(uefi::INITRD_PATH, initrd.len()),
+        initrd_size:        initrd.len() as u32,
+        manifest,
+    };
+
+    let boot_partition_data = bios::create_boot_partition(&early_bootloader, &bios_bootloader,
+                                                          &bdd, &[]);
+
+    assert!(boot_partition_data.len() as u64 <= boot_partition.size(),
+            "BIOS boot partition is too small.");
+
+    // Boot partition must be the first MBR entry for the early bootloader. UEFI firmware
+    // requires a protective partition which starts at LBA 1.
+    let mbr = bios::create_mbr(&early_bootloader, &[
+        gpt::mbr_entry(true, bios::BOOT_PARTITION_TYPE, boot_partition.first_lba,
+                       BOOT_PARTITION_SECTORS),
+        gpt::mbr_entry(false, 0xee, 1, boot_partition.first_lba - 1),
+    ]);
+
+    let mut disk = &image_file;
+
+    disk.seek(SeekFrom::Start(0))
+        .and_then(|_| disk.write_all(&mbr))
+        .and_then(|_| disk.seek(SeekFrom::Start(boot_partition.offset())))
+        .and_then(|_| disk.write_all(&boot_partition_data))
+        .expect("Failed to write BIOS boot partition.");
+
+    stamp.save();
+}
+
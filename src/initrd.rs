@@ -0,0 +1,134 @@
+use std::path::{Path, PathBuf};
+use std::fs;
+
+const BLOCK_SIZE: usize = 512;
+
+/// Write `value` as a NUL terminated octal number to the tar header field.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let text   = format!("{:0width$o}", value, width = digits);
+
+    assert!(text.len() == digits, "Value {} doesn't fit in the tar header field.", value);
+
+    field[..digits].copy_from_slice(text.as_bytes());
+    field[digits] = 0;
+}
+
+/// Create USTAR header for an entry with path `path` (relative to the archive root).
+fn header(path: &str, size: u64, directory: bool) -> [u8; BLOCK_SIZE] {
+    let mut header = [0u8; BLOCK_SIZE];
+
+    // Paths longer than 100 characters need to be split between name and prefix fields.
+    let (prefix, name) = if path.len() <= 100 {
+        ("", path)
+    } else {
+        let split = path[..path.len().min(156)].rfind('/')
+            .filter(|&split| path.len() - split - 1 <= 100)
+            .unwrap_or_else(|| panic!("Path `{}` is too long for the initrd archive.", path));
+
+        (&path[..split], &path[split + 1..])
+    };
+
+    let mode = if directory { 0o755 } else { 0o644 };
+
+    header[0..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], mode);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+
+    // Modification time is always 0 so the archive depends only on directory contents.
+    write_octal(&mut header[136..148], 0);
+
+    header[156] = if directory { b'5' } else { b'0' };
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+    // Checksum is calculated with checksum field filled with spaces.
+    header[148..156].copy_from_slice(b"        ");
+
+    let checksum: u64 = header.iter().map(|&byte| byte as u64).sum();
+
+    write_octal(&mut header[148..155], checksum);
+    header[155] = b' ';
+
+    header
+}
+
+fn add_directory(archive: &mut Vec<u8>, directory: &Path, prefix: &str) {
+    let mut entries: Vec<_> = fs::read_dir(directory)
+        .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+        .unwrap_or_else(|_| panic!("Failed to list initrd directory `{}`.",
+                                   directory.display()));
+
+    // Sort entries so the archive is reproducible.
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = entry.file_name()
+            .into_string()
+            .unwrap_or_else(|name| panic!("Invalid initrd file name {:?}.", name));
+
+        let path      = format!("{}{}", prefix, name);
+        let file_type = entry.file_type()
+            .expect("Failed to get initrd file type.");
+
+        if file_type.is_dir() {
+            archive.extend_from_slice(&header(&format!("{}/", path), 0, true));
+
+            add_directory(archive, &entry.path(), &format!("{}/", path));
+        } else {
+            let contents = fs::read(entry.path())
+                .unwrap_or_else(|_| panic!("Failed to read initrd file `{}`.",
+                                           entry.path().display()));
+
+            archive.extend_from_slice(&header(&path, contents.len() as u64, false));
+            archive.extend_from_slice(&contents);
+
+            let padding = (BLOCK_SIZE - contents.len() % BLOCK_SIZE) % BLOCK_SIZE;
+
+            archive.extend(vec![0u8; padding]);
+        }
+    }
+}
+
+/// Create initial ramdisk. If `path` is a directory, it is packed into a USTAR archive
+/// (which the kernel exposes as a file tree). Otherwise file is used as is.
+pub fn create(path: &Path) -> Vec<u8> {
+    if !path.is_dir() {
+        return fs::read(path)
+            .unwrap_or_else(|_| panic!("Failed to read initrd `{}`.", path.display()));
+    }
+
+    let mut archive = Vec::new();
+
+    add_directory(&mut archive, path, "");
+
+    // Archive ends with two zero blocks.
+    archive.extend(vec![0u8; BLOCK_SIZE * 2]);
+
+    archive
+}
+
+/// Get the path of the initrd stored next to image at `image_path`. Used by images which
+/// are booted by loaders taking the initrd as a separate file.
+pub fn path_next_to(image_path: &Path) -> PathBuf {
+    image_path.with_extension("initrd")
+}
+
+/// Create initrd from `path` next to image at `image_path`. Remove the stale one if initrd
+/// wasn't requested.
+pub fn create_next_to(image_path: &Path, path: Option<&Path>) {
+    let initrd_path = path_next_to(image_path);
+
+    match path {
+        Some(path) => {
+            fs::write(&initrd_path, create(path))
+                .expect("Failed to write initrd.");
+        }
+        None => {
+            let _ = fs::remove_file(&initrd_path);
+        }
+    }
+}
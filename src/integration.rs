@@ -0,0 +1,197 @@
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::fs;
+
+use crate::args::Options;
+use crate::build::QemuImage;
+
+use qemu_test::{QEMU_DEBUG_EXIT_PORT, QEMU_EXIT_SUCCESS, BOOT_SUCCESS_MARKER, PANIC_MARKER,
+                KERNEL_TEST_FAIL_MARKER};
+
+/// Exit status of QEMU after the kernel reported success.
+const SUCCESS_CODE: i32 = ((QEMU_EXIT_SUCCESS << 1) | 1) as i32;
+
+/// Cargo features which kernel needs to be built with to be tested.
+pub const KERNEL_FEATURES: &[&str] = &["qemu_test"];
+
+// Process exit statuses reported by the `test` command.
+const EXIT_PASSED:    i32 = 0;
+const EXIT_FAILED:    i32 = 1;
+const EXIT_TIMED_OUT: i32 = 2;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    TimedOut,
+}
+
+impl Outcome {
+    /// Get exit status for the whole test run. Failures take precedence over timeouts.
+    pub fn exit_code(outcomes: &[Outcome]) -> i32 {
+        if outcomes.iter().any(|outcome| matches!(outcome, Outcome::Failed(_))) {
+            EXIT_FAILED
+        } else if outcomes.contains(&Outcome::TimedOut) {
+            EXIT_TIMED_OUT
+        } else {
+            EXIT_PASSED
+        }
+    }
+}
+
+/// Parse CPU count from the success marker line.
+fn reported_cpus(line: &str) -> Option<u32> {
+    let start = line.find(BOOT_SUCCESS_MARKER)? + BOOT_SUCCESS_MARKER.len();
+    let line  = line[start..].trim_start().strip_prefix('(')?;
+    let end   = line.find(' ')?;
+
+    line[..end].parse().ok()
+}
+
+/// Directory where serial logs of test runs are stored.
+pub fn log_directory() -> PathBuf {
+    Path::new("build").join("test_logs")
+}
+
+/// Boot image `B` located at `image_path` in QEMU without hardware acceleration and
+/// check whether the kernel has booted successfully. Serial output of the run is saved
+/// to the log directory.
+pub fn run<B: QemuImage>(image_path: &Path, options: &Options) -> Outcome {
+    run_as::<B>(image_path, B::image_name(), options)
+}
+
+/// Like `run`, but boot the image the same way as image `B` and use `name` for the log
+/// and messages.
+pub fn run_as<B: QemuImage>(image_path: &Path, name: &str, options: &Options) -> Outcome {
+    let log_path = log_directory().join(format!("{}.log", name));
+
+    fs::create_dir_all(log_directory())
+        .expect("Couldn't create test log directory.");
+
+    let mut log = fs::File::create(&log_path)
+        .expect("Failed to create test log file.");
+
+    let cores      = B::cores(options);
+    let smp        = cores.to_string();
+    let debug_exit = format!("isa-debug-exit,iobase={:#x},iosize=0x04", QEMU_DEBUG_EXIT_PORT);
+
+    let mut args: Vec<String> = [
+        "-serial",  "stdio",
+        "-smp",     &smp,
+        "-m",       &options.memory,
+        "-accel",   "tcg",
+        "-cpu",     "max",
+        "-display", "none",
+        "-device",  &debug_exit,
+        "-no-reboot",
+    ].iter().map(|x| x.to_string()).collect();
+
+    args.extend(B::test_qemu_arguments(image_path, options));
+
+    println!("\nTesting {} in QEMU (timeout {}s)...", name, options.timeout);
+
+    let mut qemu = Command::new("qemu-system-x86_64")
+        .args(&args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Invoking `qemu-system-x86_64` failed.");
+
+    // Read serial output on a separate thread so we can enforce the timeout.
+    let (sender, receiver) = mpsc::channel();
+    let stdout             = qemu.stdout.take().unwrap();
+
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(stdout);
+        let mut line   = Vec::new();
+
+        loop {
+            line.clear();
+
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_)          => {
+                    let line = String::from_utf8_lossy(&line).trim_end().to_owned();
+
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(options.timeout);
+
+    let mut cpus                   = None;
+    let mut panic: Option<String> = None;
+    let mut failed_tests           = Vec::new();
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        match receiver.recv_timeout(remaining) {
+            Ok(line) => {
+                println!("{}", line);
+
+                writeln!(log, "{}", line)
+                    .expect("Failed to write test log file.");
+
+                if line.contains(BOOT_SUCCESS_MARKER) {
+                    cpus = Some(reported_cpus(&line));
+                }
+
+                if let Some(index) = line.find(KERNEL_TEST_FAIL_MARKER) {
+                    failed_tests.push(line[index + KERNEL_TEST_FAIL_MARKER.len()..].to_owned());
+                }
+
+                if let Some(message) = &mut panic {
+                    // Panic message is printed on the line after the marker.
+                    if message.is_empty() {
+                        *message = line;
+                    }
+                } else if line.contains(PANIC_MARKER) {
+                    panic = Some(String::new());
+                }
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            Err(mpsc::RecvTimeoutError::Timeout)      => {
+                let _ = qemu.kill();
+                let _ = qemu.wait();
+
+                println!("Serial log saved to `{}`.", log_path.display());
+
+                return Outcome::TimedOut;
+            }
+        }
+    }
+
+    let status = qemu.wait()
+        .expect("Failed to wait for QEMU to exit.");
+
+    println!("Serial log saved to `{}`.", log_path.display());
+
+    if !failed_tests.is_empty() {
+        return Outcome::Failed(format!("Kernel tests failed: {}.", failed_tests.join(", ")));
+    }
+
+    if let Some(message) = panic {
+        return Outcome::Failed(format!("Kernel panicked: {}", message));
+    }
+
+    match (cpus, status.code()) {
+        (Some(cpus), Some(SUCCESS_CODE)) => {
+            if cpus == Some(cores) {
+                Outcome::Passed
+            } else {
+                Outcome::Failed(format!("Expected {} CPUs to boot, kernel reported {:?}.",
+                                        cores, cpus))
+            }
+        }
+        _ => Outcome::Failed(format!("QEMU exited with {} before the kernel finished booting.",
+                                     status)),
+    }
+}
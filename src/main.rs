@@ -2,12 +2,26 @@ use std::path::{Path, PathBuf};
 use std::fs;
 
 use build::{ImageBuilder, build};
+use args::{Arguments, Command, Options, Target};
 
 #[macro_use] mod build;
+mod args;
+mod cache;
+mod gpt;
+mod initrd;
+mod signing;
+mod compress;
+mod qemu;
+mod integration;
 mod bios;
 mod uefi;
+mod multiboot;
+mod pvh;
+mod hybrid;
+mod fat;
+mod symbols;
 
-fn build_kernel() -> PathBuf {
+fn build_kernel(options: &Options, features: &[&str]) -> PathBuf {
     fs::create_dir_all(Path::new("build").join("kernel"))
         .expect("Couldn't create `build/kernel` directory.");
 
@@ -17,26 +31,36 @@ fn build_kernel() -> PathBuf {
     let kernel_build_dir = build::canonicalize(Path::new("build").join("kernel"))
         .expect("Couldn't get path to `build/kernel` directory");
 
-    println!("\nCompiling kernel...");
+    let profile = if options.debug { "debug" } else { "release" };
+
+    let mut args = vec!["build", "--target-dir", make_path!(kernel_build_dir)];
+
+    if !options.debug {
+        args.push("--release");
+    }
+
+    let features = features.join(",");
+
+    if !features.is_empty() {
+        args.extend(&["--features", &features]);
+    }
+
+    println!("\nCompiling kernel ({})...", profile);
     if !build(
-        "cargo", Some(&kernel_dir),
-        &[
-            "build", "--release", "--target-dir",
-            make_path!(kernel_build_dir),
-        ],
-        &[],
+        "cargo", Some(&kernel_dir), &args, &[],
         "Building kernel failed.",
     ) {
         std::process::exit(1);
     }
 
-    make_path!(kernel_build_dir, "x86_64-unknown-none", "release", "kernel")
-        .to_owned().into()
+    let kernel_path = kernel_build_dir.join("x86_64-unknown-none").join(profile).join("kernel");
+
+    symbols::embed_symbols(&kernel_path)
 }
 
-fn build_image<B: ImageBuilder>(kernel_path: &Path) {
+/// Compile bootloader of image `B` and get the builder which can create the image.
+fn build_bootloader<B: ImageBuilder>(kernel_path: &Path, options: &Options) -> B {
     let bootloader_name = B::bootloader_name();
-    let image_name      = B::image_name();
 
     fs::create_dir_all(Path::new("build").join(bootloader_name))
         .expect("Couldn't create `build/xx_bootloader` directory.");
@@ -47,7 +71,7 @@ fn build_image<B: ImageBuilder>(kernel_path: &Path) {
     let bootloader_build_dir = build::canonicalize(Path::new("build").join(bootloader_name))
         .expect("Couldn't get path to `build/xx_bootloader` directory");
     
-    let mut builder = B::new(kernel_path, &bootloader_dir, &bootloader_build_dir);
+    let mut builder = B::new(kernel_path, &bootloader_dir, &bootloader_build_dir, options);
 
     builder.build_bootloader_dependencies();
 
@@ -73,21 +97,181 @@ fn build_image<B: ImageBuilder>(kernel_path: &Path) {
         std::process::exit(1);
     }
 
-    println!("\nCreating bootable image {}...", image_name);
+    builder
+}
+
+fn build_image<B: ImageBuilder>(kernel_path: &Path, options: &Options) -> PathBuf {
+    let mut builder = build_bootloader::<B>(kernel_path, options);
+
+    println!("\nCreating bootable image {}...", B::image_name());
+
+    let image_path = Path::new("build").join(B::image_name());
+
+    builder.create_image(&image_path);
+
+    println!("Done!");
+
+    image_path
+}
+
+fn build_hybrid_image(kernel_path: &Path, options: &Options) -> PathBuf {
+    // Kernel is shared by both bootloaders so it cannot be embedded in the UEFI one.
+    let uefi_options = Options {
+        separate_kernel: true,
+        ..options.clone()
+    };
+
+    let bios = build_bootloader::<bios::BiosBuilder>(kernel_path, options);
+    let uefi = build_bootloader::<uefi::UefiBuilder>(kernel_path, &uefi_options);
+
+    println!("\nCreating bootable image {}...", hybrid::IMAGE_NAME);
+
+    let image_path = Path::new("build").join(hybrid::IMAGE_NAME);
 
-    builder.create_image(&Path::new("build").join(image_name));
+    hybrid::create_image(&bios, &uefi, kernel_path, options, &image_path);
+
+    println!("Done!");
+
+    image_path
+}
+
+fn build_pvh_image(kernel_path: &Path, options: &Options) -> PathBuf {
+    println!("\nCreating bootable image {}...", pvh::IMAGE_NAME);
+
+    let image_path = Path::new("build").join(pvh::IMAGE_NAME);
+
+    pvh::create_image(kernel_path, options, &image_path);
+
+    println!("Done!");
+
+    image_path
+}
+
+fn build_target(target: Target, kernel_path: &Path, options: &Options) -> PathBuf {
+    match target {
+        Target::Uefi => build_image::<uefi::UefiBuilder>(kernel_path, options),
+        Target::Bios => build_image::<bios::BiosBuilder>(kernel_path, options),
+        Target::Multiboot => {
+            build_image::<multiboot::MultibootBuilder>(kernel_path, options)
+        }
+        Target::Pvh  => build_pvh_image(kernel_path, options),
+        Target::Hybrid => build_hybrid_image(kernel_path, options),
+    }
+}
+
+fn clean() {
+    let build_dir = Path::new("build");
+
+    if build_dir.exists() {
+        println!("Removing `build` directory...");
+
+        fs::remove_dir_all(build_dir)
+            .expect("Couldn't remove `build` directory.");
+    }
 
     println!("Done!");
 }
 
 fn main() {
+    let arguments = Arguments::from_env();
+    let options   = &arguments.options;
+
+    let targets = match &arguments.command {
+        Command::Clean => {
+            clean();
+            return;
+        }
+        Command::Build(targets) => targets.clone(),
+        Command::Test(targets)  => targets.clone(),
+        Command::Run(target)    => vec![*target],
+    };
+
+    let mut kernel_features = Vec::new();
+
+    if let Command::Test(_) = arguments.command {
+        kernel_features.extend_from_slice(integration::KERNEL_FEATURES);
+    }
+
+    if options.kernel_tests {
+        kernel_features.push("kernel_tests");
+    }
+
     fs::create_dir_all(Path::new("build"))
         .expect("Couldn't create `build` directory.");
 
-    let kernel_path = build_kernel();
+    let kernel_path = build_kernel(options, &kernel_features);
+
+    let images: Vec<PathBuf> = targets.iter()
+        .map(|&target| build_target(target, &kernel_path, options))
+        .collect();
+
+    println!("\nEverything done!");
+
+    match arguments.command {
+        Command::Run(target) => {
+            let success = match target {
+                Target::Uefi => qemu::run::<uefi::UefiBuilder>(&images[0], options),
+                Target::Bios => qemu::run::<bios::BiosBuilder>(&images[0], options),
+                Target::Pvh  => qemu::run::<pvh::PvhImage>(&images[0], options),
+                Target::Hybrid => {
+                    qemu::run_as::<uefi::UefiBuilder>(&images[0], hybrid::IMAGE_NAME, options)
+                }
+                Target::Multiboot => unreachable!("QEMU cannot boot Multiboot2 images directly."),
+            };
 
-    build_image::<uefi::UefiBuilder>(&kernel_path);
-    build_image::<bios::BiosBuilder>(&kernel_path);
+            if !success {
+                std::process::exit(1);
+            }
+        }
+        Command::Test(_) => {
+            let results: Vec<(String, integration::Outcome)> = targets.iter()
+                .zip(images.iter())
+                .flat_map(|(&target, image)| {
+                    let name = format!("{:?}", target);
 
-    println!("\nEverything done!.");
+                    match target {
+                        Target::Uefi => {
+                            vec![(name, integration::run::<uefi::UefiBuilder>(image, options))]
+                        }
+                        Target::Bios => {
+                            vec![(name, integration::run::<bios::BiosBuilder>(image, options))]
+                        }
+                        Target::Pvh => {
+                            vec![(name, integration::run::<pvh::PvhImage>(image, options))]
+                        }
+                        Target::Hybrid => {
+                            // Hybrid image is booted by both firmware types.
+                            let bios_name = format!("{}_bios", hybrid::IMAGE_NAME);
+                            let uefi_name = format!("{}_uefi", hybrid::IMAGE_NAME);
+
+                            vec![
+                                (format!("{} (BIOS)", name),
+                                 integration::run_as::<bios::BiosBuilder>(image, &bios_name,
+                                                                          options)),
+                                (format!("{} (UEFI)", name),
+                                 integration::run_as::<uefi::UefiBuilder>(image, &uefi_name,
+                                                                          options)),
+                            ]
+                        }
+                        Target::Multiboot => {
+                            unreachable!("QEMU cannot boot Multiboot2 images directly.")
+                        }
+                    }
+                })
+                .collect();
+
+            println!("\nTest results:");
+
+            for (name, outcome) in &results {
+                println!("{}: {:?}", name, outcome);
+            }
+
+            let outcomes: Vec<integration::Outcome> = results.into_iter()
+                .map(|(_, outcome)| outcome)
+                .collect();
+
+            std::process::exit(integration::Outcome::exit_code(&outcomes));
+        }
+        _ => (),
+    }
 }
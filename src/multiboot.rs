@@ -0,0 +1,88 @@
+use std::path::{Path, PathBuf};
+use std::convert::TryInto;
+use std::fs;
+
+use crate::build::{Image, ImageBuilder, BuildParameters};
+use crate::args::Options;
+use crate::initrd;
+
+use elfparse::{Elf, Bitness, Machine};
+
+const MULTIBOOT2_MAGIC:       u32   = 0xe852_50d6;
+const MULTIBOOT2_SEARCH_SIZE: usize = 32 * 1024;
+
+/// Returns `true` if `image` contains a valid Multiboot2 header at the location where
+/// loaders will look for it.
+fn has_multiboot2_header(image: &[u8]) -> bool {
+    let search_area = &image[..image.len().min(MULTIBOOT2_SEARCH_SIZE)];
+
+    // Header is 8 byte aligned and starts with magic, architecture, header length and
+    // checksum. All four fields sum up to 0.
+    (0..search_area.len().saturating_sub(15)).step_by(8).any(|offset| {
+        let field = |index: usize| {
+            u32::from_le_bytes(search_area[offset + index * 4..][..4].try_into().unwrap())
+        };
+
+        field(0) == MULTIBOOT2_MAGIC &&
+            (0..4).fold(0u32, |sum, index| sum.wrapping_add(field(index))) == 0
+    })
+}
+
+pub struct MultibootBuilder {
+    kernel_path:          PathBuf,
+    bootloader_build_dir: PathBuf,
+    initrd_path:          Option<PathBuf>,
+}
+
+impl Image for MultibootBuilder {
+    fn image_name() -> &'static str {
+        "flugzeug_multiboot"
+    }
+}
+
+impl ImageBuilder for MultibootBuilder {
+    fn new(kernel_path: &Path, _bootloader_dir: &Path, bootloader_build_dir: &Path,
+           options: &Options) -> Self {
+        Self {
+            kernel_path:          kernel_path.to_owned(),
+            bootloader_build_dir: bootloader_build_dir.to_owned(),
+            initrd_path:          options.initrd.clone(),
+        }
+    }
+
+    fn bootloader_name() -> &'static str {
+        "multiboot_bootloader"
+    }
+
+    fn build_bootloader_dependencies(&mut self) {}
+
+    fn bootloader_build_parameters(&mut self) -> BuildParameters {
+        let kernel_path = make_path!(self.kernel_path).to_owned();
+
+        BuildParameters {
+            args: Vec::new(),
+            envs: vec![
+                (String::from("FLUGZEUG_KERNEL_PATH"), kernel_path),
+            ],
+        }
+    }
+
+    fn create_image(&mut self, image_path: &Path) {
+        // Bootloader ELF (with embedded kernel) is the image itself.
+        let image = fs::read(make_path!(self.bootloader_build_dir, "i586-unknown-none",
+                                        "release", "multiboot_bootloader"))
+            .expect("Failed to read bootloader binary.");
+
+        let elf = Elf::parse(&image).expect("Failed to parse bootloader ELF.");
+
+        assert!(elf.bitness() == Bitness::Bits32, "Bootloader is not 32 bit.");
+        assert!(elf.machine() == Machine::X86, "Bootloader is not x86 binary.");
+        assert!(has_multiboot2_header(&image), "Bootloader doesn't have a Multiboot2 header.");
+
+        fs::write(image_path, &image)
+            .expect("Failed to write Multiboot2 image.");
+
+        // Loaders take the initrd as a separate module so store it next to the image.
+        initrd::create_next_to(image_path, self.initrd_path.as_deref());
+    }
+}
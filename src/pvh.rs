@@ -0,0 +1,175 @@
+use std::path::Path;
+use std::convert::TryInto;
+use std::fs;
+
+use crate::build::{Image, QemuImage};
+use crate::args::Options;
+use crate::initrd;
+
+use elfparse::{Elf, Bitness, Machine};
+
+pub const IMAGE_NAME: &str = "flugzeug_pvh";
+
+/// Physical address at which PVH loaders put the kernel. `kernel/src/pvh.asm` maps
+/// the kernel using 2M pages so it must be 2M aligned.
+const LOAD_ADDRESS: u64 = 2 * 1024 * 1024;
+
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+
+const ET_EXEC: u16 = 2;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+fn read_u16(bytes: &[u8], offset: u64) -> u16 {
+    u16::from_le_bytes(bytes[offset as usize..][..2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: u64) -> u32 {
+    u32::from_le_bytes(bytes[offset as usize..][..4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: u64) -> u64 {
+    u64::from_le_bytes(bytes[offset as usize..][..8].try_into().unwrap())
+}
+
+fn write_u64(bytes: &mut [u8], offset: u64, value: u64) {
+    bytes[offset as usize..][..8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Find the descriptor of the Xen PVH entrypoint note in the note segment of `size` bytes
+/// at file `offset`. Returns offset of the descriptor relative to the segment start.
+fn find_pvh_entrypoint(kernel: &[u8], offset: u64, size: u64) -> Option<u64> {
+    let bytes = kernel.get(offset as usize..(offset + size) as usize)?;
+    let field = |offset: usize| {
+        bytes.get(offset..offset + 4).map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+    };
+
+    // Every note consists of name size, descriptor size, type, name and descriptor.
+    // Name and descriptor are padded to 4 bytes.
+    let mut offset = 0;
+
+    while let (Some(name_size), Some(desc_size), Some(typ)) =
+        (field(offset), field(offset + 4), field(offset + 8))
+    {
+        let name_size = name_size as usize;
+        let desc_size = desc_size as usize;
+        let name      = bytes.get(offset + 12..offset + 12 + name_size);
+        let desc      = offset + 12 + ((name_size + 3) & !3);
+
+        if name == Some(b"Xen\0") && typ == XEN_ELFNOTE_PHYS32_ENTRY {
+            assert!(desc_size == 8, "Kernel PVH entrypoint note has invalid size.");
+
+            return Some(desc as u64);
+        }
+
+        offset = desc + ((desc_size + 3) & !3);
+    }
+
+    None
+}
+
+/// Make the kernel loadable by PVH loaders. They load segments at their physical addresses
+/// and jump to the physical address from the PVH note without applying relocations.
+/// Kernel is linked with already applied relocations, so assign physical addresses starting
+/// at `LOAD_ADDRESS` to segments and replace the entrypoint offset stored in the note
+/// by the linker with the physical address.
+fn prepare_kernel(kernel: &mut [u8]) {
+    let base = {
+        let elf = Elf::parse(kernel).expect("Failed to parse kernel ELF.");
+
+        assert!(elf.bitness() == Bitness::Bits64, "Kernel is not 64 bit.");
+        assert!(elf.machine() == Machine::Amd64, "Kernel is not an AMD64 binary.");
+
+        elf.base_address()
+    };
+
+    let header_offset = read_u64(kernel, 0x20);
+    let header_size   = read_u16(kernel, 0x36) as u64;
+    let header_count  = read_u16(kernel, 0x38) as u64;
+
+    let mut entrypoint = None;
+
+    for index in 0..header_count {
+        let header = header_offset + index * header_size;
+
+        let segment_type   = read_u32(kernel, header);
+        let segment_offset = read_u64(kernel, header + 0x08);
+        let virt_addr      = read_u64(kernel, header + 0x10);
+        let file_size      = read_u64(kernel, header + 0x20);
+
+        match segment_type {
+            PT_LOAD => {
+                write_u64(kernel, header + 0x18, virt_addr - base + LOAD_ADDRESS);
+            }
+            PT_NOTE => {
+                if let Some(desc) = find_pvh_entrypoint(kernel, segment_offset, file_size) {
+                    entrypoint = Some((segment_offset + desc, virt_addr + desc));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let (desc_offset, desc_addr) = entrypoint
+        .expect("Kernel doesn't have a PVH entrypoint.");
+
+    // Linker stored the entrypoint relative to the descriptor.
+    let entrypoint = desc_addr.wrapping_add(read_u64(kernel, desc_offset));
+
+    write_u64(kernel, desc_offset, entrypoint - base + LOAD_ADDRESS);
+
+    // Image can be loaded only at its link address now.
+    kernel[0x10..0x12].copy_from_slice(&ET_EXEC.to_le_bytes());
+}
+
+/// Create the PVH image at `image_path` from the kernel at `kernel_path`. There is no
+/// bootloader, the kernel contains its own PVH entrypoint.
+pub fn create_image(kernel_path: &Path, options: &Options, image_path: &Path) {
+    let mut kernel = fs::read(kernel_path)
+        .expect("Failed to read kernel binary.");
+
+    prepare_kernel(&mut kernel);
+
+    fs::write(image_path, &kernel)
+        .expect("Failed to write PVH image.");
+
+    // Loaders take the initrd as a separate module so store it next to the image.
+    initrd::create_next_to(image_path, options.initrd.as_deref());
+}
+
+/// Kernel booted directly by QEMU `-kernel`.
+pub struct PvhImage;
+
+impl Image for PvhImage {
+    fn image_name() -> &'static str {
+        IMAGE_NAME
+    }
+}
+
+impl QemuImage for PvhImage {
+    fn qemu_arguments(image_path: &Path, options: &Options) -> Vec<String> {
+        // QEMU loads the kernel using its PVH entrypoint, no firmware or disk is needed.
+        let mut args = vec![
+            String::from("-kernel"),
+            make_path!(image_path).to_owned(),
+        ];
+
+        if options.initrd.is_some() {
+            args.push(String::from("-initrd"));
+            args.push(make_path!(initrd::path_next_to(image_path)).to_owned());
+        }
+
+        if let Some(command_line) = options.command_line() {
+            args.push(String::from("-append"));
+            args.push(String::from_utf8(command_line)
+                      .expect("Kernel command line is not valid UTF-8."));
+        }
+
+        args
+    }
+
+    fn cores(_options: &Options) -> u32 {
+        // PVH loaders don't provide realmode AP entrypoint so only the BSP is launched.
+        1
+    }
+}
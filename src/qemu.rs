@@ -0,0 +1,35 @@
+use std::path::Path;
+use std::process::Command;
+
+use crate::args::Options;
+use crate::build::QemuImage;
+
+/// Launch QEMU with KVM acceleration and boot image `B` located at `image_path`.
+/// Returns `true` if QEMU exited successfully.
+pub fn run<B: QemuImage>(image_path: &Path, options: &Options) -> bool {
+    run_as::<B>(image_path, B::image_name(), options)
+}
+
+/// Like `run`, but boot the image the same way as image `B` and report it as `name`.
+pub fn run_as<B: QemuImage>(image_path: &Path, name: &str, options: &Options) -> bool {
+    let smp = B::cores(options).to_string();
+
+    let mut args: Vec<String> = [
+        "-serial", "stdio",
+        "-smp",    &smp,
+        "-m",      &options.memory,
+        "-cpu",    "host",
+        "-enable-kvm",
+    ].iter().map(|x| x.to_string()).collect();
+
+    args.extend(B::qemu_arguments(image_path, options));
+
+    println!("\nLaunching {} in QEMU...", name);
+
+    let status = Command::new("qemu-system-x86_64")
+        .args(&args)
+        .status()
+        .expect("Invoking `qemu-system-x86_64` failed.");
+
+    status.success()
+}
@@ -0,0 +1,74 @@
+use std::io::Read;
+use std::path::Path;
+use std::fs;
+
+use integrity::{ed25519, Manifest, PUBLIC_KEY_ENV};
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Ed25519 key used to sign boot manifests.
+pub struct SigningKey {
+    secret_key: [u8; 32],
+    public_key: [u8; 32],
+}
+
+impl SigningKey {
+    /// Load secret key (64 hex digits) from `path`. If the file doesn't exist, a new random
+    /// key will be created.
+    pub fn load_or_create(path: &Path) -> Self {
+        if !path.exists() {
+            let mut secret_key = [0u8; 32];
+
+            fs::File::open("/dev/urandom")
+                .and_then(|mut random| random.read_exact(&mut secret_key))
+                .expect("Failed to get random bytes for the signing key.");
+
+            fs::write(path, to_hex(&secret_key) + "\n")
+                .unwrap_or_else(|_| panic!("Failed to write signing key `{}`.", path.display()));
+
+            // Only the owner should be able to read the secret key.
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+                    .expect("Failed to set signing key permissions.");
+            }
+
+            println!("Created new signing key `{}`.", path.display());
+        }
+
+        let contents = fs::read_to_string(path)
+            .unwrap_or_else(|_| panic!("Failed to read signing key `{}`.", path.display()));
+
+        let secret_key = integrity::parse_key(&contents)
+            .unwrap_or_else(|| panic!("Signing key `{}` is invalid (expected 64 hex digits).",
+                                      path.display()));
+
+        Self {
+            secret_key,
+            public_key: ed25519::public_key(&secret_key),
+        }
+    }
+
+    /// Get environment variable which compiles the public key into the bootloader.
+    pub fn bootloader_env(&self) -> (String, String) {
+        (String::from(PUBLIC_KEY_ENV), to_hex(&self.public_key))
+    }
+}
+
+/// Create boot manifest for given payloads. It is signed if `key` is present.
+pub fn create_manifest(key: Option<&SigningKey>, kernel: &[u8], command_line: &[u8],
+                       initrd: &[u8]) -> Manifest {
+    let mut manifest = Manifest::new(kernel, command_line, initrd);
+
+    if let Some(key) = key {
+        manifest.sign(&key.secret_key);
+
+        println!("Signed boot manifest with public key {}.", to_hex(&key.public_key));
+    }
+
+    manifest
+}
@@ -0,0 +1,98 @@
+use std::path::{Path, PathBuf};
+use std::fs;
+
+use elfparse::{Elf, SymbolType};
+use symbol_table::Symbol;
+
+/// Embed table of kernel function names in the kernel at `kernel_path`. The kernel uses it to
+/// symbolize backtraces. Returns path to the kernel with the embedded table.
+pub fn embed_symbols(kernel_path: &Path) -> PathBuf {
+    let mut kernel = fs::read(kernel_path).expect("Failed to read built kernel.");
+
+    let (table, section_range) = {
+        let elf = Elf::parse(&kernel).expect("Failed to parse built kernel.");
+
+        let base     = elf.base_address();
+        let end      = base + elf.image_size();
+        let mut code = Vec::new();
+
+        // Functions from assembly files may not have the type set, use all symbols which
+        // point to executable sections in that case.
+        elf.sections(|section| {
+            if section.execute {
+                code.push((section.virt_addr, section.bytes.map_or(0, |bytes| bytes.len())));
+            }
+        }).expect("Failed to get kernel sections.");
+
+        let is_code = |address: u64| {
+            code.iter().any(|&(start, size)| address >= start && address < start + size as u64)
+        };
+
+        let mut functions = Vec::new();
+
+        elf.symbols(|symbol| {
+            let name = match symbol.name {
+                Some(name) if !name.is_empty() => name,
+                _                              => return,
+            };
+
+            let function = match symbol.sym_type {
+                SymbolType::Function => true,
+                SymbolType::NoType   => is_code(symbol.value),
+                _                    => false,
+            };
+
+            if function && symbol.section_index != 0 && symbol.value >= base &&
+                symbol.value < end {
+                let name = format!("{:#}", rustc_demangle::demangle(name));
+
+                functions.push((symbol.value - base, symbol.size, name));
+            }
+        }).expect("Failed to get kernel symbols.");
+
+        functions.sort_by_key(|&(start, size, _)| (start, std::cmp::Reverse(size)));
+        functions.dedup_by_key(|(start, _, _)| *start);
+
+        // Symbols without size extend up to the next symbol.
+        for index in 0..functions.len() {
+            if functions[index].1 == 0 {
+                let next = functions.get(index + 1)
+                    .map_or(end - base, |(start, _, _)| *start);
+
+                functions[index].1 = next - functions[index].0;
+            }
+        }
+
+        let symbols: Vec<Symbol> = functions.iter().map(|(start, size, name)| {
+            Symbol {
+                start: *start as u32,
+                size:  *size as u32,
+                name,
+            }
+        }).collect();
+
+        let section = elf.section_by_name(symbol_table::SECTION_NAME)
+            .expect("Kernel doesn't reserve space for the symbol table.");
+        let bytes   = section.bytes
+            .expect("Kernel symbol table section has no data.");
+
+        let offset = bytes.as_ptr() as usize - kernel.as_ptr() as usize;
+
+        let mut table = vec![0u8; bytes.len()];
+        let size      = symbol_table::encode(&symbols, &mut table)
+            .unwrap_or_else(|| panic!("Kernel symbol table is too big ({} bytes, {} reserved).",
+                                      symbol_table::encoded_size(&symbols), bytes.len()));
+
+        println!("Embedded {} kernel symbols ({} bytes).", symbols.len(), size);
+
+        (table, offset..offset + bytes.len())
+    };
+
+    kernel[section_range].copy_from_slice(&table);
+
+    let output_path = Path::new("build").join("kernel").join("kernel");
+
+    fs::write(&output_path, &kernel).expect("Failed to write kernel with the symbol table.");
+
+    output_path
+}
@@ -2,18 +2,119 @@ use std::path::{Path, PathBuf};
 use std::io::Write;
 use std::fs;
 
-use crate::build::{ImageBuilder, BuildParameters};
+use crate::build::{Image, QemuImage, ImageBuilder, BuildParameters};
+use crate::args::Options;
+use crate::cache::Stamp;
+use crate::signing::{self, SigningKey};
+use crate::{gpt, initrd, compress, integration};
+
+use integrity::Manifest;
+
+// Locations of files on the EFI System Partition.
+pub const BOOTLOADER_PATH: &str = "efi/boot/bootx64.efi";
+pub const KERNEL_PATH:     &str = boot_block::ESP_KERNEL_PATH;
+pub const INITRD_PATH:     &str = boot_block::ESP_INITRD_PATH;
+pub const CONFIG_PATH:     &str = boot_block::ESP_CONFIG_PATH;
+pub const MANIFEST_PATH:   &str = boot_block::ESP_MANIFEST_PATH;
+
+const OVMF_CODE_PATH: &str = "/usr/share/OVMF/OVMF_CODE.fd";
+const OVMF_VARS_PATH: &str = "uefi_vars.fd";
+
+/// Format the EFI System Partition `partition` on `disk` as FAT32.
+pub fn format_esp(disk: &fs::File, partition: &gpt::Partition) {
+    let options = fatfs::FormatVolumeOptions::new()
+        .fat_type(fatfs::FatType::Fat32)
+        .volume_label(*b"FLUGZEUG   ");
+
+    fatfs::format_volume(gpt::PartitionStream::new(disk, partition), options)
+        .expect("Failed to format EFI System Partition.");
+}
+
+/// Write file at `path` (relative to `root`) creating all required directories. Existing
+/// file will be overwritten.
+pub fn write_file<T: fatfs::ReadWriteSeek>(root: &fatfs::Dir<T>, path: &str, contents: &[u8]) {
+    let (directories, name) = match path.rsplit_once('/') {
+        Some((directories, name)) => (Some(directories), name),
+        None                      => (None, path),
+    };
+
+    let mut directory = root.clone();
+
+    for component in directories.into_iter().flat_map(|path| path.split('/')) {
+        // Opens existing directory instead of failing.
+        directory = directory.create_dir(component)
+            .expect("Failed to create directory on the EFI System Partition.");
+    }
+
+    let mut file = directory.create_file(name)
+        .expect("Failed to create file on the EFI System Partition.");
+
+    file.truncate().unwrap();
+    file.write_all(contents).unwrap();
+}
 
 pub struct UefiBuilder {
     kernel_path:          PathBuf,
     bootloader_build_dir: PathBuf,
+    image_size:           u64,
+    separate_kernel:      bool,
+    compress_kernel:      bool,
+    initrd_path:          Option<PathBuf>,
+    command_line:         Option<Vec<u8>>,
+    signing_key:          Option<SigningKey>,
+}
+
+impl Image for UefiBuilder {
+    fn image_name() -> &'static str {
+        "flugzeug_uefi"
+    }
+}
+
+/// Get QEMU arguments which boot image at `image_path` using OVMF with variables stored
+/// at `vars_path`.
+fn qemu_arguments(image_path: &Path, vars_path: &Path) -> Vec<String> {
+    vec![
+        String::from("-drive"),
+        format!("file={},index=0,media=disk,format=raw", make_path!(image_path)),
+        String::from("-drive"),
+        format!("if=pflash,format=raw,readonly=on,file={}", OVMF_CODE_PATH),
+        String::from("-drive"),
+        format!("if=pflash,format=raw,file={}", make_path!(vars_path)),
+    ]
+}
+
+impl QemuImage for UefiBuilder {
+    fn qemu_arguments(image_path: &Path, _options: &Options) -> Vec<String> {
+        qemu_arguments(image_path, Path::new(OVMF_VARS_PATH))
+    }
+
+    fn test_qemu_arguments(image_path: &Path, _options: &Options) -> Vec<String> {
+        // OVMF writes to its variable store. Give every test run a fresh copy so the tracked
+        // one stays unchanged.
+        let vars_path = integration::log_directory().join(OVMF_VARS_PATH);
+
+        fs::create_dir_all(integration::log_directory())
+            .expect("Couldn't create test log directory.");
+        fs::copy(OVMF_VARS_PATH, &vars_path)
+            .expect("Failed to copy UEFI variable store.");
+
+        qemu_arguments(image_path, &vars_path)
+    }
 }
 
 impl ImageBuilder for UefiBuilder {
-    fn new(kernel_path: &Path, _bootloader_dir: &Path, bootloader_build_dir: &Path) -> Self {
+    fn new(kernel_path: &Path, _bootloader_dir: &Path, bootloader_build_dir: &Path,
+           options: &Options) -> Self {
         Self {
             kernel_path:          kernel_path.to_owned(),
             bootloader_build_dir: bootloader_build_dir.to_owned(),
+            image_size:           options.image_size * 1024 * 1024,
+            separate_kernel:      options.separate_kernel,
+            compress_kernel:      options.compress_kernel,
+            initrd_path:          options.initrd.clone(),
+            command_line:         options.command_line(),
+            signing_key:          options.signing_key.as_deref()
+                .map(SigningKey::load_or_create),
         }
     }
 
@@ -21,64 +122,189 @@ impl ImageBuilder for UefiBuilder {
         "uefi_bootloader"
     }
 
-    fn image_name() -> &'static str {
-        "flugzeug_uefi"
-    }
-
     fn build_bootloader_dependencies(&mut self) {}
 
     fn bootloader_build_parameters(&mut self) -> BuildParameters {
-        let kernel_path = make_path!(self.kernel_path).to_owned();
+        let mut envs: Vec<(String, String)> = self.signing_key.iter()
+            .map(SigningKey::bootloader_env)
+            .collect();
+
+        if self.separate_kernel {
+            // Bootloader will load the kernel from the EFI System Partition.
+            return BuildParameters {
+                args: Vec::new(),
+                envs,
+            };
+        }
+
+        let kernel = fs::read(&self.kernel_path)
+            .expect("Failed to read kernel binary.");
+
+        // Bootloader doesn't need the ESP to verify the embedded kernel, so the manifest
+        // is embedded too.
+        let manifest      = self.manifest(&kernel);
+        let manifest_path = self.bootloader_build_dir.join("manifest");
+
+        write_if_changed(&manifest_path, &manifest.to_bytes());
+
+        let mut kernel_path = make_path!(self.kernel_path).to_owned();
+
+        if self.compress_kernel {
+            let compressed      = compress::compress_kernel(&kernel);
+            let compressed_path = self.bootloader_build_dir.join("kernel.lz4");
+
+            write_if_changed(&compressed_path, &compressed);
+
+            kernel_path = make_path!(compressed_path).to_owned();
+        }
+
+        envs.push((String::from("FLUGZEUG_KERNEL_PATH"), kernel_path));
+        envs.push((String::from("FLUGZEUG_MANIFEST_PATH"), make_path!(manifest_path).to_owned()));
 
         BuildParameters {
             args: vec![
                 String::from("--features"),
                 String::from("with_kernel"),
             ],
-            envs: vec![
-                (String::from("FLUGZEUG_KERNEL_PATH"), kernel_path),
-            ],
+            envs,
         }
     }
 
     fn create_image(&mut self, image_path: &Path) {
-        let bootloader = std::fs::read(make_path!(self.bootloader_build_dir,
-                                                  "x86_64-unknown-uefi",
-                                                  "release", "uefi_bootloader.efi"))
-            .expect("Failed to read bootloader binary.");
-
-        if !Path::new(image_path).exists() {
-            fs::File::create(image_path)
-                .expect("Failed to create FAT bootable image.");
+        let bootloader = self.bootloader();
+
+        let read = |path: &Path, what: &str| {
+            fs::read(path).unwrap_or_else(|_| panic!("Failed to read {} `{}`.", what,
+                                                      path.display()))
+        };
+
+        let kernel = read(&self.kernel_path, "kernel");
+        let initrd = self.initrd_path.as_deref().map(initrd::create);
+
+        // Embedded kernel is verified by the manifest embedded in the bootloader.
+        let manifest = self.separate_kernel.then(|| self.manifest(&kernel).to_bytes().to_vec());
+
+        let kernel = if self.compress_kernel && self.separate_kernel {
+            compress::compress_kernel(&kernel)
+        } else {
+            kernel
+        };
+
+        // Files which are not present will be removed from the existing image.
+        let files = [
+            (BOOTLOADER_PATH, Some(bootloader)),
+            (KERNEL_PATH,     self.separate_kernel.then_some(kernel)),
+            (INITRD_PATH,     initrd),
+            (CONFIG_PATH,     self.command_line.clone()),
+            (MANIFEST_PATH,   manifest),
+        ];
+
+        let image_size = self.image_size;
+        let size_bytes = image_size.to_le_bytes();
+
+        // When kernel is embedded in the bootloader it is enough to hash only the bootloader.
+        let mut inputs: Vec<&[u8]> = vec![&size_bytes];
+
+        for (path, contents) in &files {
+            inputs.push(path.as_bytes());
+            inputs.push(contents.as_deref().unwrap_or(b"\0missing"));
         }
 
+        let stamp = Stamp::new(&self.bootloader_build_dir.join("image.stamp"), &inputs);
+
+        if stamp.is_up_to_date(&[image_path]) {
+            println!("Bootable image is up to date.");
+            return;
+        }
+
+        stamp.invalidate();
+
         let image_file = fs::OpenOptions::new()
             .read(true)
             .write(true)
+            .create(true)
+            .truncate(false)
             .open(image_path)
-            .expect("Failed to open FAT bootable image.");
+            .expect("Failed to open UEFI bootable image.");
+
+        let total_sectors = image_size / gpt::SECTOR_SIZE;
+        let partition     = gpt::whole_disk_partition(total_sectors, gpt::ESP_TYPE_GUID,
+                                                      "EFI System Partition");
 
-        image_file
-            .set_len(512 * 1024 * 1024)
-            .expect("Failed to set length of FAT image file.");
+        // Reuse existing image if it already has the same layout and contains a valid
+        // FAT32 filesystem on the ESP.
+        let reusable = image_file.metadata()
+            .map(|metadata| metadata.len() == image_size)
+            .unwrap_or(false) &&
+            gpt::read_partition(&mut &image_file, 0) ==
+                Some((gpt::ESP_TYPE_GUID, partition.first_lba, partition.last_lba)) &&
+            fatfs::FileSystem::new(gpt::PartitionStream::new(&image_file, &partition),
+                                   fatfs::FsOptions::new())
+                .map(|fs| fs.fat_type() == fatfs::FatType::Fat32)
+                .unwrap_or(false);
 
-        fatfs::format_volume(&image_file, fatfs::FormatVolumeOptions::new())
-            .expect("Failed to format FAT32 image file.");
+        if reusable {
+            println!("Updating files in the existing EFI System Partition...");
+        } else {
+            image_file
+                .set_len(image_size)
+                .expect("Failed to set length of UEFI image file.");
 
-        let fs = fatfs::FileSystem::new(&image_file, fatfs::FsOptions::new())
-            .expect("Failed to open FAT32 filesystem.");
+            gpt::write_protective_mbr(&mut &image_file, total_sectors)
+                .expect("Failed to write protective MBR.");
 
-        assert_eq!(fs.fat_type(), fatfs::FatType::Fat32, "Created invalid FAT.");
+            gpt::write_gpt(&mut &image_file, total_sectors, &[partition])
+                .expect("Failed to write GPT.");
+
+            format_esp(&image_file, &partition);
+        }
+
+        {
+            let fs = fatfs::FileSystem::new(gpt::PartitionStream::new(&image_file, &partition),
+                                            fatfs::FsOptions::new())
+                .expect("Failed to open FAT32 filesystem.");
+
+            assert_eq!(fs.fat_type(), fatfs::FatType::Fat32, "Created invalid FAT.");
+
+            for (path, contents) in &files {
+                match contents {
+                    Some(contents) => write_file(&fs.root_dir(), path, contents),
+                    None           => {
+                        // Fails if the file doesn't exist which is fine.
+                        let _ = fs.root_dir().remove(path);
+                    }
+                }
+            }
+        }
 
-        let mut bootloader_file = fs.root_dir()
-            .create_dir("efi")
-            .unwrap()
-            .create_dir("boot")
-            .unwrap()
-            .create_file("bootx64.efi")
-            .unwrap();
+        stamp.save();
+    }
+}
+
+/// Write `contents` to `path` unless it already contains them. Rewriting the same file would
+/// make cargo rebuild the bootloader.
+fn write_if_changed(path: &Path, contents: &[u8]) {
+    if fs::read(path).ok().as_deref() != Some(contents) {
+        fs::write(path, contents)
+            .unwrap_or_else(|_| panic!("Failed to write `{}`.", path.display()));
+    }
+}
+
+impl UefiBuilder {
+    /// Create boot manifest for `kernel`, the boot config and the initrd. Manifest describes
+    /// the decompressed kernel.
+    fn manifest(&self, kernel: &[u8]) -> Manifest {
+        let initrd = self.initrd_path.as_deref().map(initrd::create);
+
+        signing::create_manifest(self.signing_key.as_ref(), kernel,
+                                 self.command_line.as_deref().unwrap_or_default(),
+                                 initrd.as_deref().unwrap_or_default())
+    }
 
-        bootloader_file.truncate().unwrap();
-        bootloader_file.write_all(&bootloader).unwrap();
+    /// Read the bootloader EFI executable.
+    pub fn bootloader(&self) -> Vec<u8> {
+        fs::read(make_path!(self.bootloader_build_dir, "x86_64-unknown-uefi", "release",
+                            "uefi_bootloader.efi"))
+            .expect("Failed to read bootloader binary.")
     }
 }
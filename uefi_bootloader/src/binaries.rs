@@ -1,4 +1,4 @@
-/// ELF image of the kernel.
+/// ELF image of the kernel. It may be LZ4 compressed.
 #[cfg(feature = "with_kernel")]
 pub const KERNEL: &[u8] = include_bytes!(env!("FLUGZEUG_KERNEL_PATH"));
 
@@ -6,6 +6,14 @@ pub const KERNEL: &[u8] = include_bytes!(env!("FLUGZEUG_KERNEL_PATH"));
 #[cfg(not(feature = "with_kernel"))]
 pub const KERNEL: &[u8] = &[];
 
+/// Boot manifest which verifies the embedded kernel and payloads on the ESP.
+#[cfg(feature = "with_kernel")]
+pub const MANIFEST: &[u8] = include_bytes!(env!("FLUGZEUG_MANIFEST_PATH"));
+
+/// Boot manifest. It is stored on the ESP together with the kernel.
+#[cfg(not(feature = "with_kernel"))]
+pub const MANIFEST: &[u8] = &[];
+
 /// Realmode AP entrypoint.
 pub const AP_ENTRYPOINT: &[u8] = include_bytes!(
     concat!(env!("OUT_DIR"), "/ap_entrypoint.bin")
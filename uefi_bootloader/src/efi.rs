@@ -17,6 +17,13 @@ pub type AllocatePool = unsafe extern "efiapi" fn(
     buffer: &mut *mut u8,
 ) -> EfiStatus;
 
+pub type AllocatePages = unsafe extern "efiapi" fn(
+    allocation_type: u32,
+    memory_type:     EfiMemoryType,
+    pages:           usize,
+    memory:          &mut u64,
+) -> EfiStatus;
+
 pub type FreePool = unsafe extern "efiapi" fn(
     buffer: *mut u8,
 ) -> EfiStatus;
@@ -54,6 +61,39 @@ pub type TextString = unsafe extern "efiapi" fn (
     string:     *const u16,
 ) -> EfiStatus;
 
+pub type OpenVolume = unsafe extern "efiapi" fn(
+    this: *mut EfiSimpleFileSystemProtocol,
+    root: &mut *mut EfiFileProtocol,
+) -> EfiStatus;
+
+pub type FileOpen = unsafe extern "efiapi" fn(
+    this:       *mut EfiFileProtocol,
+    new_handle: &mut *mut EfiFileProtocol,
+    file_name:  *const u16,
+    open_mode:  u64,
+    attributes: u64,
+) -> EfiStatus;
+
+pub type FileClose = unsafe extern "efiapi" fn(
+    this: *mut EfiFileProtocol,
+) -> EfiStatus;
+
+pub type FileRead = unsafe extern "efiapi" fn(
+    this:        *mut EfiFileProtocol,
+    buffer_size: &mut usize,
+    buffer:      *mut u8,
+) -> EfiStatus;
+
+pub type FileGetPosition = unsafe extern "efiapi" fn(
+    this:     *mut EfiFileProtocol,
+    position: &mut u64,
+) -> EfiStatus;
+
+pub type FileSetPosition = unsafe extern "efiapi" fn(
+    this:     *mut EfiFileProtocol,
+    position: u64,
+) -> EfiStatus;
+
 #[repr(C)]
 pub struct EfiTableHeader {
     pub signature:   u64,
@@ -73,7 +113,7 @@ pub struct EfiBootServices {
     pub header:                                 EfiTableHeader,
     pub raise_tpl:                              usize,
     pub restore_tpl:                            usize,
-    pub allocate_pages:                         usize,
+    pub allocate_pages:                         AllocatePages,
     pub free_pages:                             usize,
     pub get_memory_map:                         GetMemoryMap,
     pub allocate_pool:                          AllocatePool,
@@ -174,12 +214,43 @@ pub struct EfiLoadedImageProtocol {
     pub unload:          usize,
 }
 
+#[repr(C)]
+pub struct EfiSimpleFileSystemProtocol {
+    pub revision:    u64,
+    pub open_volume: OpenVolume,
+}
+
+#[repr(C)]
+pub struct EfiFileProtocol {
+    pub revision:     u64,
+    pub open:         FileOpen,
+    pub close:        FileClose,
+    pub delete:       usize,
+    pub read:         FileRead,
+    pub write:        usize,
+    pub get_position: FileGetPosition,
+    pub set_position: FileSetPosition,
+    pub get_info:     usize,
+    pub set_info:     usize,
+    pub flush:        usize,
+}
+
+pub const EFI_FILE_MODE_READ: u64 = 1;
+
+pub const EFI_NOT_FOUND: EfiStatus = 0x8000000000000000 | 14;
+
 pub const EFI_LOADER_CODE:          u32 = 1;
 pub const EFI_LOADER_DATA:          u32 = 2;
 pub const EFI_BOOT_SERVICES_CODE:   u32 = 3;
 pub const EFI_BOOT_SERVICES_DATA:   u32 = 4;
 pub const EFI_CONVENTIONAL_MEMORY:  u32 = 7;
 
+/// OS defined memory type used for the initial ramdisk. Memory of this type is neither free
+/// nor boot memory so the kernel can keep using it.
+pub const FLUGZEUG_INITRD_MEMORY: u32 = 0x8000_0000;
+
+pub const ALLOCATE_MAX_ADDRESS: u32 = 1;
+
 pub type SetMode = unsafe extern "efiapi" fn(
     this: *mut EfiGraphicsOutputProtocol,
     mode: u32,
@@ -171,9 +171,10 @@ enter_kernel:
     ; Load kernel arguments.
     mov rdi, qword [rsp + 0x18]
     mov rsi, qword [rsp + 0x48]
+    mov rdx, qword [rsp + 0x30]
 
     ; Get the entrypoint of the kernel.
-    mov rdx, qword [rsp + 0x08]
+    mov rcx, qword [rsp + 0x08]
 
     ; Get the actual page tables used by the kernel.
     mov rax, qword [rsp + 0x20]
@@ -189,7 +190,7 @@ enter_kernel:
     sub rsp, 0x30
 
     ; Call the 64 bit kernel! (Jump cannot be used because of the ABI.)
-    call rdx
+    call rcx
 
 GDT_NULL equ 0x0000000000000000
 GDT_CODE equ 0x00209a0000000000
@@ -0,0 +1,272 @@
+use crate::lock::Lock;
+use crate::{efi, mm, binaries, BOOT_BLOCK};
+use efi::EfiGuid;
+
+use boot_block::{CommandLineBuffer, Initrd};
+use boot_block::{ESP_KERNEL_PATH as KERNEL_PATH, ESP_INITRD_PATH as INITRD_PATH,
+                 ESP_CONFIG_PATH as CONFIG_PATH, ESP_MANIFEST_PATH as MANIFEST_PATH};
+use integrity::Manifest;
+
+const MAX_PATH_LENGTH: usize = 256;
+
+/// Verified (and decompressed) kernel ELF image.
+static KERNEL: Lock<Option<&'static [u8]>> = Lock::new(None);
+
+/// Open root directory of the volume from which the bootloader was loaded.
+unsafe fn open_boot_volume(image_handle: usize, boot_services: &mut efi::EfiBootServices)
+    -> Option<*mut efi::EfiFileProtocol>
+{
+    const LOADED_IMAGE_PROTOCOL_GUID: EfiGuid =
+        EfiGuid(0x5B1B31A1, 0x9562, 0x11d2, [0x8E, 0x3F, 0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B]);
+    const SIMPLE_FILE_SYSTEM_PROTOCOL_GUID: EfiGuid =
+        EfiGuid(0x964e5b22, 0x6459, 0x11d2, [0x8e, 0x39, 0x00, 0xa0, 0xc9, 0x69, 0x72, 0x3b]);
+
+    let mut image = 0;
+
+    // Use EFI_OPEN_PROTOCOL_GET_PROTOCOL. In this case the caller is not required
+    // to call CloseProtocol afterwards.
+    let status = (boot_services.open_protocol)(image_handle, &LOADED_IMAGE_PROTOCOL_GUID,
+                                               &mut image, image_handle, 0, 2);
+    if  status != 0 {
+        println!("WARNING: Failed to get loaded image protocol with status {:x}.", status);
+        return None;
+    }
+
+    let device = (*(image as *const efi::EfiLoadedImageProtocol)).device;
+
+    let mut file_system = 0;
+
+    let status = (boot_services.open_protocol)(device, &SIMPLE_FILE_SYSTEM_PROTOCOL_GUID,
+                                               &mut file_system, image_handle, 0, 2);
+    if  status != 0 {
+        println!("WARNING: Boot device doesn't support simple file system protocol \
+                  (status {:x}).", status);
+        return None;
+    }
+
+    let file_system = file_system as *mut efi::EfiSimpleFileSystemProtocol;
+    let mut root    = core::ptr::null_mut();
+
+    let status = ((*file_system).open_volume)(file_system, &mut root);
+    if  status != 0 {
+        println!("WARNING: Failed to open boot volume with status {:x}.", status);
+        return None;
+    }
+
+    Some(root)
+}
+
+/// Open file at `path` (relative to the volume root) and get its size. Returns `None` if the
+/// file doesn't exist or cannot be opened.
+unsafe fn open_file(root: *mut efi::EfiFileProtocol, path: &str)
+    -> Option<(*mut efi::EfiFileProtocol, usize)>
+{
+    let mut wide_path = [0u16; MAX_PATH_LENGTH];
+
+    assert!(path.len() + 1 < MAX_PATH_LENGTH, "Path to the file on ESP is too long.");
+
+    // `path` is relative to the volume root and uses `/` as a separator. UEFI uses `\`.
+    let separator  = '\\' as u16;
+    let characters = path.encode_utf16()
+        .map(|character| if character == '/' as u16 { separator } else { character });
+
+    for (index, character) in core::iter::once(separator).chain(characters).enumerate() {
+        wide_path[index] = character;
+    }
+
+    let mut file = core::ptr::null_mut();
+
+    let status = ((*root).open)(root, &mut file, wide_path.as_ptr(), efi::EFI_FILE_MODE_READ, 0);
+    if  status != 0 {
+        if status != efi::EFI_NOT_FOUND {
+            println!("WARNING: Failed to open `{}` with status {:x}.", path, status);
+        }
+
+        return None;
+    }
+
+    // Seeking to the maximum possible position moves to the end of the file.
+    let mut size = 0;
+
+    assert_eq!(((*file).set_position)(file, !0), 0, "Failed to seek to the end of file.");
+    assert_eq!(((*file).get_position)(file, &mut size), 0, "Failed to get file size.");
+    assert_eq!(((*file).set_position)(file, 0), 0, "Failed to seek to the start of file.");
+
+    Some((file, size as usize))
+}
+
+/// Read the whole opened file to `buffer` and close it. Buffer size must be equal to the
+/// file size.
+unsafe fn read_and_close(file: *mut efi::EfiFileProtocol, buffer: &mut [u8], path: &str) {
+    let mut offset = 0;
+
+    while offset < buffer.len() {
+        let mut chunk_size = buffer.len() - offset;
+
+        let status = ((*file).read)(file, &mut chunk_size, buffer[offset..].as_mut_ptr());
+
+        assert!(status == 0 && chunk_size > 0, "Failed to read `{}` with status {:x}.",
+                path, status);
+
+        offset += chunk_size;
+    }
+
+    ((*file).close)(file);
+}
+
+/// Allocate `size` bytes of pool memory. Returned memory is never freed and will become
+/// usable by the kernel after the boot process.
+unsafe fn allocate_pool(boot_services: &mut efi::EfiBootServices, size: usize, name: &str)
+    -> &'static mut [u8]
+{
+    let mut buffer = core::ptr::NonNull::dangling().as_ptr();
+
+    if size > 0 {
+        let status = (boot_services.allocate_pool)(efi::EFI_LOADER_DATA, size, &mut buffer);
+
+        assert_eq!(status, 0, "Failed to allocate memory for `{}`.", name);
+    }
+
+    core::slice::from_raw_parts_mut(buffer, size)
+}
+
+/// Read the whole file at `path` into pool memory. Returns `None` if the file doesn't
+/// exist or cannot be read.
+unsafe fn read_file(root: *mut efi::EfiFileProtocol, boot_services: &mut efi::EfiBootServices,
+                    path: &str) -> Option<&'static [u8]> {
+    let (file, size) = open_file(root, path)?;
+
+    let buffer = allocate_pool(boot_services, size, path);
+
+    read_and_close(file, buffer, path);
+
+    Some(buffer)
+}
+
+/// Read the initial ramdisk into memory which won't be reclaimed by the kernel. Initrd
+/// must match `digest` from the boot manifest.
+unsafe fn load_initrd(root: *mut efi::EfiFileProtocol, boot_services: &mut efi::EfiBootServices,
+                      digest: &[u8; 32]) {
+    let (file, size) = match open_file(root, INITRD_PATH) {
+        Some((file, size)) if size > 0 => (file, size),
+        other                          => {
+            if let Some((file, _)) = other {
+                ((*file).close)(file);
+            }
+
+            // Make sure that the initrd wasn't removed.
+            bootlib::verify_payload("initrd", &[], digest);
+            return;
+        }
+    };
+
+    // Bootloader and kernel entry code can access only first 4GB of memory.
+    let mut phys_addr = mm::MAX_ADDRESS as u64;
+
+    let status = (boot_services.allocate_pages)(efi::ALLOCATE_MAX_ADDRESS,
+                                                efi::FLUGZEUG_INITRD_MEMORY,
+                                                (size + 0xfff) / 0x1000, &mut phys_addr);
+
+    assert_eq!(status, 0, "Failed to allocate memory for the initrd.");
+
+    let initrd = core::slice::from_raw_parts_mut(phys_addr as *mut u8, size);
+
+    read_and_close(file, initrd, INITRD_PATH);
+
+    bootlib::verify_payload("initrd", initrd, digest);
+
+    *BOOT_BLOCK.initrd.lock() = Some(Initrd {
+        phys_addr,
+        size: size as u64,
+    });
+
+    println!("Loaded initrd from the ESP: {} bytes at 0x{:x}.", size, phys_addr);
+}
+
+/// Load kernel, initrd and boot config from the EFI System Partition. Kernel and the boot
+/// manifest are loaded only if they weren't embedded in the bootloader. Kernel is
+/// decompressed if needed. All of them are verified using the boot manifest. Must be called
+/// before exiting boot services.
+pub unsafe fn load_boot_files(image_handle: usize, system_table: *mut efi::EfiSystemTable) {
+    let boot_services   = &mut *(*system_table).boot_services;
+    let kernel_embedded = !binaries::KERNEL.is_empty();
+
+    // Manifest is embedded together with the kernel.
+    let embedded_manifest = kernel_embedded.then(|| {
+        Manifest::from_bytes(binaries::MANIFEST).expect("Embedded boot manifest is invalid.")
+    });
+
+    // Boot volume is needed only if some payloads are stored on it.
+    let empty_digest = integrity::sha256(&[]);
+    let needs_volume = embedded_manifest.as_ref().map_or(true, |manifest| {
+        manifest.cmdline != empty_digest || manifest.initrd != empty_digest
+    });
+
+    let root = needs_volume.then(|| {
+        open_boot_volume(image_handle, boot_services)
+            .expect("Boot volume cannot be opened, boot payloads are stored on it.")
+    });
+
+    let manifest = embedded_manifest.unwrap_or_else(|| {
+        read_file(root.unwrap(), boot_services, MANIFEST_PATH)
+            .and_then(Manifest::from_bytes)
+            .expect("Boot manifest is missing or invalid.")
+    });
+
+    if bootlib::verify_manifest(&manifest) {
+        println!("Boot manifest signature is valid.");
+    }
+
+    let mut kernel = if kernel_embedded {
+        binaries::KERNEL
+    } else {
+        let kernel = read_file(root.unwrap(), boot_services, KERNEL_PATH)
+            .expect("Kernel is neither embedded in the bootloader nor present on the ESP.");
+
+        println!("Loaded kernel from the ESP: {} bytes.", kernel.len());
+
+        kernel
+    };
+
+    // Decompress the kernel if the host builder compressed it.
+    if let Some((compressed, size)) = bootlib::compressed_payload("kernel", kernel) {
+        let decompressed = allocate_pool(boot_services, size, "decompressed kernel");
+
+        bootlib::decompress_payload("kernel", compressed, decompressed);
+
+        println!("Decompressed kernel: {} -> {} bytes.", compressed.len(), size);
+
+        kernel = decompressed;
+    }
+
+    bootlib::verify_payload("kernel", kernel, &manifest.kernel);
+
+    *KERNEL.lock() = Some(kernel);
+
+    // Without the boot volume the manifest guarantees that there is no config and initrd.
+    let root = match root {
+        Some(root) => root,
+        None       => return,
+    };
+
+    let config = read_file(root, boot_services, CONFIG_PATH);
+
+    bootlib::verify_payload("boot config", config.unwrap_or(&[]), &manifest.cmdline);
+
+    if let Some(config) = config {
+        *BOOT_BLOCK.command_line.lock() = Some(CommandLineBuffer::new(config)
+                                               .expect("Boot config is too long."));
+
+        println!("Loaded boot config from the ESP: {} bytes.", config.len());
+    }
+
+    load_initrd(root, boot_services, &manifest.initrd);
+
+    ((*root).close)(root);
+}
+
+/// Get ELF image of the kernel. Embedded kernel takes precedence over the one loaded
+/// from the EFI System Partition. Must be called after `load_boot_files`.
+pub fn kernel() -> &'static [u8] {
+    KERNEL.lock().expect("Kernel wasn't loaded.")
+}
@@ -1,5 +1,6 @@
 use crate::{efi, framebuffer_resolutions, BOOT_BLOCK};
 use efi::EfiGuid;
+use cmdline::CommandLine;
 
 pub unsafe fn initialize(system_table: *mut efi::EfiSystemTable) {
     const EFI_GOP_GUID: EfiGuid =
@@ -24,6 +25,12 @@ pub unsafe fn initialize(system_table: *mut efi::EfiSystemTable) {
 
     let preferred_resolutions = framebuffer_resolutions::PREFERRED_RESOLUTIONS;
 
+    // Resolution requested on the command line takes priority over the preferred ones.
+    let requested_resolution = BOOT_BLOCK.command_line.lock()
+        .as_ref()
+        .and_then(|command_line| command_line.as_str())
+        .and_then(|command_line| CommandLine::new(command_line).get_resolution("resolution"));
+
     let mut best_mode  = None;
     let mut dense_mode = None;
 
@@ -54,7 +61,15 @@ pub unsafe fn initialize(system_table: *mut efi::EfiSystemTable) {
 
         let resolution = (info.horizontal_res, info.vertical_res);
 
-        if let Some(index) = preferred_resolutions.iter().position(|r| *r == resolution) {
+        let priority = if Some(resolution) == requested_resolution {
+            Some(0)
+        } else {
+            preferred_resolutions.iter()
+                .position(|r| *r == resolution)
+                .map(|index| index + 1)
+        };
+
+        if let Some(index) = priority {
             // Pick one with higher priority (lower index).
             let is_better = match best_mode {
                 Some((other_index, _)) => index < other_index,
@@ -97,6 +112,12 @@ pub unsafe fn initialize(system_table: *mut efi::EfiSystemTable) {
     // Inform the kernel about supported framebuffer modes.
     *BOOT_BLOCK.supported_modes.lock() = Some(supported_modes);
 
+    if let Some((width, height)) = requested_resolution {
+        if !matches!(best_mode, Some((0, _))) {
+            println!("WARNING: Requested resolution {}x{} is not supported.", width, height);
+        }
+    }
+
     // If we haven't found any of the preferred modes than pick one with highest pixel count.
     if best_mode.is_none() {
         best_mode = dense_mode.map(|(pixels, mode)| (pixels as usize, mode));
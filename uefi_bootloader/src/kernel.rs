@@ -1,23 +1,24 @@
 use core::convert::TryInto;
 
-use crate::{BOOT_BLOCK, ap_entrypoint, binaries, mm};
+use crate::{BOOT_BLOCK, ap_entrypoint, esp, mm};
 use mm::{BootPhysicalMemory, PhysicalMemory};
 use ap_entrypoint::APEntrypoint;
 
-use boot_block::{KERNEL_PHYSICAL_REGION_BASE, KERNEL_PHYSICAL_REGION_SIZE,
-                 KERNEL_STACK_BASE, KERNEL_STACK_SIZE, KERNEL_STACK_PADDING};
+use boot_block::{KERNEL_PHYSICAL_REGION_SIZE, KERNEL_STACK_BASE, KERNEL_STACK_SIZE,
+                 KERNEL_STACK_PADDING, KernelLayout};
 
-use page_table::{PageTable, PageType, VirtAddr, PAGE_PRESENT, PAGE_WRITE, PAGE_SIZE};
+use page_table::{PageTable, PageType, VirtAddr, PhysMem, PAGE_PRESENT, PAGE_WRITE, PAGE_SIZE};
 use elfparse::{Elf, Bitness, SegmentType, Machine};
 use crate::lock::Lock;
 
 #[derive(Copy, Clone)]
 struct KernelEntryData {
-    entrypoint:     u64,
-    kernel_cr3:     u64,
-    trampoline_cr3: u64,
-    trampoline_rsp: u64,
-    gdt:            u64,
+    entrypoint:      u64,
+    kernel_cr3:      u64,
+    trampoline_cr3:  u64,
+    trampoline_rsp:  u64,
+    gdt:             u64,
+    physical_region: u64,
 }
 
 /// Data required to enter the kernel. If it is `None` then kernel wasn't loaded yet.
@@ -58,18 +59,19 @@ fn create_kernel_stack() -> u64 {
     stack.0 + KERNEL_STACK_SIZE
 }
 
-fn create_trampoline_page_table(page_type: PageType) -> PageTable {
+fn create_trampoline_page_table(page_type: PageType, layout: &KernelLayout) -> PageTable {
     /// Map `phys_addr` at virtual address `phys_addr` and virtual address
-    /// `phys_addr` + `KERNEL_PHYSICAL_REGION_BASE`. This mapping will be both
+    /// `phys_addr` + `physical_region`. This mapping will be both
     /// writable and executable.
-    fn identity_map(page_table: &mut PageTable, phys_addr: u64, page_type: PageType) {
+    fn identity_map(page_table: &mut PageTable, phys_addr: u64, page_type: PageType,
+                    physical_region: u64) {
         let page_mask = page_type as u64 - 1;
 
         assert!(phys_addr & page_mask == 0, "Cannot map unaligned \
                 physical address {:x}.", phys_addr);
 
         for &virt_addr in &[VirtAddr(phys_addr),
-                            VirtAddr(phys_addr + KERNEL_PHYSICAL_REGION_BASE)] {
+                            VirtAddr(phys_addr + physical_region)] {
             assert!(virt_addr.0 & page_mask == 0, "Cannot map unaligned \
                     virtual address address {:x}.", virt_addr.0);
 
@@ -110,7 +112,8 @@ fn create_trampoline_page_table(page_type: PageType) -> PageTable {
 
     // Identity map first `TRAMPOLINE_PHYSICAL_REGION_SIZE` bytes of physical memory.
     for phys_addr in (0..TRAMPOLINE_PHYSICAL_REGION_SIZE).step_by(page_size as usize) {
-        identity_map(&mut trampoline_page_table, phys_addr, page_type);
+        identity_map(&mut trampoline_page_table, phys_addr, page_type,
+                     layout.physical_region_base);
     }
 
     let bootloader = mm::bootloader_image();
@@ -130,17 +133,22 @@ fn create_trampoline_page_table(page_type: PageType) -> PageTable {
         }
 
         // Map this page using small 4K pages.
-        identity_map(&mut trampoline_page_table, phys_addr, PageType::Page4K);
+        identity_map(&mut trampoline_page_table, phys_addr, PageType::Page4K,
+                     layout.physical_region_base);
     }
 
     trampoline_page_table
 }
 
-fn create_kernel_page_table(kernel: &Elf, page_type: PageType) -> PageTable {
+fn create_kernel_page_table(kernel: &Elf, page_type: PageType,
+                            layout: &KernelLayout) -> PageTable {
     // Allocate a page table that will be used by the kernel.
     let mut kernel_page_table = PageTable::new(&mut PhysicalMemory)
         .expect("Failed to allocate kernel page table.");
 
+    // Kernel can be loaded at a different base than the one it was linked at.
+    let bias = layout.kernel_base - kernel.base_address();
+
     // Map kernel to the virtual memory.
     kernel.segments(|segment| {
         // Skip non-loadable segments.
@@ -151,12 +159,13 @@ fn create_kernel_page_table(kernel: &Elf, page_type: PageType) -> PageTable {
         // Page table `map_init` function requires both address and size to be page aligned, but
         // segments in ELF files are often unaligned.
 
-        // Align virtual address down.
-        let virt_addr = VirtAddr(segment.virt_addr & !0xfff);
+        // Move the segment to the chosen kernel base and align virtual address down.
+        let segment_addr = segment.virt_addr + bias;
+        let virt_addr    = VirtAddr(segment_addr & !0xfff);
 
         // Calculate the number of bytes we have added in front of segment to satisfy alignemnt
         // requirements.
-        let front_padding = segment.virt_addr - virt_addr.0;
+        let front_padding = segment_addr - virt_addr.0;
 
         // Align virtual size up (accounting for front padding).
         let virt_size = (segment.virt_size + front_padding + 0xfff) & !0xfff;
@@ -190,20 +199,33 @@ fn create_kernel_page_table(kernel: &Elf, page_type: PageType) -> PageTable {
         ).expect("Failed to map kernel segment.");
     });
 
+    // Patch absolute addresses in the kernel so it can run at the chosen base.
+    kernel.relocate(layout.kernel_base, |virt_addr, value| {
+        for (index, &byte) in value.to_le_bytes().iter().enumerate() {
+            let phys_addr = kernel_page_table.virt_to_phys(&mut PhysicalMemory,
+                                                           VirtAddr(virt_addr + index as u64))
+                .expect("Kernel relocation points outside of the kernel image.");
+
+            unsafe {
+                *PhysicalMemory.translate(phys_addr, 1).unwrap() = byte;
+            }
+        }
+    }).expect("Kernel cannot be relocated.");
+
     // Create linear physical memory map used by the kernel.
     let page_size = page_type as u64;
     let page_mask = page_size - 1;
 
     // Make sure physical region address and size are properly aligned for used page type.
-    assert!(KERNEL_PHYSICAL_REGION_BASE & page_mask == 0,
-            "KERNEL_PHYSICAL_REGION_BASE is not aligned.");
+    assert!(layout.physical_region_base & page_mask == 0,
+            "Physical region base is not aligned.");
     assert!(KERNEL_PHYSICAL_REGION_SIZE & page_mask == 0,
             "KERNEL_PHYSICAL_REGION_SIZE is not aligned.");
 
     // Setup kernel physical memory map.
     for phys_addr in (0..KERNEL_PHYSICAL_REGION_SIZE).step_by(page_size as usize) {
-        // Map current `phys_addr` at virtual address  `phys_addr` + `KERNEL_PHYSICAL_REGION_BASE`.
-        let virt_addr = VirtAddr(phys_addr + KERNEL_PHYSICAL_REGION_BASE);
+        // Map current `phys_addr` at virtual address `phys_addr` + physical region base.
+        let virt_addr = VirtAddr(phys_addr + layout.physical_region_base);
 
         // This physical memory page will be both writable and executable. Unfortunately
         // we can't set NX bit because we will execute some code using this mapping
@@ -236,16 +258,20 @@ fn load_kernel() -> KernelEntryData {
     // allocations.
     let mut ap_entrypoint = unsafe { APEntrypoint::new() };
 
-    assert!(!binaries::KERNEL.is_empty(), "Tried to run UEFI bootloader built \
-            without `with_kernel` feature.");
-
     // Parse the kernel ELF file and make sure that it is 64 bit.
-    let kernel = Elf::parse(&binaries::KERNEL).expect("Failed to parse kernel ELF file.");
+    let kernel = Elf::parse(esp::kernel()).expect("Failed to parse kernel ELF file.");
     assert!(kernel.bitness() == Bitness::Bits64, "Loaded kernel is not 64 bit.");
     assert!(kernel.machine() == Machine::Amd64, "Loaded kernel is not an AMD64 binary.");
     assert!(kernel.base_address() == boot_block::KERNEL_BASE,
             "Loaded kernel has invalid base address.");
 
+    // Choose virtual addresses of the kernel and its memory regions.
+    let layout = bootlib::kernel_layout(BOOT_BLOCK.command_line.lock().as_ref(),
+                                        kernel.image_size());
+
+    *BOOT_BLOCK.kernel_layout.lock() = layout;
+    *NEXT_STACK_ADDRESS.lock()       = layout.stack_base;
+
     let features = cpu::get_features();
 
     // Determine max supported page size.
@@ -258,20 +284,20 @@ fn load_kernel() -> KernelEntryData {
     };
 
     // Bootloader uses identity physical memory map, but kernel will use linear physical
-    // memory map that starts at `KERNEL_PHYSICAL_REGION_BASE`.
+    // memory map that starts at `layout.physical_region_base`.
     // To be able to transition to the kernel we need to a allocate trampoline page table that
     // will map physical address 0 to virtual address 0 (like in bootloader) and
-    // physical address 0 to virtual address `KERNEL_PHYSICAL_REGION_SIZE` (like in kernel).
+    // physical address 0 to virtual address `layout.physical_region_base` (like in kernel).
 
     // Transition code will work like this:
     // 1. Bootloader executes `enter_kernel`. Enable long mode and setup paging with trampoline
     //    page table.
-    // 2. Jump to the next part of `enter_kernel`, but add `KERNEL_PHYSICAL_REGION_BASE`
+    // 2. Jump to the next part of `enter_kernel`, but add physical region base
     //    to RIP in order to use kernel-valid address.
     // 3. Switch to the actual kernel page tables, switch stack and jump to the kernel.
 
     // Create a page table that will be used when transitioning to the kernel.
-    let mut trampoline_page_table = create_trampoline_page_table(max_page_type);
+    let mut trampoline_page_table = create_trampoline_page_table(max_page_type, &layout);
 
     let gdt = mm::allocate_boot_memory(4096, 8)
         .expect("Failed to allocate GDT.");
@@ -286,7 +312,7 @@ fn load_kernel() -> KernelEntryData {
 
     // Create a page table that will be used by the kernel. It will already contain a
     // mapped in kernel.
-    let mut kernel_page_table = create_kernel_page_table(&kernel, max_page_type);
+    let mut kernel_page_table = create_kernel_page_table(&kernel, max_page_type, &layout);
 
     // Get bases of both page tables.
     let kernel_cr3     = kernel_page_table.table().0;
@@ -296,15 +322,19 @@ fn load_kernel() -> KernelEntryData {
         ap_entrypoint.finalize_and_register(trampoline_cr3);
     }
 
-    println!("Kernel base is 0x{:x}.", kernel.base_address());
-    println!("Kernel entrypoint is 0x{:x}.", kernel.entrypoint());
+    let entrypoint = kernel.entrypoint() - kernel.base_address() + layout.kernel_base;
+
+    println!("Kernel base is 0x{:x}{}.", layout.kernel_base,
+             if layout.randomized { " (randomized)" } else { "" });
+    println!("Kernel entrypoint is 0x{:x}.", entrypoint);
 
     let entry_data = KernelEntryData {
-        entrypoint: kernel.entrypoint(),
-        gdt:        gdt as u64,
+        entrypoint,
+        gdt:             gdt as u64,
         kernel_cr3,
         trampoline_cr3,
         trampoline_rsp,
+        physical_region: layout.physical_region_base,
     };
 
     // Cache entry data so APs can use them later to enter the kernel.
@@ -332,6 +362,6 @@ pub unsafe fn enter(boot_tsc: u64) -> ! {
     
     enter_kernel(entry_data.entrypoint, rsp, &BOOT_BLOCK as *const _ as u64,
                  entry_data.kernel_cr3, entry_data.trampoline_cr3,
-                 KERNEL_PHYSICAL_REGION_BASE, entry_data.gdt,
+                 entry_data.physical_region, entry_data.gdt,
                  entry_data.trampoline_rsp, boot_tsc);
 }
@@ -13,6 +13,7 @@ mod framebuffer;
 mod binaries;
 mod kernel;
 mod serial;
+mod esp;
 mod panic;
 mod lock;
 mod efi;
@@ -51,7 +52,11 @@ extern fn efi_main(image_handle: usize, system_table: *mut efi::EfiSystemTable)
             // Get addresses of ACPI tables.
             acpi_locator::locate(system_table);
 
-            // Try to initialize framebuffer device.
+            // Load kernel (if it's not embedded in the bootloader) and boot config from
+            // the ESP. Files can be read only while boot services are available.
+            esp::load_boot_files(image_handle, system_table);
+
+            // Try to initialize framebuffer device. Uses resolution from the boot config.
             framebuffer::initialize(system_table);
 
             mm::initialize_and_exit_boot_services(image_handle, system_table);
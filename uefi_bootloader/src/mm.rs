@@ -212,6 +212,7 @@ pub unsafe fn initialize_and_exit_boot_services(image_handle: usize,
                     // Memory which can be freed after we have finished boot process.
                     Some(&mut boot_memory)
                 }
+                // This also skips `FLUGZEUG_INITRD_MEMORY` so initrd is never reused.
                 _ => None,
             };
 